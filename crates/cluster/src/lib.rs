@@ -20,9 +20,10 @@ pub enum ClusterMessage {
         templates_count: u32,
     },
     NewTemplate {
+        id: u32,
         pattern: String,
     },
-    SyncState(Vec<String>), // List of patterns
+    SyncState(Vec<(u32, String)>), // List of (template ID, pattern)
 }
 
 pub struct ClusterManager {
@@ -30,9 +31,9 @@ pub struct ClusterManager {
     bind_addr: SocketAddr,
     peers: Arc<RwLock<HashMap<SocketAddr, PeerInfo>>>,
     known_peers: Vec<String>,
-    pub template_tx: mpsc::Sender<String>,
-    template_rx: Arc<RwLock<mpsc::Receiver<String>>>,
-    ext_template_tx: broadcast::Sender<String>,
+    pub template_tx: mpsc::Sender<(u32, String)>,
+    template_rx: Arc<RwLock<mpsc::Receiver<(u32, String)>>>,
+    ext_template_tx: broadcast::Sender<(u32, String)>,
 }
 
 impl ClusterManager {
@@ -40,7 +41,7 @@ impl ClusterManager {
         node_id: String,
         bind_addr: SocketAddr,
         initial_peers: Vec<String>,
-        ext_template_tx: broadcast::Sender<String>,
+        ext_template_tx: broadcast::Sender<(u32, String)>,
     ) -> Self {
         let (tx, rx) = mpsc::channel(100);
         Self {
@@ -94,9 +95,9 @@ impl ClusterManager {
         let peers_prop = peers.clone();
         tokio::spawn(async move {
             let mut rx = template_rx.write().await;
-            while let Some(pattern) = rx.recv().await {
-                debug!("Propagating new template: {}", pattern);
-                let msg = ClusterMessage::NewTemplate { pattern };
+            while let Some((id, pattern)) = rx.recv().await {
+                debug!("Propagating new template {}: {}", id, pattern);
+                let msg = ClusterMessage::NewTemplate { id, pattern };
                 if let Ok(bytes) = serde_json::to_vec(&msg) {
                     let peers_lock = peers_prop.read().await;
                     for addr in peers_lock.keys() {
@@ -127,9 +128,9 @@ impl ClusterManager {
                         },
                     );
                 }
-                Ok(ClusterMessage::NewTemplate { pattern }) => {
-                    debug!("Received new template from {}: {}", addr, pattern);
-                    let _ = ext_template_tx.send(pattern);
+                Ok(ClusterMessage::NewTemplate { id, pattern }) => {
+                    debug!("Received new template {} from {}: {}", id, addr, pattern);
+                    let _ = ext_template_tx.send((id, pattern));
                 }
                 _ => {}
             }
@@ -9,6 +9,7 @@ pub struct IngestionServer {
     tcp_addr: String,
     beep_addr: String,
     tx: mpsc::Sender<SyslogMessage>,
+    lossless: bool,
 }
 
 impl IngestionServer {
@@ -23,13 +24,21 @@ impl IngestionServer {
             tcp_addr,
             beep_addr,
             tx,
+            lossless: false,
         }
     }
 
+    /// Keeps the received text of every message (see
+    /// [`UnifiedParser::parse_lossless`]).
+    pub fn lossless(mut self, lossless: bool) -> Self {
+        self.lossless = lossless;
+        self
+    }
+
     pub async fn run(self) -> anyhow::Result<()> {
         tokio::try_join!(
-            Self::run_udp(self.udp_addr, self.tx.clone()),
-            Self::run_tcp(self.tcp_addr, self.tx.clone()),
+            Self::run_udp(self.udp_addr, self.tx.clone(), self.lossless),
+            Self::run_tcp(self.tcp_addr, self.tx.clone(), self.lossless),
             Self::run_beep(self.beep_addr, self.tx)
         )?;
         Ok(())
@@ -45,7 +54,19 @@ impl IngestionServer {
         }
     }
 
-    async fn run_udp(addr: String, tx: mpsc::Sender<SyslogMessage>) -> anyhow::Result<()> {
+    fn parse(data: &[u8], lossless: bool) -> anyhow::Result<SyslogMessage> {
+        if lossless {
+            UnifiedParser::parse_lossless(data)
+        } else {
+            UnifiedParser::parse(&String::from_utf8_lossy(data))
+        }
+    }
+
+    async fn run_udp(
+        addr: String,
+        tx: mpsc::Sender<SyslogMessage>,
+        lossless: bool,
+    ) -> anyhow::Result<()> {
         let socket = UdpSocket::bind(&addr).await?;
         info!("UDP listener started on {}", addr);
         let mut buf = [0u8; 65535];
@@ -54,7 +75,7 @@ impl IngestionServer {
             let (len, _) = socket.recv_from(&mut buf).await?;
             let data = String::from_utf8_lossy(&buf[..len]);
             debug!("UDP received: {}", data.trim());
-            match UnifiedParser::parse(&data) {
+            match Self::parse(&buf[..len], lossless) {
                 Ok(msg) => {
                     let _ = tx.send(msg).await;
                 }
@@ -69,7 +90,11 @@ impl IngestionServer {
         }
     }
 
-    async fn run_tcp(addr: String, tx: mpsc::Sender<SyslogMessage>) -> anyhow::Result<()> {
+    async fn run_tcp(
+        addr: String,
+        tx: mpsc::Sender<SyslogMessage>,
+        lossless: bool,
+    ) -> anyhow::Result<()> {
         let listener = TcpListener::bind(&addr).await?;
         info!("TCP listener started on {}", addr);
 
@@ -106,7 +131,7 @@ impl IngestionServer {
                             if reader.read_exact(&mut msg_buf).await.is_ok() {
                                 let data = String::from_utf8_lossy(&msg_buf);
                                 debug!("TCP (Octet) received: {}", data.trim());
-                                match UnifiedParser::parse(&data) {
+                                match Self::parse(&msg_buf, lossless) {
                                     Ok(msg) => {
                                         let _ = tx_clone.send(msg).await;
                                     }
@@ -129,7 +154,13 @@ impl IngestionServer {
                             msg_bytes.extend(line);
                             let data = String::from_utf8_lossy(&msg_bytes);
                             debug!("TCP (Delimited) received: {}", data.trim());
-                            match UnifiedParser::parse(data.trim_end()) {
+                            // Only the line terminator is framing; trailing
+                            // blanks belong to the message.
+                            let end = msg_bytes
+                                .iter()
+                                .rposition(|b| !matches!(b, b'\r' | b'\n'))
+                                .map_or(0, |i| i + 1);
+                            match Self::parse(&msg_bytes[..end], lossless) {
                                 Ok(msg) => {
                                     let _ = tx_clone.send(msg).await;
                                 }
@@ -1,5 +1,6 @@
 pub mod rfc3164;
 pub mod rfc5424;
+pub mod structured_data;
 
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
@@ -19,6 +20,8 @@ pub struct SyslogMessage {
     pub message: String,
     pub is_rfc5424: bool,
     pub node_id: Option<String>,
+    /// The bytes exactly as received, kept only when parsing losslessly.
+    pub raw: Option<Vec<u8>>,
 }
 
 pub struct UnifiedParser;
@@ -43,4 +46,13 @@ impl UnifiedParser {
         debug!("Attempting RFC 3164 parse");
         rfc3164::RFC3164Parser::parse(input)
     }
+
+    /// Like [`UnifiedParser::parse`] on `input` read as UTF-8 (invalid
+    /// sequences replaced), also keeping the bytes untouched in `raw` so they
+    /// can be stored byte for byte.
+    pub fn parse_lossless(input: &[u8]) -> anyhow::Result<SyslogMessage> {
+        let mut msg = Self::parse(&String::from_utf8_lossy(input))?;
+        msg.raw = Some(input.to_vec());
+        Ok(msg)
+    }
 }
@@ -32,6 +32,7 @@ impl RFC3164Parser {
             message: msg.to_string(),
             is_rfc5424: false,
             node_id: None,
+            raw: None,
         })
     }
 }
@@ -2,7 +2,7 @@ use crate::SyslogMessage;
 use chrono::{DateTime, Utc};
 use nom::{
     IResult,
-    bytes::complete::{tag, take_until, take_while},
+    bytes::complete::{tag, take_while},
     character::complete::{digit1, space0, space1},
     combinator::map_res,
     sequence::delimited,
@@ -32,6 +32,7 @@ impl RFC5424Parser {
             message: msg.to_string(),
             is_rfc5424: true,
             node_id: None,
+            raw: None,
         })
     }
 }
@@ -66,6 +67,29 @@ fn parse_string_or_nil(input: &str) -> IResult<&str, Option<String>> {
     }
 }
 
+/// Takes every SD-ELEMENT at the start of `input`, skipping `]` inside
+/// quoted values, and returns them without the first `[` and the last `]`.
+fn parse_sd_elements(input: &str) -> IResult<&str, &str> {
+    let (rest, _) = tag("[")(input)?;
+    let mut in_value = false;
+    let mut escaped = false;
+    for (i, c) in rest.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_value => escaped = true,
+            '"' => in_value = !in_value,
+            ']' if !in_value && !rest[i + 1..].starts_with('[') => {
+                return Ok((&rest[i + 1..], &rest[..i]));
+            }
+            _ => {}
+        }
+    }
+    Err(nom::Err::Error(nom::error::Error::new(
+        input,
+        nom::error::ErrorKind::TakeUntil,
+    )))
+}
+
 type RFC5424Header<'a> = (
     u8, // pri
     u8, // version
@@ -98,9 +122,7 @@ fn parse_rfc5424(input: &str) -> IResult<&str, RFC5424Header<'_>> {
         let (input, _) = tag("-")(input)?;
         (input, None)
     } else {
-        let (input, _) = tag("[")(input)?;
-        let (input, content) = take_until("]")(input)?;
-        let (input, _) = tag("]")(input)?;
+        let (input, content) = parse_sd_elements(input)?;
         (input, Some(content.to_string()))
     };
 
@@ -139,4 +161,13 @@ mod tests {
         assert_eq!(msg.structured_data.unwrap(), "exampleSDID@32473 iut=\"3\"");
         assert_eq!(msg.message, "Message with SD");
     }
+
+    #[test]
+    fn test_parse_rfc5424_multiple_sd_elements() {
+        let input =
+            r#"<34>1 2003-10-11T22:14:15.003Z myhost myapp 1234 ID47 [a x="]"][b@1 y="2"] Body"#;
+        let msg = RFC5424Parser::parse(input).unwrap();
+        assert_eq!(msg.structured_data.unwrap(), r#"a x="]"][b@1 y="2""#);
+        assert_eq!(msg.message, "Body");
+    }
 }
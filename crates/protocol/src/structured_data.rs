@@ -0,0 +1,119 @@
+/// One SD-ELEMENT, with unescaped parameter values.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SdElement {
+    pub id: String,
+    pub params: Vec<(String, String)>,
+}
+
+/// Splits structured data, as held in
+/// [`SyslogMessage::structured_data`](crate::SyslogMessage::structured_data)
+/// (the SD-ELEMENTs without the first `[` and the last `]`), into its
+/// elements. `None` if it is malformed.
+pub fn parse(sd: &str) -> Option<Vec<SdElement>> {
+    let text = format!("[{}]", sd);
+    let mut rest = text.as_str();
+    let mut elements = Vec::new();
+    while !rest.is_empty() {
+        rest = rest.strip_prefix('[')?;
+        let end = rest.find([' ', ']'])?;
+        let id = &rest[..end];
+        if !is_name(id) {
+            return None;
+        }
+        rest = &rest[end..];
+
+        let mut params = Vec::new();
+        while let Some(param) = rest.strip_prefix(' ') {
+            let (name, value) = param.split_once('=')?;
+            if !is_name(name) {
+                return None;
+            }
+            let value = value.strip_prefix('"')?;
+            let mut unescaped = String::new();
+            let mut escaped = false;
+            let mut close = None;
+            for (i, c) in value.char_indices() {
+                if escaped {
+                    // Only these three are escapes; other backslashes are
+                    // literal.
+                    if !matches!(c, '"' | '\\' | ']') {
+                        unescaped.push('\\');
+                    }
+                    unescaped.push(c);
+                    escaped = false;
+                } else if c == '\\' {
+                    escaped = true;
+                } else if c == '"' {
+                    close = Some(i);
+                    break;
+                } else {
+                    unescaped.push(c);
+                }
+            }
+            params.push((name.to_string(), unescaped));
+            rest = &value[close? + 1..];
+        }
+        rest = rest.strip_prefix(']')?;
+        elements.push(SdElement {
+            id: id.to_string(),
+            params,
+        });
+    }
+    Some(elements)
+}
+
+/// Writes `elements` back in the form [`parse`] reads, escaping values.
+pub fn render(elements: &[SdElement]) -> String {
+    let mut out = String::new();
+    for (i, element) in elements.iter().enumerate() {
+        if i > 0 {
+            out.push_str("][");
+        }
+        out.push_str(&element.id);
+        for (name, value) in &element.params {
+            out.push(' ');
+            out.push_str(name);
+            out.push_str("=\"");
+            for c in value.chars() {
+                if matches!(c, '"' | '\\' | ']') {
+                    out.push('\\');
+                }
+                out.push(c);
+            }
+            out.push('"');
+        }
+    }
+    out
+}
+
+/// SD-NAME: printable ASCII except `=`, space, `]` and `"`.
+fn is_name(s: &str) -> bool {
+    !s.is_empty()
+        && s.bytes()
+            .all(|b| b.is_ascii_graphic() && !matches!(b, b'=' | b']' | b'"'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_render_elements() {
+        let sd = r#"exampleSDID@32473 iut="3" eventID="1011"][meta note="a \"b\" \] c""#;
+        let elements = parse(sd).unwrap();
+        assert_eq!(elements.len(), 2);
+        assert_eq!(elements[0].id, "exampleSDID@32473");
+        assert_eq!(elements[0].params[1], ("eventID".into(), "1011".into()));
+        assert_eq!(elements[1].params[0].1, r#"a "b" ] c"#);
+        assert_eq!(render(&elements), sd);
+
+        // A literal backslash parses, but renders in its escaped form.
+        let literal = parse(r#"x k="a\b""#).unwrap();
+        assert_eq!(literal[0].params[0].1, r"a\b");
+        assert_eq!(render(&literal), r#"x k="a\\b""#);
+
+        assert!(parse("x k=1").is_none());
+        assert!(parse(r#"x k="1"#).is_none());
+        assert!(parse("").is_none());
+    }
+}
@@ -0,0 +1,345 @@
+use crate::StorageEngine;
+use crate::compression::{BlockDictionaries, BlockEncoder, CompressionConfig};
+use crate::dictionary::{self, TemplateDictionary};
+use crate::encryption::Keyring;
+use crate::index::ChunkIndex;
+use crate::logshrink::LogChunk;
+use crate::miner::{MinerConfig, TemplateMiner};
+use crate::reader::{ColumnSet, ReadMode};
+use crate::retention::container_size;
+use crate::segment::Manifest;
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tracing::info;
+
+/// How containers are compacted.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompactionConfig {
+    /// Records per merged chunk.
+    pub chunk_records: usize,
+    pub compression: CompressionConfig,
+    /// How templates are re-mined; one miner is shared by all the merged
+    /// chunks of a container.
+    pub miner: MinerConfig,
+}
+
+impl Default for CompactionConfig {
+    fn default() -> Self {
+        Self {
+            chunk_records: 100_000,
+            compression: CompressionConfig::default(),
+            miner: MinerConfig::default(),
+        }
+    }
+}
+
+/// Outcome of compacting one container.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompactionReport {
+    pub path: PathBuf,
+    pub records: usize,
+    pub chunks_before: usize,
+    pub chunks_after: usize,
+    /// Distinct template IDs referenced before and after re-mining.
+    pub templates_before: usize,
+    pub templates_after: usize,
+    /// Size of the container and its sidecars.
+    pub bytes_before: u64,
+    pub bytes_after: u64,
+}
+
+/// Merges the chunks of the container at `path` into chunks of
+/// `config.chunk_records` records, mines templates again over each merged
+/// chunk, and atomically replaces the container.
+///
+/// Chunks that reference a store dictionary keep doing so: newly mined
+/// templates are added to `dictionary`, or to the dictionary the chunks name
+/// when `None`. A writer may keep adding templates to a shared `dictionary`
+/// meanwhile; it is only locked while a merged chunk is mined. Sealed frames
+/// are opened, and the new ones sealed, with `keys`.
+pub fn compact_file(
+    path: &Path,
+    dictionary: Option<&Mutex<TemplateDictionary>>,
+    config: &CompactionConfig,
+    keys: Option<&Keyring>,
+) -> anyhow::Result<CompactionReport> {
+    let mut report = CompactionReport {
+        path: path.to_path_buf(),
+        records: 0,
+        chunks_before: 0,
+        chunks_after: 0,
+        templates_before: 0,
+        templates_after: 0,
+        bytes_before: container_size(path),
+        bytes_after: 0,
+    };
+
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".compact");
+    let tmp = PathBuf::from(tmp);
+    remove_container(&tmp)?;
+    // Keep the existing zstd dictionaries so readers holding the old
+    // container can still decode it while the new one is swapped in.
+    let zdict = BlockDictionaries::sidecar_path(path);
+    if zdict.exists() {
+        fs::copy(&zdict, BlockDictionaries::sidecar_path(&tmp))?;
+    }
+
+    let mut compactor = Compactor {
+        tmp: tmp.to_string_lossy().into_owned(),
+        encoder: BlockEncoder::new(config.compression.clone()).with_keys(keys),
+        dictionary: dictionary.map(Dictionary::Shared),
+        miner: TemplateMiner::new(config.miner.clone()),
+        templates: HashSet::new(),
+        chunks: 0,
+    };
+    let mut templates_before = HashSet::new();
+    let mut pending: Option<LogChunk> = None;
+    let mut pending_records = 0;
+
+    let result = (|| -> anyhow::Result<()> {
+        let chunks = StorageEngine::iter_columns(path, ColumnSet::ALL, ReadMode::Buffered, keys)?;
+        for columns in chunks {
+            let columns = columns?;
+            report.chunks_before += 1;
+            report.records += columns.len;
+            templates_before.extend(columns.template_ids.iter().flatten().copied());
+            if compactor.dictionary.is_none()
+                && let Some(name) = &columns.dictionary
+            {
+                let dir = path.parent().unwrap_or(Path::new("."));
+                let opened = TemplateDictionary::open(dir.join(name), keys)?;
+                compactor.dictionary = Some(Dictionary::Owned(opened));
+            }
+
+            for record in columns.records() {
+                pending
+                    .get_or_insert_with(|| compactor.new_chunk())
+                    .add_message(record.to_syslog());
+                pending_records += 1;
+                if pending_records >= config.chunk_records {
+                    compactor.save(pending.take().unwrap_or_default())?;
+                    pending_records = 0;
+                }
+            }
+        }
+        if let Some(chunk) = pending.take() {
+            compactor.save(chunk)?;
+        }
+        Ok(())
+    })();
+    if let Err(e) = result {
+        remove_container(&tmp)?;
+        return Err(e);
+    }
+
+    report.chunks_after = compactor.chunks;
+    report.templates_before = templates_before.len();
+    report.templates_after = compactor.templates.len();
+    if report.chunks_after == 0 {
+        // Nothing readable to keep; leave the original alone.
+        remove_container(&tmp)?;
+        report.bytes_after = report.bytes_before;
+        return Ok(report);
+    }
+
+    replace(&BlockDictionaries::sidecar_path(&tmp), &zdict)?;
+    fs::rename(&tmp, path)?;
+    fs::rename(
+        ChunkIndex::sidecar_path(&tmp),
+        ChunkIndex::sidecar_path(path),
+    )?;
+    report.bytes_after = container_size(path);
+    Ok(report)
+}
+
+/// Compacts every segment listed in `manifest` except `active` and those
+/// already compacted, marking each one in the manifest.
+pub fn compact_segments(
+    dir: &Path,
+    manifest: &mut Manifest,
+    active: Option<&str>,
+    dictionary: Option<&Mutex<TemplateDictionary>>,
+    config: &CompactionConfig,
+    keys: Option<&Keyring>,
+) -> anyhow::Result<Vec<CompactionReport>> {
+    let mut reports = Vec::new();
+    for segment in &mut manifest.segments {
+        if segment.compacted || active == Some(segment.file.as_str()) {
+            continue;
+        }
+        let report = compact_file(&dir.join(&segment.file), dictionary, config, keys)?;
+        info!(
+            "Compacted {}: {} -> {} chunks, {} -> {} templates, {} -> {} bytes",
+            segment.file,
+            report.chunks_before,
+            report.chunks_after,
+            report.templates_before,
+            report.templates_after,
+            report.bytes_before,
+            report.bytes_after
+        );
+        segment.compacted = true;
+        reports.push(report);
+    }
+    Ok(reports)
+}
+
+pub(crate) enum Dictionary<'a> {
+    Shared(&'a Mutex<TemplateDictionary>),
+    Owned(TemplateDictionary),
+}
+
+impl Dictionary<'_> {
+    /// Runs `f` on the dictionary, holding a shared one's lock for the call.
+    pub(crate) fn with<R>(&mut self, f: impl FnOnce(&mut TemplateDictionary) -> R) -> R {
+        match self {
+            Dictionary::Shared(d) => f(&mut dictionary::lock(d)),
+            Dictionary::Owned(d) => f(d),
+        }
+    }
+}
+
+/// Writes merged chunks into the temporary container.
+struct Compactor<'a> {
+    tmp: String,
+    encoder: BlockEncoder,
+    dictionary: Option<Dictionary<'a>>,
+    miner: TemplateMiner,
+    templates: HashSet<u32>,
+    chunks: usize,
+}
+
+impl Compactor<'_> {
+    fn new_chunk(&mut self) -> LogChunk {
+        let mut chunk = match &mut self.dictionary {
+            Some(d) => d.with(|d| d.new_chunk()),
+            None => LogChunk::new(),
+        };
+        chunk.miner = std::mem::take(&mut self.miner);
+        chunk
+    }
+
+    fn save(&mut self, mut chunk: LogChunk) -> anyhow::Result<()> {
+        match &mut self.dictionary {
+            Some(d) => d.with(|d| -> anyhow::Result<()> {
+                d.process(&mut chunk)?;
+                d.persist()
+            })?,
+            None => {
+                chunk.finish_and_process();
+            }
+        }
+        self.miner = std::mem::take(&mut chunk.miner);
+        let meta = StorageEngine::save_chunk_with(&chunk, &self.tmp, &mut self.encoder)?;
+        self.templates.extend(meta.template_ids);
+        self.chunks += 1;
+        Ok(())
+    }
+}
+
+pub(crate) fn remove_container(path: &Path) -> anyhow::Result<()> {
+    for p in [
+        path.to_path_buf(),
+        ChunkIndex::sidecar_path(path),
+        BlockDictionaries::sidecar_path(path),
+    ] {
+        match fs::remove_file(&p) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(())
+}
+
+/// Renames `from` over `to`, or removes `to` if `from` does not exist.
+pub(crate) fn replace(from: &Path, to: &Path) -> anyhow::Result<()> {
+    if from.exists() {
+        fs::rename(from, to)?;
+        return Ok(());
+    }
+    match fs::remove_file(to) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+    use sankshepa_protocol::SyslogMessage;
+
+    fn msg(text: String) -> SyslogMessage {
+        SyslogMessage {
+            priority: 34,
+            facility: 4,
+            severity: 2,
+            timestamp: Some(Utc::now()),
+            hostname: Some("host".to_string()),
+            app_name: Some("sshd".to_string()),
+            procid: None,
+            msgid: None,
+            structured_data: None,
+            message: text,
+            is_rfc5424: true,
+            node_id: None,
+            raw: None,
+        }
+    }
+
+    #[test]
+    fn test_compaction_merges_chunks_and_templates() {
+        let dir = std::env::temp_dir().join("sankshepa_test_compaction");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("logs.lshrink");
+        let mut dictionary = TemplateDictionary::open(dir.join("logs.templates"), None).unwrap();
+
+        // One record per chunk: every message becomes its own template.
+        let mut expected = Vec::new();
+        for i in 0..20 {
+            let text = format!("User user{} failed login from IP 192.168.1.{}", i, i);
+            let mut chunk = dictionary.new_chunk();
+            chunk.add_message(msg(text.clone()));
+            chunk.finish_and_process();
+            dictionary.absorb(&chunk).unwrap();
+            dictionary.persist().unwrap();
+            StorageEngine::save_chunk(&chunk, &path.to_string_lossy()).unwrap();
+            expected.push(text);
+        }
+
+        let config = CompactionConfig {
+            chunk_records: 8,
+            ..Default::default()
+        };
+        let dictionary = Mutex::new(dictionary);
+        let report = compact_file(&path, Some(&dictionary), &config, None).unwrap();
+        assert_eq!(report.records, 20);
+        assert_eq!(report.chunks_before, 20);
+        assert_eq!(report.chunks_after, 3);
+        assert_eq!(report.templates_before, 20);
+        assert_eq!(report.templates_after, 1);
+        assert!(report.bytes_after < report.bytes_before);
+
+        let mut messages = Vec::new();
+        for columns in
+            StorageEngine::iter_columns(&path, ColumnSet::ALL, ReadMode::Buffered, None).unwrap()
+        {
+            let columns = columns.unwrap();
+            assert_eq!(columns.dictionary.as_deref(), Some("logs.templates"));
+            messages.extend(columns.records().filter_map(|r| r.message()));
+        }
+        messages.sort();
+        expected.sort();
+        assert_eq!(messages, expected);
+        assert!(!ChunkIndex::sidecar_path(dir.join("logs.lshrink.compact")).exists());
+        assert_eq!(ChunkIndex::load(&path, None).unwrap().entries.len(), 3);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,361 @@
+use crate::container::{self, FrameReader};
+use crate::encryption::{Keyring, SealError};
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use tracing::{debug, warn};
+use zstd::bulk::Compressor;
+use zstd::dict::DecoderDictionary;
+use zstd::stream::{Decoder, decode_all, encode_all};
+
+/// Magic bytes at the start of a `.lshrink.zdict` sidecar.
+pub const ZDICT_MAGIC: [u8; 4] = *b"LSHZ";
+
+/// Upper bound on the size of a trained dictionary.
+const MAX_DICTIONARY_BYTES: usize = 16 * 1024;
+/// Upper bound on the raw block bytes kept for training.
+const MAX_SAMPLE_BYTES: usize = 1024 * 1024;
+
+/// Compressed blocks of a chunk, for per-column compression levels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Column {
+    Timestamp,
+    Priority,
+    Hostname,
+    AppName,
+    ProcId,
+    MsgId,
+    StructuredData,
+    TemplateId,
+    VariableCount,
+    Variables,
+    IsRfc5424,
+    NodeId,
+    StringPool,
+    Layout,
+}
+
+impl FromStr for Column {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "timestamp" => Column::Timestamp,
+            "priority" => Column::Priority,
+            "hostname" => Column::Hostname,
+            "app_name" => Column::AppName,
+            "procid" => Column::ProcId,
+            "msgid" => Column::MsgId,
+            "structured_data" => Column::StructuredData,
+            "template_id" => Column::TemplateId,
+            "variable_count" => Column::VariableCount,
+            "variables" => Column::Variables,
+            "is_rfc5424" => Column::IsRfc5424,
+            "node_id" => Column::NodeId,
+            "string_pool" => Column::StringPool,
+            "layout" => Column::Layout,
+            other => return Err(format!("unknown column '{}'", other)),
+        })
+    }
+}
+
+/// A `COLUMN=LEVEL` override, or `default=LEVEL` for every other column.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ColumnLevel {
+    pub column: Option<Column>,
+    pub level: i32,
+}
+
+impl FromStr for ColumnLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (column, level) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected COLUMN=LEVEL, got '{}'", s))?;
+        let level: i32 = level
+            .parse()
+            .map_err(|_| format!("invalid compression level '{}'", level))?;
+        if !zstd::compression_level_range().contains(&level) {
+            return Err(format!("compression level {} out of range", level));
+        }
+        let column = match column {
+            "default" => None,
+            name => Some(name.parse()?),
+        };
+        Ok(Self { column, level })
+    }
+}
+
+/// How chunk blocks are compressed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompressionConfig {
+    pub default_level: i32,
+    pub levels: HashMap<Column, i32>,
+    /// Number of chunks to sample before training a zstd dictionary; 0
+    /// disables dictionaries.
+    pub dictionary_chunks: usize,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            default_level: 3,
+            levels: HashMap::new(),
+            dictionary_chunks: 0,
+        }
+    }
+}
+
+impl CompressionConfig {
+    /// Applies `COLUMN=LEVEL` overrides on top of the defaults.
+    pub fn with_levels(mut self, overrides: &[ColumnLevel]) -> Self {
+        for o in overrides {
+            match o.column {
+                Some(column) => {
+                    self.levels.insert(column, o.level);
+                }
+                None => self.default_level = o.level,
+            }
+        }
+        self
+    }
+
+    pub fn level(&self, column: Column) -> i32 {
+        self.levels
+            .get(&column)
+            .copied()
+            .unwrap_or(self.default_level)
+    }
+}
+
+/// A trained zstd dictionary. The ID is the one zstd embeds in the
+/// dictionary header, so it identifies the content across segments.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BlockDictionary {
+    pub id: u32,
+    pub bytes: Vec<u8>,
+}
+
+impl BlockDictionary {
+    fn train(samples: &VecDeque<Vec<u8>>) -> anyhow::Result<Self> {
+        let total: usize = samples.iter().map(Vec::len).sum();
+        let samples: Vec<&[u8]> = samples.iter().map(Vec::as_slice).collect();
+        let bytes = zstd::dict::from_samples(&samples, MAX_DICTIONARY_BYTES.min(total / 4))?;
+        let id = match bytes.get(4..8) {
+            Some(id) => u32::from_le_bytes(id.try_into()?),
+            None => return Err(anyhow::anyhow!("Trained dictionary is too short")),
+        };
+        Ok(Self { id, bytes })
+    }
+}
+
+/// Compresses the blocks of successive chunks, training a dictionary from
+/// the first `dictionary_chunks` chunks and using it for the ones after.
+/// Frames written with the encoder are sealed with its keys, if any.
+#[derive(Default)]
+pub struct BlockEncoder {
+    config: CompressionConfig,
+    keys: Option<Keyring>,
+    dictionary: Option<BlockDictionary>,
+    compressors: HashMap<i32, Compressor<'static>>,
+    samples: VecDeque<Vec<u8>>,
+    sample_bytes: usize,
+    sampled_chunks: usize,
+    /// Containers whose sidecar already holds the current dictionary.
+    stored_in: HashSet<PathBuf>,
+}
+
+impl BlockEncoder {
+    pub fn new(config: CompressionConfig) -> Self {
+        Self {
+            config,
+            ..Default::default()
+        }
+    }
+
+    /// Seals the frames written with this encoder with `keys`.
+    pub fn with_keys(mut self, keys: Option<&Keyring>) -> Self {
+        self.keys = keys.cloned();
+        self
+    }
+
+    pub fn keys(&self) -> Option<&Keyring> {
+        self.keys.as_ref()
+    }
+
+    pub fn dictionary(&self) -> Option<&BlockDictionary> {
+        self.dictionary.as_ref()
+    }
+
+    /// Compresses one block at the level configured for `column`.
+    pub fn compress(&mut self, column: Column, raw: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let level = self.config.level(column);
+        if self.config.dictionary_chunks > 0 {
+            self.sample(raw);
+        }
+        let Some(dict) = &self.dictionary else {
+            return Ok(encode_all(raw, level)?);
+        };
+        let compressor = match self.compressors.entry(level) {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => e.insert(Compressor::with_dictionary(level, &dict.bytes)?),
+        };
+        Ok(compressor.compress(raw)?)
+    }
+
+    fn sample(&mut self, raw: &[u8]) {
+        if raw.is_empty() {
+            return;
+        }
+        self.sample_bytes += raw.len();
+        self.samples.push_back(raw.to_vec());
+        while self.sample_bytes > MAX_SAMPLE_BYTES {
+            let dropped = self.samples.pop_front().map_or(0, |s| s.len());
+            self.sample_bytes -= dropped;
+        }
+    }
+
+    /// Makes sure the sidecar of the container at `path` holds the current
+    /// dictionary before a chunk compressed with it is written there.
+    pub fn store_dictionary(&mut self, path: &Path) -> anyhow::Result<()> {
+        let Some(dict) = &self.dictionary else {
+            return Ok(());
+        };
+        if self.stored_in.contains(path) {
+            return Ok(());
+        }
+        if BlockDictionaries::load(path, self.keys.as_ref())?
+            .get(dict.id)
+            .is_none()
+        {
+            let payload = postcard::to_allocvec(dict)?;
+            container::append_frame_with(
+                BlockDictionaries::sidecar_path(path),
+                ZDICT_MAGIC,
+                &payload,
+                self.keys.as_ref(),
+            )?;
+        }
+        self.stored_in.insert(path.to_path_buf());
+        Ok(())
+    }
+
+    /// Counts a written chunk, training the first dictionary once enough
+    /// chunks have been sampled.
+    pub fn finish_chunk(&mut self) {
+        if self.config.dictionary_chunks == 0 || self.dictionary.is_some() {
+            return;
+        }
+        self.sampled_chunks += 1;
+        if self.sampled_chunks >= self.config.dictionary_chunks {
+            self.retrain();
+        }
+    }
+
+    /// Trains a new dictionary from the most recent samples, e.g. when a
+    /// new segment is started. Keeps the current one if training fails.
+    pub fn retrain(&mut self) {
+        if self.config.dictionary_chunks == 0 || self.sampled_chunks == 0 {
+            return;
+        }
+        match BlockDictionary::train(&self.samples) {
+            Ok(dict) => {
+                debug!(
+                    "Trained zstd dictionary {} ({} bytes) from {} samples",
+                    dict.id,
+                    dict.bytes.len(),
+                    self.samples.len()
+                );
+                if self.dictionary.as_ref().is_none_or(|d| d.id != dict.id) {
+                    self.compressors.clear();
+                    self.stored_in.clear();
+                    self.dictionary = Some(dict);
+                }
+            }
+            Err(e) => debug!("Not enough data to train a zstd dictionary yet: {}", e),
+        }
+    }
+}
+
+/// Dictionaries stored in the `.zdict` sidecar of one container.
+#[derive(Default)]
+pub struct BlockDictionaries {
+    by_id: HashMap<u32, DecoderDictionary<'static>>,
+}
+
+impl BlockDictionaries {
+    pub fn sidecar_path(path: impl AsRef<Path>) -> PathBuf {
+        let mut os = path.as_ref().as_os_str().to_owned();
+        os.push(".zdict");
+        PathBuf::from(os)
+    }
+
+    /// Loads the sidecar of the container at `path`, opening sealed entries
+    /// with `keys`; a missing sidecar yields no dictionaries.
+    pub fn load(path: impl AsRef<Path>, keys: Option<&Keyring>) -> anyhow::Result<Self> {
+        let sidecar = Self::sidecar_path(path);
+        let mut dicts = Self::default();
+        if !sidecar.exists() {
+            return Ok(dicts);
+        }
+        let mut frames = FrameReader::open_with(&sidecar, ZDICT_MAGIC, keys)?;
+        loop {
+            match frames.next_frame() {
+                Ok(Some((_, payload))) => {
+                    let dict: BlockDictionary = postcard::from_bytes(&payload)?;
+                    dicts
+                        .by_id
+                        .insert(dict.id, DecoderDictionary::copy(&dict.bytes));
+                }
+                Ok(None) => break,
+                Err(e) if e.is::<SealError>() => return Err(e),
+                Err(e) => {
+                    // Chunks are written after their dictionary, so a torn
+                    // entry is never referenced.
+                    warn!("Ignoring torn tail of {}: {}", sidecar.display(), e);
+                    break;
+                }
+            }
+        }
+        Ok(dicts)
+    }
+
+    pub fn get(&self, id: u32) -> Option<&DecoderDictionary<'static>> {
+        self.by_id.get(&id)
+    }
+
+    /// Decompresses a block written with dictionary `id`, if any.
+    pub fn decompress(&self, id: Option<u32>, block: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let Some(id) = id else {
+            return Ok(decode_all(block)?);
+        };
+        let dict = self
+            .get(id)
+            .ok_or_else(|| anyhow::anyhow!("zstd dictionary {} not found", id))?;
+        let mut out = Vec::new();
+        Decoder::with_prepared_dictionary(block, dict)?.read_to_end(&mut out)?;
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_column_levels() {
+        let config = CompressionConfig::default().with_levels(&[
+            "default=5".parse().unwrap(),
+            "variables=19".parse().unwrap(),
+        ]);
+        assert_eq!(config.level(Column::Variables), 19);
+        assert_eq!(config.level(Column::Timestamp), 5);
+        assert!("variables".parse::<ColumnLevel>().is_err());
+        assert!("bogus=3".parse::<ColumnLevel>().is_err());
+        assert!("timestamp=99".parse::<ColumnLevel>().is_err());
+    }
+}
@@ -0,0 +1,777 @@
+use crate::encryption::{self, Keyring, SealError};
+use crate::mmap::Mmap;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::borrow::Cow;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// Magic bytes at the start of every `.lshrink` file.
+pub const MAGIC: [u8; 4] = *b"LSHK";
+/// Magic bytes at the start of a `.lshrink.idx` sidecar index.
+pub const INDEX_MAGIC: [u8; 4] = *b"LSHI";
+/// Current container format version.
+pub const FORMAT_VERSION: u16 = 13;
+/// Oldest format version that can still be read. Versions 1 to 6 predate
+/// reading older layouts and are rejected.
+pub const MIN_FORMAT_VERSION: u16 = 7;
+/// Magic (4 bytes) + version (2 bytes) + reserved (2 bytes).
+pub const HEADER_LEN: u64 = 8;
+/// Length (4 bytes) + CRC32C of the length and payload (4 bytes).
+pub const FRAME_OVERHEAD: u64 = 8;
+/// Set in the length of a frame whose payload is sealed (see
+/// [`crate::encryption`]); the other bits hold the stored length.
+const SEALED: u32 = 1 << 31;
+
+const CRC32C_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0x82F6_3B78
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// CRC-32C (Castagnoli) checksum of `data`.
+pub fn crc32c(data: &[u8]) -> u32 {
+    !crc32c_update(!0, data)
+}
+
+fn crc32c_update(mut crc: u32, data: &[u8]) -> u32 {
+    for &b in data {
+        crc = CRC32C_TABLE[((crc ^ b as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    crc
+}
+
+/// Checksum stored in a frame. Covering the length as well means a run of
+/// zero bytes never passes for an empty frame.
+fn frame_checksum(len: u32, payload: &[u8]) -> u32 {
+    !crc32c_update(crc32c_update(!0, &len.to_le_bytes()), payload)
+}
+
+/// Stored length of a frame and whether it is sealed, from its length field.
+fn split_len(field: u32) -> (usize, bool) {
+    ((field & !SEALED) as usize, field & SEALED != 0)
+}
+
+/// The payload of the frame at `offset` of a file starting with `magic`,
+/// decrypted with `keys` if it is sealed, and whether a sealed payload was
+/// bound to that place (see [`Keyring::open_frame`]).
+fn open_payload<'a>(
+    stored: Cow<'a, [u8]>,
+    sealed: bool,
+    magic: [u8; 4],
+    offset: u64,
+    keys: Option<&Keyring>,
+) -> Result<(Cow<'a, [u8]>, bool), SealError> {
+    if sealed {
+        let (payload, bound) = encryption::open(&stored, magic, offset, keys)?;
+        Ok((Cow::Owned(payload), bound))
+    } else {
+        Ok((stored, true))
+    }
+}
+
+/// Writes the file header to an empty container.
+pub fn write_header<W: Write>(w: &mut W, magic: [u8; 4]) -> io::Result<()> {
+    write_header_version(w, magic, FORMAT_VERSION)
+}
+
+/// Writes a header claiming an explicit format `version`, for rewriting
+/// files without changing their layout.
+pub fn write_header_version<W: Write>(w: &mut W, magic: [u8; 4], version: u16) -> io::Result<()> {
+    w.write_all(&magic)?;
+    w.write_u16::<LittleEndian>(version)?;
+    w.write_u16::<LittleEndian>(0)?;
+    Ok(())
+}
+
+/// Reads and validates the file header, returning the format version.
+/// Versions from [`MIN_FORMAT_VERSION`] up to [`FORMAT_VERSION`] are
+/// accepted.
+pub fn read_header<R: Read>(r: &mut R, magic: [u8; 4]) -> anyhow::Result<u16> {
+    let mut found = [0u8; 4];
+    r.read_exact(&mut found)?;
+    if found != magic {
+        return Err(anyhow::anyhow!("Not a LogShrink container (bad magic)"));
+    }
+    let version = r.read_u16::<LittleEndian>()?;
+    let _reserved = r.read_u16::<LittleEndian>()?;
+    if !(MIN_FORMAT_VERSION..=FORMAT_VERSION).contains(&version) {
+        return Err(anyhow::anyhow!(
+            "Unsupported LogShrink format version {} (expected {} to {})",
+            version,
+            MIN_FORMAT_VERSION,
+            FORMAT_VERSION
+        ));
+    }
+    Ok(version)
+}
+
+/// Appends one length-prefixed frame to the container at `path`, sealed
+/// with `keys` if given, creating the file and its header if needed. Returns
+/// the byte offset of the frame.
+pub fn append_frame(
+    path: impl AsRef<Path>,
+    payload: &[u8],
+    keys: Option<&Keyring>,
+) -> anyhow::Result<u64> {
+    Ok(append_frame_with(path, MAGIC, payload, keys)?.0)
+}
+
+/// Like [`append_frame`], for containers identified by `magic`. Returns the
+/// byte offset of the frame and the length of its stored payload once the
+/// frame is synced to disk.
+pub fn append_frame_with(
+    path: impl AsRef<Path>,
+    magic: [u8; 4],
+    payload: &[u8],
+    keys: Option<&Keyring>,
+) -> anyhow::Result<(u64, u32)> {
+    let path = path.as_ref();
+    let mut file = OpenOptions::new()
+        .read(true)
+        .append(true)
+        .create(true)
+        .open(path)?;
+
+    let len = file.metadata()?.len();
+    if len == 0 {
+        write_header(&mut file, magic)?;
+        sync_parent(path)?;
+    } else {
+        file.seek(SeekFrom::Start(0))?;
+        let version = read_header(&mut file, magic)?;
+        // Only chunk layouts changed between versions; sidecar frames are
+        // still compatible.
+        if magic == MAGIC && version != FORMAT_VERSION {
+            return Err(anyhow::anyhow!(
+                "Cannot append to a format version {} container; run `sankshepa migrate` first",
+                version
+            ));
+        }
+    }
+
+    let offset = file.seek(SeekFrom::End(0))?;
+    let frame = encode_frame(payload, magic, offset, keys)?;
+
+    file.write_all(&frame)?;
+    file.sync_data()?;
+
+    Ok((offset, (frame.len() as u64 - FRAME_OVERHEAD) as u32))
+}
+
+/// Syncs the directory holding `path`, so a newly created file survives a
+/// crash along with its contents.
+pub fn sync_parent(path: &Path) -> io::Result<()> {
+    let dir = match path.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()
+}
+
+/// Prefixes `payload` with its `u32` length and CRC32C, sealing it first
+/// with `keys` if given for the frame at `offset` of a file starting with
+/// `magic`.
+pub fn encode_frame(
+    payload: &[u8],
+    magic: [u8; 4],
+    offset: u64,
+    keys: Option<&Keyring>,
+) -> anyhow::Result<Vec<u8>> {
+    let sealed = keys
+        .map(|keys| keys.seal(payload, magic, offset))
+        .transpose()?;
+    let (stored, flag) = match &sealed {
+        Some(sealed) => (sealed.as_slice(), SEALED),
+        None => (payload, 0),
+    };
+    let frame_len = u32::try_from(stored.len())
+        .ok()
+        .filter(|&len| len < SEALED)
+        .ok_or_else(|| anyhow::anyhow!("Chunk too large for a single frame"))?
+        | flag;
+    let mut frame = Vec::with_capacity(stored.len() + FRAME_OVERHEAD as usize);
+    frame.write_u32::<LittleEndian>(frame_len)?;
+    frame.write_u32::<LittleEndian>(frame_checksum(frame_len, stored))?;
+    frame.extend_from_slice(stored);
+    Ok(frame)
+}
+
+/// Sequential reader over the frames of a container, opening sealed ones
+/// with its keys.
+pub struct FrameReader {
+    reader: BufReader<File>,
+    magic: [u8; 4],
+    keys: Option<Keyring>,
+    offset: u64,
+    version: u16,
+    key_id: Option<u32>,
+    bound: bool,
+}
+
+impl FrameReader {
+    pub fn open(path: impl AsRef<Path>, keys: Option<&Keyring>) -> anyhow::Result<Self> {
+        Self::open_with(path, MAGIC, keys)
+    }
+
+    /// Opens a container identified by `magic`.
+    pub fn open_with(
+        path: impl AsRef<Path>,
+        magic: [u8; 4],
+        keys: Option<&Keyring>,
+    ) -> anyhow::Result<Self> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let version = read_header(&mut reader, magic)?;
+        Ok(Self {
+            reader,
+            magic,
+            keys: keys.cloned(),
+            offset: HEADER_LEN,
+            version,
+            key_id: None,
+            bound: true,
+        })
+    }
+
+    /// Format version from the file header.
+    pub fn version(&self) -> u16 {
+        self.version
+    }
+
+    /// Byte offset just past the last frame returned.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// ID of the key the last frame returned was sealed with, if any.
+    pub fn key_id(&self) -> Option<u32> {
+        self.key_id
+    }
+
+    /// Whether the last frame returned is plain or sealed bound to its
+    /// place; false for frames sealed before sealing bound them.
+    pub fn bound(&self) -> bool {
+        self.bound
+    }
+
+    /// Repositions the reader at the frame starting at `offset`.
+    pub fn seek_to(&mut self, offset: u64) -> anyhow::Result<()> {
+        if offset < HEADER_LEN {
+            return Err(anyhow::anyhow!("Frame offset {} inside header", offset));
+        }
+        self.reader.seek(SeekFrom::Start(offset))?;
+        self.offset = offset;
+        Ok(())
+    }
+
+    /// Returns the next frame payload together with its byte offset, or
+    /// `None` at a clean end of file. A truncated frame or a checksum
+    /// mismatch is an error, and so is a sealed frame that cannot be opened
+    /// (a [`SealError`]).
+    pub fn next_frame(&mut self) -> anyhow::Result<Option<(u64, Vec<u8>)>> {
+        let offset = self.offset;
+        let mut prefix = [0u8; FRAME_OVERHEAD as usize];
+        let mut filled = 0;
+        while filled < prefix.len() {
+            let n = self.reader.read(&mut prefix[filled..])?;
+            if n == 0 {
+                break;
+            }
+            filled += n;
+        }
+        if filled == 0 {
+            return Ok(None);
+        }
+        if filled < prefix.len() {
+            return Err(anyhow::anyhow!(
+                "Truncated frame header at offset {}",
+                offset
+            ));
+        }
+
+        let field = u32::from_le_bytes(prefix[..4].try_into()?);
+        let (len, sealed) = split_len(field);
+        let crc = u32::from_le_bytes(prefix[4..].try_into()?);
+        let mut payload = vec![0u8; len];
+        self.reader.read_exact(&mut payload).map_err(|e| {
+            if e.kind() == io::ErrorKind::UnexpectedEof {
+                anyhow::anyhow!("Truncated frame at offset {}", offset)
+            } else {
+                e.into()
+            }
+        })?;
+
+        if frame_checksum(field, &payload) != crc {
+            return Err(anyhow::anyhow!(
+                "Checksum mismatch in frame at offset {}",
+                offset
+            ));
+        }
+
+        self.key_id = sealed.then(|| encryption::key_id(&payload)).flatten();
+        let (payload, bound) = open_payload(
+            Cow::Owned(payload),
+            sealed,
+            self.magic,
+            offset,
+            self.keys.as_ref(),
+        )?;
+        self.bound = bound;
+        let payload = payload.into_owned();
+        self.offset += FRAME_OVERHEAD + len as u64;
+        Ok(Some((offset, payload)))
+    }
+}
+
+/// Reader over the frames of a memory-mapped container. Payloads are
+/// returned as slices of the mapping rather than copied, except for sealed
+/// ones, which are decrypted into a buffer.
+pub struct MappedFrames {
+    map: Mmap,
+    magic: [u8; 4],
+    keys: Option<Keyring>,
+    offset: usize,
+    version: u16,
+}
+
+impl MappedFrames {
+    pub fn open(path: impl AsRef<Path>, keys: Option<&Keyring>) -> anyhow::Result<Self> {
+        Self::open_with(path, MAGIC, keys)
+    }
+
+    /// Maps a container identified by `magic`.
+    pub fn open_with(
+        path: impl AsRef<Path>,
+        magic: [u8; 4],
+        keys: Option<&Keyring>,
+    ) -> anyhow::Result<Self> {
+        // SAFETY: containers are only appended to or replaced by rename,
+        // including when a torn tail is cut off (see `truncate_torn_tail`).
+        let map = unsafe { Mmap::map(&File::open(path)?)? };
+        let version = read_header(&mut &map[..], magic)?;
+        Ok(Self {
+            map,
+            magic,
+            keys: keys.cloned(),
+            offset: HEADER_LEN as usize,
+            version,
+        })
+    }
+
+    /// Format version from the file header.
+    pub fn version(&self) -> u16 {
+        self.version
+    }
+
+    /// Repositions the reader at the frame starting at `offset`.
+    pub fn seek_to(&mut self, offset: u64) -> anyhow::Result<()> {
+        if offset < HEADER_LEN {
+            return Err(anyhow::anyhow!("Frame offset {} inside header", offset));
+        }
+        self.offset = offset as usize;
+        Ok(())
+    }
+
+    /// Like [`FrameReader::next_frame`], borrowing the payload from the
+    /// mapping.
+    pub fn next_frame(&mut self) -> anyhow::Result<Option<(u64, Cow<'_, [u8]>)>> {
+        let offset = self.offset;
+        let data = &self.map[..];
+        if offset >= data.len() {
+            return Ok(None);
+        }
+        if data.len() - offset < FRAME_OVERHEAD as usize {
+            return Err(anyhow::anyhow!(
+                "Truncated frame header at offset {}",
+                offset
+            ));
+        }
+        let prefix = &data[offset..offset + FRAME_OVERHEAD as usize];
+        let field = u32::from_le_bytes(prefix[..4].try_into()?);
+        let (len, sealed) = split_len(field);
+        let crc = u32::from_le_bytes(prefix[4..].try_into()?);
+        let start = offset + FRAME_OVERHEAD as usize;
+        let payload = start
+            .checked_add(len)
+            .and_then(|end| data.get(start..end))
+            .ok_or_else(|| anyhow::anyhow!("Truncated frame at offset {}", offset))?;
+        if frame_checksum(field, payload) != crc {
+            return Err(anyhow::anyhow!(
+                "Checksum mismatch in frame at offset {}",
+                offset
+            ));
+        }
+
+        let (payload, _) = open_payload(
+            Cow::Borrowed(payload),
+            sealed,
+            self.magic,
+            offset as u64,
+            self.keys.as_ref(),
+        )?;
+        self.offset = start + len;
+        Ok(Some((offset as u64, payload)))
+    }
+}
+
+/// Byte range of a container that holds no readable frame.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DamagedRange {
+    pub offset: u64,
+    pub len: u64,
+    pub reason: String,
+}
+
+/// An intact frame found by [`scan_frames`].
+#[derive(Debug)]
+pub struct ScannedFrame {
+    pub offset: u64,
+    /// Bytes the frame takes up in the file.
+    pub len: u64,
+    /// The payload, decrypted if the frame is sealed.
+    pub payload: Vec<u8>,
+}
+
+/// Result of [`scan_frames`].
+#[derive(Debug, Default)]
+pub struct FrameScan {
+    /// Format version from the file header.
+    pub version: u16,
+    /// Intact frames in file order.
+    pub frames: Vec<ScannedFrame>,
+    pub damaged: Vec<DamagedRange>,
+}
+
+/// Reads every intact frame of the container at `path`, skipping damaged
+/// ones and opening sealed ones with `keys`. After a bad frame, scanning
+/// resumes at the next offset holding a frame whose length fits and whose
+/// checksum matches. A sealed frame that fails authentication counts as
+/// damaged, but one whose key is missing fails the scan: it is intact and
+/// must not be dropped.
+pub fn scan_frames(
+    path: impl AsRef<Path>,
+    magic: [u8; 4],
+    keys: Option<&Keyring>,
+) -> anyhow::Result<FrameScan> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let file_len = reader.get_ref().metadata()?.len();
+    let version = read_header(&mut reader, magic)?;
+
+    let mut scan = FrameScan {
+        version,
+        ..Default::default()
+    };
+    let mut pos = HEADER_LEN;
+    while pos < file_len {
+        if let Some((stored, sealed)) = frame_at(&mut reader, pos, file_len)? {
+            let len = FRAME_OVERHEAD + stored.len() as u64;
+            match open_payload(Cow::Owned(stored), sealed, magic, pos, keys) {
+                Ok((payload, _)) => scan.frames.push(ScannedFrame {
+                    offset: pos,
+                    len,
+                    payload: payload.into_owned(),
+                }),
+                Err(e @ SealError::Unauthenticated(_)) => scan.damaged.push(DamagedRange {
+                    offset: pos,
+                    len,
+                    reason: e.to_string(),
+                }),
+                Err(e) => return Err(e.into()),
+            }
+            pos += len;
+            continue;
+        }
+
+        let next = next_frame_at(&mut reader, pos + 1, file_len)?;
+        let end = next.unwrap_or(file_len);
+        let reason = match next {
+            Some(_) => "corrupt frame",
+            None if file_len - pos < FRAME_OVERHEAD => "truncated frame header",
+            None => "corrupt or truncated tail",
+        };
+        scan.damaged.push(DamagedRange {
+            offset: pos,
+            len: end - pos,
+            reason: reason.to_string(),
+        });
+        pos = end;
+    }
+    Ok(scan)
+}
+
+/// Cuts the torn tail off the container at `path`, as a crash mid-append
+/// leaves it: bytes after the last intact frame that hold no frame. New
+/// frames would otherwise land after the garbage, where readers stop.
+/// Damage followed by intact frames is left to `sankshepa fsck --repair`
+/// and reported as an error. A torn header is cut as well, leaving an empty
+/// file that the next append starts afresh. The intact part is copied to a
+/// temporary file that is renamed over the original, so readers mapping it
+/// keep the old file. Returns the number of bytes cut.
+pub fn truncate_torn_tail(path: impl AsRef<Path>, magic: [u8; 4]) -> anyhow::Result<u64> {
+    let path = path.as_ref();
+    let mut reader = BufReader::new(File::open(path)?);
+    let file_len = reader.get_ref().metadata()?.len();
+
+    let mut end = 0;
+    if file_len >= HEADER_LEN {
+        read_header(&mut reader, magic)?;
+        end = HEADER_LEN;
+        while let Some((stored, _)) = frame_at(&mut reader, end, file_len)? {
+            end += FRAME_OVERHEAD + stored.len() as u64;
+        }
+    }
+    if end == file_len {
+        return Ok(0);
+    }
+    if end > 0
+        && let Some(next) = next_frame_at(&mut reader, end + 1, file_len)?
+    {
+        return Err(anyhow::anyhow!(
+            "{} is damaged at offset {} but has frames from offset {}; run `sankshepa fsck --repair` first",
+            path.display(),
+            end,
+            next
+        ));
+    }
+    // Readers may have the container mapped, so it is replaced rather than
+    // shrunk in place.
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let mut tmp = File::create(&tmp_path)?;
+    reader.seek(SeekFrom::Start(0))?;
+    io::copy(&mut reader.take(end), &mut tmp)?;
+    tmp.sync_all()?;
+    std::fs::rename(&tmp_path, path)?;
+    sync_parent(path)?;
+    Ok(file_len - end)
+}
+
+/// The stored payload of an intact frame starting at `pos` of a file of
+/// `file_len` bytes, if there is one, and whether it is sealed.
+fn frame_at<R: Read + Seek>(
+    r: &mut R,
+    pos: u64,
+    file_len: u64,
+) -> io::Result<Option<(Vec<u8>, bool)>> {
+    if file_len.saturating_sub(pos) < FRAME_OVERHEAD {
+        return Ok(None);
+    }
+    r.seek(SeekFrom::Start(pos))?;
+    let field = r.read_u32::<LittleEndian>()?;
+    let crc = r.read_u32::<LittleEndian>()?;
+    let (len, sealed) = split_len(field);
+    if !fits(pos, len, file_len) {
+        return Ok(None);
+    }
+    let mut payload = vec![0u8; len];
+    r.read_exact(&mut payload)?;
+    Ok((frame_checksum(field, &payload) == crc).then_some((payload, sealed)))
+}
+
+/// Offset of the first intact frame at or after `from`. The file is read
+/// forward a block at a time, and only offsets whose length field fits in
+/// the rest of the file have their checksum checked. A frame that runs past
+/// the block is only checked if another frame header could follow it, so a
+/// large frame directly followed by more damage is missed.
+fn next_frame_at<R: Read + Seek>(r: &mut R, from: u64, file_len: u64) -> io::Result<Option<u64>> {
+    const BLOCK: usize = 64 * 1024;
+    let mut buf = vec![0u8; BLOCK];
+    let mut start = from;
+    while file_len.saturating_sub(start) >= FRAME_OVERHEAD {
+        let filled = (file_len - start).min(BLOCK as u64) as usize;
+        r.seek(SeekFrom::Start(start))?;
+        r.read_exact(&mut buf[..filled])?;
+        // Offsets whose length field lies wholly in this block; the rest are
+        // looked at with the next one.
+        let headers = filled - FRAME_OVERHEAD as usize + 1;
+        for i in 0..headers {
+            let pos = start + i as u64;
+            let field = u32::from_le_bytes(buf[i..i + 4].try_into().unwrap());
+            let (len, _) = split_len(field);
+            if !fits(pos, len, file_len) {
+                continue;
+            }
+            let payload = i + FRAME_OVERHEAD as usize;
+            let intact = match buf[..filled].get(payload..payload + len) {
+                Some(stored) => {
+                    let crc = u32::from_le_bytes(buf[i + 4..payload].try_into().unwrap());
+                    frame_checksum(field, stored) == crc
+                }
+                // Checking a frame past the block means reading it, so only
+                // do that if what follows it looks like a frame too.
+                None => {
+                    let end = pos + FRAME_OVERHEAD + len as u64;
+                    header_fits(r, end, file_len)? && frame_at(r, pos, file_len)?.is_some()
+                }
+            };
+            if intact {
+                return Ok(Some(pos));
+            }
+        }
+        start += headers as u64;
+    }
+    Ok(None)
+}
+
+/// Whether the bytes at `pos` could start a frame: its length field fits in
+/// the file, or too little is left for a frame header (the end of the file
+/// or a torn header).
+fn header_fits<R: Read + Seek>(r: &mut R, pos: u64, file_len: u64) -> io::Result<bool> {
+    if file_len - pos < FRAME_OVERHEAD {
+        return Ok(true);
+    }
+    r.seek(SeekFrom::Start(pos))?;
+    let (len, _) = split_len(r.read_u32::<LittleEndian>()?);
+    Ok(fits(pos, len, file_len))
+}
+
+/// Whether a frame of `len` stored bytes at `pos` ends within the file.
+fn fits(pos: u64, len: usize, file_len: u64) -> bool {
+    pos + FRAME_OVERHEAD + len as u64 <= file_len
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crc32c_check_value() {
+        assert_eq!(crc32c(b"123456789"), 0xE306_9283);
+        assert_eq!(crc32c(b""), 0);
+    }
+
+    #[test]
+    fn test_scan_resyncs_after_corrupt_length() {
+        let path = "test_scan_frames.lshrink";
+        let _ = std::fs::remove_file(path);
+        append_frame(path, b"one", None).unwrap();
+        let second = append_frame(path, b"two", None).unwrap();
+        append_frame(path, b"three", None).unwrap();
+
+        let mut data = std::fs::read(path).unwrap();
+        data[second as usize] = 0xff;
+        std::fs::write(path, &data).unwrap();
+
+        let scan = scan_frames(path, MAGIC, None).unwrap();
+        let payloads: Vec<&[u8]> = scan.frames.iter().map(|f| f.payload.as_slice()).collect();
+        assert_eq!(payloads, vec![&b"one"[..], &b"three"[..]]);
+        assert_eq!(
+            scan.damaged,
+            vec![DamagedRange {
+                offset: second,
+                len: FRAME_OVERHEAD + 3,
+                reason: "corrupt frame".to_string(),
+            }]
+        );
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_truncate_torn_tail_keeps_intact_frames() {
+        let path = "test_torn_tail.lshrink";
+        let _ = std::fs::remove_file(path);
+        append_frame(path, b"one", None).unwrap();
+        let second = append_frame(path, b"two", None).unwrap();
+        let len = std::fs::metadata(path).unwrap().len();
+        std::fs::OpenOptions::new()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_len(len - 1)
+            .unwrap();
+
+        assert_eq!(truncate_torn_tail(path, MAGIC).unwrap(), len - 1 - second);
+        assert_eq!(truncate_torn_tail(path, MAGIC).unwrap(), 0);
+        append_frame(path, b"three", None).unwrap();
+        let mut frames = FrameReader::open(path, None).unwrap();
+        assert_eq!(frames.next_frame().unwrap().unwrap().1, b"one");
+        assert_eq!(frames.next_frame().unwrap().unwrap().1, b"three");
+        assert!(frames.next_frame().unwrap().is_none());
+
+        // Damage before an intact frame is not a torn tail.
+        append_frame(path, b"four", None).unwrap();
+        let mut data = std::fs::read(path).unwrap();
+        data[second as usize] = 0xff;
+        std::fs::write(path, &data).unwrap();
+        assert!(truncate_torn_tail(path, MAGIC).is_err());
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_scan_skips_large_damaged_range() {
+        let path = "test_scan_large_damage.lshrink";
+        let _ = std::fs::remove_file(path);
+        append_frame(path, b"one", None).unwrap();
+        let mut data = std::fs::read(path).unwrap();
+        let damaged = data.len() as u64;
+        // A megabyte of noise, in which plenty of length fields fit.
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        data.extend((0..1 << 20).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        }));
+        std::fs::write(path, &data).unwrap();
+        append_frame(path, b"two", None).unwrap();
+
+        let scan = scan_frames(path, MAGIC, None).unwrap();
+        let payloads: Vec<&[u8]> = scan.frames.iter().map(|f| f.payload.as_slice()).collect();
+        assert_eq!(payloads, vec![&b"one"[..], &b"two"[..]]);
+        assert_eq!(scan.damaged.len(), 1);
+        assert_eq!(scan.damaged[0].offset, damaged);
+        assert_eq!(scan.damaged[0].len, 1 << 20);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_mapped_frames_match_buffered() {
+        let path = "test_mapped_frames.lshrink";
+        let _ = std::fs::remove_file(path);
+        append_frame(path, b"one", None).unwrap();
+        let second = append_frame(path, b"", None).unwrap();
+        append_frame(path, b"three", None).unwrap();
+
+        let mut buffered = FrameReader::open(path, None).unwrap();
+        let mut mapped = MappedFrames::open(path, None).unwrap();
+        while let Some((offset, payload)) = buffered.next_frame().unwrap() {
+            let (mapped_offset, mapped_payload) = mapped.next_frame().unwrap().unwrap();
+            assert_eq!(
+                (offset, payload.as_slice()),
+                (mapped_offset, &*mapped_payload)
+            );
+        }
+        assert!(mapped.next_frame().unwrap().is_none());
+
+        mapped.seek_to(second).unwrap();
+        let (offset, payload) = mapped.next_frame().unwrap().unwrap();
+        assert_eq!((offset, &*payload), (second, &b""[..]));
+
+        let len = std::fs::metadata(path).unwrap().len();
+        let file = OpenOptions::new().write(true).open(path).unwrap();
+        file.set_len(len - 1).unwrap();
+        let mut mapped = MappedFrames::open(path, None).unwrap();
+        mapped.next_frame().unwrap();
+        mapped.next_frame().unwrap();
+        assert!(mapped.next_frame().is_err());
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
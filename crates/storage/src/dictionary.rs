@@ -0,0 +1,496 @@
+use crate::container::{self, FrameReader};
+use crate::encryption::{Keyring, SealError};
+use crate::logshrink::{LogChunk, Template};
+use std::collections::{HashMap, HashSet};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use tracing::warn;
+
+/// Magic bytes at the start of a template dictionary file.
+pub const DICTIONARY_MAGIC: [u8; 4] = *b"LSHT";
+
+/// Template IDs are hashes folded to 31 bits; see [`template_id`].
+const ID_MASK: u32 = 0x7fff_ffff;
+
+/// The ID a store dictionary gives `pattern` unless another pattern holds
+/// it: a 31-bit FNV-1a hash of the pattern. Every node of a cluster, and
+/// every store, thus numbers the same template the same way without
+/// coordinating.
+pub fn template_id(pattern: &str) -> u32 {
+    let mut hash: u32 = 0x811c_9dc5;
+    for &b in pattern.as_bytes() {
+        hash = (hash ^ b as u32).wrapping_mul(0x0100_0193);
+    }
+    (hash ^ (hash >> 31)) & ID_MASK
+}
+
+/// The first ID from [`template_id`] on that `taken` reports free.
+pub(crate) fn free_id(pattern: &str, taken: impl Fn(u32) -> bool) -> u32 {
+    let mut id = template_id(pattern);
+    while taken(id) {
+        id = (id + 1) & ID_MASK;
+    }
+    id
+}
+
+/// A store's dictionary, shared by its writer and the compaction and merge
+/// passes running beside it. Hold the lock only briefly; see [`lock`].
+pub type SharedDictionary = Arc<Mutex<TemplateDictionary>>;
+
+/// Locks a shared dictionary. A panic while it was held cannot have left it
+/// half-changed, so a poisoned lock is taken over.
+pub fn lock(dictionary: &Mutex<TemplateDictionary>) -> MutexGuard<'_, TemplateDictionary> {
+    dictionary.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Store-wide, append-only mapping of template patterns to stable IDs. A
+/// pattern's ID is derived from the pattern itself (see [`template_id`]) and
+/// never reused, so a template ID means the same pattern in every chunk that
+/// references the dictionary, and on every node of a cluster.
+#[derive(Debug, Default)]
+pub struct TemplateDictionary {
+    path: Option<PathBuf>,
+    /// Keys new entries are sealed with.
+    keys: Option<Keyring>,
+    /// Templates in the order they were added, as persisted.
+    entries: Vec<(u32, String)>,
+    /// Index into `entries` by ID.
+    patterns: HashMap<u32, usize>,
+    ids: HashMap<String, u32>,
+    persisted: usize,
+    /// Length of the file up to the end of its last complete entry.
+    end: u64,
+}
+
+impl TemplateDictionary {
+    /// Opens the dictionary at `path` read-only, loading any existing
+    /// entries with `keys`, which also seal the entries persisted from then
+    /// on. A torn entry at the end is ignored and left in place; only
+    /// [`Self::open_for_writing`] removes it.
+    pub fn open(path: impl AsRef<Path>, keys: Option<&Keyring>) -> anyhow::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut dict = Self {
+            path: Some(path.clone()),
+            keys: keys.cloned(),
+            ..Default::default()
+        };
+        if path.exists() && std::fs::metadata(&path)?.len() > 0 {
+            let mut frames = FrameReader::open_with(&path, DICTIONARY_MAGIC, keys)?;
+            dict.end = container::HEADER_LEN;
+            loop {
+                match frames.next_frame() {
+                    Ok(Some((_, payload))) => {
+                        let template: Template = postcard::from_bytes(&payload)?;
+                        dict.insert_with_id(template.pattern, template.id)?;
+                        dict.end = frames.offset();
+                    }
+                    Ok(None) => break,
+                    Err(e) if e.is::<SealError>() => return Err(e),
+                    Err(e) => {
+                        // Chunks are only written after their templates are
+                        // persisted, so a torn entry is never referenced.
+                        warn!("Ignoring torn tail of {}: {}", path.display(), e);
+                        break;
+                    }
+                }
+            }
+        }
+        dict.persisted = dict.entries.len();
+        Ok(dict)
+    }
+
+    /// Opens the dictionary at `path` for the writer that owns it, cutting
+    /// a torn entry left by a crash off the end of the file so that new
+    /// entries follow the last complete one.
+    pub fn open_for_writing(
+        path: impl AsRef<Path>,
+        keys: Option<&Keyring>,
+    ) -> anyhow::Result<Self> {
+        let dict = Self::open(path.as_ref(), keys)?;
+        let path = path.as_ref();
+        if path.exists() && std::fs::metadata(path)?.len() > dict.end {
+            let file = OpenOptions::new().write(true).open(path)?;
+            file.set_len(dict.end)?;
+            file.sync_all()?;
+        }
+        Ok(dict)
+    }
+
+    /// File name of the dictionary, as referenced from chunks.
+    pub fn file_name(&self) -> Option<String> {
+        self.path
+            .as_ref()
+            .and_then(|p| p.file_name())
+            .map(|n| n.to_string_lossy().into_owned())
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn pattern(&self, id: u32) -> Option<&str> {
+        self.patterns.get(&id).map(|&i| self.entries[i].1.as_str())
+    }
+
+    /// Every template as `(id, pattern)`, in the order they were added, so
+    /// `iter().skip(n)` yields those added after the first `n`.
+    pub fn iter(&self) -> impl Iterator<Item = (u32, &str)> {
+        self.entries.iter().map(|(id, p)| (*id, p.as_str()))
+    }
+
+    pub fn id(&self, pattern: &str) -> Option<u32> {
+        self.ids.get(pattern).copied()
+    }
+
+    /// Returns the ID of `pattern`, assigning one if it is new.
+    pub fn get_or_insert(&mut self, pattern: &str) -> u32 {
+        if let Some(id) = self.id(pattern) {
+            return id;
+        }
+        let id = free_id(pattern, |id| self.patterns.contains_key(&id));
+        self.insert(pattern.to_string(), id);
+        id
+    }
+
+    /// Registers a template a cluster peer announced under its ID there, and
+    /// returns its ID here. Taking over the peer's ID keeps the nodes in
+    /// step where hashing collided and the peer had to pick another ID. A
+    /// pattern already known, or an ID held by another pattern here, keeps
+    /// the local numbering.
+    pub fn adopt(&mut self, id: u32, pattern: &str) -> u32 {
+        if let Some(local) = self.id(pattern) {
+            if local != id {
+                warn!(
+                    "Template {:?} is {} here but {} on a peer",
+                    pattern, local, id
+                );
+            }
+            return local;
+        }
+        if id > ID_MASK || self.patterns.contains_key(&id) {
+            let local = self.get_or_insert(pattern);
+            warn!(
+                "Template {:?} is {} on a peer but {} here",
+                pattern, id, local
+            );
+            return local;
+        }
+        self.insert(pattern.to_string(), id);
+        id
+    }
+
+    fn insert(&mut self, pattern: String, id: u32) {
+        self.patterns.insert(id, self.entries.len());
+        self.ids.insert(pattern.clone(), id);
+        self.entries.push((id, pattern));
+    }
+
+    fn insert_with_id(&mut self, pattern: String, id: u32) -> anyhow::Result<()> {
+        if let Some(existing) = self.pattern(id) {
+            return Err(anyhow::anyhow!(
+                "Template ID {} already holds {:?}",
+                id,
+                existing
+            ));
+        }
+        self.insert(pattern, id);
+        Ok(())
+    }
+
+    /// Creates an empty chunk whose new templates get IDs from this
+    /// dictionary's scheme.
+    pub fn new_chunk(&self) -> LogChunk {
+        let mut chunk = LogChunk::new();
+        chunk.templates = self.ids.clone();
+        chunk.dictionary = self.file_name();
+        chunk
+    }
+
+    /// Mines the buffered messages of `chunk`, created from
+    /// [`Self::new_chunk`], and adopts its new templates, which are
+    /// returned. Templates added here since the chunk was created are
+    /// registered with it first, so that its new ones do not take their IDs.
+    pub fn process(&mut self, chunk: &mut LogChunk) -> anyhow::Result<Vec<String>> {
+        for (id, pattern) in self.iter() {
+            if !chunk.templates.contains_key(pattern) {
+                chunk.register_template(pattern.to_string(), id);
+            }
+        }
+        let new_templates = chunk.finish_and_process();
+        self.absorb(chunk)?;
+        Ok(new_templates)
+    }
+
+    /// Adopts the templates a chunk created from [`Self::new_chunk`] mined.
+    pub fn absorb(&mut self, chunk: &LogChunk) -> anyhow::Result<()> {
+        let mut new: Vec<(&String, u32)> = chunk
+            .templates
+            .iter()
+            .filter(|(pattern, _)| !self.ids.contains_key(*pattern))
+            .map(|(pattern, &id)| (pattern, id))
+            .collect();
+        new.sort_by_key(|&(_, id)| id);
+        for (pattern, id) in new {
+            self.insert_with_id(pattern.clone(), id)?;
+        }
+        Ok(())
+    }
+
+    /// Appends entries added since the last call to the dictionary file.
+    pub fn persist(&mut self) -> anyhow::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        if self.persisted == self.entries.len() {
+            return Ok(());
+        }
+
+        let mut file = OpenOptions::new().append(true).create(true).open(path)?;
+        let mut buf = Vec::new();
+        let start = file.metadata()?.len();
+        if start != self.end {
+            return Err(anyhow::anyhow!(
+                "Template dictionary {} ends at {} rather than {}; only its writer may add to it",
+                path.display(),
+                start,
+                self.end
+            ));
+        }
+        if start == 0 {
+            container::write_header(&mut buf, DICTIONARY_MAGIC)?;
+        }
+        for (id, pattern) in self.entries.iter().skip(self.persisted) {
+            let entry = Template {
+                id: *id,
+                pattern: pattern.clone(),
+            };
+            let payload = postcard::to_allocvec(&entry)?;
+            let offset = start + buf.len() as u64;
+            buf.extend(container::encode_frame(
+                &payload,
+                DICTIONARY_MAGIC,
+                offset,
+                self.keys.as_ref(),
+            )?);
+        }
+        file.write_all(&buf)?;
+        file.sync_data()?;
+        self.end = start + buf.len() as u64;
+        self.persisted = self.entries.len();
+        Ok(())
+    }
+}
+
+/// Loads each dictionary referenced by decoded chunks once, resolving names
+/// relative to the directory of the container being read.
+#[derive(Default)]
+pub struct DictionaryCache {
+    keys: Option<Keyring>,
+    loaded: HashMap<PathBuf, Arc<TemplateDictionary>>,
+}
+
+impl DictionaryCache {
+    /// A cache opening sealed dictionaries with `keys`.
+    pub fn new(keys: Option<&Keyring>) -> Self {
+        Self {
+            keys: keys.cloned(),
+            loaded: HashMap::new(),
+        }
+    }
+
+    /// Fills `chunk.templates` with the dictionary entries its records use.
+    pub fn resolve(&mut self, container: &Path, chunk: &mut LogChunk) -> anyhow::Result<()> {
+        let Some(name) = &chunk.dictionary else {
+            return Ok(());
+        };
+        let used: HashSet<u32> = chunk.records.iter().map(|r| r.template_id).collect();
+        for (id, pattern) in self.lookup(container, name, &used)? {
+            chunk.templates.insert(pattern, id);
+        }
+        Ok(())
+    }
+
+    /// Patterns of the `used` template IDs in the dictionary `name`, which
+    /// lives next to the container at `container`.
+    pub fn lookup(
+        &mut self,
+        container: &Path,
+        name: &str,
+        used: &HashSet<u32>,
+    ) -> anyhow::Result<Vec<(u32, String)>> {
+        let dir = container.parent().unwrap_or(Path::new("."));
+        let path = dir.join(name);
+
+        let mut dict = self.load(&path, false)?;
+        if used.iter().any(|&id| dict.pattern(id).is_none()) {
+            // The store may have grown since the dictionary was cached.
+            dict = self.load(&path, true)?;
+        }
+        used.iter()
+            .map(|&id| {
+                dict.pattern(id)
+                    .map(|p| (id, p.to_string()))
+                    .ok_or_else(|| anyhow::anyhow!("Template {} missing from {}", id, name))
+            })
+            .collect()
+    }
+
+    fn load(&mut self, path: &Path, reload: bool) -> anyhow::Result<Arc<TemplateDictionary>> {
+        if !reload && let Some(dict) = self.loaded.get(path) {
+            return Ok(dict.clone());
+        }
+        if !path.exists() {
+            return Err(anyhow::anyhow!(
+                "Template dictionary {} not found",
+                path.display()
+            ));
+        }
+        let dict = Arc::new(TemplateDictionary::open(path, self.keys.as_ref())?);
+        self.loaded.insert(path.to_path_buf(), dict.clone());
+        Ok(dict)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_dictionary_persist_and_reload() {
+        let path = "test_dictionary.templates";
+        let _ = fs::remove_file(path);
+
+        let login = template_id("User <*> logged in");
+        let mut dict = TemplateDictionary::open(path, None).unwrap();
+        assert_eq!(dict.get_or_insert("User <*> logged in"), login);
+        let restart = dict.get_or_insert("System restart");
+        assert_eq!(dict.get_or_insert("User <*> logged in"), login);
+        dict.persist().unwrap();
+        let disk = dict.get_or_insert("Disk <*> full");
+        dict.persist().unwrap();
+
+        let reloaded = TemplateDictionary::open(path, None).unwrap();
+        assert_eq!(reloaded.len(), 3);
+        assert_eq!(reloaded.pattern(restart), Some("System restart"));
+        assert_eq!(reloaded.id("Disk <*> full"), Some(disk));
+        assert_eq!(
+            reloaded.iter().map(|(id, _)| id).collect::<Vec<_>>(),
+            vec![login, restart, disk]
+        );
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_ids_do_not_depend_on_insertion_order() {
+        let patterns = ["a <*>", "b <*> c", "Disk <*> full", "System restart"];
+        let mut first = TemplateDictionary::default();
+        let mut second = TemplateDictionary::default();
+        for p in patterns {
+            first.get_or_insert(p);
+        }
+        for p in patterns.iter().rev() {
+            second.get_or_insert(p);
+        }
+        for p in patterns {
+            assert_eq!(first.id(p), Some(template_id(p)));
+            assert_eq!(first.id(p), second.id(p));
+        }
+    }
+
+    /// Two distinct patterns with the same 31-bit hash.
+    fn colliding_pair() -> (String, String) {
+        let mut seen = HashMap::new();
+        for i in 0u32.. {
+            let pattern = format!("pattern {}", i);
+            if let Some(other) = seen.insert(template_id(&pattern), pattern.clone()) {
+                return (other, pattern);
+            }
+        }
+        unreachable!()
+    }
+
+    #[test]
+    fn test_colliding_patterns_probe_and_peers_adopt() {
+        let (a, b) = colliding_pair();
+        let hash = template_id(&a);
+
+        let mut node = TemplateDictionary::default();
+        assert_eq!(node.get_or_insert(&a), hash);
+        assert_eq!(node.get_or_insert(&b), hash + 1);
+
+        // A peer that saw them the other way round takes over the IDs it is
+        // sent instead of hashing them itself.
+        let mut peer = TemplateDictionary::default();
+        assert_eq!(peer.adopt(hash + 1, &b), hash + 1);
+        assert_eq!(peer.adopt(hash, &a), hash);
+        assert_eq!(peer.adopt(hash, &a), hash);
+
+        // An ID already taken by another pattern keeps the local numbering.
+        let mut other = TemplateDictionary::default();
+        other.get_or_insert(&b);
+        assert_eq!(other.adopt(hash, &a), hash + 1);
+    }
+
+    #[test]
+    fn test_dictionary_continues_ids_in_chunks() {
+        let path = "test_dictionary_chunks.templates";
+        let _ = fs::remove_file(path);
+        let mut dict = TemplateDictionary::open(path, None).unwrap();
+        dict.get_or_insert("existing");
+
+        let mut chunk = dict.new_chunk();
+        chunk.register_template("from peer".to_string(), dict.get_or_insert("from peer"));
+        chunk.import_template("mined".to_string());
+        assert_eq!(chunk.templates["mined"], template_id("mined"));
+
+        dict.absorb(&chunk).unwrap();
+        assert_eq!(dict.id("mined"), Some(template_id("mined")));
+        assert_eq!(dict.len(), 3);
+    }
+
+    #[test]
+    fn test_dictionary_drops_torn_tail() {
+        let path = "test_dictionary_torn.templates";
+        let _ = fs::remove_file(path);
+
+        let mut dict = TemplateDictionary::open(path, None).unwrap();
+        dict.get_or_insert("kept");
+        dict.persist().unwrap();
+        dict.get_or_insert("torn");
+        dict.persist().unwrap();
+        let len = fs::metadata(path).unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_len(len - 1)
+            .unwrap();
+
+        let mut dict = TemplateDictionary::open(path, None).unwrap();
+        assert_eq!(dict.len(), 1);
+        assert_eq!(dict.id("torn"), None);
+        assert_eq!(fs::metadata(path).unwrap().len(), len - 1);
+        dict.get_or_insert("next");
+        assert!(dict.persist().is_err());
+
+        let mut dict = TemplateDictionary::open_for_writing(path, None).unwrap();
+        assert_eq!(dict.len(), 1);
+        assert!(fs::metadata(path).unwrap().len() < len - 1);
+        let next = dict.get_or_insert("next");
+        dict.persist().unwrap();
+        assert_eq!(
+            TemplateDictionary::open(path, None).unwrap().pattern(next),
+            Some("next")
+        );
+
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,371 @@
+use crate::container::FrameReader;
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::Arc;
+
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+/// Bytes sealing adds to a payload: key ID (4), nonce and tag.
+pub const SEAL_OVERHEAD: usize = 4 + NONCE_LEN + TAG_LEN;
+
+/// Why a sealed frame could not be opened. Unlike a torn frame, this never
+/// means the data after it can be discarded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SealError {
+    /// No key file was given.
+    NoKeyFile(u32),
+    /// The key file has no key with this ID.
+    UnknownKey(u32),
+    /// Too short to hold a key ID, nonce and tag.
+    Malformed,
+    /// The ciphertext or its key ID was modified, or the key is wrong.
+    Unauthenticated(u32),
+}
+
+impl fmt::Display for SealError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SealError::NoKeyFile(id) => {
+                write!(f, "Frame is encrypted with key {}; pass --key-file", id)
+            }
+            SealError::UnknownKey(id) => write!(f, "Key {} is not in the key file", id),
+            SealError::Malformed => write!(f, "Encrypted frame too short"),
+            SealError::Unauthenticated(id) => {
+                write!(f, "Encrypted frame failed authentication with key {}", id)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SealError {}
+
+/// ChaCha20-Poly1305 keys by ID. New frames are sealed with the highest ID;
+/// the others stay available for opening frames sealed before a rotation.
+/// Clones share the keys.
+#[derive(Clone)]
+pub struct Keyring {
+    keys: Arc<BTreeMap<u32, ChaCha20Poly1305>>,
+    active: u32,
+}
+
+impl fmt::Debug for Keyring {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Keyring")
+            .field("ids", &self.keys.keys().collect::<Vec<_>>())
+            .field("active", &self.active)
+            .finish()
+    }
+}
+
+impl Keyring {
+    /// Reads a key file: one `<id> <64 hex digits>` line per key, with
+    /// blank lines and `#` comments ignored.
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("Cannot read key file {}: {}", path.display(), e))?;
+        Self::parse(&text)
+            .map_err(|e| anyhow::anyhow!("Invalid key file {}: {}", path.display(), e))
+    }
+
+    fn parse(text: &str) -> anyhow::Result<Self> {
+        let mut keys = BTreeMap::new();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = || anyhow::anyhow!("line {}: expected '<id> <64 hex digits>'", n + 1);
+            let (id, hex) = line.split_once(char::is_whitespace).ok_or_else(invalid)?;
+            let id: u32 = id.parse().map_err(|_| invalid())?;
+            let key = decode_key(hex.trim()).ok_or_else(invalid)?;
+            let cipher = ChaCha20Poly1305::new(Key::from_slice(&key));
+            if keys.insert(id, cipher).is_some() {
+                return Err(anyhow::anyhow!("line {}: duplicate key ID {}", n + 1, id));
+            }
+        }
+        let active = *keys
+            .keys()
+            .next_back()
+            .ok_or_else(|| anyhow::anyhow!("no keys"))?;
+        Ok(Self {
+            keys: Arc::new(keys),
+            active,
+        })
+    }
+
+    /// Appends a new random key to the key file at `path`, creating it
+    /// readable only by its owner, and returns its ID. The new key has the
+    /// highest ID, so it seals everything written from then on.
+    pub fn generate(path: impl AsRef<Path>) -> anyhow::Result<u32> {
+        let path = path.as_ref();
+        let id = if path.exists() {
+            Self::load(path)?.active + 1
+        } else {
+            1
+        };
+        let mut key = [0u8; KEY_LEN];
+        getrandom::fill(&mut key).map_err(|e| anyhow::anyhow!("No randomness: {}", e))?;
+        let hex: String = key.iter().map(|b| format!("{:02x}", b)).collect();
+
+        let mut options = OpenOptions::new();
+        options.append(true).create(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut file = options.open(path)?;
+        writeln!(file, "{} {}", id, hex)?;
+        file.sync_all()?;
+        Ok(id)
+    }
+
+    /// ID of the key new frames are sealed with.
+    pub fn active_id(&self) -> u32 {
+        self.active
+    }
+
+    /// Encrypts `payload` with the active key as key ID, random nonce and
+    /// ciphertext, for the frame at `offset` of a file starting with
+    /// `magic`. The key ID, magic and offset are authenticated too, so the
+    /// frame cannot be moved elsewhere in the file or into another file
+    /// kind unnoticed.
+    pub fn seal(&self, payload: &[u8], magic: [u8; 4], offset: u64) -> anyhow::Result<Vec<u8>> {
+        let id = self.active.to_le_bytes();
+        let mut nonce = [0u8; NONCE_LEN];
+        getrandom::fill(&mut nonce).map_err(|e| anyhow::anyhow!("No randomness: {}", e))?;
+        let ciphertext = self.keys[&self.active]
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: payload,
+                    aad: &bound_aad(id, magic, offset),
+                },
+            )
+            .map_err(|_| anyhow::anyhow!("Encryption failed"))?;
+
+        let mut sealed = Vec::with_capacity(SEAL_OVERHEAD + payload.len());
+        sealed.extend_from_slice(&id);
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&ciphertext);
+        Ok(sealed)
+    }
+
+    /// Decrypts the output of [`Keyring::seal`] with the key it names.
+    pub fn open(&self, sealed: &[u8], magic: [u8; 4], offset: u64) -> Result<Vec<u8>, SealError> {
+        self.open_frame(sealed, magic, offset)
+            .map(|(payload, _)| payload)
+    }
+
+    /// Like [`Keyring::open`], also saying whether the payload was bound to
+    /// `magic` and `offset`. Frames sealed before sealing bound them only
+    /// authenticate their key ID; they still open, but should be resealed.
+    pub(crate) fn open_frame(
+        &self,
+        sealed: &[u8],
+        magic: [u8; 4],
+        offset: u64,
+    ) -> Result<(Vec<u8>, bool), SealError> {
+        let id = key_id(sealed).ok_or(SealError::Malformed)?;
+        let cipher = self.keys.get(&id).ok_or(SealError::UnknownKey(id))?;
+        let (header, ciphertext) = sealed.split_at(4 + NONCE_LEN);
+        let id_bytes: [u8; 4] = header[..4].try_into().unwrap();
+        let decrypt = |aad: &[u8]| {
+            cipher.decrypt(
+                Nonce::from_slice(&header[4..]),
+                Payload {
+                    msg: ciphertext,
+                    aad,
+                },
+            )
+        };
+        if let Ok(payload) = decrypt(&bound_aad(id_bytes, magic, offset)) {
+            return Ok((payload, true));
+        }
+        decrypt(&id_bytes)
+            .map(|payload| (payload, false))
+            .map_err(|_| SealError::Unauthenticated(id))
+    }
+}
+
+/// Additional data a frame is sealed with: key ID, file magic and offset.
+fn bound_aad(id: [u8; 4], magic: [u8; 4], offset: u64) -> [u8; 16] {
+    let mut aad = [0u8; 16];
+    aad[..4].copy_from_slice(&id);
+    aad[4..8].copy_from_slice(&magic);
+    aad[8..].copy_from_slice(&offset.to_le_bytes());
+    aad
+}
+
+/// ID of the key a sealed payload was encrypted with.
+pub fn key_id(sealed: &[u8]) -> Option<u32> {
+    if sealed.len() < SEAL_OVERHEAD {
+        return None;
+    }
+    Some(u32::from_le_bytes(sealed[..4].try_into().ok()?))
+}
+
+/// Opens the sealed frame at `offset` of a file starting with `magic` with
+/// `keys`, saying whether it was bound to its place (see
+/// [`Keyring::open_frame`]).
+pub(crate) fn open(
+    sealed: &[u8],
+    magic: [u8; 4],
+    offset: u64,
+    keys: Option<&Keyring>,
+) -> Result<(Vec<u8>, bool), SealError> {
+    match keys {
+        Some(keys) => keys.open_frame(sealed, magic, offset),
+        None => Err(SealError::NoKeyFile(
+            key_id(sealed).ok_or(SealError::Malformed)?,
+        )),
+    }
+}
+
+/// Whether the file at `path` holds a frame that is not sealed with the
+/// active key of `keys`, or not bound to its place; never without keys. A
+/// torn tail is left to the caller.
+pub fn needs_reseal(
+    path: impl AsRef<Path>,
+    magic: [u8; 4],
+    keys: Option<&Keyring>,
+) -> anyhow::Result<bool> {
+    let Some(keys) = keys else {
+        return Ok(false);
+    };
+    let mut frames = FrameReader::open_with(path, magic, Some(keys))?;
+    loop {
+        match frames.next_frame() {
+            Ok(Some(_)) if frames.key_id() != Some(keys.active_id()) || !frames.bound() => {
+                return Ok(true);
+            }
+            Ok(Some(_)) => {}
+            Err(e) if e.is::<SealError>() => return Err(e),
+            Ok(None) | Err(_) => return Ok(false),
+        }
+    }
+}
+
+fn decode_key(hex: &str) -> Option<[u8; KEY_LEN]> {
+    if hex.len() != KEY_LEN * 2 {
+        return None;
+    }
+    let mut key = [0u8; KEY_LEN];
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::container::MAGIC;
+
+    const KEY_1: &str = "1 000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+    const KEY_2: &str = "2 f0e0d0c0b0a09080706050403020100ff0e0d0c0b0a09080706050403020100f";
+
+    #[test]
+    fn test_seal_open_and_rotate() {
+        let old = Keyring::parse(KEY_1).unwrap();
+        let sealed = old.seal(b"user alice from 10.0.0.1", MAGIC, 8).unwrap();
+        assert_eq!(sealed.len(), SEAL_OVERHEAD + 24);
+        assert_eq!(key_id(&sealed), Some(1));
+        assert_eq!(
+            old.open(&sealed, MAGIC, 8).unwrap(),
+            b"user alice from 10.0.0.1"
+        );
+
+        // A rotated keyring seals with the new key and still opens the old.
+        let rotated = Keyring::parse(&format!("# keys\n{}\n\n{}\n", KEY_1, KEY_2)).unwrap();
+        assert_eq!(rotated.active_id(), 2);
+        assert_eq!(
+            rotated.open(&sealed, MAGIC, 8).unwrap(),
+            b"user alice from 10.0.0.1"
+        );
+        let resealed = rotated.seal(b"x", MAGIC, 8).unwrap();
+        assert_eq!(key_id(&resealed), Some(2));
+        assert_eq!(old.open(&resealed, MAGIC, 8), Err(SealError::UnknownKey(2)));
+
+        let mut tampered = sealed.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert_eq!(
+            old.open(&tampered, MAGIC, 8),
+            Err(SealError::Unauthenticated(1))
+        );
+        // The key ID is authenticated, so relabelling a frame fails too.
+        let mut relabelled = sealed.clone();
+        relabelled[0] = 2;
+        assert_eq!(
+            rotated.open(&relabelled, MAGIC, 8),
+            Err(SealError::Unauthenticated(2))
+        );
+        assert_eq!(old.open(&sealed[..10], MAGIC, 8), Err(SealError::Malformed));
+
+        assert!(Keyring::parse("1 abcd").is_err());
+        assert!(Keyring::parse(&format!("{}\n{}", KEY_1, KEY_1)).is_err());
+        assert!(Keyring::parse("# empty\n").is_err());
+    }
+
+    #[test]
+    fn test_frames_are_bound_to_their_place() {
+        let keys = Keyring::parse(KEY_1).unwrap();
+        let sealed = keys.seal(b"payload", MAGIC, 8).unwrap();
+        assert_eq!(
+            keys.open_frame(&sealed, MAGIC, 8).unwrap(),
+            (b"payload".to_vec(), true)
+        );
+        // Moved within the file, or into a file of another kind.
+        assert_eq!(
+            keys.open(&sealed, MAGIC, 40),
+            Err(SealError::Unauthenticated(1))
+        );
+        assert_eq!(
+            keys.open(&sealed, crate::wal::WAL_MAGIC, 8),
+            Err(SealError::Unauthenticated(1))
+        );
+
+        // Frames sealed before binding only authenticated their key ID.
+        let nonce = [7u8; NONCE_LEN];
+        let ciphertext = keys.keys[&1]
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: b"payload",
+                    aad: &1u32.to_le_bytes(),
+                },
+            )
+            .unwrap();
+        let legacy = [&1u32.to_le_bytes()[..], &nonce, &ciphertext].concat();
+        assert_eq!(
+            keys.open_frame(&legacy, MAGIC, 40).unwrap(),
+            (b"payload".to_vec(), false)
+        );
+    }
+
+    #[test]
+    fn test_generate_appends_next_key() {
+        let path = Path::new("test_generate.keys");
+        let _ = fs::remove_file(path);
+        assert_eq!(Keyring::generate(path).unwrap(), 1);
+        assert_eq!(Keyring::generate(path).unwrap(), 2);
+        let keys = Keyring::load(path).unwrap();
+        assert_eq!(keys.active_id(), 2);
+        let sealed = keys.seal(b"payload", MAGIC, 8).unwrap();
+        assert_eq!(keys.open(&sealed, MAGIC, 8).unwrap(), b"payload");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o077, 0);
+        }
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,320 @@
+use crate::compression::BlockDictionaries;
+use crate::container::{self, FrameReader, INDEX_MAGIC};
+use crate::encryption::Keyring;
+use crate::logshrink::LogChunk;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Summary of one chunk, stored in the sidecar index so queries can decide
+/// whether a chunk is worth decompressing.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChunkMeta {
+    /// Byte offset of the chunk frame in the container.
+    pub offset: u64,
+    /// Length of the frame payload.
+    pub len: u32,
+    pub min_timestamp: i64,
+    pub max_timestamp: i64,
+    pub record_count: u32,
+    pub hostnames: Vec<String>,
+    pub app_names: Vec<String>,
+    pub template_ids: Vec<u32>,
+}
+
+impl ChunkMeta {
+    pub fn from_chunk(chunk: &LogChunk, offset: u64, len: u32) -> Self {
+        let mut hostnames = BTreeSet::new();
+        let mut app_names = BTreeSet::new();
+        let mut template_ids = BTreeSet::new();
+        let mut min_timestamp = i64::MAX;
+        let mut max_timestamp = i64::MIN;
+
+        for record in &chunk.records {
+            min_timestamp = min_timestamp.min(record.timestamp);
+            max_timestamp = max_timestamp.max(record.timestamp);
+            template_ids.insert(record.template_id);
+            if let Some(host) = record
+                .hostname_id
+                .and_then(|id| chunk.string_pool.get(id as usize))
+            {
+                hostnames.insert(host.clone());
+            }
+            if let Some(app) = record
+                .app_name_id
+                .and_then(|id| chunk.string_pool.get(id as usize))
+            {
+                app_names.insert(app.clone());
+            }
+        }
+
+        Self {
+            offset,
+            len,
+            min_timestamp,
+            max_timestamp,
+            record_count: chunk.records.len() as u32,
+            hostnames: hostnames.into_iter().collect(),
+            app_names: app_names.into_iter().collect(),
+            template_ids: template_ids.into_iter().collect(),
+        }
+    }
+
+    /// Returns false only if no record in the chunk can satisfy `filter`.
+    pub fn may_match(&self, filter: &ChunkFilter) -> bool {
+        if self.record_count == 0 {
+            return false;
+        }
+        if filter.since.is_some_and(|since| self.max_timestamp < since) {
+            return false;
+        }
+        if filter.until.is_some_and(|until| self.min_timestamp > until) {
+            return false;
+        }
+        if let Some(host) = &filter.host
+            && !self.hostnames.contains(host)
+        {
+            return false;
+        }
+        if let Some(app) = &filter.app_name
+            && !self.app_names.contains(app)
+        {
+            return false;
+        }
+        if let Some(tid) = filter.template_id
+            && self.template_ids.binary_search(&tid).is_err()
+        {
+            return false;
+        }
+        true
+    }
+}
+
+/// Chunk-level predicates used to skip chunks via the index. Timestamps are
+/// Unix milliseconds, inclusive on both ends.
+#[derive(Debug, Clone, Default)]
+pub struct ChunkFilter {
+    pub since: Option<i64>,
+    pub until: Option<i64>,
+    pub host: Option<String>,
+    pub app_name: Option<String>,
+    pub template_id: Option<u32>,
+}
+
+/// Sidecar index (`<file>.idx`) listing every chunk of a `.lshrink` container.
+#[derive(Debug, Clone, Default)]
+pub struct ChunkIndex {
+    pub entries: Vec<ChunkMeta>,
+}
+
+impl ChunkIndex {
+    pub fn sidecar_path(path: impl AsRef<Path>) -> PathBuf {
+        let mut os = path.as_ref().as_os_str().to_owned();
+        os.push(".idx");
+        PathBuf::from(os)
+    }
+
+    /// Appends the entry for a freshly written chunk to the sidecar.
+    pub fn append(
+        path: impl AsRef<Path>,
+        meta: &ChunkMeta,
+        keys: Option<&Keyring>,
+    ) -> anyhow::Result<()> {
+        let payload = postcard::to_allocvec(meta)?;
+        container::append_frame_with(Self::sidecar_path(path), INDEX_MAGIC, &payload, keys)?;
+        Ok(())
+    }
+
+    /// Reads the sidecar index for the container at `path`.
+    pub fn load(path: impl AsRef<Path>, keys: Option<&Keyring>) -> anyhow::Result<Self> {
+        let mut frames = FrameReader::open_with(Self::sidecar_path(path), INDEX_MAGIC, keys)?;
+        let mut entries = Vec::new();
+        while let Some((_, payload)) = frames.next_frame()? {
+            entries.push(postcard::from_bytes(&payload)?);
+        }
+        Ok(Self { entries })
+    }
+
+    /// Loads the sidecar index, rebuilding it from the container if it is
+    /// missing, unreadable or does not cover the whole file. Only for the
+    /// writer of the container; readers use [`ChunkIndex::load_or_scan`].
+    pub fn load_or_rebuild(path: impl AsRef<Path>, keys: Option<&Keyring>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        if let Ok(index) = Self::load(path, keys)
+            && index.covers(fs::metadata(path)?.len())
+        {
+            return Ok(index);
+        }
+        Self::rebuild(path, keys)
+    }
+
+    /// Like [`ChunkIndex::load_or_rebuild`], but a stale sidecar is only
+    /// replaced in memory, so reading never writes to the store.
+    pub fn load_or_scan(path: impl AsRef<Path>, keys: Option<&Keyring>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        if let Ok(index) = Self::load(path, keys)
+            && index.covers(fs::metadata(path)?.len())
+        {
+            return Ok(index);
+        }
+        Self::scan(path, keys)
+    }
+
+    /// Scans every chunk of the container and rewrites the sidecar index.
+    pub fn rebuild(path: impl AsRef<Path>, keys: Option<&Keyring>) -> anyhow::Result<Self> {
+        let index = Self::scan(path.as_ref(), keys)?;
+        index.write(path, keys)?;
+        Ok(index)
+    }
+
+    /// Builds the index of the container at `path` by scanning its chunks.
+    pub fn scan(path: impl AsRef<Path>, keys: Option<&Keyring>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let mut frames = FrameReader::open(path, keys)?;
+        let blocks = BlockDictionaries::load(path, keys)?;
+        let mut entries = Vec::new();
+        while let Some((offset, payload)) = frames.next_frame()? {
+            let chunk = crate::StorageEngine::decode_chunk(&payload, frames.version(), &blocks)?;
+            let len = frames.offset() - offset - container::FRAME_OVERHEAD;
+            entries.push(ChunkMeta::from_chunk(&chunk, offset, len as u32));
+        }
+        Ok(Self { entries })
+    }
+
+    /// Atomically replaces the sidecar with this index.
+    pub fn write(&self, path: impl AsRef<Path>, keys: Option<&Keyring>) -> anyhow::Result<()> {
+        let sidecar = Self::sidecar_path(path);
+        let mut tmp = sidecar.clone().into_os_string();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+
+        let mut buf = Vec::new();
+        container::write_header(&mut buf, INDEX_MAGIC)?;
+        for meta in &self.entries {
+            let payload = postcard::to_allocvec(meta)?;
+            let offset = buf.len() as u64;
+            buf.extend(container::encode_frame(
+                &payload,
+                INDEX_MAGIC,
+                offset,
+                keys,
+            )?);
+        }
+        fs::write(&tmp, buf)?;
+        fs::rename(tmp, sidecar)?;
+        Ok(())
+    }
+
+    /// Whether the entries describe contiguous frames accounting for every
+    /// byte of a container of `file_len` bytes.
+    pub(crate) fn covers(&self, file_len: u64) -> bool {
+        let mut expected = container::HEADER_LEN;
+        for meta in &self.entries {
+            if meta.offset != expected {
+                return false;
+            }
+            expected += container::FRAME_OVERHEAD + meta.len as u64;
+        }
+        expected == file_len
+    }
+
+    /// Entries whose chunks may contain records matching `filter`.
+    pub fn matching<'a>(&'a self, filter: &'a ChunkFilter) -> impl Iterator<Item = &'a ChunkMeta> {
+        self.entries.iter().filter(move |m| m.may_match(filter))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::StorageEngine;
+    use chrono::{TimeZone, Utc};
+    use sankshepa_protocol::SyslogMessage;
+
+    fn chunk_from(host: &str, secs: &[i64]) -> LogChunk {
+        let mut chunk = LogChunk::new();
+        for &s in secs {
+            chunk.add_message(SyslogMessage {
+                priority: 34,
+                facility: 4,
+                severity: 2,
+                timestamp: Utc.timestamp_opt(s, 0).single(),
+                hostname: Some(host.to_string()),
+                app_name: Some("app".to_string()),
+                procid: None,
+                msgid: None,
+                structured_data: None,
+                message: format!("event at {}", s),
+                is_rfc5424: true,
+                node_id: None,
+                raw: None,
+            });
+        }
+        chunk.finish_and_process();
+        chunk
+    }
+
+    #[test]
+    fn test_index_skips_chunks() {
+        let path = "test_index_skip.lshrink";
+        let _ = fs::remove_file(path);
+        let _ = fs::remove_file(ChunkIndex::sidecar_path(path));
+
+        StorageEngine::save_chunk(&chunk_from("alpha", &[100, 200]), path).unwrap();
+        StorageEngine::save_chunk(&chunk_from("beta", &[300, 400]), path).unwrap();
+
+        let index = ChunkIndex::load(path, None).unwrap();
+        assert_eq!(index.entries.len(), 2);
+        assert_eq!(index.entries[0].min_timestamp, 100_000);
+        assert_eq!(index.entries[1].max_timestamp, 400_000);
+        assert_eq!(index.entries[1].hostnames, vec!["beta".to_string()]);
+
+        let by_host = ChunkFilter {
+            host: Some("beta".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(index.matching(&by_host).count(), 1);
+
+        let by_time = ChunkFilter {
+            since: Some(250_000),
+            until: Some(260_000),
+            ..Default::default()
+        };
+        assert_eq!(index.matching(&by_time).count(), 0);
+
+        let chunks: Vec<LogChunk> = StorageEngine::iter_matching_chunks(path, &by_host, None)
+            .unwrap()
+            .map(|c| c.unwrap())
+            .collect();
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].string_pool[0], "beta");
+
+        fs::remove_file(path).unwrap();
+        fs::remove_file(ChunkIndex::sidecar_path(path)).unwrap();
+    }
+
+    #[test]
+    fn test_index_rebuilds_stale_sidecar() {
+        let path = "test_index_rebuild.lshrink";
+        let _ = fs::remove_file(path);
+        let _ = fs::remove_file(ChunkIndex::sidecar_path(path));
+
+        StorageEngine::save_chunk(&chunk_from("alpha", &[100]), path).unwrap();
+        fs::remove_file(ChunkIndex::sidecar_path(path)).unwrap();
+        // The sidecar now only knows about the second chunk.
+        StorageEngine::save_chunk(&chunk_from("beta", &[200]), path).unwrap();
+
+        // Readers scan the container but leave the sidecar alone.
+        let index = ChunkIndex::load_or_scan(path, None).unwrap();
+        assert_eq!(index.entries.len(), 2);
+        assert_eq!(ChunkIndex::load(path, None).unwrap().entries.len(), 1);
+
+        let index = ChunkIndex::load_or_rebuild(path, None).unwrap();
+        assert_eq!(index.entries.len(), 2);
+        assert_eq!(ChunkIndex::load(path, None).unwrap().entries.len(), 2);
+
+        fs::remove_file(path).unwrap();
+        fs::remove_file(ChunkIndex::sidecar_path(path)).unwrap();
+    }
+}
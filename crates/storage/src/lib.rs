@@ -1,38 +1,261 @@
+pub mod compaction;
+pub mod compression;
+pub mod container;
+pub mod dictionary;
+pub mod encryption;
+pub mod index;
 pub mod logshrink;
-
-use logshrink::{LogChunk, LogRecord, Template};
+pub mod masking;
+pub mod merging;
+pub mod migrate;
+pub mod miner;
+pub mod mmap;
+pub mod packing;
+pub mod reader;
+pub mod recovery;
+pub mod retention;
+pub mod segment;
+pub mod structured;
+pub mod varcodec;
+pub mod wal;
+pub mod writer;
+
+use compression::{BlockDictionaries, BlockEncoder, Column};
+use encryption::Keyring;
+use index::{ChunkFilter, ChunkIndex, ChunkMeta};
+use logshrink::{LogChunk, Template, VarType};
+use reader::{ChunkColumns, ChunkSource, ColumnSet, ProjectedChunks, ReadMode};
 use serde::{Deserialize, Serialize};
-use std::fs::File;
-use std::io::{Read, Write};
-use zstd::stream::{decode_all, encode_all};
+use std::borrow::Cow;
+use std::path::{Path, PathBuf};
+use structured::SdShape;
 
+/// A chunk as stored in a frame. Deserializing borrows the blocks from the
+/// frame payload instead of copying them, so a mapped container is decoded
+/// straight from the mapping.
 #[derive(Serialize, Deserialize)]
-pub struct CompressedChunk {
+pub struct CompressedChunk<'a> {
     pub templates: Vec<Template>,
-    pub string_pool: Vec<String>,
-    pub timestamp_block: Vec<u8>,
-    pub priority_block: Vec<u8>,
-    pub hostname_id_block: Vec<u8>,
-    pub app_name_id_block: Vec<u8>,
-    pub procid_id_block: Vec<u8>,
-    pub msgid_id_block: Vec<u8>,
-    pub sd_id_block: Vec<u8>,
-    pub template_id_block: Vec<u8>,
-    pub variable_block: Vec<u8>,
-    pub is_rfc5424_block: Vec<u8>,
-    pub node_id_id_block: Vec<u8>,
+    /// postcard `Vec<String>` of the interned metadata strings.
+    #[serde(borrow)]
+    pub string_pool_block: Cow<'a, [u8]>,
+    #[serde(borrow)]
+    pub timestamp_block: Cow<'a, [u8]>,
+    #[serde(borrow)]
+    pub priority_block: Cow<'a, [u8]>,
+    #[serde(borrow)]
+    pub hostname_id_block: Cow<'a, [u8]>,
+    #[serde(borrow)]
+    pub app_name_id_block: Cow<'a, [u8]>,
+    #[serde(borrow)]
+    pub procid_id_block: Cow<'a, [u8]>,
+    #[serde(borrow)]
+    pub msgid_id_block: Cow<'a, [u8]>,
+    #[serde(borrow)]
+    pub sd_id_block: Cow<'a, [u8]>,
+    #[serde(borrow)]
+    pub template_id_block: Cow<'a, [u8]>,
+    /// Number of variables of each record.
+    #[serde(borrow)]
+    pub variable_count_block: Cow<'a, [u8]>,
+    /// One column per `(template_id, slot)`, in order of first appearance.
+    #[serde(borrow)]
+    pub variable_columns: Vec<VariableColumn<'a>>,
+    #[serde(borrow)]
+    pub is_rfc5424_block: Cow<'a, [u8]>,
+    #[serde(borrow)]
+    pub node_id_id_block: Cow<'a, [u8]>,
+    /// postcard `Vec<Option<Layout>>` of lossless records; empty when the
+    /// chunk has none.
+    #[serde(borrow)]
+    pub layout_block: Cow<'a, [u8]>,
+    /// Distinct structured data shapes, indexed by `sd_shape_block`.
+    pub sd_shapes: Vec<SdShape>,
+    #[serde(borrow)]
+    pub sd_shape_block: Cow<'a, [u8]>,
+    /// Structured data values, one column per `(shape, position)` with the
+    /// shape in `template_id`.
+    #[serde(borrow)]
+    pub sd_value_columns: Vec<VariableColumn<'a>>,
+    /// Template dictionary holding this chunk's templates; `templates` is
+    /// empty when set.
+    pub dictionary: Option<String>,
+    /// ID of the zstd dictionary, stored in the container's `.zdict`
+    /// sidecar, that every block was compressed with.
+    pub zstd_dictionary: Option<u32>,
+}
+
+/// Values of one template variable slot (or structured data parameter),
+/// compressed separately so that similar values sit next to each other.
+#[derive(Serialize, Deserialize)]
+pub struct VariableColumn<'a> {
+    pub template_id: u32,
+    pub slot: u32,
+    pub ty: VarType,
+    /// zstd-compressed postcard `(dict, data)` of a [`SlotColumn`].
+    #[serde(borrow)]
+    pub block: Cow<'a, [u8]>,
 }
 
 pub struct StorageEngine;
 
 impl StorageEngine {
-    pub fn save_chunk(chunk: LogChunk, path: &str) -> anyhow::Result<()> {
+    /// Appends `chunk` as a new frame at the end of the container at `path`
+    /// and records its summary in the sidecar index, both synced to disk by
+    /// the time it returns.
+    pub fn save_chunk(chunk: &LogChunk, path: &str) -> anyhow::Result<ChunkMeta> {
+        Self::save_chunk_with(chunk, path, &mut BlockEncoder::default())
+    }
+
+    /// Like [`StorageEngine::save_chunk`], compressing blocks with `encoder`
+    /// and storing its dictionary, if any, next to the container first.
+    /// Frames are sealed with the encoder's keys.
+    pub fn save_chunk_with(
+        chunk: &LogChunk,
+        path: &str,
+        encoder: &mut BlockEncoder,
+    ) -> anyhow::Result<ChunkMeta> {
+        encoder.store_dictionary(Path::new(path))?;
+        let payload = Self::encode_chunk(chunk, encoder)?;
+        let (offset, len) =
+            container::append_frame_with(path, container::MAGIC, &payload, encoder.keys())?;
+        let meta = ChunkMeta::from_chunk(chunk, offset, len);
+        ChunkIndex::append(path, &meta, encoder.keys())?;
+        encoder.finish_chunk();
+        Ok(meta)
+    }
+
+    /// Loads the first chunk stored in the container at `path`.
+    pub fn load_chunk(path: &str, keys: Option<&Keyring>) -> anyhow::Result<LogChunk> {
+        Self::iter_chunks(path, keys)?
+            .next()
+            .unwrap_or_else(|| Err(anyhow::anyhow!("No chunks in {}", path)))
+    }
+
+    /// Like [`StorageEngine::load_chunk`], reading through a memory mapping.
+    pub fn load_chunk_mapped(path: &str, keys: Option<&Keyring>) -> anyhow::Result<LogChunk> {
+        Self::iter_chunks_mapped(path, keys)?
+            .next()
+            .unwrap_or_else(|| Err(anyhow::anyhow!("No chunks in {}", path)))
+    }
+
+    /// Loads every readable chunk of the container at `path`, skipping and
+    /// reporting corrupt or truncated ones instead of failing.
+    pub fn recover_chunks(
+        path: impl AsRef<Path>,
+        keys: Option<&Keyring>,
+    ) -> anyhow::Result<recovery::Recovered> {
+        recovery::recover(path, keys)
+    }
+
+    /// Streams every chunk stored in the container at `path`, in write
+    /// order, opening sealed frames with `keys`.
+    pub fn iter_chunks(
+        path: impl AsRef<Path>,
+        keys: Option<&Keyring>,
+    ) -> anyhow::Result<ChunkIter> {
+        Ok(ChunkIter {
+            source: ChunkSource::open(path.as_ref(), None, ReadMode::Buffered, keys)?,
+        })
+    }
+
+    /// Like [`StorageEngine::iter_chunks`], mapping the container into
+    /// memory and decompressing blocks without copying them first.
+    pub fn iter_chunks_mapped(
+        path: impl AsRef<Path>,
+        keys: Option<&Keyring>,
+    ) -> anyhow::Result<ChunkIter> {
+        Ok(ChunkIter {
+            source: ChunkSource::open(path.as_ref(), None, ReadMode::Mapped, keys)?,
+        })
+    }
+
+    /// Streams the chunks of the container at `path` one at a time,
+    /// decompressing only the blocks of `columns`.
+    pub fn iter_columns(
+        path: impl AsRef<Path>,
+        columns: ColumnSet,
+        mode: ReadMode,
+        keys: Option<&Keyring>,
+    ) -> anyhow::Result<ProjectedChunks> {
+        Ok(ProjectedChunks::new(
+            ChunkSource::open(path.as_ref(), None, mode, keys)?,
+            columns,
+        ))
+    }
+
+    /// Streams only the chunks whose index entry may satisfy `filter`,
+    /// skipping the others without decompressing them.
+    pub fn iter_matching_chunks(
+        path: impl AsRef<Path>,
+        filter: &ChunkFilter,
+        keys: Option<&Keyring>,
+    ) -> anyhow::Result<MatchingChunks> {
+        let path = path.as_ref();
+        let index = ChunkIndex::load_or_scan(path, keys)?;
+        let offsets: Vec<u64> = index.matching(filter).map(|m| m.offset).collect();
+        Ok(MatchingChunks {
+            source: ChunkSource::open(path, Some(offsets), ReadMode::Buffered, keys)?,
+        })
+    }
+
+    /// Like [`StorageEngine::iter_matching_chunks`], decompressing only the
+    /// blocks of `columns` from each selected chunk.
+    pub fn iter_matching_columns(
+        path: impl AsRef<Path>,
+        filter: &ChunkFilter,
+        columns: ColumnSet,
+        mode: ReadMode,
+        keys: Option<&Keyring>,
+    ) -> anyhow::Result<ProjectedChunks> {
+        let path = path.as_ref();
+        let index = ChunkIndex::load_or_scan(path, keys)?;
+        let offsets: Vec<u64> = index.matching(filter).map(|m| m.offset).collect();
+        Ok(ProjectedChunks::new(
+            ChunkSource::open(path, Some(offsets), mode, keys)?,
+            columns,
+        ))
+    }
+
+    /// Like [`StorageEngine::iter_matching_chunks`] across several
+    /// containers, opening each one only when the previous is exhausted.
+    pub fn iter_matching_files(
+        paths: Vec<PathBuf>,
+        filter: ChunkFilter,
+        keys: Option<Keyring>,
+    ) -> impl Iterator<Item = anyhow::Result<LogChunk>> {
+        Self::iter_projected_files(paths, filter, ColumnSet::ALL, ReadMode::Buffered, keys)
+            .map(|columns| columns.map(ChunkColumns::into_chunk))
+    }
+
+    /// Like [`StorageEngine::iter_matching_columns`] across several
+    /// containers, opening each one only when the previous is exhausted.
+    pub fn iter_projected_files(
+        paths: Vec<PathBuf>,
+        filter: ChunkFilter,
+        columns: ColumnSet,
+        mode: ReadMode,
+        keys: Option<Keyring>,
+    ) -> impl Iterator<Item = anyhow::Result<ChunkColumns>> {
+        paths.into_iter().flat_map(move |path| {
+            let opened = Self::iter_matching_columns(&path, &filter, columns, mode, keys.as_ref());
+            let (chunks, err) = match opened {
+                Ok(chunks) => (Some(chunks), None),
+                Err(e) => (None, Some(Err(e))),
+            };
+            chunks.into_iter().flatten().chain(err)
+        })
+    }
+
+    fn encode_chunk(chunk: &LogChunk, encoder: &mut BlockEncoder) -> anyhow::Result<Vec<u8>> {
         let mut templates = Vec::new();
-        for (pattern, &id) in &chunk.templates {
-            templates.push(Template {
-                id,
-                pattern: pattern.clone(),
-            });
+        if chunk.dictionary.is_none() {
+            for (pattern, &id) in &chunk.templates {
+                templates.push(Template {
+                    id,
+                    pattern: pattern.clone(),
+                });
+            }
         }
 
         // Columnar extraction
@@ -43,23 +266,29 @@ impl StorageEngine {
         let mut procid_ids = Vec::new();
         let mut msgid_ids = Vec::new();
         let mut sd_ids = Vec::new();
+        let mut sd_shape_ids = Vec::new();
+        let mut sd_values = Vec::new();
         let mut ids = Vec::new();
         let mut variables = Vec::new();
         let mut is_rfc5424s = Vec::new();
         let mut node_id_ids = Vec::new();
+        let mut layouts = Vec::new();
 
-        for record in chunk.records {
+        for record in &chunk.records {
             timestamps.push(record.timestamp);
-            priorities.push(record.priority);
+            priorities.push(record.priority as u32);
             hostname_ids.push(record.hostname_id);
             app_name_ids.push(record.app_name_id);
             procid_ids.push(record.procid_id);
             msgid_ids.push(record.msgid_id);
             sd_ids.push(record.structured_data_id);
+            sd_shape_ids.push(record.sd_shape);
+            sd_values.push(record.sd_values.clone());
             ids.push(record.template_id);
-            variables.push(record.variables);
+            variables.push(record.variables.clone());
             is_rfc5424s.push(record.is_rfc5424);
             node_id_ids.push(record.node_id_id);
+            layouts.push(record.layout.clone());
         }
 
         // Delta encoding for timestamps
@@ -72,113 +301,121 @@ impl StorageEngine {
         }
 
         let ts_data = postcard::to_allocvec(&delta_ts)?;
-        let pri_data = priorities;
-        let host_data = postcard::to_allocvec(&hostname_ids)?;
-        let app_data = postcard::to_allocvec(&app_name_ids)?;
-        let proc_data = postcard::to_allocvec(&procid_ids)?;
-        let msgid_data = postcard::to_allocvec(&msgid_ids)?;
-        let sd_data = postcard::to_allocvec(&sd_ids)?;
-        let id_data = postcard::to_allocvec(&ids)?;
-        let var_data = postcard::to_allocvec(&variables)?;
-        let rfc_data = postcard::to_allocvec(&is_rfc5424s)?;
-        let node_id_data = postcard::to_allocvec(&node_id_ids)?;
+        let pri_data = packing::encode_u32s(&priorities);
+        let host_data = packing::encode_optional(&hostname_ids);
+        let app_data = packing::encode_optional(&app_name_ids);
+        let proc_data = packing::encode_optional(&procid_ids);
+        let msgid_data = packing::encode_optional(&msgid_ids);
+        let sd_data = packing::encode_optional(&sd_ids);
+        let sd_shape_data = packing::encode_optional(&sd_shape_ids);
+        // Free-text values are templated like messages, with the masking
+        // and miner settings the chunk was mined with.
+        let sd_typed = varcodec::encode_variables_mined(
+            &sd_shape_ids
+                .iter()
+                .map(|s| s.unwrap_or(u32::MAX))
+                .collect::<Vec<_>>(),
+            &sd_values,
+            chunk.miner.config(),
+        )?;
+        let mut sd_value_columns = Vec::with_capacity(sd_typed.columns.len());
+        for column in sd_typed.columns {
+            let data = postcard::to_allocvec(&(&column.dict, &column.data))?;
+            sd_value_columns.push(VariableColumn {
+                template_id: column.template_id,
+                slot: column.slot,
+                ty: column.ty,
+                block: encoder.compress(Column::StructuredData, &data)?.into(),
+            });
+        }
+        let id_data = packing::encode_u32s(&ids);
+        let typed = varcodec::encode_variables(&ids, &variables)?;
+        let var_count_data = postcard::to_allocvec(&typed.counts)?;
+        let mut variable_columns = Vec::with_capacity(typed.columns.len());
+        for column in typed.columns {
+            let data = postcard::to_allocvec(&(&column.dict, &column.data))?;
+            variable_columns.push(VariableColumn {
+                template_id: column.template_id,
+                slot: column.slot,
+                ty: column.ty,
+                block: encoder.compress(Column::Variables, &data)?.into(),
+            });
+        }
+        let pool_data = postcard::to_allocvec(&chunk.string_pool)?;
+        let rfc_data = packing::encode_bools(&is_rfc5424s);
+        let node_id_data = packing::encode_optional(&node_id_ids);
+        let layout_block = match layouts.iter().any(Option::is_some) {
+            true => encoder
+                .compress(Column::Layout, &postcard::to_allocvec(&layouts)?)?
+                .into(),
+            false => Cow::Borrowed(&[][..]),
+        };
 
         let compressed = CompressedChunk {
             templates,
-            string_pool: chunk.string_pool,
-            timestamp_block: encode_all(&ts_data[..], 3)?,
-            priority_block: encode_all(&pri_data[..], 3)?,
-            hostname_id_block: encode_all(&host_data[..], 3)?,
-            app_name_id_block: encode_all(&app_data[..], 3)?,
-            procid_id_block: encode_all(&proc_data[..], 3)?,
-            msgid_id_block: encode_all(&msgid_data[..], 3)?,
-            sd_id_block: encode_all(&sd_data[..], 3)?,
-            template_id_block: encode_all(&id_data[..], 3)?,
-            variable_block: encode_all(&var_data[..], 3)?,
-            is_rfc5424_block: encode_all(&rfc_data[..], 3)?,
-            node_id_id_block: encode_all(&node_id_data[..], 3)?,
+            string_pool_block: encoder.compress(Column::StringPool, &pool_data)?.into(),
+            timestamp_block: encoder.compress(Column::Timestamp, &ts_data)?.into(),
+            priority_block: encoder.compress(Column::Priority, &pri_data)?.into(),
+            hostname_id_block: encoder.compress(Column::Hostname, &host_data)?.into(),
+            app_name_id_block: encoder.compress(Column::AppName, &app_data)?.into(),
+            procid_id_block: encoder.compress(Column::ProcId, &proc_data)?.into(),
+            msgid_id_block: encoder.compress(Column::MsgId, &msgid_data)?.into(),
+            sd_id_block: encoder.compress(Column::StructuredData, &sd_data)?.into(),
+            template_id_block: encoder.compress(Column::TemplateId, &id_data)?.into(),
+            variable_count_block: encoder
+                .compress(Column::VariableCount, &var_count_data)?
+                .into(),
+            variable_columns,
+            is_rfc5424_block: encoder.compress(Column::IsRfc5424, &rfc_data)?.into(),
+            node_id_id_block: encoder.compress(Column::NodeId, &node_id_data)?.into(),
+            layout_block,
+            sd_shapes: chunk.sd_shapes.clone(),
+            sd_shape_block: encoder
+                .compress(Column::StructuredData, &sd_shape_data)?
+                .into(),
+            sd_value_columns,
+            dictionary: chunk.dictionary.clone(),
+            zstd_dictionary: encoder.dictionary().map(|d| d.id),
         };
 
-        let mut file = File::create(path)?;
-        let serialized = postcard::to_allocvec(&compressed)?;
-        file.write_all(&serialized)?;
-
-        Ok(())
+        Ok(postcard::to_allocvec(&compressed)?)
     }
 
-    pub fn load_chunk(path: &str) -> anyhow::Result<LogChunk> {
-        let mut file = File::open(path)?;
-        let mut buf = Vec::new();
-        file.read_to_end(&mut buf)?;
-
-        let compressed: CompressedChunk = postcard::from_bytes(&buf)?;
-
-        let ts_data = decode_all(&compressed.timestamp_block[..])?;
-        let delta_ts: Vec<i64> = postcard::from_bytes(&ts_data)?;
-
-        let mut timestamps = Vec::new();
-        if !delta_ts.is_empty() {
-            let mut current = delta_ts[0];
-            timestamps.push(current);
-            for delta in delta_ts.iter().skip(1) {
-                current += delta;
-                timestamps.push(current);
-            }
-        }
-
-        let priorities = decode_all(&compressed.priority_block[..])?;
-
-        let host_data = decode_all(&compressed.hostname_id_block[..])?;
-        let hostname_ids: Vec<Option<u32>> = postcard::from_bytes(&host_data)?;
-
-        let app_data = decode_all(&compressed.app_name_id_block[..])?;
-        let app_name_ids: Vec<Option<u32>> = postcard::from_bytes(&app_data)?;
-
-        let proc_data = decode_all(&compressed.procid_id_block[..])?;
-        let procid_ids: Vec<Option<u32>> = postcard::from_bytes(&proc_data)?;
-
-        let msgid_data = decode_all(&compressed.msgid_id_block[..])?;
-        let msgid_ids: Vec<Option<u32>> = postcard::from_bytes(&msgid_data)?;
+    pub(crate) fn decode_chunk(
+        buf: &[u8],
+        version: u16,
+        blocks: &BlockDictionaries,
+    ) -> anyhow::Result<LogChunk> {
+        let stored = migrate::parse_chunk(buf, version)?;
+        Ok(ChunkColumns::decode(stored, blocks, ColumnSet::ALL)?.into_chunk())
+    }
+}
 
-        let sd_data = decode_all(&compressed.sd_id_block[..])?;
-        let sd_ids: Vec<Option<u32>> = postcard::from_bytes(&sd_data)?;
+/// Iterator over the chunks of a `.lshrink` container.
+pub struct ChunkIter {
+    source: ChunkSource,
+}
 
-        let id_data = decode_all(&compressed.template_id_block[..])?;
-        let ids: Vec<u32> = postcard::from_bytes(&id_data)?;
+impl Iterator for ChunkIter {
+    type Item = anyhow::Result<LogChunk>;
 
-        let var_data = decode_all(&compressed.variable_block[..])?;
-        let variables: Vec<Vec<String>> = postcard::from_bytes(&var_data)?;
+    fn next(&mut self) -> Option<Self::Item> {
+        let columns = self.source.next_columns(ColumnSet::ALL)?;
+        Some(columns.map(ChunkColumns::into_chunk))
+    }
+}
 
-        let rfc_data = decode_all(&compressed.is_rfc5424_block[..])?;
-        let is_rfc5424s: Vec<bool> = postcard::from_bytes(&rfc_data)?;
+/// Iterator over the chunks selected by an index lookup.
+pub struct MatchingChunks {
+    source: ChunkSource,
+}
 
-        let node_id_data = decode_all(&compressed.node_id_id_block[..])?;
-        let node_id_ids: Vec<Option<u32>> = postcard::from_bytes(&node_id_data)?;
+impl Iterator for MatchingChunks {
+    type Item = anyhow::Result<LogChunk>;
 
-        let mut chunk = LogChunk::new();
-        chunk.string_pool = compressed.string_pool;
-        for t in compressed.templates {
-            chunk.templates.insert(t.pattern, t.id);
-        }
-        chunk.next_template_id = chunk.templates.len() as u32;
-
-        for i in 0..ids.len() {
-            chunk.records.push(LogRecord {
-                timestamp: timestamps[i],
-                priority: priorities[i],
-                hostname_id: hostname_ids[i],
-                app_name_id: app_name_ids[i],
-                procid_id: procid_ids[i],
-                msgid_id: msgid_ids[i],
-                structured_data_id: sd_ids[i],
-                template_id: ids[i],
-                variables: variables[i].clone(),
-                is_rfc5424: is_rfc5424s[i],
-                node_id_id: node_id_ids[i],
-            });
-        }
-
-        Ok(chunk)
+    fn next(&mut self) -> Option<Self::Item> {
+        let columns = self.source.next_columns(ColumnSet::ALL)?;
+        Some(columns.map(ChunkColumns::into_chunk))
     }
 }
 
@@ -205,15 +442,19 @@ mod tests {
             structured_data: None,
             message: "Something happened".to_string(),
             is_rfc5424: true,
+            node_id: None,
+            raw: None,
         };
         chunk.add_message(msg);
         chunk.finish_and_process();
 
         let path = "test_chunk.lshrink";
+        let _ = fs::remove_file(path);
+        let _ = fs::remove_file(ChunkIndex::sidecar_path(path));
 
-        StorageEngine::save_chunk(chunk, path).unwrap();
+        StorageEngine::save_chunk(&chunk, path).unwrap();
 
-        let loaded_chunk = StorageEngine::load_chunk(path).unwrap();
+        let loaded_chunk = StorageEngine::load_chunk(path, None).unwrap();
 
         assert_eq!(loaded_chunk.records.len(), 1);
         let hostname = loaded_chunk.records[0]
@@ -229,5 +470,338 @@ mod tests {
         assert_eq!(loaded_chunk.templates.len(), 1);
 
         fs::remove_file(path).unwrap();
+        let _ = fs::remove_file(ChunkIndex::sidecar_path(path));
+    }
+
+    fn chunk_of(texts: &[&str]) -> LogChunk {
+        let mut chunk = LogChunk::new();
+        for text in texts {
+            chunk.add_message(SyslogMessage {
+                priority: 34,
+                facility: 4,
+                severity: 2,
+                timestamp: Some(Utc::now()),
+                hostname: Some("testhost".to_string()),
+                app_name: None,
+                procid: None,
+                msgid: None,
+                structured_data: None,
+                message: text.to_string(),
+                is_rfc5424: true,
+                node_id: None,
+                raw: None,
+            });
+        }
+        chunk.finish_and_process();
+        chunk
+    }
+
+    #[test]
+    fn test_storage_appends_chunks() {
+        let path = "test_append.lshrink";
+        let _ = fs::remove_file(path);
+        let _ = fs::remove_file(ChunkIndex::sidecar_path(path));
+
+        StorageEngine::save_chunk(&chunk_of(&["first a", "first b"]), path).unwrap();
+        StorageEngine::save_chunk(&chunk_of(&["second"]), path).unwrap();
+        StorageEngine::save_chunk(&chunk_of(&["third x", "third y", "third z"]), path).unwrap();
+
+        let counts: Vec<usize> = StorageEngine::iter_chunks(path, None)
+            .unwrap()
+            .map(|c| c.unwrap().records.len())
+            .collect();
+        assert_eq!(counts, vec![2, 1, 3]);
+        let mapped: Vec<usize> = StorageEngine::iter_chunks_mapped(path, None)
+            .unwrap()
+            .map(|c| c.unwrap().records.len())
+            .collect();
+        assert_eq!(mapped, counts);
+        let first = StorageEngine::load_chunk_mapped(path, None).unwrap();
+        assert_eq!(
+            first.records[1].variables,
+            StorageEngine::load_chunk(path, None).unwrap().records[1].variables
+        );
+
+        fs::remove_file(path).unwrap();
+        let _ = fs::remove_file(ChunkIndex::sidecar_path(path));
+    }
+
+    #[test]
+    fn test_storage_variable_columns_keep_record_order() {
+        let path = "test_variable_columns.lshrink";
+        let _ = fs::remove_file(path);
+        let _ = fs::remove_file(ChunkIndex::sidecar_path(path));
+
+        let texts = [
+            "User alice logged in from 10.0.0.1",
+            "Disk sda1 is 91 percent full",
+            "User bob logged in from 10.0.0.2",
+            "Disk sdb2 is 97 percent full",
+            "User carol logged in from 10.0.0.3",
+        ];
+        let chunk = chunk_of(&texts);
+        let expected: Vec<Vec<String>> =
+            chunk.records.iter().map(|r| r.variables.clone()).collect();
+        StorageEngine::save_chunk(&chunk, path).unwrap();
+
+        let loaded = StorageEngine::load_chunk(path, None).unwrap();
+        let variables: Vec<Vec<String>> =
+            loaded.records.iter().map(|r| r.variables.clone()).collect();
+        assert_eq!(variables, expected);
+
+        fs::remove_file(path).unwrap();
+        let _ = fs::remove_file(ChunkIndex::sidecar_path(path));
+    }
+
+    #[test]
+    fn test_storage_trained_zstd_dictionary() {
+        use crate::compression::CompressionConfig;
+
+        let path = "test_zstd_dictionary.lshrink";
+        let _ = fs::remove_file(path);
+        let _ = fs::remove_file(ChunkIndex::sidecar_path(path));
+        let _ = fs::remove_file(BlockDictionaries::sidecar_path(path));
+
+        let mut encoder = BlockEncoder::new(CompressionConfig {
+            dictionary_chunks: 8,
+            ..Default::default()
+        });
+        let mut dictionary_ids = Vec::new();
+        for i in 0..20 {
+            let texts: Vec<String> = (0..10)
+                .map(|j| {
+                    format!(
+                        "Accepted publickey for user{} from 10.0.{}.{} port {}",
+                        j,
+                        i,
+                        j,
+                        40000 + i * j
+                    )
+                })
+                .collect();
+            let texts: Vec<&str> = texts.iter().map(String::as_str).collect();
+            StorageEngine::save_chunk_with(&chunk_of(&texts), path, &mut encoder).unwrap();
+            dictionary_ids.push(encoder.dictionary().map(|d| d.id));
+        }
+        assert!(dictionary_ids[..7].iter().all(Option::is_none));
+        let id = encoder.dictionary().expect("dictionary trained").id;
+        assert!(
+            BlockDictionaries::load(path, None)
+                .unwrap()
+                .get(id)
+                .is_some()
+        );
+
+        let chunks: Vec<LogChunk> = StorageEngine::iter_chunks(path, None)
+            .unwrap()
+            .map(|c| c.unwrap())
+            .collect();
+        assert_eq!(chunks.len(), 20);
+        assert_eq!(
+            chunks[19].records[9].variables,
+            vec!["user9", "10.0.19.9", "40171"]
+        );
+        // The index can still be rebuilt from dictionary-compressed chunks.
+        assert_eq!(ChunkIndex::rebuild(path, None).unwrap().entries.len(), 20);
+
+        fs::remove_file(path).unwrap();
+        let _ = fs::remove_file(ChunkIndex::sidecar_path(path));
+        let _ = fs::remove_file(BlockDictionaries::sidecar_path(path));
+    }
+
+    #[test]
+    fn test_storage_truncated_tail() {
+        let path = "test_truncated.lshrink";
+        let _ = fs::remove_file(path);
+        let _ = fs::remove_file(ChunkIndex::sidecar_path(path));
+
+        StorageEngine::save_chunk(&chunk_of(&["kept"]), path).unwrap();
+        StorageEngine::save_chunk(&chunk_of(&["lost"]), path).unwrap();
+        let len = fs::metadata(path).unwrap().len();
+        let file = fs::OpenOptions::new().write(true).open(path).unwrap();
+        file.set_len(len - 3).unwrap();
+
+        let mut iter = StorageEngine::iter_chunks(path, None).unwrap();
+        assert_eq!(iter.next().unwrap().unwrap().records.len(), 1);
+        assert!(iter.next().unwrap().is_err());
+
+        fs::remove_file(path).unwrap();
+        let _ = fs::remove_file(ChunkIndex::sidecar_path(path));
+    }
+
+    #[test]
+    fn test_storage_sealed_frames_stay_in_place() {
+        let path = "test_sealed.lshrink";
+        let key_file = "test_sealed.keys";
+        let _ = fs::remove_file(path);
+        let _ = fs::remove_file(ChunkIndex::sidecar_path(path));
+        let _ = fs::remove_file(key_file);
+        Keyring::generate(key_file).unwrap();
+        let keys = Keyring::load(key_file).unwrap();
+
+        let mut encoder = BlockEncoder::default().with_keys(Some(&keys));
+        StorageEngine::save_chunk_with(&chunk_of(&["first"]), path, &mut encoder).unwrap();
+        let second =
+            StorageEngine::save_chunk_with(&chunk_of(&["second", "x"]), path, &mut encoder)
+                .unwrap();
+        let counts: Vec<usize> = StorageEngine::iter_chunks(path, Some(&keys))
+            .unwrap()
+            .map(|c| c.unwrap().records.len())
+            .collect();
+        assert_eq!(counts, vec![1, 2]);
+        assert_eq!(
+            ChunkIndex::load(path, Some(&keys)).unwrap().entries.len(),
+            2
+        );
+        assert!(StorageEngine::load_chunk(path, None).is_err());
+
+        // The second frame moved into the place of the first.
+        let data = fs::read(path).unwrap();
+        let mut spliced = data[..container::HEADER_LEN as usize].to_vec();
+        spliced.extend_from_slice(&data[second.offset as usize..]);
+        fs::write(path, spliced).unwrap();
+        let err = StorageEngine::load_chunk(path, Some(&keys)).err().unwrap();
+        assert!(err.is::<encryption::SealError>());
+
+        fs::remove_file(path).unwrap();
+        let _ = fs::remove_file(ChunkIndex::sidecar_path(path));
+        fs::remove_file(key_file).unwrap();
+    }
+
+    #[test]
+    fn test_storage_rejects_foreign_file() {
+        let path = "test_foreign.lshrink";
+        fs::write(path, b"not a logshrink file").unwrap();
+
+        assert!(StorageEngine::iter_chunks(path, None).is_err());
+        assert!(StorageEngine::save_chunk(&chunk_of(&["x"]), path).is_err());
+
+        fs::remove_file(path).unwrap();
+        let _ = fs::remove_file(ChunkIndex::sidecar_path(path));
+    }
+
+    /// xorshift64*, enough randomness for generated inputs without a crate.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 >> 12;
+            self.0 ^= self.0 << 25;
+            self.0 ^= self.0 >> 27;
+            self.0.wrapping_mul(0x2545_F491_4F6C_DD1D)
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
+        }
+
+        fn pick<'a>(&mut self, items: &[&'a str]) -> &'a str {
+            items[self.below(items.len())]
+        }
+    }
+
+    /// A syslog line with irregular whitespace in its header and body.
+    fn random_line(rng: &mut Rng) -> String {
+        const WORDS: &[&str] = &[
+            "User", "failed", "login", "from", "port", "ok", "a=b,", "(pid)", "/var/log",
+        ];
+        const GAPS: &[&str] = &[" ", " ", " ", "  ", "\t", " \t "];
+        const HEADER_GAPS: &[&str] = &[" ", "  "];
+
+        let mut line = rng.pick(&["", "", " "]).to_string();
+        line.push_str(&format!("<{}>", rng.below(192)));
+        let gap = |rng: &mut Rng| rng.pick(HEADER_GAPS);
+        if rng.below(2) == 0 {
+            line.push_str(&format!(
+                "Oct{}{:2}{}22:14:{:02}{}host-{}{}",
+                gap(rng),
+                1 + rng.below(28),
+                gap(rng),
+                rng.below(60),
+                gap(rng),
+                rng.below(3),
+                gap(rng)
+            ));
+        } else {
+            let sd = rng.pick(&["-", "[id@1 k=\"v\"]"]);
+            line.push_str(&format!(
+                "1 2023-10-11T22:14:15.{:03}Z{}host-{} app - - {}{}",
+                rng.below(1000),
+                gap(rng),
+                rng.below(3),
+                sd,
+                gap(rng)
+            ));
+        }
+        for i in 0..1 + rng.below(7) {
+            if i > 0 {
+                line.push_str(rng.pick(GAPS));
+            }
+            match rng.below(4) {
+                0 => line.push_str(&format!("user{}", rng.below(50))),
+                1 => line.push_str(&format!("10.0.{}.{}", rng.below(4), rng.below(256))),
+                _ => line.push_str(rng.pick(WORDS)),
+            }
+        }
+        line.push_str(rng.pick(&["", "", " ", "\t", "  "]));
+        line
+    }
+
+    #[test]
+    fn test_lossless_round_trip_is_byte_exact() {
+        let path = "test_lossless.lshrink";
+        let _ = fs::remove_file(path);
+        let _ = fs::remove_file(ChunkIndex::sidecar_path(path));
+
+        // Records are grouped by template within a chunk, so every line
+        // carries its arrival number to put them back in order by.
+        let arrival = |line: &[u8]| -> usize {
+            let at = line.windows(2).rposition(|w| w == b" #").unwrap() + 2;
+            let digits = line[at..].iter().take_while(|b| b.is_ascii_digit());
+            std::str::from_utf8(&line[at..at + digits.count()])
+                .unwrap()
+                .parse()
+                .unwrap()
+        };
+
+        let mut rng = Rng(0x9E37_79B9_7F4A_7C15);
+        let mut expected = Vec::new();
+        for _ in 0..4 {
+            let mut chunk = LogChunk::new();
+            for _ in 0..250 {
+                let mut line = random_line(&mut rng).into_bytes();
+                line.extend_from_slice(format!(" #{}", expected.len()).as_bytes());
+                // Senders that are not UTF-8 (e.g. Latin-1), in the message
+                // or in the header.
+                match rng.below(8) {
+                    0 => line.extend_from_slice(b" caf\xe9"),
+                    1 => {
+                        let at = line.windows(5).position(|w| w == b"host-").unwrap();
+                        line[at + 1] = 0xf4;
+                    }
+                    _ => {}
+                }
+                chunk
+                    .add_message(sankshepa_protocol::UnifiedParser::parse_lossless(&line).unwrap());
+                expected.push(line);
+            }
+            chunk.finish_and_process();
+            StorageEngine::save_chunk(&chunk, path).unwrap();
+        }
+
+        let mut restored = Vec::new();
+        for columns in
+            StorageEngine::iter_columns(path, ColumnSet::ALL, ReadMode::Buffered, None).unwrap()
+        {
+            let columns = columns.unwrap();
+            let mut lines: Vec<Vec<u8>> =
+                columns.records().map(|r| r.original().unwrap()).collect();
+            lines.sort_by_key(|line| arrival(line));
+            restored.extend(lines);
+        }
+        assert_eq!(restored, expected);
+
+        fs::remove_file(path).unwrap();
+        fs::remove_file(ChunkIndex::sidecar_path(path)).unwrap();
     }
 }
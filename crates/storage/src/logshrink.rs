@@ -1,7 +1,14 @@
+use crate::masking::{self, Masking};
+use crate::miner::{TemplateMiner, VARIADIC};
+use crate::structured::SdShape;
 use chrono::Utc;
 use sankshepa_protocol::SyslogMessage;
 use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, HashSet};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Template {
@@ -17,11 +24,414 @@ pub struct LogRecord {
     pub app_name_id: Option<u32>,
     pub procid_id: Option<u32>,
     pub msgid_id: Option<u32>,
+    /// Structured data kept verbatim, when it does not split into a shape.
     pub structured_data_id: Option<u32>,
+    /// Index into the chunk's `sd_shapes`, with the parameter values.
+    pub sd_shape: Option<u32>,
+    pub sd_values: Vec<String>,
     pub template_id: u32,
     pub variables: Vec<String>,
     pub is_rfc5424: bool,
     pub node_id_id: Option<u32>,
+    /// Set for messages received losslessly.
+    pub layout: Option<Layout>,
+}
+
+/// What a message needs, beyond its template and variables, to be given back
+/// exactly as received: templates only keep single spaces between tokens.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Layout {
+    /// Everything before the first message token, i.e. the syslog header as
+    /// received. All the received bytes when the message has no tokens or
+    /// the layout is `verbatim`.
+    pub header: Vec<u8>,
+    /// The whitespace after each token, or empty when tokens are separated
+    /// by single spaces and nothing follows the last one.
+    pub gaps: Vec<String>,
+    /// The received bytes did not split around the message, e.g. because
+    /// the message was not valid UTF-8, so `header` holds all of them.
+    pub verbatim: bool,
+}
+
+impl Layout {
+    /// Splits the received bytes `raw` of a message whose body is `message`.
+    /// `None` if `message` is not what `raw` ends with, ignoring trailing
+    /// whitespace.
+    pub fn capture(raw: &[u8], message: &str) -> Option<Layout> {
+        let end = raw.trim_ascii_end().len();
+        let start = end.checked_sub(message.len())?;
+        if &raw[start..end] != message.as_bytes() {
+            return None;
+        }
+
+        // The message and the ASCII whitespace after it are valid UTF-8.
+        let body = std::str::from_utf8(&raw[start..]).ok()?;
+        let mut header = None;
+        let mut gaps = Vec::new();
+        let mut pos = 0;
+        for token in message.split_whitespace() {
+            // Only whitespace precedes the token, so this finds the token
+            // itself.
+            let at = pos + body[pos..].find(token)?;
+            match header {
+                None => header = Some(raw[..start + at].to_vec()),
+                Some(_) => gaps.push(body[pos..at].to_string()),
+            }
+            pos = at + token.len();
+        }
+        let Some(header) = header else {
+            return Some(Layout::verbatim(raw));
+        };
+        gaps.push(body[pos..].to_string());
+
+        let (last, separators) = gaps.split_last()?;
+        if last.is_empty() && separators.iter().all(|g| g == " ") {
+            gaps.clear();
+        }
+        Some(Layout {
+            header,
+            gaps,
+            verbatim: false,
+        })
+    }
+
+    /// Keeps all of `raw`, for messages [`Layout::capture`] cannot split.
+    pub fn verbatim(raw: &[u8]) -> Layout {
+        Layout {
+            header: raw.to_vec(),
+            gaps: Vec::new(),
+            verbatim: true,
+        }
+    }
+
+    /// Rebuilds the received bytes from the reconstructed `message`.
+    pub fn restore(&self, message: &str) -> Vec<u8> {
+        let mut out = self.header.clone();
+        if self.verbatim {
+            return out;
+        }
+        let mut tokens = message.split_whitespace().peekable();
+        let mut i = 0;
+        while let Some(token) = tokens.next() {
+            out.extend_from_slice(token.as_bytes());
+            match self.gaps.get(i) {
+                Some(gap) => out.extend_from_slice(gap.as_bytes()),
+                None if tokens.peek().is_some() => out.push(b' '),
+                None => {}
+            }
+            i += 1;
+        }
+        out
+    }
+}
+
+/// Shape of the values in one template variable slot, used to pick a
+/// compact encoding. Every type round-trips its values byte for byte.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum VarType {
+    /// Canonical decimal `i64` (no leading zeros or `+`).
+    Int,
+    /// Dotted-quad IPv4 address in canonical form.
+    Ipv4,
+    /// IPv6 address in canonical (RFC 5952) form.
+    Ipv6,
+    /// `8-4-4-4-12` UUID in a single letter case.
+    Uuid { upper: bool },
+    /// Hex digit string in a single letter case.
+    Hex { upper: bool },
+    /// Anything else.
+    Str,
+    /// Free text, mined into templates local to the column: each value is
+    /// a template and its variables, both indexing the column's strings.
+    /// Never inferred; see [`crate::varcodec::encode_variables_mined`].
+    Template,
+}
+
+impl VarType {
+    const INT: u8 = 1;
+    const IPV4: u8 = 1 << 1;
+    const IPV6: u8 = 1 << 2;
+    const UUID_LOWER: u8 = 1 << 3;
+    const UUID_UPPER: u8 = 1 << 4;
+    const HEX_LOWER: u8 = 1 << 5;
+    const HEX_UPPER: u8 = 1 << 6;
+
+    /// Infers the most specific type able to represent every value exactly.
+    pub fn infer<'a>(values: impl IntoIterator<Item = &'a str>) -> VarType {
+        let mut candidates = u8::MAX;
+        let mut any = false;
+        for value in values {
+            any = true;
+            candidates &= Self::candidates(value);
+            if candidates == 0 {
+                return VarType::Str;
+            }
+        }
+        if !any {
+            return VarType::Str;
+        }
+        [
+            (Self::INT, VarType::Int),
+            (Self::IPV4, VarType::Ipv4),
+            (Self::IPV6, VarType::Ipv6),
+            (Self::UUID_LOWER, VarType::Uuid { upper: false }),
+            (Self::UUID_UPPER, VarType::Uuid { upper: true }),
+            (Self::HEX_LOWER, VarType::Hex { upper: false }),
+            (Self::HEX_UPPER, VarType::Hex { upper: true }),
+        ]
+        .into_iter()
+        .find(|(flag, _)| candidates & flag != 0)
+        .map(|(_, ty)| ty)
+        .unwrap_or(VarType::Str)
+    }
+
+    /// Bitset of the types `value` can be encoded as.
+    fn candidates(value: &str) -> u8 {
+        let mut flags = 0;
+        if value.parse::<i64>().is_ok_and(|n| n.to_string() == value) {
+            flags |= Self::INT;
+        }
+        if value
+            .parse::<Ipv4Addr>()
+            .is_ok_and(|ip| ip.to_string() == value)
+        {
+            flags |= Self::IPV4;
+        }
+        if value
+            .parse::<Ipv6Addr>()
+            .is_ok_and(|ip| ip.to_string() == value)
+        {
+            flags |= Self::IPV6;
+        }
+
+        let has_lower = value.bytes().any(|b| matches!(b, b'a'..=b'f'));
+        let has_upper = value.bytes().any(|b| matches!(b, b'A'..=b'F'));
+        let case_flags = |lower: u8, upper: u8| {
+            let mut f = 0;
+            if !has_upper {
+                f |= lower;
+            }
+            if !has_lower {
+                f |= upper;
+            }
+            f
+        };
+
+        let is_uuid = value.len() == 36
+            && value.bytes().enumerate().all(|(i, b)| match i {
+                8 | 13 | 18 | 23 => b == b'-',
+                _ => b.is_ascii_hexdigit(),
+            });
+        if is_uuid {
+            flags |= case_flags(Self::UUID_LOWER, Self::UUID_UPPER);
+        }
+        if !value.is_empty() && value.bytes().all(|b| b.is_ascii_hexdigit()) {
+            flags |= case_flags(Self::HEX_LOWER, Self::HEX_UPPER);
+        }
+        flags
+    }
+}
+
+/// Splits messages into the tokens templates are mined from. Text is split
+/// at whitespace and around delimiter characters, and each run of
+/// delimiters becomes a separator token together with the spaces around
+/// it: `user=alice, port=22` is `user`, `=`, `alice`, `, `, `port`, `=`,
+/// `22`. Joining the tokens with a space between two words gives the text
+/// back, so separators never need to be stored with the variables.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tokenizer {
+    delimiters: Vec<char>,
+}
+
+impl Default for Tokenizer {
+    fn default() -> Self {
+        Self::DEFAULT_DELIMITERS.parse().expect("valid delimiters")
+    }
+}
+
+impl Tokenizer {
+    pub const DEFAULT_DELIMITERS: &str = "=:,[]\"'";
+
+    /// Most words glued into one token when masking, enough for a full
+    /// IPv6 address; keeps tokenizing linear in long delimited runs.
+    const MAX_GLUED_WORDS: usize = 8;
+
+    /// Splits at whitespace only.
+    pub fn whitespace() -> Self {
+        Self {
+            delimiters: Vec::new(),
+        }
+    }
+
+    pub fn delimiters(&self) -> &[char] {
+        &self.delimiters
+    }
+
+    /// Whether `token` is a separator rather than a word.
+    pub fn is_separator(&self, token: &str) -> bool {
+        match token.chars().next() {
+            Some(c) if c.is_ascii_alphanumeric() => false,
+            Some(c) => c == ' ' || self.delimiters.contains(&c),
+            None => false,
+        }
+    }
+
+    /// Tokens of `text`, which must be [`canonical`]. With `masking`, words
+    /// joined by delimiters alone stay one token where together they mask
+    /// as one, e.g. `fe80::1` or `22:14:15`, rather than being split apart.
+    pub fn tokenize<'a>(&self, text: &'a str, masking: Option<&Masking>) -> Vec<&'a str> {
+        if !text.contains(self.delimiters.as_slice()) {
+            return text.split(' ').filter(|t| !t.is_empty()).collect();
+        }
+        // Alternating runs of word characters and of spaces and delimiters.
+        let mut runs: Vec<(usize, usize, bool)> = Vec::new();
+        for (at, c) in text.char_indices() {
+            let word = c != ' ' && !self.delimiters.contains(&c);
+            match runs.last_mut() {
+                Some((_, end, w)) if *w == word => *end = at + c.len_utf8(),
+                _ => runs.push((at, at + c.len_utf8(), word)),
+            }
+        }
+
+        let mut tokens = Vec::with_capacity(runs.len());
+        let mut i = 0;
+        while i < runs.len() {
+            let (start, end, word) = runs[i];
+            if !word {
+                if &text[start..end] != " " {
+                    tokens.push(&text[start..end]);
+                }
+                i += 1;
+                continue;
+            }
+            let mut last = i;
+            if let Some(masking) = masking {
+                let mut glued = i;
+                while glued + 2 < runs.len()
+                    && glued < i + 2 * (Self::MAX_GLUED_WORDS - 1)
+                    && !text[runs[glued + 1].0..runs[glued + 1].1].contains(' ')
+                {
+                    glued += 2;
+                }
+                while glued > i {
+                    let span = &text[start..runs[glued].1];
+                    if masking.mask(span) != span {
+                        last = glued;
+                        break;
+                    }
+                    glued -= 2;
+                }
+            }
+            tokens.push(&text[start..runs[last].1]);
+            i = last + 1;
+        }
+        tokens
+    }
+
+    /// Joins `tokens` back into text, with a space between two words.
+    /// Empty tokens, such as a `<*...>` variable that took none, are left
+    /// out.
+    pub fn join<S: AsRef<str>>(&self, tokens: impl IntoIterator<Item = S>) -> String {
+        let mut out = String::new();
+        let mut after_word = false;
+        for token in tokens {
+            let token = token.as_ref();
+            if token.is_empty() {
+                continue;
+            }
+            let word = !self.is_separator(token);
+            if word && after_word {
+                out.push(' ');
+            }
+            out.push_str(token);
+            after_word = word;
+        }
+        out
+    }
+}
+
+impl FromStr for Tokenizer {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut delimiters: Vec<char> = Vec::new();
+        for c in s.chars() {
+            // Markers must survive tokenization whole.
+            if c.is_whitespace() || matches!(c, '<' | '>' | '*') {
+                return Err(format!("'{}' cannot be a delimiter", c.escape_default()));
+            }
+            if !delimiters.contains(&c) {
+                delimiters.push(c);
+            }
+        }
+        Ok(Self { delimiters })
+    }
+}
+
+impl fmt::Display for Tokenizer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.delimiters.iter().try_for_each(|c| write!(f, "{}", c))
+    }
+}
+
+/// `message` as templates give it back: without leading or trailing
+/// whitespace, and with single spaces between words.
+pub fn canonical(message: &str) -> Cow<'_, str> {
+    let mut previous = ' ';
+    let is_canonical = message.chars().all(|c| {
+        let ok = !c.is_whitespace() || (c == ' ' && previous != ' ');
+        previous = c;
+        ok
+    }) && !message.ends_with(' ');
+    if is_canonical {
+        Cow::Borrowed(message)
+    } else {
+        Cow::Owned(message.split_whitespace().collect::<Vec<_>>().join(" "))
+    }
+}
+
+/// Rebuilds a message by substituting `variables`, in order, for the
+/// markers of `pattern`: `<*>`, `<*...>` and placeholders such as `<IP>`.
+/// A `<*...>` that took no tokens takes one of the spaces around it along.
+/// Markers without a variable are left as they are.
+pub fn reconstruct(pattern: &str, variables: &[String]) -> String {
+    let mut out =
+        String::with_capacity(pattern.len() + variables.iter().map(String::len).sum::<usize>());
+    let mut vars = variables.iter();
+    let mut rest = pattern;
+    let mut drop_space = false;
+    fn push_literal(out: &mut String, literal: &str, drop_space: &mut bool) {
+        let literal = match literal.strip_prefix(' ') {
+            Some(stripped) if *drop_space => stripped,
+            _ => literal,
+        };
+        *drop_space = false;
+        out.push_str(literal);
+    }
+    let mut scanned = 0;
+    while let Some(at) = rest[scanned..].find('<').map(|i| scanned + i) {
+        let Some(len) = masking::marker_len(&rest[at..]) else {
+            scanned = at + 1;
+            continue;
+        };
+        let marker = &rest[at..at + len];
+        push_literal(&mut out, &rest[..at], &mut drop_space);
+        match vars.next() {
+            Some(var) if var.is_empty() && marker == VARIADIC => {
+                if out.ends_with(' ') {
+                    out.pop();
+                } else {
+                    drop_space = true;
+                }
+            }
+            Some(var) => out.push_str(var),
+            None => out.push_str(marker),
+        }
+        rest = &rest[at + len..];
+        scanned = 0;
+    }
+    push_literal(&mut out, rest, &mut drop_space);
+    out
 }
 
 pub struct LogChunk {
@@ -29,8 +439,19 @@ pub struct LogChunk {
     pub templates: HashMap<String, u32>,
     pub string_pool: Vec<String>,
     pub string_map: HashMap<String, u32>,
+    pub sd_shapes: Vec<SdShape>,
+    pub sd_shape_map: HashMap<SdShape, u32>,
     pub records: Vec<LogRecord>,
     pub next_template_id: u32,
+    /// File name of the store's template dictionary when template IDs are
+    /// store-wide rather than local to this chunk.
+    pub dictionary: Option<String>,
+    /// Groups the messages into templates. A writer moves it from chunk to
+    /// chunk so later chunks keep matching earlier templates.
+    pub miner: TemplateMiner,
+    /// The IDs in `templates`, to find a free one without scanning them.
+    /// Rebuilt whenever `templates` was changed directly.
+    template_ids: HashSet<u32>,
 }
 
 impl LogChunk {
@@ -46,8 +467,13 @@ impl Default for LogChunk {
             templates: HashMap::new(),
             string_pool: Vec::new(),
             string_map: HashMap::new(),
+            sd_shapes: Vec::new(),
+            sd_shape_map: HashMap::new(),
             records: Vec::with_capacity(10),
             next_template_id: 0,
+            dictionary: None,
+            miner: TemplateMiner::default(),
+            template_ids: HashSet::new(),
         }
     }
 }
@@ -65,149 +491,154 @@ impl LogChunk {
         }
     }
 
+    /// Splits structured data into an interned shape and its values, or
+    /// interns it verbatim if it does not split. Returns the verbatim ID,
+    /// the shape ID and the values.
+    fn intern_structured_data(
+        &mut self,
+        sd: Option<String>,
+    ) -> (Option<u32>, Option<u32>, Vec<String>) {
+        let Some(sd) = sd else {
+            return (None, None, Vec::new());
+        };
+        let Some((shape, values)) = SdShape::split(&sd) else {
+            return (self.intern_string(Some(sd)), None, Vec::new());
+        };
+        let id = match self.sd_shape_map.get(&shape) {
+            Some(&id) => id,
+            None => {
+                let id = self.sd_shapes.len() as u32;
+                self.sd_shape_map.insert(shape.clone(), id);
+                self.sd_shapes.push(shape);
+                id
+            }
+        };
+        (None, Some(id), values)
+    }
+
     pub fn add_message(&mut self, msg: SyslogMessage) {
         self.raw_messages.push(msg);
     }
 
     pub fn import_template(&mut self, pattern: String) {
         if !self.templates.contains_key(&pattern) {
-            let id = self.next_template_id;
+            let id = self.new_template_id(&pattern);
             self.templates.insert(pattern, id);
-            self.next_template_id += 1;
         }
     }
 
+    /// Picks the ID of a template new to this chunk: the dictionary's
+    /// hash-derived ID when IDs are store-wide, the next local one if not.
+    fn new_template_id(&mut self, pattern: &str) -> u32 {
+        if self.dictionary.is_some() {
+            if self.template_ids.len() != self.templates.len() {
+                self.template_ids = self.templates.values().copied().collect();
+            }
+            let taken = &self.template_ids;
+            let id = crate::dictionary::free_id(pattern, |id| taken.contains(&id));
+            self.template_ids.insert(id);
+            return id;
+        }
+        let id = self.next_template_id;
+        self.next_template_id += 1;
+        id
+    }
+
+    /// Adds `pattern` under an ID assigned elsewhere (e.g. by the store's
+    /// template dictionary).
+    pub fn register_template(&mut self, pattern: String, id: u32) {
+        if let Some(old) = self.templates.insert(pattern, id) {
+            self.template_ids.remove(&old);
+        }
+        self.template_ids.insert(id);
+        self.next_template_id = self.next_template_id.max(id + 1);
+    }
+
+    /// Mines templates for the buffered messages with `miner` and turns
+    /// them into records. Returns the templates that are new to this chunk.
     pub fn finish_and_process(&mut self) -> Vec<String> {
         let mut new_templates = Vec::new();
-        let old_templates: HashSet<String> = self.templates.keys().cloned().collect();
-
         if self.raw_messages.is_empty() {
             return new_templates;
         }
 
-        let mut groups: HashMap<usize, Vec<usize>> = HashMap::new();
+        let mut clusters: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
         for (idx, msg) in self.raw_messages.iter().enumerate() {
-            let token_count = msg.message.split_whitespace().count();
-            groups.entry(token_count).or_default().push(idx);
-        }
-
-        let mut group_keys: Vec<usize> = groups.keys().cloned().collect();
-        group_keys.sort();
-
-        for len in group_keys {
-            if let Some(indices) = groups.get(&len) {
-                self.process_group(indices);
-            }
-        }
-
-        for pattern in self.templates.keys() {
-            if !old_templates.contains(pattern) {
-                new_templates.push(pattern.clone());
-            }
+            let text = canonical(&msg.message);
+            let tokens = self.miner.tokenize(&text);
+            clusters
+                .entry(self.miner.add(&tokens))
+                .or_default()
+                .push(idx);
         }
 
-        self.raw_messages.clear();
-        new_templates
-    }
-
-    fn process_group(&mut self, indices: &[usize]) {
-        let mut group_templates: Vec<(Vec<String>, Vec<usize>)> = Vec::new();
-
-        for &idx in indices {
-            let msg_text = &self.raw_messages[idx].message;
-            let tokens: Vec<String> = msg_text.split_whitespace().map(|s| s.to_string()).collect();
-
-            let mut found_match = false;
-            for (template_tokens, member_indices) in &mut group_templates {
-                if self.is_similar(template_tokens, &tokens) {
-                    self.merge_into_template(template_tokens, &tokens);
-                    member_indices.push(idx);
-                    found_match = true;
-                    break;
+        // Templates are only final once every message has been added, as a
+        // later message may still generalize one.
+        for (cluster, indices) in clusters {
+            let tokens = self.miner.template(cluster).to_vec();
+            let pattern = self.miner.pattern(cluster);
+            let template_id = match self.templates.get(&pattern) {
+                Some(&id) => id,
+                None => {
+                    let id = self.new_template_id(&pattern);
+                    self.templates.insert(pattern.clone(), id);
+                    new_templates.push(pattern);
+                    id
                 }
-            }
-
-            if !found_match {
-                group_templates.push((tokens, vec![idx]));
-            }
-        }
-
-        for (tokens, member_indices) in group_templates {
-            let template_str = tokens.join(" ");
-            let template_id = if let Some(&id) = self.templates.get(&template_str) {
-                id
-            } else {
-                let id = self.next_template_id;
-                self.templates.insert(template_str.clone(), id);
-                self.next_template_id += 1;
-                id
             };
-
-            for &idx in &member_indices {
-                let msg = self.raw_messages[idx].clone();
-                let msg_tokens: Vec<&str> = msg.message.split_whitespace().collect();
-                let mut variables = Vec::new();
-
-                for (i, token) in tokens.iter().enumerate() {
-                    if token == "<*>" && i < msg_tokens.len() {
-                        variables.push(msg_tokens[i].to_string());
-                    }
-                }
-
-                let hostname_id = self.intern_string(msg.hostname);
-                let app_name_id = self.intern_string(msg.app_name);
-                let procid_id = self.intern_string(msg.procid);
-                let msgid_id = self.intern_string(msg.msgid);
-                let structured_data_id = self.intern_string(msg.structured_data);
-                let node_id_id = self.intern_string(msg.node_id);
-
-                self.records.push(LogRecord {
-                    timestamp: msg.timestamp.unwrap_or_else(Utc::now).timestamp_millis(),
-                    priority: msg.priority,
-                    hostname_id,
-                    app_name_id,
-                    procid_id,
-                    msgid_id,
-                    structured_data_id,
-                    template_id,
-                    variables,
-                    is_rfc5424: msg.is_rfc5424,
-                    node_id_id,
-                });
-            }
-        }
-    }
-
-    fn is_similar(&self, template: &[String], tokens: &[String]) -> bool {
-        if template.len() != tokens.len() {
-            return false;
-        }
-        if template.is_empty() {
-            return true;
-        }
-
-        let mut sim_count = 0;
-        for (t, s) in template.iter().zip(tokens.iter()) {
-            if t == s || t == "<*>" {
-                sim_count += 1;
+            for idx in indices {
+                self.push_record(idx, &tokens, template_id);
             }
         }
 
-        sim_count as f32 / template.len() as f32 >= 0.5
+        self.raw_messages.clear();
+        new_templates
     }
 
-    fn merge_into_template(&self, template: &mut [String], tokens: &[String]) {
-        for i in 0..template.len() {
-            if template[i] != tokens[i] && template[i] != "<*>" {
-                template[i] = "<*>".to_string();
-            }
-        }
+    fn push_record(&mut self, idx: usize, template: &[String], template_id: u32) {
+        let msg = self.raw_messages[idx].clone();
+        let text = canonical(&msg.message);
+        let tokens = self.miner.tokenize(&text);
+        let variables = self.miner.variables(template, &tokens);
+
+        let hostname_id = self.intern_string(msg.hostname);
+        let app_name_id = self.intern_string(msg.app_name);
+        let procid_id = self.intern_string(msg.procid);
+        let msgid_id = self.intern_string(msg.msgid);
+        let (structured_data_id, sd_shape, sd_values) =
+            self.intern_structured_data(msg.structured_data);
+        let node_id_id = self.intern_string(msg.node_id);
+        // A message that does not split is kept whole, so every lossless
+        // record can be given back.
+        let layout = msg
+            .raw
+            .as_deref()
+            .map(|raw| Layout::capture(raw, &msg.message).unwrap_or_else(|| Layout::verbatim(raw)));
+
+        self.records.push(LogRecord {
+            timestamp: msg.timestamp.unwrap_or_else(Utc::now).timestamp_millis(),
+            priority: msg.priority,
+            hostname_id,
+            app_name_id,
+            procid_id,
+            msgid_id,
+            structured_data_id,
+            sd_shape,
+            sd_values,
+            template_id,
+            variables,
+            is_rfc5424: msg.is_rfc5424,
+            node_id_id,
+            layout,
+        });
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::masking::Masking;
+    use crate::miner::MinerConfig;
     use chrono::Utc;
     use sankshepa_protocol::SyslogMessage;
 
@@ -224,7 +655,193 @@ mod tests {
             structured_data: None,
             message: text.to_string(),
             is_rfc5424: false,
+            node_id: None,
+            raw: None,
+        }
+    }
+
+    #[test]
+    fn test_var_type_inference() {
+        assert_eq!(VarType::infer(["12", "-7", "0"]), VarType::Int);
+        assert_eq!(VarType::infer(["12", "007"]), VarType::Hex { upper: false });
+        assert_eq!(VarType::infer(["192.168.1.2", "10.0.0.1"]), VarType::Ipv4);
+        assert_eq!(VarType::infer(["192.168.01.2"]), VarType::Str);
+        assert_eq!(VarType::infer(["fe80::1", "::1"]), VarType::Ipv6);
+        assert_eq!(
+            VarType::infer(["123e4567-e89b-12d3-a456-426614174000"]),
+            VarType::Uuid { upper: false }
+        );
+        assert_eq!(
+            VarType::infer(["DEADBEEF", "0A"]),
+            VarType::Hex { upper: true }
+        );
+        assert_eq!(VarType::infer(["deadbeef", "0A"]), VarType::Str);
+        assert_eq!(VarType::infer(["alice", "12"]), VarType::Str);
+        assert_eq!(VarType::infer([""]), VarType::Str);
+    }
+
+    #[test]
+    fn test_layout_restores_whitespace() {
+        for raw in [
+            "<34>Oct 11 22:14:15 host su:  'su root'\tfailed \t",
+            "<34>1 2003-10-11T22:14:15.003Z host app - - - single",
+            "<34>1 2003-10-11T22:14:15.003Z host app - - -  ",
+        ] {
+            let msg = sankshepa_protocol::UnifiedParser::parse(raw).unwrap();
+            let layout = Layout::capture(raw.as_bytes(), &msg.message).unwrap();
+            let canonical = msg.message.split_whitespace().collect::<Vec<_>>().join(" ");
+            assert_eq!(layout.restore(&canonical), raw.as_bytes());
         }
+
+        let layout = Layout::capture(b"<34>Oct 11 22:14:15 host a b c", "a b c").unwrap();
+        assert!(layout.gaps.is_empty());
+        assert!(Layout::capture(b"<34>Oct 11 22:14:15 host a b", "c").is_none());
+        // Invalid UTF-8 in the message keeps the bytes whole.
+        let raw = b"<34>Oct 11 22:14:15 host su: bad \xff byte";
+        let msg = sankshepa_protocol::UnifiedParser::parse_lossless(raw).unwrap();
+        assert!(Layout::capture(raw, &msg.message).is_none());
+        assert_eq!(Layout::verbatim(raw).restore(&msg.message), raw);
+    }
+
+    #[test]
+    fn test_tokenizer_round_trips() {
+        let tokenizer = Tokenizer::default();
+        let masking = Masking::default();
+        let cases: [(&str, &[&str]); 5] = [
+            (
+                "user=alice, port=22",
+                &["user", "=", "alice", ", ", "port", "=", "22"],
+            ),
+            (
+                "su: 'su root' failed",
+                &["su", ": '", "su", "root", "' ", "failed"],
+            ),
+            // Shapes that contain delimiters stay whole.
+            (
+                "at 22:14:15 from fe80::1 [pid 7]",
+                &["at", "22:14:15", "from", "fe80::1", " [", "pid", "7", "]"],
+            ),
+            ("=x==", &["=", "x", "=="]),
+            ("", &[]),
+        ];
+        for (text, expected) in cases {
+            let tokens = tokenizer.tokenize(text, Some(&masking));
+            assert_eq!(tokens, expected);
+            assert_eq!(tokenizer.join(&tokens), text);
+        }
+        assert_eq!(
+            tokenizer.tokenize("at 22:14:15", None),
+            ["at", "22", ":", "14", ":", "15"]
+        );
+        assert_eq!(
+            tokenizer.tokenize("2001:db8:0:0:0:0:0:1", Some(&masking)),
+            ["2001:db8:0:0:0:0:0:1"]
+        );
+        // A long delimited run is only glued a few words at a time.
+        let run = vec!["a"; 10_000].join(":");
+        assert_eq!(
+            tokenizer.join(tokenizer.tokenize(&run, Some(&masking))),
+            run
+        );
+        assert_eq!(
+            Tokenizer::whitespace().tokenize("a=b, c", Some(&masking)),
+            ["a=b,", "c"]
+        );
+
+        assert_eq!("=;".parse::<Tokenizer>().unwrap().delimiters(), ['=', ';']);
+        assert!("= ".parse::<Tokenizer>().is_err());
+        assert!("*".parse::<Tokenizer>().is_err());
+        assert_eq!(
+            Tokenizer::default().to_string(),
+            Tokenizer::DEFAULT_DELIMITERS
+        );
+
+        assert!(matches!(canonical("a b"), Cow::Borrowed("a b")));
+        assert_eq!(canonical(" a \t b  "), "a b");
+    }
+
+    #[test]
+    fn test_key_value_messages_share_template() {
+        let texts = [
+            "conn user=alice, port=22, state=[open]",
+            "conn user=bob, port=2222, state=[closed]",
+            "conn user=carol,  port=80,\tstate=[half open]",
+        ];
+        let mut chunk = LogChunk::new();
+        for text in texts {
+            chunk.add_message(create_msg(text));
+        }
+        chunk.finish_and_process();
+
+        let patterns: Vec<&String> = chunk.templates.keys().collect();
+        assert_eq!(patterns, ["conn user=<*>, port=<NUM>, state=[<*...>]"]);
+        let rebuilt: Vec<String> = chunk
+            .records
+            .iter()
+            .map(|r| reconstruct(patterns[0], &r.variables))
+            .collect();
+        let expected: Vec<String> = texts.iter().map(|t| canonical(t).into_owned()).collect();
+        assert_eq!(rebuilt, expected);
+    }
+
+    #[test]
+    fn test_reconstruct_variadic() {
+        let vars = |v: &[&str]| v.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            reconstruct("Disk <*...> full", &vars(&["/dev/mapper/vg0 root"])),
+            "Disk /dev/mapper/vg0 root full"
+        );
+        assert_eq!(reconstruct("Disk <*...> full", &vars(&[""])), "Disk full");
+        assert_eq!(reconstruct("<*...> done", &vars(&[""])), "done");
+        assert_eq!(
+            reconstruct("<*> failed: <*...>", &vars(&["job", ""])),
+            "job failed:"
+        );
+        assert_eq!(reconstruct("a <*> <*", &vars(&["x"])), "a x <*");
+        assert_eq!(reconstruct("a <*...>", &[]), "a <*...>");
+        assert_eq!(
+            reconstruct("from <IP> port <NUM> <html>", &vars(&["10.0.0.1", "22"])),
+            "from 10.0.0.1 port 22 <html>"
+        );
+    }
+
+    #[test]
+    fn test_variable_length_records_reconstruct() {
+        let texts = [
+            "Disk /dev/sda full",
+            "Disk /dev/mapper/vg0 root full",
+            "Disk full",
+            "Backup job failed: disk full",
+            "Backup job failed: out of memory",
+            // Text that reads as markers is kept as variables.
+            "Literal <*> and <IP> x<*...>y",
+        ];
+        let mut chunk = LogChunk::new();
+        chunk.miner = TemplateMiner::new(MinerConfig {
+            masking: Masking::none(),
+            ..Default::default()
+        });
+        for text in texts {
+            chunk.add_message(create_msg(text));
+        }
+        chunk.finish_and_process();
+
+        assert_eq!(chunk.templates.len(), 3);
+        assert!(chunk.templates.contains_key("Literal <*> and <*> <*>"));
+        let patterns: HashMap<u32, &str> = chunk
+            .templates
+            .iter()
+            .map(|(p, &id)| (id, p.as_str()))
+            .collect();
+        let mut rebuilt: Vec<String> = chunk
+            .records
+            .iter()
+            .map(|r| reconstruct(patterns[&r.template_id], &r.variables))
+            .collect();
+        rebuilt.sort();
+        let mut expected: Vec<String> = texts.iter().map(|t| t.to_string()).collect();
+        expected.sort();
+        assert_eq!(rebuilt, expected);
     }
 
     #[test]
@@ -239,7 +856,7 @@ mod tests {
         assert_eq!(chunk.templates.len(), 2);
 
         let patterns: Vec<String> = chunk.templates.keys().cloned().collect();
-        assert!(patterns.iter().any(|p| p == "User <*> logged in from <*>"));
+        assert!(patterns.iter().any(|p| p == "User <*> logged in from <IP>"));
         assert!(patterns.iter().any(|p| p == "System restart"));
 
         assert_eq!(chunk.records.len(), 3);
@@ -0,0 +1,461 @@
+use crate::miner::{VARIADIC, WILDCARD};
+use regex::Regex;
+use std::fmt;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+/// A well-known shape of variable token, masked as a typed placeholder
+/// before templates are mined.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Shape {
+    /// `8-4-4-4-12` hex digits.
+    Uuid,
+    /// Six hex byte pairs separated by `:` or `-`.
+    Mac,
+    /// IPv4 or IPv6 address.
+    Ip,
+    /// `local@domain.tld`.
+    Email,
+    /// A number with a unit, e.g. `150ms`, `1.5s` or `2h30m`.
+    Duration,
+    /// `HH:MM:SS` or an ISO 8601 date, with optional fractions and time.
+    Time,
+    /// `0x`-prefixed hex, or eight or more hex digits with a digit among
+    /// them.
+    Hex,
+    /// Decimal number, optionally signed or with a fraction.
+    Num,
+    /// Absolute or `./`/`../`-relative path.
+    Path,
+}
+
+impl Shape {
+    /// Every shape, in the order tokens are tested against them.
+    pub const ALL: [Shape; 9] = [
+        Shape::Uuid,
+        Shape::Mac,
+        Shape::Ip,
+        Shape::Email,
+        Shape::Duration,
+        Shape::Time,
+        Shape::Num,
+        Shape::Hex,
+        Shape::Path,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Shape::Uuid => "uuid",
+            Shape::Mac => "mac",
+            Shape::Ip => "ip",
+            Shape::Email => "email",
+            Shape::Duration => "duration",
+            Shape::Time => "time",
+            Shape::Hex => "hex",
+            Shape::Num => "num",
+            Shape::Path => "path",
+        }
+    }
+
+    /// The placeholder a token of this shape is masked as.
+    pub fn placeholder(self) -> &'static str {
+        match self {
+            Shape::Uuid => "<UUID>",
+            Shape::Mac => "<MAC>",
+            Shape::Ip => "<IP>",
+            Shape::Email => "<EMAIL>",
+            Shape::Duration => "<DURATION>",
+            Shape::Time => "<TIME>",
+            Shape::Hex => "<HEX>",
+            Shape::Num => "<NUM>",
+            Shape::Path => "<PATH>",
+        }
+    }
+
+    pub fn matches(self, token: &str) -> bool {
+        match self {
+            Shape::Uuid => is_uuid(token),
+            Shape::Mac => is_mac(token),
+            Shape::Ip => token.parse::<Ipv4Addr>().is_ok() || token.parse::<Ipv6Addr>().is_ok(),
+            Shape::Email => is_email(token),
+            Shape::Duration => is_duration(token),
+            Shape::Time => is_time(token),
+            Shape::Hex => is_hex(token),
+            Shape::Num => is_num(token),
+            Shape::Path => is_path(token),
+        }
+    }
+}
+
+impl FromStr for Shape {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Shape::ALL
+            .into_iter()
+            .find(|shape| shape.name() == s)
+            .ok_or_else(|| {
+                let names: Vec<&str> = Shape::ALL.iter().map(|s| s.name()).collect();
+                format!(
+                    "unknown shape '{}', expected one of {}",
+                    s,
+                    names.join(", ")
+                )
+            })
+    }
+}
+
+/// A user-defined `NAME=REGEX` rule: tokens the regular expression matches
+/// as a whole are masked as `<NAME>`. Matching takes time linear in the
+/// token whatever the expression.
+#[derive(Debug, Clone)]
+pub struct MaskRule {
+    placeholder: String,
+    pattern: String,
+    regex: Regex,
+}
+
+impl MaskRule {
+    pub fn placeholder(&self) -> &str {
+        &self.placeholder
+    }
+
+    pub fn matches(&self, token: &str) -> bool {
+        self.regex.is_match(token)
+    }
+}
+
+impl PartialEq for MaskRule {
+    fn eq(&self, other: &Self) -> bool {
+        self.placeholder == other.placeholder && self.pattern == other.pattern
+    }
+}
+
+impl Eq for MaskRule {}
+
+impl FromStr for MaskRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, pattern) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected NAME=REGEX, got '{}'", s))?;
+        let placeholder = format!("<{}>", name);
+        if !is_placeholder(&placeholder) {
+            return Err(format!(
+                "rule name '{}' must be upper-case letters, digits and '_', starting with a letter",
+                name
+            ));
+        }
+        if pattern.is_empty() {
+            return Err(format!("rule '{}' has an empty regex", name));
+        }
+        let regex = Regex::new(&format!("^(?:{})$", pattern))
+            .map_err(|e| format!("rule '{}' has an invalid regex: {}", name, e))?;
+        Ok(Self {
+            placeholder,
+            pattern: pattern.to_string(),
+            regex,
+        })
+    }
+}
+
+impl fmt::Display for MaskRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = &self.placeholder[1..self.placeholder.len() - 1];
+        write!(f, "{}={}", name, self.pattern)
+    }
+}
+
+/// Which tokens are masked before mining: user rules first, then the
+/// enabled built-in shapes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Masking {
+    pub shapes: Vec<Shape>,
+    pub rules: Vec<MaskRule>,
+}
+
+impl Default for Masking {
+    fn default() -> Self {
+        Self {
+            shapes: Shape::ALL.to_vec(),
+            rules: Vec::new(),
+        }
+    }
+}
+
+impl Masking {
+    /// Masks nothing but marker-like text.
+    pub fn none() -> Self {
+        Self {
+            shapes: Vec::new(),
+            rules: Vec::new(),
+        }
+    }
+
+    /// What the miner sees of `token`: the placeholder of the first rule or
+    /// shape it matches, or the token itself. Text that would read as a
+    /// marker in a template is always masked as `<*>`, so it is stored as a
+    /// variable and comes back unchanged.
+    pub fn mask<'t>(&'t self, token: &'t str) -> &'t str {
+        if has_marker(token) {
+            return WILDCARD;
+        }
+        if let Some(rule) = self.rules.iter().find(|r| r.matches(token)) {
+            return &rule.placeholder;
+        }
+        // Every shape needs a digit or one of these; most words have neither.
+        if !token
+            .bytes()
+            .any(|b| b.is_ascii_digit() || b"@/:-.".contains(&b))
+        {
+            return token;
+        }
+        match self.shapes.iter().find(|s| s.matches(token)) {
+            Some(shape) => shape.placeholder(),
+            None => token,
+        }
+    }
+}
+
+/// Length of the marker `s` starts with: `<*>`, `<*...>` or a placeholder
+/// such as `<IP>`.
+pub fn marker_len(s: &str) -> Option<usize> {
+    if s.starts_with(WILDCARD) {
+        return Some(WILDCARD.len());
+    }
+    if s.starts_with(VARIADIC) {
+        return Some(VARIADIC.len());
+    }
+    let name = s.strip_prefix('<')?;
+    let end = name.find('>')?;
+    let name = &name[..end];
+    let valid = name.starts_with(|c: char| c.is_ascii_uppercase())
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_uppercase() || b.is_ascii_digit() || b == b'_');
+    valid.then_some(end + 2)
+}
+
+/// Whether `token` is a typed placeholder such as `<IP>`.
+pub fn is_placeholder(token: &str) -> bool {
+    token != WILDCARD && token != VARIADIC && marker_len(token) == Some(token.len())
+}
+
+/// Whether `token` holds a marker anywhere.
+fn has_marker(token: &str) -> bool {
+    token
+        .match_indices('<')
+        .any(|(at, _)| token[at..].starts_with("<*") || marker_len(&token[at..]).is_some())
+}
+
+fn is_uuid(s: &str) -> bool {
+    s.len() == 36
+        && s.bytes().enumerate().all(|(i, b)| match i {
+            8 | 13 | 18 | 23 => b == b'-',
+            _ => b.is_ascii_hexdigit(),
+        })
+}
+
+fn is_mac(s: &str) -> bool {
+    s.len() == 17
+        && s.bytes().enumerate().all(|(i, b)| match i % 3 {
+            2 => b == s.as_bytes()[2] && (b == b':' || b == b'-'),
+            _ => b.is_ascii_hexdigit(),
+        })
+}
+
+fn is_email(s: &str) -> bool {
+    let Some((local, domain)) = s.split_once('@') else {
+        return false;
+    };
+    let label = |l: &str| {
+        !l.is_empty()
+            && l.bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+    };
+    !local.is_empty()
+        && local
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"._%+-".contains(&b))
+        && domain.contains('.')
+        && domain.split('.').all(label)
+}
+
+fn is_num(s: &str) -> bool {
+    let s = s.strip_prefix(['-', '+']).unwrap_or(s);
+    let (int, frac) = s.split_once('.').unwrap_or((s, "0"));
+    !int.is_empty()
+        && !frac.is_empty()
+        && int.bytes().all(|b| b.is_ascii_digit())
+        && frac.bytes().all(|b| b.is_ascii_digit())
+}
+
+/// One or more `<number><unit>` parts, e.g. `1h30m` or `2.5ms`.
+fn is_duration(s: &str) -> bool {
+    const UNITS: [&str; 9] = ["ns", "us", "µs", "ms", "s", "m", "h", "d", "w"];
+    let mut rest = s;
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(rest.len());
+        if !is_num(&rest[..digits]) {
+            return false;
+        }
+        rest = &rest[digits..];
+        let Some(unit) = UNITS.into_iter().find(|u| rest.starts_with(u)) else {
+            return false;
+        };
+        rest = &rest[unit.len()..];
+    }
+    !s.is_empty()
+}
+
+/// `HH:MM:SS[.frac]`, or `YYYY-MM-DD` optionally followed by `T` and such a
+/// time with an optional `Z` or offset.
+fn is_time(s: &str) -> bool {
+    fn digits(s: &str, n: usize) -> bool {
+        s.len() == n && s.bytes().all(|b| b.is_ascii_digit())
+    }
+    fn clock(s: &str) -> bool {
+        let s = s.strip_suffix('Z').unwrap_or(s);
+        let s = match s.get(8..).and_then(|t| t.find(['+', '-'])) {
+            Some(at) => {
+                let (time, offset) = s.split_at(8 + at);
+                let offset = &offset[1..];
+                let colon = offset.len() == 5
+                    && offset.as_bytes()[2] == b':'
+                    && digits(&offset[..2], 2)
+                    && digits(&offset[3..], 2);
+                if !(digits(offset, 4) || colon) {
+                    return false;
+                }
+                time
+            }
+            None => s,
+        };
+        let (hms, frac) = s.split_once('.').unwrap_or((s, "0"));
+        hms.len() == 8
+            && hms.as_bytes()[2] == b':'
+            && hms.as_bytes()[5] == b':'
+            && [&hms[..2], &hms[3..5], &hms[6..]]
+                .iter()
+                .all(|p| digits(p, 2))
+            && !frac.is_empty()
+            && digits(frac, frac.len())
+    }
+    if clock(s) {
+        return true;
+    }
+    let (date, time) = s.split_once('T').map_or((s, None), |(d, t)| (d, Some(t)));
+    let is_date = date.len() == 10
+        && date.as_bytes()[4] == b'-'
+        && date.as_bytes()[7] == b'-'
+        && digits(&date[..4], 4)
+        && digits(&date[5..7], 2)
+        && digits(&date[8..], 2);
+    is_date && time.is_none_or(clock)
+}
+
+fn is_hex(s: &str) -> bool {
+    if let Some(digits) = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        return !digits.is_empty() && digits.bytes().all(|b| b.is_ascii_hexdigit());
+    }
+    s.len() >= 8
+        && s.bytes().all(|b| b.is_ascii_hexdigit())
+        && s.bytes().any(|b| b.is_ascii_digit())
+}
+
+fn is_path(s: &str) -> bool {
+    let rest = ["/", "./", "../", "~/"]
+        .into_iter()
+        .find_map(|prefix| s.strip_prefix(prefix));
+    match rest {
+        Some(rest) => !rest.is_empty() && !rest.starts_with('/') && !rest.contains("//"),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builtin_shapes() {
+        let masking = Masking::default();
+        for (token, placeholder) in [
+            ("123e4567-e89b-12d3-a456-426614174000", "<UUID>"),
+            ("00:1A:2b:3c:4D:5e", "<MAC>"),
+            ("00-1a-2b-3c-4d-5e", "<MAC>"),
+            ("192.168.1.1", "<IP>"),
+            ("fe80::1", "<IP>"),
+            ("alice@example.com", "<EMAIL>"),
+            ("150ms", "<DURATION>"),
+            ("1h30m", "<DURATION>"),
+            ("2.5s", "<DURATION>"),
+            ("22:14:15", "<TIME>"),
+            ("2003-10-11T22:14:15.003Z", "<TIME>"),
+            ("2026-10-16", "<TIME>"),
+            ("2026-10-16T14:00:00+02:00", "<TIME>"),
+            ("0x7fff", "<HEX>"),
+            ("deadbeef01", "<HEX>"),
+            ("42", "<NUM>"),
+            ("-3.25", "<NUM>"),
+            ("/var/log/syslog", "<PATH>"),
+            ("./run.sh", "<PATH>"),
+        ] {
+            assert_eq!(masking.mask(token), placeholder, "{}", token);
+        }
+        // Words that only look a bit like a shape are kept.
+        for token in [
+            "deadbeef",
+            "face",
+            "1.2.3",
+            "/",
+            "user@host",
+            "5x",
+            "ms",
+            "12:30",
+            "-",
+            "error:",
+        ] {
+            assert_eq!(masking.mask(token), token);
+        }
+        assert_eq!(Masking::none().mask("42"), "42");
+    }
+
+    #[test]
+    fn test_user_rules_and_markers() {
+        let rule: MaskRule = r"TICKET=INC\d{5}".parse().unwrap();
+        assert_eq!(rule.to_string(), r"TICKET=INC\d{5}");
+        let masking = Masking {
+            shapes: vec![Shape::Num],
+            rules: vec![rule, "JOB=job-.*|cron".parse().unwrap()],
+        };
+        assert_eq!(masking.mask("INC00042"), "<TICKET>");
+        assert_eq!(masking.mask("INC0042"), "INC0042");
+        // The whole token has to match, in every alternative.
+        assert_eq!(masking.mask("xINC00042"), "xINC00042");
+        assert_eq!(masking.mask("job-nightly"), "<JOB>");
+        assert_eq!(masking.mask("job-"), "<JOB>");
+        assert_eq!(masking.mask("cron"), "<JOB>");
+        assert_eq!(masking.mask("crond"), "crond");
+        assert_eq!(masking.mask("17"), "<NUM>");
+
+        // Text a template would read as a marker is always a variable.
+        for token in ["<*>", "<IP>", "a<*...>b", "x<*", "<NUM>,"] {
+            assert_eq!(Masking::none().mask(token), WILDCARD, "{}", token);
+        }
+        assert_eq!(Masking::none().mask("<html>"), "<html>");
+
+        assert!("lower=x".parse::<MaskRule>().is_err());
+        assert!("X=".parse::<MaskRule>().is_err());
+        assert!("X".parse::<MaskRule>().is_err());
+        assert!("X=(".parse::<MaskRule>().is_err());
+        assert_eq!("ip".parse::<Shape>(), Ok(Shape::Ip));
+        assert!("word".parse::<Shape>().is_err());
+        assert!(is_placeholder("<IP>"));
+        assert!(!is_placeholder("<*>"));
+        assert_eq!(marker_len("<*...> tail"), Some(6));
+    }
+}
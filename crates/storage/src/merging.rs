@@ -0,0 +1,413 @@
+use crate::StorageEngine;
+use crate::compaction::{Dictionary, remove_container, replace};
+use crate::compression::{BlockDictionaries, BlockEncoder, Column, CompressionConfig};
+use crate::dictionary::TemplateDictionary;
+use crate::encryption::Keyring;
+use crate::index::ChunkIndex;
+use crate::logshrink::{canonical, reconstruct};
+use crate::masking::is_placeholder;
+use crate::miner::{MinerConfig, TemplateMiner, VARIADIC, WILDCARD};
+use crate::reader::{ColumnSet, ReadMode};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// How near-duplicate templates are found and merged.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MergeConfig {
+    /// Fraction of positions at which two templates must agree, or one has
+    /// a `<*>`, to be merged. Stricter than mining, so only templates that
+    /// differ in a detail are.
+    pub similarity: f32,
+    pub compression: CompressionConfig,
+    /// Tokenizer, routing and wildcard limit the templates are compared
+    /// with; its `similarity` gives way to the one above.
+    pub miner: MinerConfig,
+}
+
+impl Default for MergeConfig {
+    fn default() -> Self {
+        Self {
+            similarity: 0.8,
+            compression: CompressionConfig::default(),
+            miner: MinerConfig::default(),
+        }
+    }
+}
+
+/// Outcome of a merge pass.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MergeReport {
+    /// Distinct template IDs referenced before and after the pass.
+    pub templates_before: usize,
+    pub templates_after: usize,
+    pub records_remapped: usize,
+    pub files_rewritten: usize,
+    /// Each merged template's old ID and the ID of the template it was
+    /// merged into.
+    pub remapped: BTreeMap<u32, u32>,
+}
+
+/// Merges the near-duplicate templates used by the containers at `paths`,
+/// such as `User alice logged in` mined in one chunk and `User <*> logged
+/// in` in a later one, and rewrites the containers so their records use
+/// the merged templates.
+///
+/// Only chunks that reference a store dictionary take part: `dictionary`,
+/// or the one the chunks name when `None`. Merged templates are added to
+/// it like mined ones; the IDs they replace stay in it, unused. The caller
+/// must make sure nobody else writes to the containers meanwhile; a writer
+/// may keep adding templates to a shared `dictionary`, which is only locked
+/// while the merges are planned. Sealed frames are opened, and the new ones
+/// sealed, with `keys`.
+pub fn merge_templates(
+    paths: &[PathBuf],
+    dictionary: Option<&Mutex<TemplateDictionary>>,
+    config: &MergeConfig,
+    keys: Option<&Keyring>,
+) -> anyhow::Result<MergeReport> {
+    let mut dictionary = dictionary.map(Dictionary::Shared);
+    let mut uses = Vec::new();
+    for path in paths {
+        let mut ids = BTreeSet::new();
+        let columns = ColumnSet::of(&[Column::TemplateId]);
+        for chunk in StorageEngine::iter_columns(path, columns, ReadMode::Buffered, keys)? {
+            let chunk = chunk?;
+            let Some(name) = &chunk.dictionary else {
+                continue;
+            };
+            let dictionary = match &mut dictionary {
+                Some(d) => d,
+                None => {
+                    let dir = path.parent().unwrap_or(Path::new("."));
+                    let opened = TemplateDictionary::open(dir.join(name), keys)?;
+                    dictionary.insert(Dictionary::Owned(opened))
+                }
+            };
+            if dictionary.with(|d| d.file_name()).as_ref() == Some(name) {
+                ids.extend(chunk.template_ids.iter().flatten().copied());
+            }
+        }
+        uses.push((path, ids));
+    }
+    let Some(dictionary) = &mut dictionary else {
+        return Ok(MergeReport::default());
+    };
+    let Some(name) = dictionary.with(|d| d.file_name()) else {
+        return Ok(MergeReport::default());
+    };
+
+    let used: BTreeSet<u32> = uses.iter().flat_map(|(_, ids)| ids).copied().collect();
+    let miner = TemplateMiner::new(MinerConfig {
+        similarity: config.similarity,
+        ..config.miner.clone()
+    });
+    let (miner, remaps) = dictionary.with(|d| -> anyhow::Result<_> {
+        let planned = plan(miner, d, &used);
+        // Chunks are only written after the templates they use are persisted.
+        d.persist()?;
+        Ok(planned)
+    })?;
+
+    let mut report = MergeReport {
+        templates_before: used.len(),
+        remapped: remaps.iter().map(|(&from, r)| (from, r.to)).collect(),
+        ..Default::default()
+    };
+    let mut after = BTreeSet::new();
+    for (path, ids) in uses {
+        if !ids.iter().any(|id| remaps.contains_key(id)) {
+            after.extend(ids);
+            continue;
+        }
+        let rewrite = Rewrite {
+            dictionary: &name,
+            remaps: &remaps,
+            miner: &miner,
+            compression: &config.compression,
+            keys,
+        };
+        let (remapped, ids) = rewrite.file(path)?;
+        report.records_remapped += remapped;
+        report.files_rewritten += 1;
+        after.extend(ids);
+    }
+    report.templates_after = after.len();
+    Ok(report)
+}
+
+/// How the records of a template move to the template it was merged into.
+struct Remap {
+    to: u32,
+    from_pattern: String,
+    from_tokens: Vec<String>,
+    to_pattern: String,
+    to_tokens: Vec<String>,
+}
+
+impl Remap {
+    /// The variables a record with `variables` has under the merged
+    /// template, or `None` if they would not give back the same message.
+    fn variables(&self, variables: &[String], miner: &TemplateMiner) -> Option<Vec<String>> {
+        let mut values = variables.iter().peekable();
+        let tokens: Vec<&str> = self
+            .from_tokens
+            .iter()
+            .map(|t| {
+                let marker = t == WILDCARD || t == VARIADIC || is_placeholder(t);
+                match values.next_if(|_| marker) {
+                    Some(value) => value.as_str(),
+                    None => t.as_str(),
+                }
+            })
+            .collect();
+        let moved = miner.variables(&self.to_tokens, &tokens);
+        (reconstruct(&self.to_pattern, &moved) == reconstruct(&self.from_pattern, variables))
+            .then_some(moved)
+    }
+}
+
+/// Runs the `used` templates through `miner` and returns it with a remap
+/// for each template that ended up sharing a cluster with others. The most
+/// general templates go first, so the specific ones join them rather than
+/// the other way round.
+fn plan(
+    mut miner: TemplateMiner,
+    dictionary: &mut TemplateDictionary,
+    used: &BTreeSet<u32>,
+) -> (TemplateMiner, HashMap<u32, Remap>) {
+    let tokenizer = miner.config().tokenizer.clone();
+    let mut templates: Vec<(u32, String, Vec<String>)> = used
+        .iter()
+        .filter_map(|&id| {
+            let pattern = dictionary.pattern(id)?.to_string();
+            let text = canonical(&pattern);
+            let tokens = tokenizer.tokenize(&text, None);
+            let tokens = tokens.into_iter().map(str::to_string).collect();
+            Some((id, pattern, tokens))
+        })
+        .collect();
+    let wildcards = |tokens: &[String]| {
+        tokens
+            .iter()
+            .filter(|t| *t == WILDCARD || *t == VARIADIC)
+            .count()
+    };
+    templates.sort_by_key(|(id, _, tokens)| (std::cmp::Reverse(wildcards(tokens)), *id));
+
+    let mut clusters: BTreeMap<usize, Vec<(u32, String, Vec<String>)>> = BTreeMap::new();
+    for template in templates {
+        let tokens: Vec<&str> = template.2.iter().map(String::as_str).collect();
+        let cluster = miner.add_masked(&tokens);
+        clusters.entry(cluster).or_default().push(template);
+    }
+
+    let mut remaps = HashMap::new();
+    for (cluster, members) in clusters {
+        if members.len() < 2 {
+            continue;
+        }
+        let to_pattern = miner.pattern(cluster);
+        let to = dictionary.get_or_insert(&to_pattern);
+        for (id, from_pattern, from_tokens) in members {
+            if id != to {
+                let remap = Remap {
+                    to,
+                    from_pattern,
+                    from_tokens,
+                    to_pattern: to_pattern.clone(),
+                    to_tokens: miner.template(cluster).to_vec(),
+                };
+                remaps.insert(id, remap);
+            }
+        }
+    }
+    (miner, remaps)
+}
+
+/// Rewrites containers with their records moved to merged templates.
+struct Rewrite<'a> {
+    dictionary: &'a str,
+    remaps: &'a HashMap<u32, Remap>,
+    miner: &'a TemplateMiner,
+    compression: &'a CompressionConfig,
+    keys: Option<&'a Keyring>,
+}
+
+impl Rewrite<'_> {
+    /// Rewrites the container at `path` and atomically replaces it. Returns
+    /// the number of records moved and the template IDs used afterwards.
+    fn file(&self, path: &Path) -> anyhow::Result<(usize, BTreeSet<u32>)> {
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".merge");
+        let tmp = PathBuf::from(tmp);
+        remove_container(&tmp)?;
+        // Keep the existing zstd dictionaries, as compaction does.
+        let zdict = BlockDictionaries::sidecar_path(path);
+        if zdict.exists() {
+            fs::copy(&zdict, BlockDictionaries::sidecar_path(&tmp))?;
+        }
+
+        let mut encoder = BlockEncoder::new(self.compression.clone()).with_keys(self.keys);
+        let mut remapped = 0;
+        let mut ids = BTreeSet::new();
+        let result = (|| -> anyhow::Result<()> {
+            for chunk in StorageEngine::iter_chunks(path, self.keys)? {
+                let mut chunk = chunk?;
+                if chunk.dictionary.as_deref() == Some(self.dictionary) {
+                    for record in &mut chunk.records {
+                        let Some(remap) = self.remaps.get(&record.template_id) else {
+                            continue;
+                        };
+                        if let Some(variables) = remap.variables(&record.variables, self.miner) {
+                            record.template_id = remap.to;
+                            record.variables = variables;
+                            remapped += 1;
+                        }
+                    }
+                    ids.extend(chunk.records.iter().map(|r| r.template_id));
+                }
+                StorageEngine::save_chunk_with(&chunk, &tmp.to_string_lossy(), &mut encoder)?;
+            }
+            Ok(())
+        })();
+        if let Err(e) = result {
+            remove_container(&tmp)?;
+            return Err(e);
+        }
+
+        replace(&BlockDictionaries::sidecar_path(&tmp), &zdict)?;
+        fs::rename(&tmp, path)?;
+        fs::rename(
+            ChunkIndex::sidecar_path(&tmp),
+            ChunkIndex::sidecar_path(path),
+        )?;
+        Ok((remapped, ids))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+    use sankshepa_protocol::SyslogMessage;
+
+    fn msg(text: &str) -> SyslogMessage {
+        SyslogMessage {
+            priority: 34,
+            facility: 4,
+            severity: 2,
+            timestamp: Some(Utc::now()),
+            hostname: Some("host".to_string()),
+            app_name: Some("sshd".to_string()),
+            procid: None,
+            msgid: None,
+            structured_data: None,
+            message: text.to_string(),
+            is_rfc5424: true,
+            node_id: None,
+            raw: None,
+        }
+    }
+
+    fn messages(path: &Path) -> Vec<(u32, String)> {
+        let mut messages = Vec::new();
+        for columns in
+            StorageEngine::iter_columns(path, ColumnSet::ALL, ReadMode::Buffered, None).unwrap()
+        {
+            let columns = columns.unwrap();
+            messages.extend(
+                columns
+                    .records()
+                    .map(|r| (r.template_id.unwrap(), r.message().unwrap())),
+            );
+        }
+        messages
+    }
+
+    #[test]
+    fn test_merge_remaps_near_duplicate_templates() {
+        let dir = std::env::temp_dir().join("sankshepa_test_merging");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let mut dictionary = TemplateDictionary::open(dir.join("logs.templates"), None).unwrap();
+
+        // Every chunk mines its templates afresh, so messages that would
+        // have shared one end up with near-duplicates.
+        let files = [
+            (
+                "a.lshrink",
+                vec![
+                    vec!["User alice logged in from 10.0.0.1"],
+                    vec!["Disk /dev/sda full", "Disk /dev/mapper/vg0 root full"],
+                ],
+            ),
+            (
+                "b.lshrink",
+                vec![
+                    vec!["User bob logged in from 10.0.0.2"],
+                    vec!["Disk /dev/sdb full"],
+                ],
+            ),
+            ("c.lshrink", vec![vec!["Backup done"]]),
+        ];
+        let mut paths = Vec::new();
+        for (file, chunks) in &files {
+            let path = dir.join(file);
+            for texts in chunks {
+                let mut chunk = dictionary.new_chunk();
+                for text in texts {
+                    chunk.add_message(msg(text));
+                }
+                chunk.finish_and_process();
+                dictionary.absorb(&chunk).unwrap();
+                dictionary.persist().unwrap();
+                StorageEngine::save_chunk(&chunk, &path.to_string_lossy()).unwrap();
+            }
+            paths.push(path);
+        }
+        let before: Vec<_> = paths.iter().map(|p| messages(p)).collect();
+        let c_modified = fs::metadata(&paths[2]).unwrap().modified().unwrap();
+
+        let dictionary = Mutex::new(dictionary);
+        let report =
+            merge_templates(&paths, Some(&dictionary), &MergeConfig::default(), None).unwrap();
+        let dictionary = dictionary.into_inner().unwrap();
+        assert_eq!(report.templates_before, 5);
+        assert_eq!(report.templates_after, 3);
+        assert_eq!(report.records_remapped, 3);
+        assert_eq!(report.files_rewritten, 2);
+        assert_eq!(report.remapped.len(), 3);
+
+        let merged = dictionary.id("User <*> logged in from <IP>").unwrap();
+        let slot = dictionary.id("Disk <PATH> <*...> full").unwrap();
+        let after: Vec<_> = paths.iter().map(|p| messages(p)).collect();
+        for (before, after) in before.iter().zip(&after) {
+            let text =
+                |m: &Vec<(u32, String)>| m.iter().map(|(_, t)| t.clone()).collect::<Vec<_>>();
+            assert_eq!(text(before), text(after));
+        }
+        assert_eq!(after[0][0].0, merged);
+        assert_eq!(after[1][0].0, merged);
+        assert!(
+            after[0][1..]
+                .iter()
+                .chain(&after[1][1..])
+                .all(|(id, _)| *id == slot)
+        );
+        // The untouched file is not rewritten; the others get a fresh index.
+        assert_eq!(
+            fs::metadata(&paths[2]).unwrap().modified().unwrap(),
+            c_modified
+        );
+        let index = ChunkIndex::load(&paths[1], None).unwrap();
+        assert_eq!(index.entries[0].template_ids, vec![merged]);
+        assert!(!dir.join("a.lshrink.merge").exists());
+
+        // The templates in use are settled now.
+        let again = merge_templates(&paths, None, &MergeConfig::default(), None).unwrap();
+        assert!(again.remapped.is_empty());
+        assert_eq!(again.templates_before, 3);
+    }
+}
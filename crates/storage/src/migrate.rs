@@ -0,0 +1,701 @@
+use crate::compaction::{remove_container, replace};
+use crate::compression::{BlockDictionaries, BlockEncoder};
+use crate::container::{self, FORMAT_VERSION, FrameReader, MAGIC};
+use crate::dictionary::DICTIONARY_MAGIC;
+use crate::encryption::{self, Keyring};
+use crate::index::ChunkIndex;
+use crate::logshrink::{Layout, LogChunk, LogRecord, Template};
+use crate::reader::{ColumnSet, ReadMode};
+use crate::retention::container_size;
+use crate::{CompressedChunk, StorageEngine, VariableColumn};
+use chrono::{DateTime, Utc};
+use sankshepa_protocol::SyslogMessage;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::BTreeSet;
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+/// Version reported for a headerless file, written before containers had a
+/// header. Versions 1 to 6 were layouts of the container before it could
+/// read older ones and are not supported.
+pub const HEADERLESS_VERSION: u16 = 0;
+
+/// The layout written before containers had a header: the whole file is one
+/// postcard-serialized chunk whose blocks are each zstd-compressed postcard.
+#[derive(Serialize, Deserialize)]
+struct HeaderlessChunk {
+    templates: Vec<Template>,
+    string_pool: Vec<String>,
+    timestamp_block: Vec<u8>,
+    priority_block: Vec<u8>,
+    hostname_id_block: Vec<u8>,
+    app_name_id_block: Vec<u8>,
+    procid_id_block: Vec<u8>,
+    msgid_id_block: Vec<u8>,
+    sd_id_block: Vec<u8>,
+    template_id_block: Vec<u8>,
+    variable_block: Vec<u8>,
+    is_rfc5424_block: Vec<u8>,
+    node_id_id_block: Vec<u8>,
+}
+
+impl HeaderlessChunk {
+    /// Decodes the blocks into a processed chunk with chunk-local template
+    /// IDs.
+    fn into_chunk(self) -> anyhow::Result<LogChunk> {
+        fn block<T: serde::de::DeserializeOwned>(data: &[u8]) -> anyhow::Result<T> {
+            Ok(postcard::from_bytes(&zstd::decode_all(data)?)?)
+        }
+        // Timestamps are stored as the first value and then deltas.
+        let deltas: Vec<i64> = block(&self.timestamp_block)?;
+        let timestamps = deltas.iter().scan(0i64, |current, delta| {
+            *current += delta;
+            Some(*current)
+        });
+        let priorities = zstd::decode_all(&self.priority_block[..])?;
+        let hostname_ids: Vec<Option<u32>> = block(&self.hostname_id_block)?;
+        let app_name_ids: Vec<Option<u32>> = block(&self.app_name_id_block)?;
+        let procid_ids: Vec<Option<u32>> = block(&self.procid_id_block)?;
+        let msgid_ids: Vec<Option<u32>> = block(&self.msgid_id_block)?;
+        let sd_ids: Vec<Option<u32>> = block(&self.sd_id_block)?;
+        let template_ids: Vec<u32> = block(&self.template_id_block)?;
+        let variables: Vec<Vec<String>> = block(&self.variable_block)?;
+        let is_rfc5424s: Vec<bool> = block(&self.is_rfc5424_block)?;
+        let node_id_ids: Vec<Option<u32>> = block(&self.node_id_id_block)?;
+
+        let n = template_ids.len();
+        let lengths = [
+            deltas.len(),
+            priorities.len(),
+            hostname_ids.len(),
+            app_name_ids.len(),
+            procid_ids.len(),
+            msgid_ids.len(),
+            sd_ids.len(),
+            variables.len(),
+            is_rfc5424s.len(),
+            node_id_ids.len(),
+        ];
+        if lengths.iter().any(|&len| len != n) {
+            return Err(anyhow::anyhow!(
+                "Headerless chunk has columns of different lengths"
+            ));
+        }
+
+        let mut chunk = LogChunk::new();
+        chunk.string_pool = self.string_pool;
+        for t in self.templates {
+            chunk.next_template_id = chunk.next_template_id.max(t.id + 1);
+            chunk.templates.insert(t.pattern, t.id);
+        }
+        let mut variables = variables.into_iter();
+        for (i, timestamp) in timestamps.enumerate() {
+            chunk.records.push(LogRecord {
+                timestamp,
+                priority: priorities[i],
+                hostname_id: hostname_ids[i],
+                app_name_id: app_name_ids[i],
+                procid_id: procid_ids[i],
+                msgid_id: msgid_ids[i],
+                structured_data_id: sd_ids[i],
+                sd_shape: None,
+                sd_values: Vec::new(),
+                template_id: template_ids[i],
+                variables: variables.next().unwrap_or_default(),
+                is_rfc5424: is_rfc5424s[i],
+                node_id_id: node_id_ids[i],
+                layout: None,
+            });
+        }
+        Ok(chunk)
+    }
+}
+
+/// Whether the file at `path` starts with a container header.
+fn has_header(path: &Path) -> anyhow::Result<bool> {
+    let mut magic = [0u8; 4];
+    let mut file = fs::File::open(path)?;
+    match file.read_exact(&mut magic) {
+        Ok(()) => Ok(magic == MAGIC),
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Chunk layout of format versions 7 to 9. Version 7 stored the string pool
+/// as a plain `Vec<String>` and later ones as a compressed block; version 9
+/// added `layout_block` (`()` takes no bytes for the older ones). None had
+/// structured data shapes.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct LegacyChunk<'a, Pool, Layouts> {
+    pub templates: Vec<Template>,
+    pub string_pool: Pool,
+    #[serde(borrow)]
+    pub timestamp_block: Cow<'a, [u8]>,
+    #[serde(borrow)]
+    pub priority_block: Cow<'a, [u8]>,
+    #[serde(borrow)]
+    pub hostname_id_block: Cow<'a, [u8]>,
+    #[serde(borrow)]
+    pub app_name_id_block: Cow<'a, [u8]>,
+    #[serde(borrow)]
+    pub procid_id_block: Cow<'a, [u8]>,
+    #[serde(borrow)]
+    pub msgid_id_block: Cow<'a, [u8]>,
+    #[serde(borrow)]
+    pub sd_id_block: Cow<'a, [u8]>,
+    #[serde(borrow)]
+    pub template_id_block: Cow<'a, [u8]>,
+    #[serde(borrow)]
+    pub variable_count_block: Cow<'a, [u8]>,
+    #[serde(borrow)]
+    pub variable_columns: Vec<VariableColumn<'a>>,
+    #[serde(borrow)]
+    pub is_rfc5424_block: Cow<'a, [u8]>,
+    #[serde(borrow)]
+    pub node_id_id_block: Cow<'a, [u8]>,
+    pub layout_block: Layouts,
+    pub dictionary: Option<String>,
+    pub zstd_dictionary: Option<u32>,
+}
+
+impl<'a, Pool, Layouts> LegacyChunk<'a, Pool, Layouts> {
+    /// Converts to the current layout, with the string pool and layout
+    /// blocks as given. Structured data stays in `sd_id_block`.
+    fn upgrade(
+        self,
+        string_pool_block: Cow<'a, [u8]>,
+        layout_block: Cow<'a, [u8]>,
+    ) -> CompressedChunk<'a> {
+        CompressedChunk {
+            templates: self.templates,
+            string_pool_block,
+            timestamp_block: self.timestamp_block,
+            priority_block: self.priority_block,
+            hostname_id_block: self.hostname_id_block,
+            app_name_id_block: self.app_name_id_block,
+            procid_id_block: self.procid_id_block,
+            msgid_id_block: self.msgid_id_block,
+            sd_id_block: self.sd_id_block,
+            template_id_block: self.template_id_block,
+            variable_count_block: self.variable_count_block,
+            variable_columns: self.variable_columns,
+            is_rfc5424_block: self.is_rfc5424_block,
+            node_id_id_block: self.node_id_id_block,
+            layout_block,
+            sd_shapes: Vec::new(),
+            sd_shape_block: Cow::Borrowed(&[]),
+            sd_value_columns: Vec::new(),
+            dictionary: self.dictionary,
+            zstd_dictionary: self.zstd_dictionary,
+        }
+    }
+}
+
+/// A chunk payload parsed according to its container's format version.
+pub(crate) struct StoredChunk<'a> {
+    pub chunk: CompressedChunk<'a>,
+    /// The string pool, for layouts that stored it uncompressed; the
+    /// chunk's `string_pool_block` is empty then.
+    pub string_pool: Option<Vec<String>>,
+    /// The layout block holds [`LegacyLayout`]s.
+    pub legacy_layouts: bool,
+}
+
+/// A lossless layout as stored by format versions 9 to 11, which kept the
+/// header as text and could not keep a message whole.
+#[derive(Serialize, Deserialize)]
+struct LegacyLayout {
+    header: String,
+    gaps: Vec<String>,
+}
+
+/// Parses a decompressed layout block, of [`LegacyLayout`]s if `legacy`.
+pub(crate) fn parse_layouts(data: &[u8], legacy: bool) -> anyhow::Result<Vec<Option<Layout>>> {
+    if !legacy {
+        return Ok(postcard::from_bytes(data)?);
+    }
+    let layouts: Vec<Option<LegacyLayout>> = postcard::from_bytes(data)?;
+    Ok(layouts
+        .into_iter()
+        .map(|layout| {
+            layout.map(|l| Layout {
+                header: l.header.into_bytes(),
+                gaps: l.gaps,
+                verbatim: false,
+            })
+        })
+        .collect())
+}
+
+/// Parses a chunk frame payload of a container of format `version`.
+pub(crate) fn parse_chunk(payload: &[u8], version: u16) -> anyhow::Result<StoredChunk<'_>> {
+    match version {
+        FORMAT_VERSION | 10..=12 => Ok(StoredChunk {
+            chunk: postcard::from_bytes(payload)?,
+            string_pool: None,
+            legacy_layouts: version < 12,
+        }),
+        9 => {
+            let mut legacy: LegacyChunk<Cow<[u8]>, Cow<[u8]>> = postcard::from_bytes(payload)?;
+            let pool = std::mem::take(&mut legacy.string_pool);
+            let layouts = std::mem::take(&mut legacy.layout_block);
+            Ok(StoredChunk {
+                chunk: legacy.upgrade(pool, layouts),
+                string_pool: None,
+                legacy_layouts: true,
+            })
+        }
+        8 => {
+            let mut legacy: LegacyChunk<Cow<[u8]>, ()> = postcard::from_bytes(payload)?;
+            let pool = std::mem::take(&mut legacy.string_pool);
+            Ok(StoredChunk {
+                chunk: legacy.upgrade(pool, Cow::Borrowed(&[])),
+                string_pool: None,
+                legacy_layouts: false,
+            })
+        }
+        7 => {
+            let mut legacy: LegacyChunk<Vec<String>, ()> = postcard::from_bytes(payload)?;
+            let pool = std::mem::take(&mut legacy.string_pool);
+            Ok(StoredChunk {
+                chunk: legacy.upgrade(Cow::Borrowed(&[]), Cow::Borrowed(&[])),
+                string_pool: Some(pool),
+                legacy_layouts: false,
+            })
+        }
+        other => Err(anyhow::anyhow!(
+            "No chunk layout for format version {}",
+            other
+        )),
+    }
+}
+
+/// A logged message as written before format version 9, without `raw`.
+#[derive(Deserialize)]
+struct LegacyMessage {
+    priority: u8,
+    facility: u8,
+    severity: u8,
+    timestamp: Option<DateTime<Utc>>,
+    hostname: Option<String>,
+    app_name: Option<String>,
+    procid: Option<String>,
+    msgid: Option<String>,
+    structured_data: Option<String>,
+    message: String,
+    is_rfc5424: bool,
+    node_id: Option<String>,
+}
+
+/// Parses a WAL frame payload of a log of format `version`.
+pub(crate) fn parse_message(payload: &[u8], version: u16) -> anyhow::Result<SyslogMessage> {
+    if version >= 9 {
+        return Ok(postcard::from_bytes(payload)?);
+    }
+    let m: LegacyMessage = postcard::from_bytes(payload)?;
+    Ok(SyslogMessage {
+        priority: m.priority,
+        facility: m.facility,
+        severity: m.severity,
+        timestamp: m.timestamp,
+        hostname: m.hostname,
+        app_name: m.app_name,
+        procid: m.procid,
+        msgid: m.msgid,
+        structured_data: m.structured_data,
+        message: m.message,
+        is_rfc5424: m.is_rfc5424,
+        node_id: m.node_id,
+        raw: None,
+    })
+}
+
+/// Outcome of migrating one container.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MigrationReport {
+    pub path: PathBuf,
+    /// Format version the container had, or [`HEADERLESS_VERSION`].
+    pub from_version: u16,
+    /// The container was rewritten because it held frames not sealed with
+    /// the active key.
+    pub resealed: bool,
+    pub chunks: usize,
+    pub records: usize,
+    pub bytes_before: u64,
+    pub bytes_after: u64,
+}
+
+impl MigrationReport {
+    pub fn migrated(&self) -> bool {
+        self.from_version != FORMAT_VERSION || self.resealed
+    }
+}
+
+/// Rewrites the container at `path` in the current format and atomically
+/// replaces it, together with its index and zstd dictionaries. With
+/// `keys`, containers holding frames not sealed with the active key, or
+/// not bound to their place, are rewritten too, and so are the template
+/// dictionaries they use.
+///
+/// A file without a header is read as the single chunk written before
+/// containers had one.
+///
+/// Template dictionaries keep their layout across versions; write-ahead
+/// logs are upgraded by [`crate::wal::Wal::open`].
+pub fn migrate_file(path: &Path, keys: Option<&Keyring>) -> anyhow::Result<MigrationReport> {
+    if !has_header(path)? {
+        return migrate_headerless(path, keys);
+    }
+    let from_version = FrameReader::open(path, keys)?.version();
+    let bytes_before = container_size(path);
+    let mut report = MigrationReport {
+        path: path.to_path_buf(),
+        from_version,
+        resealed: from_version == FORMAT_VERSION && encryption::needs_reseal(path, MAGIC, keys)?,
+        chunks: 0,
+        records: 0,
+        bytes_before,
+        bytes_after: bytes_before,
+    };
+    reseal_dictionaries(path, keys)?;
+    if !report.migrated() {
+        return Ok(report);
+    }
+
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".migrate");
+    let tmp = PathBuf::from(tmp);
+    remove_container(&tmp)?;
+
+    // Blocks are recompressed without the old zstd dictionaries; compaction
+    // or the writer trains new ones later.
+    let mut encoder = BlockEncoder::default().with_keys(keys);
+    let result = (|| -> anyhow::Result<()> {
+        for chunk in StorageEngine::iter_chunks(path, keys)? {
+            let chunk = chunk?;
+            report.chunks += 1;
+            report.records += chunk.records.len();
+            StorageEngine::save_chunk_with(&chunk, &tmp.to_string_lossy(), &mut encoder)?;
+        }
+        Ok(())
+    })();
+    if let Err(e) = result {
+        remove_container(&tmp)?;
+        return Err(e);
+    }
+    if report.chunks == 0 {
+        // An empty container only needs a new header.
+        container::write_header(&mut fs::File::create(&tmp)?, MAGIC)?;
+        ChunkIndex::default().write(&tmp, keys)?;
+    }
+
+    replace(
+        &BlockDictionaries::sidecar_path(&tmp),
+        &BlockDictionaries::sidecar_path(path),
+    )?;
+    fs::rename(&tmp, path)?;
+    fs::rename(
+        ChunkIndex::sidecar_path(&tmp),
+        ChunkIndex::sidecar_path(path),
+    )?;
+    report.bytes_after = container_size(path);
+    Ok(report)
+}
+
+/// Rewrites the headerless file at `path` as a container holding its chunk.
+fn migrate_headerless(path: &Path, keys: Option<&Keyring>) -> anyhow::Result<MigrationReport> {
+    let buf = fs::read(path)?;
+    let chunk = postcard::from_bytes::<HeaderlessChunk>(&buf)
+        .map_err(|e| anyhow::anyhow!("Not a LogShrink container or headerless chunk: {}", e))?
+        .into_chunk()?;
+
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".migrate");
+    let tmp = PathBuf::from(tmp);
+    remove_container(&tmp)?;
+    let mut encoder = BlockEncoder::default().with_keys(keys);
+    if let Err(e) = StorageEngine::save_chunk_with(&chunk, &tmp.to_string_lossy(), &mut encoder) {
+        remove_container(&tmp)?;
+        return Err(e);
+    }
+    fs::rename(&tmp, path)?;
+    fs::rename(
+        ChunkIndex::sidecar_path(&tmp),
+        ChunkIndex::sidecar_path(path),
+    )?;
+    Ok(MigrationReport {
+        path: path.to_path_buf(),
+        from_version: HEADERLESS_VERSION,
+        resealed: false,
+        chunks: 1,
+        records: chunk.records.len(),
+        bytes_before: buf.len() as u64,
+        bytes_after: container_size(path),
+    })
+}
+
+/// Reseals the template dictionaries referenced by the chunks of `path`.
+fn reseal_dictionaries(path: &Path, keys: Option<&Keyring>) -> anyhow::Result<()> {
+    if keys.is_none() {
+        return Ok(());
+    }
+    let mut names = BTreeSet::new();
+    let chunks = StorageEngine::iter_columns(path, ColumnSet::empty(), ReadMode::Buffered, keys)?;
+    for columns in chunks {
+        names.extend(columns?.dictionary);
+    }
+    let dir = path.parent().unwrap_or(Path::new("."));
+    for name in names {
+        reseal_file(&dir.join(name), DICTIONARY_MAGIC, keys)?;
+    }
+    Ok(())
+}
+
+/// Rewrites every frame of the file at `path` sealed with the active key
+/// and bound to its place, keeping its format version, if any frame is not
+/// yet.
+fn reseal_file(path: &Path, magic: [u8; 4], keys: Option<&Keyring>) -> anyhow::Result<()> {
+    if !encryption::needs_reseal(path, magic, keys)? {
+        return Ok(());
+    }
+    let mut frames = FrameReader::open_with(path, magic, keys)?;
+    let mut buf = Vec::new();
+    container::write_header_version(&mut buf, magic, frames.version())?;
+    while let Some((_, payload)) = frames.next_frame()? {
+        let offset = buf.len() as u64;
+        buf.extend(container::encode_frame(&payload, magic, offset, keys)?);
+    }
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".migrate");
+    fs::write(&tmp, buf)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logshrink::reconstruct;
+
+    /// Encodes `chunk` the way format `version` stored it.
+    fn encode_legacy(chunk: LogChunk, version: u16) -> Vec<u8> {
+        let string_pool = chunk.string_pool.clone();
+        let buf = StorageEngine::encode_chunk(&chunk, &mut BlockEncoder::default()).unwrap();
+        let c: CompressedChunk = postcard::from_bytes(&buf).unwrap();
+        let pool = c.string_pool_block.clone();
+        let layouts = c.layout_block.clone();
+        match version {
+            7 => downgrade(c, string_pool, ()),
+            8 => downgrade(c, pool, ()),
+            _ => downgrade(c, pool, layouts),
+        }
+    }
+
+    fn downgrade<Pool: Serialize, Layouts: Serialize>(
+        c: CompressedChunk,
+        string_pool: Pool,
+        layout_block: Layouts,
+    ) -> Vec<u8> {
+        postcard::to_allocvec(&LegacyChunk {
+            templates: c.templates,
+            string_pool,
+            timestamp_block: c.timestamp_block,
+            priority_block: c.priority_block,
+            hostname_id_block: c.hostname_id_block,
+            app_name_id_block: c.app_name_id_block,
+            procid_id_block: c.procid_id_block,
+            msgid_id_block: c.msgid_id_block,
+            sd_id_block: c.sd_id_block,
+            template_id_block: c.template_id_block,
+            variable_count_block: c.variable_count_block,
+            variable_columns: c.variable_columns,
+            is_rfc5424_block: c.is_rfc5424_block,
+            node_id_id_block: c.node_id_id_block,
+            layout_block,
+            dictionary: c.dictionary,
+            zstd_dictionary: c.zstd_dictionary,
+        })
+        .unwrap()
+    }
+
+    fn chunk() -> LogChunk {
+        let mut chunk = LogChunk::new();
+        for i in 0..10 {
+            chunk.add_message(SyslogMessage {
+                priority: 34,
+                facility: 4,
+                severity: 2,
+                timestamp: Some(Utc::now()),
+                hostname: Some(format!("web-{}", i % 2)),
+                app_name: Some("sshd".to_string()),
+                procid: None,
+                msgid: None,
+                structured_data: None,
+                message: format!("Accepted password for user{}", i),
+                is_rfc5424: true,
+                node_id: None,
+                raw: None,
+            });
+        }
+        chunk.finish_and_process();
+        chunk
+    }
+
+    fn read_hosts(path: &Path) -> Vec<String> {
+        let mut hosts = Vec::new();
+        for chunk in StorageEngine::iter_chunks(path, None).unwrap() {
+            let chunk = chunk.unwrap();
+            for record in &chunk.records {
+                let id = record.hostname_id.unwrap() as usize;
+                hosts.push(chunk.string_pool[id].clone());
+            }
+        }
+        hosts
+    }
+
+    #[test]
+    fn test_reads_and_migrates_old_versions() {
+        for version in [7, 8, 9] {
+            let path = PathBuf::from(format!("test_migrate_v{}.lshrink", version));
+            let path = path.as_path();
+            remove_container(path).unwrap();
+            let mut buf = Vec::new();
+            container::write_header_version(&mut buf, MAGIC, version).unwrap();
+            let payload = encode_legacy(chunk(), version);
+            let offset = buf.len() as u64;
+            buf.extend(container::encode_frame(&payload, MAGIC, offset, None).unwrap());
+            fs::write(path, buf).unwrap();
+
+            let before = read_hosts(path);
+            assert_eq!(before.len(), 10);
+            assert_eq!(before[3], "web-1");
+            assert_eq!(ChunkIndex::rebuild(path, None).unwrap().entries.len(), 1);
+
+            // Old containers are read-only until migrated.
+            let err = StorageEngine::save_chunk(&chunk(), &path.to_string_lossy()).unwrap_err();
+            assert!(err.to_string().contains("migrate"));
+
+            let report = migrate_file(path, None).unwrap();
+            assert!(report.migrated());
+            assert_eq!((report.chunks, report.records), (1, 10));
+            assert_eq!(
+                FrameReader::open(path, None).unwrap().version(),
+                FORMAT_VERSION
+            );
+            assert_eq!(read_hosts(path), before);
+            assert!(!migrate_file(path, None).unwrap().migrated());
+
+            remove_container(path).unwrap();
+        }
+    }
+
+    /// Writes `chunk` the way files were written before containers had a
+    /// header.
+    fn write_headerless(chunk: &LogChunk, path: &Path) {
+        fn block<T: Serialize>(value: &T) -> Vec<u8> {
+            zstd::encode_all(&postcard::to_allocvec(value).unwrap()[..], 3).unwrap()
+        }
+        let records = &chunk.records;
+        let mut deltas = Vec::new();
+        let mut last = 0;
+        for r in records {
+            deltas.push(r.timestamp - last);
+            last = r.timestamp;
+        }
+        let column =
+            |f: fn(&LogRecord) -> Option<u32>| block(&records.iter().map(f).collect::<Vec<_>>());
+        let headerless = HeaderlessChunk {
+            templates: chunk
+                .templates
+                .iter()
+                .map(|(pattern, &id)| Template {
+                    id,
+                    pattern: pattern.clone(),
+                })
+                .collect(),
+            string_pool: chunk.string_pool.clone(),
+            timestamp_block: block(&deltas),
+            priority_block: zstd::encode_all(
+                &records.iter().map(|r| r.priority).collect::<Vec<_>>()[..],
+                3,
+            )
+            .unwrap(),
+            hostname_id_block: column(|r| r.hostname_id),
+            app_name_id_block: column(|r| r.app_name_id),
+            procid_id_block: column(|r| r.procid_id),
+            msgid_id_block: column(|r| r.msgid_id),
+            sd_id_block: column(|r| r.structured_data_id),
+            template_id_block: block(&records.iter().map(|r| r.template_id).collect::<Vec<_>>()),
+            variable_block: block(&records.iter().map(|r| &r.variables).collect::<Vec<_>>()),
+            is_rfc5424_block: block(&records.iter().map(|r| r.is_rfc5424).collect::<Vec<_>>()),
+            node_id_id_block: column(|r| r.node_id_id),
+        };
+        fs::write(path, postcard::to_allocvec(&headerless).unwrap()).unwrap();
+    }
+
+    fn messages(chunk: &LogChunk) -> Vec<(i64, String)> {
+        let patterns: std::collections::HashMap<u32, &String> =
+            chunk.templates.iter().map(|(p, &id)| (id, p)).collect();
+        chunk
+            .records
+            .iter()
+            .map(|r| {
+                (
+                    r.timestamp,
+                    reconstruct(patterns[&r.template_id], &r.variables),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_migrates_headerless_file() {
+        let path = Path::new("test_migrate_headerless.lshrink");
+        remove_container(path).unwrap();
+        let chunk = chunk();
+        write_headerless(&chunk, path);
+        assert!(FrameReader::open(path, None).is_err());
+
+        let report = migrate_file(path, None).unwrap();
+        assert_eq!(report.from_version, HEADERLESS_VERSION);
+        assert!(report.migrated());
+        assert_eq!((report.chunks, report.records), (1, 10));
+        assert_eq!(
+            FrameReader::open(path, None).unwrap().version(),
+            FORMAT_VERSION
+        );
+        let migrated = StorageEngine::load_chunk(&path.to_string_lossy(), None).unwrap();
+        assert_eq!(messages(&migrated), messages(&chunk));
+        assert_eq!(read_hosts(path)[3], "web-1");
+        assert!(!migrate_file(path, None).unwrap().migrated());
+
+        // Anything else is still refused.
+        fs::write(path, b"not a chunk").unwrap();
+        assert!(migrate_file(path, None).is_err());
+
+        remove_container(path).unwrap();
+    }
+
+    #[test]
+    fn test_reads_legacy_layouts() {
+        let legacy = vec![
+            None,
+            Some(LegacyLayout {
+                header: "<34>Oct 11 22:14:15 host ".to_string(),
+                gaps: vec!["\t".to_string(), String::new()],
+            }),
+        ];
+        let data = postcard::to_allocvec(&legacy).unwrap();
+        let layouts = parse_layouts(&data, true).unwrap();
+        assert_eq!(layouts[0], None);
+        let layout = layouts[1].as_ref().unwrap();
+        assert!(!layout.verbatim);
+        assert_eq!(
+            layout.restore("su: failed"),
+            b"<34>Oct 11 22:14:15 host su:\tfailed"
+        );
+    }
+}
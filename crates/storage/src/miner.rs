@@ -0,0 +1,699 @@
+use crate::logshrink::{Tokenizer, canonical};
+use crate::masking::{Masking, is_placeholder};
+use std::collections::HashMap;
+
+/// Marker for a variable token in a template.
+pub const WILDCARD: &str = "<*>";
+/// Marker for a variable run of zero or more tokens; a template has at most
+/// one, so the tokens it takes are whatever the tokens before and after it
+/// leave over.
+pub const VARIADIC: &str = "<*...>";
+
+/// How the template miner groups messages.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MinerConfig {
+    /// Number of leading tokens the prefix tree descends by before
+    /// comparing a message with the templates of a leaf.
+    pub depth: usize,
+    /// Fraction of positions at which a message must equal a template (or
+    /// hit a `<*>`) to join it.
+    pub similarity: f32,
+    /// Children per tree node; tokens beyond that share the `<*>` child.
+    pub max_children: usize,
+    /// Whether a message that matches no template of its own length may join
+    /// one of another length, which then gets a `<*...>` slot.
+    pub variable_length: bool,
+    /// Largest fraction of a template's words that may be `<*>` or
+    /// `<*...>`; a message that would make a template more general starts
+    /// a new one instead.
+    pub max_wildcard_ratio: f32,
+    /// Tokens replaced by typed placeholders such as `<IP>` before
+    /// messages are compared.
+    pub masking: Masking,
+    /// How messages are split into tokens.
+    pub tokenizer: Tokenizer,
+}
+
+impl Default for MinerConfig {
+    fn default() -> Self {
+        Self {
+            depth: 1,
+            similarity: 0.5,
+            max_children: 1000,
+            variable_length: true,
+            max_wildcard_ratio: 0.75,
+            masking: Masking::default(),
+            tokenizer: Tokenizer::default(),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Node {
+    children: HashMap<String, Node>,
+    /// Clusters whose templates end at this node; only set on leaves.
+    clusters: Vec<usize>,
+}
+
+/// Drain-style template miner. Messages are routed through a prefix tree,
+/// first by token count and then by their first `depth` tokens, so each one
+/// is only compared with the few templates at the leaf it reaches. A token
+/// containing a digit is routed to the `<*>` child, since it is likely a
+/// variable.
+///
+/// A message that matches none of them is compared with the templates of
+/// other lengths that start with the same token. If it lines up well enough
+/// with the start and end of one, the tokens in between become a `<*...>`
+/// slot, e.g. `Disk /dev/sda full` and `Disk /dev/mapper/vg0 root full`
+/// make `Disk <*...> full`.
+///
+/// Separators, such as the `=` of `user=alice`, only ever line up with the
+/// same separator, so they are never made variables.
+///
+/// The tree keeps its clusters between calls, so a writer that holds on to
+/// one miner matches each chunk against the templates of the earlier ones.
+#[derive(Debug, Default)]
+pub struct TemplateMiner {
+    config: MinerConfig,
+    by_len: HashMap<usize, Node>,
+    /// Clusters of every length by the route of their first token.
+    by_first: HashMap<String, Vec<usize>>,
+    clusters: Vec<Vec<String>>,
+}
+
+impl TemplateMiner {
+    pub fn new(config: MinerConfig) -> Self {
+        Self {
+            config,
+            ..Default::default()
+        }
+    }
+
+    pub fn config(&self) -> &MinerConfig {
+        &self.config
+    }
+
+    /// Number of clusters.
+    pub fn len(&self) -> usize {
+        self.clusters.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.clusters.is_empty()
+    }
+
+    /// Tokens of the template of `cluster`, with `<*>` for variables.
+    pub fn template(&self, cluster: usize) -> &[String] {
+        &self.clusters[cluster]
+    }
+
+    /// The template of `cluster` as text.
+    pub fn pattern(&self, cluster: usize) -> String {
+        self.config.tokenizer.join(&self.clusters[cluster])
+    }
+
+    /// Tokens of the [`canonical`] message `text`, to be passed to
+    /// [`TemplateMiner::add`].
+    pub fn tokenize<'a>(&self, text: &'a str) -> Vec<&'a str> {
+        self.config
+            .tokenizer
+            .tokenize(text, Some(&self.config.masking))
+    }
+
+    /// The variables of `tokens` under `template`, in order: the tokens at
+    /// its `<*>`s and placeholders. The tokens of a `<*...>` slot make one
+    /// variable, joined as they were in the message.
+    pub fn variables(&self, template: &[String], tokens: &[&str]) -> Vec<String> {
+        let pick = |template: &[String], tokens: &[&str]| -> Vec<String> {
+            template
+                .iter()
+                .zip(tokens)
+                .filter(|(t, _)| *t == WILDCARD || is_placeholder(t))
+                .map(|(_, token)| token.to_string())
+                .collect()
+        };
+        let Some(v) = template.iter().position(|t| t == VARIADIC) else {
+            return pick(template, tokens);
+        };
+        let head = v.min(tokens.len());
+        let end = tokens
+            .len()
+            .saturating_sub(template.len() - 1 - v)
+            .max(head);
+        let mut vars = pick(&template[..v], &tokens[..head]);
+        vars.push(self.config.tokenizer.join(&tokens[head..end]));
+        vars.extend(pick(&template[v + 1..], &tokens[end..]));
+        vars
+    }
+
+    /// Adds a known template, e.g. from the store's dictionary, as a cluster
+    /// for later messages to join.
+    pub fn seed(&mut self, pattern: &str) -> usize {
+        let pattern = canonical(pattern);
+        let tokens = self.config.tokenizer.tokenize(&pattern, None);
+        self.insert(tokens.into_iter().map(str::to_string).collect())
+    }
+
+    /// Masks `tokens` and assigns them to the most similar cluster,
+    /// generalizing the cluster's template where they differ, or starts a
+    /// new cluster. Returns the cluster.
+    pub fn add(&mut self, tokens: &[&str]) -> usize {
+        // Placeholders borrow from the masking, so it is moved out meanwhile.
+        let masking = std::mem::replace(&mut self.config.masking, Masking::none());
+        let masked: Vec<&str> = tokens.iter().map(|t| masking.mask(t)).collect();
+        let id = self.add_masked(&masked);
+        self.config.masking = masking;
+        id
+    }
+
+    /// Like [`TemplateMiner::add`] for tokens that are masked already, such
+    /// as the tokens of another template.
+    pub fn add_masked(&mut self, tokens: &[&str]) -> usize {
+        let found = self.best_match(tokens).or_else(|| {
+            self.config
+                .variable_length
+                .then(|| self.best_match_any_length(tokens))
+                .flatten()
+        });
+        if let Some((id, alignment)) = found {
+            let merged = merge(&self.clusters[id], tokens, alignment.cut);
+            if merged == self.clusters[id] || !self.too_general(&merged) {
+                self.clusters[id] = merged;
+                return id;
+            }
+        }
+        self.insert(tokens.iter().map(|t| t.to_string()).collect())
+    }
+
+    /// Whether more than `max_wildcard_ratio` of the words of `template`
+    /// are `<*>` or `<*...>`.
+    fn too_general(&self, template: &[String]) -> bool {
+        let tokenizer = &self.config.tokenizer;
+        let words = template
+            .iter()
+            .filter(|t| !tokenizer.is_separator(t))
+            .count();
+        let wildcards = template
+            .iter()
+            .filter(|t| *t == WILDCARD || *t == VARIADIC)
+            .count();
+        wildcards as f32 > self.config.max_wildcard_ratio * words as f32
+    }
+
+    fn best_match(&self, tokens: &[&str]) -> Option<(usize, Alignment)> {
+        let mut node = self.by_len.get(&tokens.len())?;
+        for token in tokens.iter().take(self.config.depth) {
+            let child = match node.children.get(*token) {
+                Some(child) if !has_digit(token) => Some(child),
+                _ => node.children.get(WILDCARD),
+            };
+            match child {
+                Some(child) => node = child,
+                None => return None,
+            }
+        }
+        self.most_similar(node.clusters.iter().copied(), tokens)
+    }
+
+    fn best_match_any_length(&self, tokens: &[&str]) -> Option<(usize, Alignment)> {
+        let first = tokens.first().map_or(WILDCARD, |t| route(t));
+        let mut keys = vec![WILDCARD];
+        if first != WILDCARD {
+            keys.push(first);
+        }
+        let candidates = keys
+            .into_iter()
+            .filter_map(|key| self.by_first.get(key))
+            .flatten()
+            .copied()
+            .filter(|&id| {
+                let template = &self.clusters[id];
+                template.len() != tokens.len() || template.iter().any(|t| t == VARIADIC)
+            });
+        self.most_similar(candidates, tokens)
+    }
+
+    /// The most similar of `candidates`, if similar enough; on a tie, the
+    /// more general one, so a template that has been generalized keeps
+    /// absorbing messages.
+    fn most_similar(
+        &self,
+        candidates: impl IntoIterator<Item = usize>,
+        tokens: &[&str],
+    ) -> Option<(usize, Alignment)> {
+        let mut best: Option<(usize, Alignment)> = None;
+        for id in candidates {
+            let Some(alignment) = align(
+                &self.clusters[id],
+                tokens,
+                self.config.variable_length,
+                &self.config.tokenizer,
+            ) else {
+                continue;
+            };
+            if best.as_ref().is_none_or(|(_, b)| alignment.better_than(b)) {
+                best = Some((id, alignment));
+            }
+        }
+        let (id, alignment) = best?;
+        let required = self.config.similarity * alignment.len as f32;
+        (alignment.len == 0 || alignment.same as f32 >= required).then_some((id, alignment))
+    }
+
+    fn insert(&mut self, tokens: Vec<String>) -> usize {
+        let id = self.clusters.len();
+        let max_children = self.config.max_children;
+        let mut node = self.by_len.entry(tokens.len()).or_default();
+        for token in tokens.iter().take(self.config.depth) {
+            let key = match route(token) {
+                key if !node.children.contains_key(key) && node.children.len() >= max_children => {
+                    WILDCARD
+                }
+                key => key,
+            };
+            node = node.children.entry(key.to_string()).or_default();
+        }
+        node.clusters.push(id);
+        let first = tokens.first().map_or(WILDCARD, |t| route(t));
+        self.by_first.entry(first.to_string()).or_default().push(id);
+        self.clusters.push(tokens);
+        id
+    }
+}
+
+/// How a message lines up with a template.
+#[derive(Debug, Clone, Copy)]
+struct Alignment {
+    /// Positions where the message equals the template or hits a `<*>` or
+    /// `<*...>`.
+    same: usize,
+    /// What `same` is a fraction of: the longer of message and template,
+    /// with a `<*...>` counting as one token.
+    len: usize,
+    /// Variables in the template.
+    wildcards: usize,
+    /// For a variable-length match, the number of tokens lined up from the
+    /// start and from the end; the rest go into the `<*...>` slot.
+    cut: Option<(usize, usize)>,
+}
+
+impl Alignment {
+    fn better_than(&self, other: &Alignment) -> bool {
+        // Compare same / len without rounding.
+        let (a, b) = (self.same * other.len.max(1), other.same * self.len.max(1));
+        a > b || (a == b && self.wildcards > other.wildcards)
+    }
+}
+
+/// Lines `tokens` up with `template`: position by position if they have the
+/// same length and the template no `<*...>`, around the `<*...>` slot if it
+/// has one, and otherwise, if `variable_length`, at the split between a
+/// head and a tail that matches the most tokens. `None` if a separator
+/// would line up with anything but itself, or a slot would start or end
+/// with one.
+fn align(
+    template: &[String],
+    tokens: &[&str],
+    variable_length: bool,
+    tokenizer: &Tokenizer,
+) -> Option<Alignment> {
+    let (l, n) = (template.len(), tokens.len());
+    let wildcards = template
+        .iter()
+        .filter(|t| *t == WILDCARD || *t == VARIADIC)
+        .count();
+
+    if let Some(v) = template.iter().position(|t| t == VARIADIC) {
+        if n < l - 1 {
+            return None;
+        }
+        // The slot counts as one position that always matches, however
+        // many tokens it takes.
+        let tail = l - 1 - v;
+        if !bare(&tokens[v..n - tail], tokenizer) {
+            return None;
+        }
+        let same = matches(&template[..v], &tokens[..v], tokenizer)?
+            + matches(&template[v + 1..], &tokens[n - tail..], tokenizer)?
+            + 1;
+        return Some(Alignment {
+            same,
+            len: l,
+            wildcards,
+            cut: Some((v, tail)),
+        });
+    }
+    if n == l {
+        return Some(Alignment {
+            same: matches(template, tokens, tokenizer)?,
+            len: n,
+            wildcards,
+            cut: None,
+        });
+    }
+    if !variable_length {
+        return None;
+    }
+
+    // Try every split of the shorter side into a head lined up from the
+    // start and a tail lined up from the end, moving one position at a time
+    // from the tail to the head. Ties go to the longest head, so a
+    // free-text tail becomes the slot.
+    let m = l.min(n);
+    let at_head = |i: usize| pair(&template[i], tokens[i], tokenizer);
+    let at_tail = |i: usize| pair(&template[l - m + i], tokens[n - m + i], tokenizer);
+    // Whatever ends up in the slot, from the template or from the message.
+    let fits = |a: usize| {
+        bare(&template[a..a + l - m], tokenizer) && bare(&tokens[a..a + n - m], tokenizer)
+    };
+    let (mut same, mut clashes) = (0, 0);
+    for i in 0..m {
+        match at_tail(i) {
+            Some(s) => same += s,
+            None => clashes += 1,
+        }
+    }
+    let mut best = (clashes == 0 && fits(0)).then_some((same, 0));
+    for i in 0..m {
+        match at_tail(i) {
+            Some(s) => same -= s,
+            None => clashes -= 1,
+        }
+        match at_head(i) {
+            Some(s) => same += s,
+            None => clashes += 1,
+        }
+        if clashes == 0 && fits(i + 1) && best.is_none_or(|(b, _)| same >= b) {
+            best = Some((same, i + 1));
+        }
+    }
+    let (same, a) = best?;
+    Some(Alignment {
+        same,
+        len: l.max(n),
+        wildcards: wildcards + 1,
+        cut: Some((a, m - a)),
+    })
+}
+
+/// 1 if `token` equals `t` or `t` is `<*>`, 0 if it differs, and `None` if
+/// they cannot share a position: a separator only lines up with itself, and
+/// the `<*...>` of a template being added only with the slot.
+fn pair(t: &str, token: &str, tokenizer: &Tokenizer) -> Option<usize> {
+    if t == token {
+        Some(1)
+    } else if tokenizer.is_separator(t) || tokenizer.is_separator(token) || token == VARIADIC {
+        None
+    } else {
+        Some(usize::from(t == WILDCARD))
+    }
+}
+
+fn matches(template: &[String], tokens: &[&str], tokenizer: &Tokenizer) -> Option<usize> {
+    let mut same = 0;
+    for (t, token) in template.iter().zip(tokens) {
+        same += pair(t, token, tokenizer)?;
+    }
+    Some(same)
+}
+
+/// Whether `run` can fill a `<*...>` slot, which is joined to its
+/// neighbours like a word: it is empty or starts and ends with words.
+fn bare<S: AsRef<str>>(run: &[S], tokenizer: &Tokenizer) -> bool {
+    let word = |t: &S| !tokenizer.is_separator(t.as_ref());
+    run.first().is_none_or(word) && run.last().is_none_or(word)
+}
+
+/// `template` generalized to also cover `tokens`, lined up as `cut` says.
+/// A `<*>` next to the `<*...>` slot is folded into it.
+fn merge(template: &[String], tokens: &[&str], cut: Option<(usize, usize)>) -> Vec<String> {
+    let generalize = |(t, token): (&String, &&str)| {
+        if t == WILDCARD || t == token {
+            t.clone()
+        } else {
+            WILDCARD.to_string()
+        }
+    };
+    let Some((head, tail)) = cut else {
+        return template.iter().zip(tokens).map(generalize).collect();
+    };
+    let (l, n) = (template.len(), tokens.len());
+    let mut merged: Vec<String> = template[..head]
+        .iter()
+        .zip(&tokens[..head])
+        .map(generalize)
+        .collect();
+    while merged.last().is_some_and(|t| t == WILDCARD) {
+        merged.pop();
+    }
+    merged.push(VARIADIC.to_string());
+    merged.extend(
+        template[l - tail..]
+            .iter()
+            .zip(&tokens[n - tail..])
+            .map(generalize)
+            .skip_while(|t| t == WILDCARD),
+    );
+    merged
+}
+
+/// Key a token is routed by: the token itself, or `<*>` if it is likely a
+/// variable.
+fn route(token: &str) -> &str {
+    if token == WILDCARD || token == VARIADIC || has_digit(token) {
+        WILDCARD
+    } else {
+        token
+    }
+}
+
+fn has_digit(token: &str) -> bool {
+    token.bytes().any(|b| b.is_ascii_digit())
+}
+
+/// The miner `LogChunk` used before the prefix tree: every message is
+/// compared with each template of its token count in turn and joins the
+/// first similar one. Kept as the baseline for `bench --miner-lines`;
+/// returns the number of templates found.
+pub fn linear_scan<'a>(messages: impl IntoIterator<Item = &'a str>, similarity: f32) -> usize {
+    let mut groups: HashMap<usize, Vec<Vec<String>>> = HashMap::new();
+    for message in messages {
+        let tokens: Vec<&str> = message.split_whitespace().collect();
+        let templates = groups.entry(tokens.len()).or_default();
+        let similar = templates.iter_mut().find(|template| {
+            let same = template
+                .iter()
+                .zip(&tokens)
+                .filter(|(t, token)| t == token || *t == WILDCARD)
+                .count();
+            tokens.is_empty() || same as f32 / tokens.len() as f32 >= similarity
+        });
+        match similar {
+            Some(template) => {
+                for (t, token) in template.iter_mut().zip(&tokens) {
+                    if t != token && t != WILDCARD {
+                        *t = WILDCARD.to_string();
+                    }
+                }
+            }
+            None => templates.push(tokens.iter().map(|t| t.to_string()).collect()),
+        }
+    }
+    groups.values().map(Vec::len).sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Config without masking, so the tests see the tokens as they are.
+    fn unmasked() -> MinerConfig {
+        MinerConfig {
+            masking: Masking::none(),
+            ..Default::default()
+        }
+    }
+
+    fn add(miner: &mut TemplateMiner, text: &str) -> usize {
+        let tokens = miner.tokenize(text);
+        miner.add(&tokens)
+    }
+
+    fn pattern(miner: &TemplateMiner, cluster: usize) -> String {
+        miner.pattern(cluster)
+    }
+
+    fn variables(miner: &TemplateMiner, cluster: usize, text: &str) -> Vec<String> {
+        miner.variables(miner.template(cluster), &miner.tokenize(text))
+    }
+
+    #[test]
+    fn test_prefix_tree_clusters() {
+        let mut miner = TemplateMiner::new(MinerConfig {
+            depth: 2,
+            ..unmasked()
+        });
+        let a = add(&mut miner, "Connection from 10.0.0.1 closed");
+        let b = add(&mut miner, "Connection from 10.0.0.2 closed");
+        assert_eq!(a, b);
+        assert_eq!(pattern(&miner, a), "Connection from <*> closed");
+
+        // Different second token: a different leaf, so never merged even
+        // though three of four tokens match.
+        let c = add(&mut miner, "Connection to 10.0.0.1 closed");
+        assert_ne!(a, c);
+        // A leading number is routed to the wildcard child.
+        let d = add(&mut miner, "42 jobs queued now");
+        let e = add(&mut miner, "17 jobs queued now");
+        assert_eq!(d, e);
+        assert_eq!(pattern(&miner, d), "<*> jobs queued now");
+        assert_eq!(miner.len(), 3);
+
+        assert_eq!(add(&mut miner, ""), add(&mut miner, ""));
+
+        // Below the threshold a new cluster starts.
+        let mut flat = TemplateMiner::new(MinerConfig {
+            depth: 0,
+            similarity: 0.5,
+            ..unmasked()
+        });
+        let g = add(&mut flat, "a b c d");
+        assert_ne!(add(&mut flat, "a x y z"), g);
+        assert_eq!(add(&mut flat, "a b x y"), g);
+    }
+
+    #[test]
+    fn test_seeded_templates_absorb_messages() {
+        let mut miner = TemplateMiner::default();
+        let specific = miner.seed("User alice logged in");
+        let general = miner.seed("User <*> logged in");
+        assert_eq!(add(&mut miner, "User alice logged in"), general);
+        assert_eq!(add(&mut miner, "User bob logged in"), general);
+        assert_eq!(pattern(&miner, specific), "User alice logged in");
+        assert_eq!(pattern(&miner, general), "User <*> logged in");
+
+        // A seeded template starting with a variable catches any first token
+        // without a child of its own.
+        let any = miner.seed("<*> connected");
+        assert_eq!(add(&mut miner, "carol connected"), any);
+    }
+
+    #[test]
+    fn test_variable_length_templates() {
+        let mut miner = TemplateMiner::new(unmasked());
+        let a = add(&mut miner, "Disk /dev/sda full");
+        assert_eq!(add(&mut miner, "Disk /dev/mapper/vg0 root full"), a);
+        assert_eq!(pattern(&miner, a), "Disk <*...> full");
+        // The slot takes any number of tokens, none included.
+        assert_eq!(add(&mut miner, "Disk full"), a);
+        assert_eq!(add(&mut miner, "Disk a b c d e full"), a);
+        assert_eq!(pattern(&miner, a), "Disk <*...> full");
+
+        assert_eq!(variables(&miner, a, "Disk full"), vec![""]);
+        assert_eq!(
+            variables(&miner, a, "Disk /dev/mapper/vg0 root full"),
+            vec!["/dev/mapper/vg0 root"]
+        );
+
+        // A free-text tail becomes the slot.
+        let b = add(&mut miner, "Backup job failed: disk full");
+        assert_eq!(add(&mut miner, "Backup job failed: out of memory"), b);
+        assert_eq!(pattern(&miner, b), "Backup job failed: <*...>");
+        assert_eq!(
+            variables(&miner, b, "Backup job failed: out of memory"),
+            vec!["out of memory"]
+        );
+
+        // Fewer tokens than the template has outside its slot, or turned
+        // off: separate templates.
+        assert_ne!(add(&mut miner, "Backup done"), b);
+        let mut fresh = TemplateMiner::new(unmasked());
+        let d = add(&mut fresh, "Disk /dev/sda full");
+        assert_ne!(add(&mut fresh, "Disk is not full at all"), d);
+        let mut fixed = TemplateMiner::new(MinerConfig {
+            variable_length: false,
+            ..unmasked()
+        });
+        let c = add(&mut fixed, "Disk /dev/sda full");
+        assert_ne!(add(&mut fixed, "Disk /dev/mapper/vg0 root full"), c);
+    }
+
+    #[test]
+    fn test_masked_tokens_become_placeholders() {
+        let mut miner = TemplateMiner::default();
+        // Seen once, the address is still a variable.
+        let a = add(&mut miner, "Connection from 10.0.0.1 closed after 150ms");
+        assert_eq!(
+            pattern(&miner, a),
+            "Connection from <IP> closed after <DURATION>"
+        );
+        assert_eq!(
+            variables(&miner, a, "Connection from 10.0.0.1 closed after 2s"),
+            vec!["10.0.0.1", "2s"]
+        );
+        // Different shapes at one position generalize to <*>.
+        assert_eq!(add(&mut miner, "Connection from db01 closed after 2s"), a);
+        assert_eq!(
+            pattern(&miner, a),
+            "Connection from <*> closed after <DURATION>"
+        );
+
+        // A numeric first token is routed by its placeholder.
+        let b = add(&mut miner, "42 jobs queued");
+        assert_eq!(add(&mut miner, "7 jobs queued"), b);
+        assert_eq!(pattern(&miner, b), "<NUM> jobs queued");
+    }
+
+    #[test]
+    fn test_separators_stay_literal() {
+        let mut miner = TemplateMiner::default();
+        let a = add(&mut miner, "login user=alice, port=22 from 10.0.0.1:2201");
+        assert_eq!(add(&mut miner, "login user=bob, port=2222 from db01:22"), a);
+        assert_eq!(
+            pattern(&miner, a),
+            "login user=<*>, port=<NUM> from <*>:<NUM>"
+        );
+        assert_eq!(
+            variables(&miner, a, "login user=carol, port=22 from host:80"),
+            vec!["carol", "22", "host", "80"]
+        );
+
+        // Messages alike but for their separators stay apart.
+        let b = add(&mut miner, "status: ok now");
+        assert_ne!(add(&mut miner, "status= ok now"), b);
+        assert_ne!(add(&mut miner, "status ok now"), b);
+
+        // A slot starts and ends with a word; its separators are kept in
+        // its variable.
+        let c = add(&mut miner, "job done: took 5s");
+        assert_eq!(add(&mut miner, "job done: retries=3 took 5s"), c);
+        assert_eq!(pattern(&miner, c), "job done: <*...> took <DURATION>");
+        assert_eq!(
+            variables(&miner, c, "job done: a=1, b=[x]y took 5s"),
+            vec!["a=1, b=[x]y", "5s"]
+        );
+        assert_ne!(add(&mut miner, "job done: retries=3, took 5s"), c);
+    }
+
+    #[test]
+    fn test_wildcard_ratio_limits_templates() {
+        let mut miner = TemplateMiner::new(MinerConfig {
+            max_wildcard_ratio: 0.5,
+            ..unmasked()
+        });
+        let a = add(&mut miner, "task alpha beta done");
+        assert_eq!(add(&mut miner, "task gamma delta done"), a);
+        assert_eq!(pattern(&miner, a), "task <*> <*> done");
+        // Similar enough, but joining would leave only `task` literal.
+        let b = add(&mut miner, "task gamma delta failed");
+        assert_ne!(b, a);
+        assert_eq!(pattern(&miner, a), "task <*> <*> done");
+        assert_eq!(pattern(&miner, b), "task gamma delta failed");
+    }
+
+    #[test]
+    fn test_linear_scan_baseline() {
+        let lines = ["a b c", "a x c", "q r s", "single"];
+        assert_eq!(linear_scan(lines, 0.5), 3);
+    }
+}
@@ -0,0 +1,130 @@
+use std::fs::File;
+use std::io;
+use std::ops::Deref;
+
+/// A read-only, private mapping of a whole file.
+///
+/// The mapping is a snapshot of the file's length at open time; bytes
+/// appended later are not visible. Bytes cut off the file while it is
+/// mapped are gone from under the mapping too, and reading them raises
+/// `SIGBUS`; see [`Mmap::map`]. Platforms without `mmap` read the file into
+/// memory instead.
+pub struct Mmap {
+    #[cfg(unix)]
+    ptr: *mut libc::c_void,
+    #[cfg(unix)]
+    len: usize,
+    #[cfg(not(unix))]
+    data: Vec<u8>,
+}
+
+// The mapping is read-only and owned by this value.
+unsafe impl Send for Mmap {}
+unsafe impl Sync for Mmap {}
+
+impl Mmap {
+    /// Maps the whole of `file`.
+    ///
+    /// # Safety
+    ///
+    /// The file must not be truncated, by this or any other process, while
+    /// the mapping is alive. Appending to it, or replacing it by renaming
+    /// another file over its path, is fine.
+    #[cfg(unix)]
+    pub unsafe fn map(file: &File) -> io::Result<Self> {
+        use std::os::fd::AsRawFd;
+
+        let len = usize::try_from(file.metadata()?.len())
+            .map_err(|_| io::Error::other("File too large to map"))?;
+        if len == 0 {
+            // mmap rejects empty mappings.
+            return Ok(Self {
+                ptr: std::ptr::null_mut(),
+                len,
+            });
+        }
+        // SAFETY: a fresh private read-only mapping of an open descriptor;
+        // the result is checked before use.
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_PRIVATE,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self { ptr, len })
+    }
+
+    /// Reads the whole of `file`.
+    ///
+    /// # Safety
+    ///
+    /// Always safe here; `unsafe` for the sake of the `mmap` version.
+    #[cfg(not(unix))]
+    pub unsafe fn map(file: &File) -> io::Result<Self> {
+        use std::io::Read;
+
+        let mut data = Vec::new();
+        (&*file).read_to_end(&mut data)?;
+        Ok(Self { data })
+    }
+}
+
+impl Deref for Mmap {
+    type Target = [u8];
+
+    #[cfg(unix)]
+    fn deref(&self) -> &[u8] {
+        if self.len == 0 {
+            return &[];
+        }
+        // SAFETY: `ptr` maps `len` readable bytes until `self` is dropped.
+        unsafe { std::slice::from_raw_parts(self.ptr as *const u8, self.len) }
+    }
+
+    #[cfg(not(unix))]
+    fn deref(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        if self.len > 0 {
+            // SAFETY: unmaps exactly the range returned by `mmap`.
+            unsafe {
+                libc::munmap(self.ptr, self.len);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_map_matches_file() {
+        let path = "test_mmap.bin";
+        fs::write(path, b"mapped bytes").unwrap();
+        // SAFETY: the file is only rewritten once the mapping is dropped.
+        let map = unsafe { Mmap::map(&File::open(path).unwrap()) }.unwrap();
+        assert_eq!(&map[..], b"mapped bytes");
+        drop(map);
+
+        fs::write(path, b"").unwrap();
+        // SAFETY: as above.
+        let empty = unsafe { Mmap::map(&File::open(path).unwrap()) }.unwrap();
+        assert!(empty.is_empty());
+
+        fs::remove_file(path).unwrap();
+    }
+}
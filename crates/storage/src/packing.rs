@@ -0,0 +1,263 @@
+use bitvec::prelude::*;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+/// Column encodings, chosen per column by [`encode_u32s`].
+const BIT_PACKED: u8 = 0;
+const RUN_LENGTH: u8 = 1;
+const DICTIONARY: u8 = 2;
+
+/// Encodes an integer column, picking whichever of bit-packing, run-length
+/// or dictionary encoding yields fewer bytes.
+///
+/// Layout: encoding tag (u8), value count (u32 LE), then either one packed
+/// run of the values; or the run count (u32 LE) followed by packed run
+/// values and packed run lengths; or the count of distinct values (u32 LE)
+/// followed by the packed distinct values and each value's packed index
+/// among them. The last suits a few scattered values, such as hashed
+/// template IDs.
+pub fn encode_u32s(values: &[u32]) -> Vec<u8> {
+    let (run_values, run_lengths) = runs(values);
+    let (distinct, indices) = dictionary(values);
+    let packed_cost = packed_len(values);
+    let rle_cost = 4 + packed_len(&run_values) + packed_len(&run_lengths);
+    let dict_cost = 4 + packed_len(&distinct) + packed_len(&indices);
+
+    let mut out = Vec::with_capacity(5 + packed_cost.min(rle_cost).min(dict_cost));
+    if dict_cost < packed_cost && dict_cost < rle_cost {
+        out.push(DICTIONARY);
+        out.write_u32::<LittleEndian>(values.len() as u32).unwrap();
+        out.write_u32::<LittleEndian>(distinct.len() as u32)
+            .unwrap();
+        write_packed(&mut out, &distinct);
+        write_packed(&mut out, &indices);
+    } else if rle_cost < packed_cost {
+        out.push(RUN_LENGTH);
+        out.write_u32::<LittleEndian>(values.len() as u32).unwrap();
+        out.write_u32::<LittleEndian>(run_values.len() as u32)
+            .unwrap();
+        write_packed(&mut out, &run_values);
+        write_packed(&mut out, &run_lengths);
+    } else {
+        out.push(BIT_PACKED);
+        out.write_u32::<LittleEndian>(values.len() as u32).unwrap();
+        write_packed(&mut out, values);
+    }
+    out
+}
+
+pub fn decode_u32s(mut buf: &[u8]) -> anyhow::Result<Vec<u32>> {
+    read_u32s(&mut buf)
+}
+
+/// Encodes a boolean column as a 1-bit column (a bitmap, or runs of it).
+pub fn encode_bools(values: &[bool]) -> Vec<u8> {
+    encode_u32s(&values.iter().map(|&b| b as u32).collect::<Vec<_>>())
+}
+
+pub fn decode_bools(buf: &[u8]) -> anyhow::Result<Vec<bool>> {
+    Ok(decode_u32s(buf)?.into_iter().map(|v| v != 0).collect())
+}
+
+/// Encodes an optional ID column as a presence bitmap followed by the
+/// present values only.
+pub fn encode_optional(values: &[Option<u32>]) -> Vec<u8> {
+    let presence: Vec<bool> = values.iter().map(Option::is_some).collect();
+    let present: Vec<u32> = values.iter().flatten().copied().collect();
+    let mut out = encode_bools(&presence);
+    out.extend(encode_u32s(&present));
+    out
+}
+
+pub fn decode_optional(mut buf: &[u8]) -> anyhow::Result<Vec<Option<u32>>> {
+    let presence = read_u32s(&mut buf)?;
+    let mut present = read_u32s(&mut buf)?.into_iter();
+    presence
+        .into_iter()
+        .map(|p| match p {
+            0 => Ok(None),
+            _ => present
+                .next()
+                .map(Some)
+                .ok_or_else(|| anyhow::anyhow!("Optional column has too few values")),
+        })
+        .collect()
+}
+
+fn read_u32s(buf: &mut &[u8]) -> anyhow::Result<Vec<u32>> {
+    let tag = buf.read_u8()?;
+    let count = buf.read_u32::<LittleEndian>()? as usize;
+    match tag {
+        BIT_PACKED => read_packed(buf, count),
+        RUN_LENGTH => {
+            let runs = buf.read_u32::<LittleEndian>()? as usize;
+            let run_values = read_packed(buf, runs)?;
+            let run_lengths = read_packed(buf, runs)?;
+            let mut values = Vec::with_capacity(count);
+            for (value, len) in run_values.into_iter().zip(run_lengths) {
+                values.extend(std::iter::repeat_n(value, len as usize));
+            }
+            if values.len() != count {
+                return Err(anyhow::anyhow!(
+                    "Run-length column holds {} values (expected {})",
+                    values.len(),
+                    count
+                ));
+            }
+            Ok(values)
+        }
+        DICTIONARY => {
+            let len = buf.read_u32::<LittleEndian>()? as usize;
+            let distinct = read_packed(buf, len)?;
+            read_packed(buf, count)?
+                .into_iter()
+                .map(|i| {
+                    distinct.get(i as usize).copied().ok_or_else(|| {
+                        anyhow::anyhow!("Dictionary index {} out of {} values", i, len)
+                    })
+                })
+                .collect()
+        }
+        other => Err(anyhow::anyhow!("Unknown column encoding {}", other)),
+    }
+}
+
+/// Splits `values` into parallel lists of run values and run lengths.
+fn runs(values: &[u32]) -> (Vec<u32>, Vec<u32>) {
+    let mut run_values = Vec::new();
+    let mut run_lengths: Vec<u32> = Vec::new();
+    for &v in values {
+        match run_values.last() {
+            Some(&last) if last == v => *run_lengths.last_mut().unwrap() += 1,
+            _ => {
+                run_values.push(v);
+                run_lengths.push(1);
+            }
+        }
+    }
+    (run_values, run_lengths)
+}
+
+/// The distinct values, sorted, and the index of each value among them.
+fn dictionary(values: &[u32]) -> (Vec<u32>, Vec<u32>) {
+    let mut distinct = values.to_vec();
+    distinct.sort_unstable();
+    distinct.dedup();
+    let indices = values
+        .iter()
+        .map(|v| distinct.binary_search(v).unwrap_or_default() as u32)
+        .collect();
+    (distinct, indices)
+}
+
+/// Minimum and bit width of the offsets from it.
+fn frame(values: &[u32]) -> (u32, u8) {
+    let min = values.iter().copied().min().unwrap_or(0);
+    let max = values.iter().copied().max().unwrap_or(0);
+    (min, (32 - (max - min).leading_zeros()) as u8)
+}
+
+fn packed_len(values: &[u32]) -> usize {
+    let (_, width) = frame(values);
+    5 + (values.len() * width as usize).div_ceil(8)
+}
+
+/// Writes the minimum (u32 LE) and bit width (u8), then each value's
+/// offset from the minimum in `width` bits, least significant bit first.
+fn write_packed(out: &mut Vec<u8>, values: &[u32]) {
+    let (min, width) = frame(values);
+    out.write_u32::<LittleEndian>(min).unwrap();
+    out.push(width);
+    let mut bits: BitVec<u8, Lsb0> = BitVec::with_capacity(values.len() * width as usize);
+    for &v in values {
+        bits.extend_from_bitslice(&(v - min).view_bits::<Lsb0>()[..width as usize]);
+    }
+    out.extend_from_slice(bits.as_raw_slice());
+}
+
+fn read_packed(buf: &mut &[u8], count: usize) -> anyhow::Result<Vec<u32>> {
+    let min = buf.read_u32::<LittleEndian>()?;
+    let width = buf.read_u8()? as usize;
+    if width > 32 {
+        return Err(anyhow::anyhow!("Invalid bit width {}", width));
+    }
+    let len = (count * width).div_ceil(8);
+    if buf.len() < len {
+        return Err(anyhow::anyhow!("Truncated packed column"));
+    }
+    let (data, rest) = buf.split_at(len);
+    *buf = rest;
+    if width == 0 {
+        return Ok(vec![min; count]);
+    }
+    let bits = data.view_bits::<Lsb0>();
+    Ok(bits
+        .chunks_exact(width)
+        .take(count)
+        .map(|offset| min.wrapping_add(offset.load_le::<u32>()))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bit_packing_round_trip() {
+        let values = vec![1000, 1003, 1001, 1007, 1000, 1002];
+        let buf = encode_u32s(&values);
+        assert_eq!(buf[0], BIT_PACKED);
+        // Offsets fit in 3 bits: 6 values in 18 bits.
+        assert_eq!(buf.len(), 5 + 5 + 3);
+        assert_eq!(decode_u32s(&buf).unwrap(), values);
+
+        let extremes = vec![0, u32::MAX, 7];
+        assert_eq!(decode_u32s(&encode_u32s(&extremes)).unwrap(), extremes);
+        assert!(decode_u32s(&encode_u32s(&[])).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_long_runs_use_rle() {
+        let mut values = vec![3; 500];
+        values.extend(vec![9; 500]);
+        let buf = encode_u32s(&values);
+        assert_eq!(buf[0], RUN_LENGTH);
+        assert!(buf.len() < 30);
+        assert_eq!(decode_u32s(&buf).unwrap(), values);
+    }
+
+    #[test]
+    fn test_scattered_values_use_dictionary() {
+        let ids = [1_834_592_211, 77, 2_000_000_001];
+        let values: Vec<u32> = (0..300).map(|i| ids[i * 7 % 3]).collect();
+        let buf = encode_u32s(&values);
+        assert_eq!(buf[0], DICTIONARY);
+        // Three 31-bit values, then 2 bits per index.
+        assert_eq!(buf.len(), 9 + (5 + 12) + (5 + 75));
+        assert_eq!(decode_u32s(&buf).unwrap(), values);
+
+        let mut corrupt = buf.clone();
+        corrupt[5] = 2;
+        assert!(decode_u32s(&corrupt).is_err());
+    }
+
+    #[test]
+    fn test_bools_and_optionals() {
+        let flags: Vec<bool> = (0..100).map(|i| i % 3 == 0).collect();
+        let buf = encode_bools(&flags);
+        assert_eq!(buf.len(), 5 + 5 + 13);
+        assert_eq!(decode_bools(&buf).unwrap(), flags);
+
+        let ids = vec![Some(4), None, Some(4), Some(12), None, None];
+        assert_eq!(decode_optional(&encode_optional(&ids)).unwrap(), ids);
+        assert_eq!(
+            decode_optional(&encode_optional(&[None, None])).unwrap(),
+            vec![None, None]
+        );
+    }
+
+    #[test]
+    fn test_truncated_column_is_an_error() {
+        let buf = encode_u32s(&[1, 2, 3, 4, 5, 6, 7, 8]);
+        assert!(decode_u32s(&buf[..buf.len() - 1]).is_err());
+    }
+}
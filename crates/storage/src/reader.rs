@@ -0,0 +1,839 @@
+use crate::compression::{BlockDictionaries, Column};
+use crate::container::{FrameReader, MappedFrames};
+use crate::dictionary::DictionaryCache;
+use crate::encryption::Keyring;
+use crate::logshrink::{self, Layout, LogChunk, LogRecord};
+use crate::migrate::{self, StoredChunk};
+use crate::packing;
+use crate::structured::{SdMatch, SdShape};
+use crate::varcodec::{self, SlotColumn, TypedVariables};
+use chrono::{TimeZone, Utc};
+use sankshepa_protocol::SyslogMessage;
+use sankshepa_protocol::structured_data;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+/// The chunk columns a reader decodes; the others are left compressed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ColumnSet(u16);
+
+impl ColumnSet {
+    pub const ALL: ColumnSet = ColumnSet((1 << 14) - 1);
+
+    pub const fn empty() -> Self {
+        ColumnSet(0)
+    }
+
+    pub fn of(columns: &[Column]) -> Self {
+        columns.iter().fold(Self::empty(), |set, &c| set.with(c))
+    }
+
+    /// Adds `column`. Variables need the template IDs and variable counts
+    /// to be placed back into records, and string ID columns the string
+    /// pool to be resolved, so they bring those along.
+    pub fn with(self, column: Column) -> Self {
+        let set = ColumnSet(self.0 | 1 << column as u16);
+        match column {
+            Column::Variables => set.with(Column::TemplateId).with(Column::VariableCount),
+            Column::Hostname
+            | Column::AppName
+            | Column::ProcId
+            | Column::MsgId
+            | Column::StructuredData
+            | Column::NodeId => set.with(Column::StringPool),
+            _ => set,
+        }
+    }
+
+    pub fn contains(self, column: Column) -> bool {
+        self.0 & (1 << column as u16) != 0
+    }
+}
+
+/// The decoded columns of one chunk. Columns outside the requested
+/// [`ColumnSet`] are `None`.
+#[derive(Debug, Default)]
+pub struct ChunkColumns {
+    pub len: usize,
+    pub timestamps: Option<Vec<i64>>,
+    pub priorities: Option<Vec<u8>>,
+    pub hostname_ids: Option<Vec<Option<u32>>>,
+    pub app_name_ids: Option<Vec<Option<u32>>>,
+    pub procid_ids: Option<Vec<Option<u32>>>,
+    pub msgid_ids: Option<Vec<Option<u32>>>,
+    /// Verbatim structured data; see [`LogRecord::structured_data_id`].
+    pub sd_ids: Option<Vec<Option<u32>>>,
+    pub sd_shape_ids: Option<Vec<Option<u32>>>,
+    pub sd_values: Option<Vec<Vec<String>>>,
+    pub template_ids: Option<Vec<u32>>,
+    pub variables: Option<Vec<Vec<String>>>,
+    pub is_rfc5424: Option<Vec<bool>>,
+    pub node_id_ids: Option<Vec<Option<u32>>>,
+    pub layouts: Option<Vec<Option<Layout>>>,
+    pub string_pool: Vec<String>,
+    pub sd_shapes: Vec<SdShape>,
+    /// Patterns by template ID.
+    pub patterns: HashMap<u32, String>,
+    pub dictionary: Option<String>,
+}
+
+/// One record of a [`ChunkColumns`]; fields of columns that were not
+/// decoded are `None`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record<'a> {
+    pub timestamp: Option<i64>,
+    pub priority: Option<u8>,
+    pub hostname: Option<&'a str>,
+    pub app_name: Option<&'a str>,
+    pub procid: Option<&'a str>,
+    pub msgid: Option<&'a str>,
+    /// Structured data kept verbatim, or the shape and values it was split
+    /// into; see [`Record::structured_data`].
+    pub sd_text: Option<&'a str>,
+    pub sd_shape: Option<&'a SdShape>,
+    pub sd_values: Option<&'a [String]>,
+    pub template_id: Option<u32>,
+    pub pattern: Option<&'a str>,
+    pub variables: Option<&'a [String]>,
+    pub is_rfc5424: Option<bool>,
+    pub node_id: Option<&'a str>,
+    /// Only set for records stored losslessly.
+    pub layout: Option<&'a Layout>,
+}
+
+impl Record<'_> {
+    /// The original message text, if the template and its variables were
+    /// decoded.
+    pub fn message(&self) -> Option<String> {
+        Some(logshrink::reconstruct(self.pattern?, self.variables?))
+    }
+
+    /// The structured data text, without the first `[` and the last `]`.
+    pub fn structured_data(&self) -> Option<Cow<'_, str>> {
+        if let Some(text) = self.sd_text {
+            return Some(Cow::Borrowed(text));
+        }
+        Some(Cow::Owned(self.sd_shape?.render(self.sd_values?)))
+    }
+
+    /// Whether the structured data has parameter `m.param` of element `m.id`
+    /// with value `m.value`.
+    pub fn sd_matches(&self, m: &SdMatch) -> bool {
+        if let (Some(shape), Some(values)) = (self.sd_shape, self.sd_values) {
+            return shape.value(values, &m.id, &m.param) == Some(m.value.as_str());
+        }
+        let Some(elements) = self.sd_text.and_then(structured_data::parse) else {
+            return false;
+        };
+        elements
+            .iter()
+            .filter(|e| e.id == m.id)
+            .find_map(|e| e.params.iter().find(|(name, _)| *name == m.param))
+            .is_some_and(|(_, value)| *value == m.value)
+    }
+
+    /// The bytes exactly as received, for records stored losslessly.
+    pub fn original(&self) -> Option<Vec<u8>> {
+        Some(self.layout?.restore(&self.message()?))
+    }
+
+    /// Rebuilds the syslog message the record was stored from, e.g. to mine
+    /// it again. Undecoded fields are left empty.
+    pub fn to_syslog(&self) -> SyslogMessage {
+        let priority = self.priority.unwrap_or_default();
+        SyslogMessage {
+            priority,
+            facility: priority >> 3,
+            severity: priority & 0x07,
+            timestamp: self
+                .timestamp
+                .and_then(|ts| Utc.timestamp_millis_opt(ts).single()),
+            hostname: self.hostname.map(str::to_string),
+            app_name: self.app_name.map(str::to_string),
+            procid: self.procid.map(str::to_string),
+            msgid: self.msgid.map(str::to_string),
+            structured_data: self.structured_data().map(Cow::into_owned),
+            message: self.message().unwrap_or_default(),
+            is_rfc5424: self.is_rfc5424.unwrap_or_default(),
+            node_id: self.node_id.map(str::to_string),
+            raw: self.original(),
+        }
+    }
+}
+
+impl ChunkColumns {
+    /// Decompresses the `columns` of `stored`, leaving the other blocks
+    /// untouched.
+    pub(crate) fn decode(
+        stored: StoredChunk,
+        blocks: &BlockDictionaries,
+        columns: ColumnSet,
+    ) -> anyhow::Result<Self> {
+        let StoredChunk {
+            chunk: compressed,
+            string_pool,
+            legacy_layouts,
+        } = stored;
+        let dict = compressed.zstd_dictionary;
+        let decode_all = |block: &[u8]| blocks.decompress(dict, block);
+        let optional = |column: Column, block: &[u8]| -> anyhow::Result<_> {
+            match columns.contains(column) {
+                true => Ok(Some(packing::decode_optional(&decode_all(block)?)?)),
+                false => Ok(None),
+            }
+        };
+
+        let timestamps = match columns.contains(Column::Timestamp) {
+            true => {
+                let delta_ts: Vec<i64> =
+                    postcard::from_bytes(&decode_all(&compressed.timestamp_block)?)?;
+                let mut current = 0i64;
+                Some(
+                    delta_ts
+                        .into_iter()
+                        .map(|delta| {
+                            current += delta;
+                            current
+                        })
+                        .collect::<Vec<_>>(),
+                )
+            }
+            false => None,
+        };
+        let priorities = match columns.contains(Column::Priority) {
+            true => Some(
+                packing::decode_u32s(&decode_all(&compressed.priority_block)?)?
+                    .into_iter()
+                    .map(|p| p as u8)
+                    .collect::<Vec<_>>(),
+            ),
+            false => None,
+        };
+        let hostname_ids = optional(Column::Hostname, &compressed.hostname_id_block)?;
+        let app_name_ids = optional(Column::AppName, &compressed.app_name_id_block)?;
+        let procid_ids = optional(Column::ProcId, &compressed.procid_id_block)?;
+        let msgid_ids = optional(Column::MsgId, &compressed.msgid_id_block)?;
+        let sd_ids = optional(Column::StructuredData, &compressed.sd_id_block)?;
+        // Chunks upgraded from before shapes existed have no shape block.
+        let sd_shape_ids = match compressed.sd_shape_block.is_empty() {
+            true => sd_ids.as_ref().map(|ids| vec![None; ids.len()]),
+            false => optional(Column::StructuredData, &compressed.sd_shape_block)?,
+        };
+        let sd_values = match &sd_shape_ids {
+            Some(ids) => {
+                let shapes = &compressed.sd_shapes;
+                let shape_of = |id: &Option<u32>| id.and_then(|id| shapes.get(id as usize));
+                let mut columns = Vec::with_capacity(compressed.sd_value_columns.len());
+                for column in compressed.sd_value_columns {
+                    let (dict, data) = postcard::from_bytes(&decode_all(&column.block)?)?;
+                    columns.push(SlotColumn {
+                        template_id: column.template_id,
+                        slot: column.slot,
+                        ty: column.ty,
+                        dict,
+                        data,
+                    });
+                }
+                let typed = TypedVariables {
+                    counts: ids
+                        .iter()
+                        .map(|id| shape_of(id).map_or(0, |s| s.len() as u32))
+                        .collect(),
+                    columns,
+                };
+                let keys: Vec<u32> = ids.iter().map(|id| id.unwrap_or(u32::MAX)).collect();
+                Some(varcodec::decode_variables(&keys, typed)?)
+            }
+            None => None,
+        };
+        let template_ids = match columns.contains(Column::TemplateId) {
+            true => Some(packing::decode_u32s(&decode_all(
+                &compressed.template_id_block,
+            )?)?),
+            false => None,
+        };
+
+        let variables = match (columns.contains(Column::Variables), &template_ids) {
+            (true, Some(ids)) => {
+                let mut slots = Vec::with_capacity(compressed.variable_columns.len());
+                for column in compressed.variable_columns {
+                    let (dict, data) = postcard::from_bytes(&decode_all(&column.block)?)?;
+                    slots.push(SlotColumn {
+                        template_id: column.template_id,
+                        slot: column.slot,
+                        ty: column.ty,
+                        dict,
+                        data,
+                    });
+                }
+                let typed = TypedVariables {
+                    counts: postcard::from_bytes(&decode_all(&compressed.variable_count_block)?)?,
+                    columns: slots,
+                };
+                Some(varcodec::decode_variables(ids, typed)?)
+            }
+            _ => None,
+        };
+
+        let is_rfc5424 = match columns.contains(Column::IsRfc5424) {
+            true => Some(packing::decode_bools(&decode_all(
+                &compressed.is_rfc5424_block,
+            )?)?),
+            false => None,
+        };
+        let node_id_ids = optional(Column::NodeId, &compressed.node_id_id_block)?;
+        let string_pool = match (columns.contains(Column::StringPool), string_pool) {
+            (true, Some(pool)) => pool,
+            (true, None) => postcard::from_bytes(&decode_all(&compressed.string_pool_block)?)?,
+            (false, _) => Vec::new(),
+        };
+
+        let lens = [
+            timestamps.as_ref().map(Vec::len),
+            priorities.as_ref().map(Vec::len),
+            hostname_ids.as_ref().map(Vec::len),
+            app_name_ids.as_ref().map(Vec::len),
+            procid_ids.as_ref().map(Vec::len),
+            msgid_ids.as_ref().map(Vec::len),
+            sd_ids.as_ref().map(Vec::len),
+            sd_shape_ids.as_ref().map(Vec::len),
+            template_ids.as_ref().map(Vec::len),
+            variables.as_ref().map(Vec::len),
+            is_rfc5424.as_ref().map(Vec::len),
+            node_id_ids.as_ref().map(Vec::len),
+        ];
+        let mut decoded = lens.into_iter().flatten();
+        let len = match decoded.next() {
+            Some(len) if decoded.all(|l| l == len) => len,
+            Some(_) => return Err(anyhow::anyhow!("Chunk columns have different lengths")),
+            // Nothing selected: the template ID column is the cheapest
+            // way to count the records.
+            None => packing::decode_u32s(&decode_all(&compressed.template_id_block)?)?.len(),
+        };
+        // Chunks without lossless records leave the layout block empty.
+        let layouts = match columns.contains(Column::Layout) {
+            true if compressed.layout_block.is_empty() => Some(vec![None; len]),
+            true => {
+                let layouts =
+                    migrate::parse_layouts(&decode_all(&compressed.layout_block)?, legacy_layouts)?;
+                if layouts.len() != len {
+                    return Err(anyhow::anyhow!("Chunk columns have different lengths"));
+                }
+                Some(layouts)
+            }
+            false => None,
+        };
+
+        Ok(Self {
+            len,
+            timestamps,
+            priorities,
+            hostname_ids,
+            app_name_ids,
+            procid_ids,
+            msgid_ids,
+            sd_ids,
+            sd_shape_ids,
+            sd_values,
+            template_ids,
+            variables,
+            is_rfc5424,
+            node_id_ids,
+            layouts,
+            string_pool,
+            sd_shapes: compressed.sd_shapes,
+            patterns: compressed
+                .templates
+                .into_iter()
+                .map(|t| (t.id, t.pattern))
+                .collect(),
+            dictionary: compressed.dictionary,
+        })
+    }
+
+    /// Looks up the patterns of the decoded template IDs in the store
+    /// dictionary the chunk refers to, if any.
+    pub(crate) fn resolve_templates(
+        &mut self,
+        container: &Path,
+        dictionaries: &mut DictionaryCache,
+    ) -> anyhow::Result<()> {
+        let (Some(name), Some(ids)) = (&self.dictionary, &self.template_ids) else {
+            return Ok(());
+        };
+        let used: HashSet<u32> = ids.iter().copied().collect();
+        self.patterns
+            .extend(dictionaries.lookup(container, name, &used)?);
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn string(&self, id: Option<u32>) -> Option<&str> {
+        id.and_then(|id| self.string_pool.get(id as usize))
+            .map(String::as_str)
+    }
+
+    /// The `i`-th record, built from the decoded columns.
+    pub fn record(&self, i: usize) -> Record<'_> {
+        let id =
+            |column: &Option<Vec<Option<u32>>>| column.as_ref().and_then(|c| self.string(c[i]));
+        let template_id = self.template_ids.as_ref().map(|c| c[i]);
+        Record {
+            timestamp: self.timestamps.as_ref().map(|c| c[i]),
+            priority: self.priorities.as_ref().map(|c| c[i]),
+            hostname: id(&self.hostname_ids),
+            app_name: id(&self.app_name_ids),
+            procid: id(&self.procid_ids),
+            msgid: id(&self.msgid_ids),
+            sd_text: id(&self.sd_ids),
+            sd_shape: self
+                .sd_shape_ids
+                .as_ref()
+                .and_then(|c| c[i])
+                .and_then(|s| self.sd_shapes.get(s as usize)),
+            sd_values: self.sd_values.as_ref().map(|c| c[i].as_slice()),
+            template_id,
+            pattern: template_id
+                .and_then(|t| self.patterns.get(&t))
+                .map(String::as_str),
+            variables: self.variables.as_ref().map(|c| c[i].as_slice()),
+            is_rfc5424: self.is_rfc5424.as_ref().map(|c| c[i]),
+            node_id: id(&self.node_id_ids),
+            layout: self.layouts.as_ref().and_then(|c| c[i].as_ref()),
+        }
+    }
+
+    pub fn records(&self) -> impl Iterator<Item = Record<'_>> {
+        (0..self.len).map(|i| self.record(i))
+    }
+
+    /// Builds a full chunk; columns that were not decoded take their
+    /// default value.
+    pub fn into_chunk(self) -> LogChunk {
+        fn take<T: Default + Clone>(column: Option<Vec<T>>, len: usize) -> std::vec::IntoIter<T> {
+            column
+                .unwrap_or_else(|| vec![T::default(); len])
+                .into_iter()
+        }
+        let len = self.len;
+        let mut timestamps = take(self.timestamps, len);
+        let mut priorities = take(self.priorities, len);
+        let mut hostname_ids = take(self.hostname_ids, len);
+        let mut app_name_ids = take(self.app_name_ids, len);
+        let mut procid_ids = take(self.procid_ids, len);
+        let mut msgid_ids = take(self.msgid_ids, len);
+        let mut sd_ids = take(self.sd_ids, len);
+        let mut sd_shape_ids = take(self.sd_shape_ids, len);
+        let mut sd_values = take(self.sd_values, len);
+        let mut template_ids = take(self.template_ids, len);
+        let mut variables = take(self.variables, len);
+        let mut is_rfc5424 = take(self.is_rfc5424, len);
+        let mut node_id_ids = take(self.node_id_ids, len);
+        let mut layouts = take(self.layouts, len);
+
+        let mut chunk = LogChunk::new();
+        chunk.string_pool = self.string_pool;
+        for (id, shape) in self.sd_shapes.iter().enumerate() {
+            chunk.sd_shape_map.insert(shape.clone(), id as u32);
+        }
+        chunk.sd_shapes = self.sd_shapes;
+        for (id, pattern) in self.patterns {
+            chunk.templates.insert(pattern, id);
+        }
+        chunk.next_template_id = chunk.templates.values().max().map_or(0, |&id| id + 1);
+        chunk.dictionary = self.dictionary;
+        for _ in 0..len {
+            chunk.records.push(LogRecord {
+                timestamp: timestamps.next().unwrap_or_default(),
+                priority: priorities.next().unwrap_or_default(),
+                hostname_id: hostname_ids.next().flatten(),
+                app_name_id: app_name_ids.next().flatten(),
+                procid_id: procid_ids.next().flatten(),
+                msgid_id: msgid_ids.next().flatten(),
+                structured_data_id: sd_ids.next().flatten(),
+                sd_shape: sd_shape_ids.next().flatten(),
+                sd_values: sd_values.next().unwrap_or_default(),
+                template_id: template_ids.next().unwrap_or_default(),
+                variables: variables.next().unwrap_or_default(),
+                is_rfc5424: is_rfc5424.next().unwrap_or_default(),
+                node_id_id: node_id_ids.next().flatten(),
+                layout: layouts.next().flatten(),
+            });
+        }
+        chunk
+    }
+
+    /// Splits an already decoded chunk into columns, all of them present.
+    pub fn from_chunk(chunk: LogChunk) -> Self {
+        let mut columns = Self {
+            len: chunk.records.len(),
+            string_pool: chunk.string_pool,
+            sd_shapes: chunk.sd_shapes,
+            patterns: chunk.templates.into_iter().map(|(p, id)| (id, p)).collect(),
+            dictionary: chunk.dictionary,
+            ..Default::default()
+        };
+        let mut timestamps = Vec::with_capacity(columns.len);
+        let mut priorities = Vec::with_capacity(columns.len);
+        let mut hostname_ids = Vec::with_capacity(columns.len);
+        let mut app_name_ids = Vec::with_capacity(columns.len);
+        let mut procid_ids = Vec::with_capacity(columns.len);
+        let mut msgid_ids = Vec::with_capacity(columns.len);
+        let mut sd_ids = Vec::with_capacity(columns.len);
+        let mut sd_shape_ids = Vec::with_capacity(columns.len);
+        let mut sd_values = Vec::with_capacity(columns.len);
+        let mut template_ids = Vec::with_capacity(columns.len);
+        let mut variables = Vec::with_capacity(columns.len);
+        let mut is_rfc5424 = Vec::with_capacity(columns.len);
+        let mut node_id_ids = Vec::with_capacity(columns.len);
+        let mut layouts = Vec::with_capacity(columns.len);
+        for record in chunk.records {
+            timestamps.push(record.timestamp);
+            priorities.push(record.priority);
+            hostname_ids.push(record.hostname_id);
+            app_name_ids.push(record.app_name_id);
+            procid_ids.push(record.procid_id);
+            msgid_ids.push(record.msgid_id);
+            sd_ids.push(record.structured_data_id);
+            sd_shape_ids.push(record.sd_shape);
+            sd_values.push(record.sd_values);
+            template_ids.push(record.template_id);
+            variables.push(record.variables);
+            is_rfc5424.push(record.is_rfc5424);
+            node_id_ids.push(record.node_id_id);
+            layouts.push(record.layout);
+        }
+        columns.timestamps = Some(timestamps);
+        columns.priorities = Some(priorities);
+        columns.hostname_ids = Some(hostname_ids);
+        columns.app_name_ids = Some(app_name_ids);
+        columns.procid_ids = Some(procid_ids);
+        columns.msgid_ids = Some(msgid_ids);
+        columns.sd_ids = Some(sd_ids);
+        columns.sd_shape_ids = Some(sd_shape_ids);
+        columns.sd_values = Some(sd_values);
+        columns.template_ids = Some(template_ids);
+        columns.variables = Some(variables);
+        columns.is_rfc5424 = Some(is_rfc5424);
+        columns.node_id_ids = Some(node_id_ids);
+        columns.layouts = Some(layouts);
+        columns
+    }
+}
+
+/// How a container is read.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ReadMode {
+    /// Buffered file reads, copying each frame into memory.
+    #[default]
+    Buffered,
+    /// A memory mapping; blocks are decompressed straight from it.
+    Mapped,
+}
+
+/// Reads chunk frames from a container, either all of them in write order
+/// or only those at the given offsets, decoding one chunk at a time.
+pub(crate) struct ChunkSource {
+    frames: Frames,
+    offsets: Option<std::vec::IntoIter<u64>>,
+    path: PathBuf,
+    keys: Option<Keyring>,
+    dictionaries: DictionaryCache,
+    blocks: BlockDictionaries,
+}
+
+/// Where a [`ChunkSource`] reads frames from.
+enum Frames {
+    Buffered(FrameReader),
+    Mapped(MappedFrames),
+}
+
+impl ChunkSource {
+    pub(crate) fn open(
+        path: &Path,
+        offsets: Option<Vec<u64>>,
+        mode: ReadMode,
+        keys: Option<&Keyring>,
+    ) -> anyhow::Result<Self> {
+        let frames = match mode {
+            ReadMode::Buffered => Frames::Buffered(FrameReader::open(path, keys)?),
+            ReadMode::Mapped => Frames::Mapped(MappedFrames::open(path, keys)?),
+        };
+        Ok(Self {
+            frames,
+            offsets: offsets.map(Vec::into_iter),
+            path: path.to_path_buf(),
+            keys: keys.cloned(),
+            dictionaries: DictionaryCache::new(keys),
+            blocks: BlockDictionaries::load(path, keys)?,
+        })
+    }
+
+    pub(crate) fn next_columns(
+        &mut self,
+        columns: ColumnSet,
+    ) -> Option<anyhow::Result<ChunkColumns>> {
+        let offset = match &mut self.offsets {
+            Some(offsets) => Some(offsets.next()?),
+            None => None,
+        };
+        let version = match &self.frames {
+            Frames::Buffered(frames) => frames.version(),
+            Frames::Mapped(frames) => frames.version(),
+        };
+        let frame = match &mut self.frames {
+            Frames::Buffered(frames) => offset
+                .map_or(Ok(()), |o| frames.seek_to(o))
+                .and_then(|_| frames.next_frame())
+                .map(|frame| frame.map(|(_, payload)| Cow::Owned(payload))),
+            Frames::Mapped(frames) => offset
+                .map_or(Ok(()), |o| frames.seek_to(o))
+                .and_then(|_| frames.next_frame())
+                .map(|frame| frame.map(|(_, payload)| payload)),
+        };
+        let payload = match (frame, offset) {
+            (Ok(Some(payload)), _) => payload,
+            (Ok(None), None) => return None,
+            (Ok(None), Some(offset)) => {
+                return Some(Err(anyhow::anyhow!("Missing chunk at offset {}", offset)));
+            }
+            (Err(e), _) => return Some(Err(e)),
+        };
+        Some(decode_frame(
+            &payload,
+            version,
+            columns,
+            &self.path,
+            self.keys.as_ref(),
+            &mut self.blocks,
+            &mut self.dictionaries,
+        ))
+    }
+}
+
+/// Decodes the `columns` of the chunk in a frame payload of a container of
+/// format `version` and resolves its templates.
+fn decode_frame(
+    payload: &[u8],
+    version: u16,
+    columns: ColumnSet,
+    path: &Path,
+    keys: Option<&Keyring>,
+    blocks: &mut BlockDictionaries,
+    dictionaries: &mut DictionaryCache,
+) -> anyhow::Result<ChunkColumns> {
+    let stored = migrate::parse_chunk(payload, version)?;
+    if let Some(id) = stored.chunk.zstd_dictionary
+        && blocks.get(id).is_none()
+    {
+        // The writer may have trained it since the sidecar was read.
+        *blocks = BlockDictionaries::load(path, keys)?;
+    }
+    let mut decoded = ChunkColumns::decode(stored, blocks, columns)?;
+    decoded.resolve_templates(path, dictionaries)?;
+    Ok(decoded)
+}
+
+/// Iterator over the chunks of a container, decoding only the selected
+/// columns of each. At most one chunk is held in memory at a time.
+pub struct ProjectedChunks {
+    source: ChunkSource,
+    columns: ColumnSet,
+}
+
+impl ProjectedChunks {
+    pub(crate) fn new(source: ChunkSource, columns: ColumnSet) -> Self {
+        Self { source, columns }
+    }
+}
+
+impl Iterator for ProjectedChunks {
+    type Item = anyhow::Result<ChunkColumns>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.source.next_columns(self.columns)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::StorageEngine;
+    use crate::index::{ChunkFilter, ChunkIndex};
+    use std::fs;
+
+    fn chunk_of(texts: &[&str]) -> LogChunk {
+        let mut chunk = LogChunk::new();
+        for text in texts {
+            chunk.add_message(SyslogMessage {
+                priority: 34,
+                facility: 4,
+                severity: 2,
+                timestamp: Some(Utc::now()),
+                hostname: Some("web-1".to_string()),
+                app_name: Some("sshd".to_string()),
+                procid: None,
+                msgid: None,
+                structured_data: None,
+                message: text.to_string(),
+                is_rfc5424: true,
+                node_id: None,
+                raw: None,
+            });
+        }
+        chunk.finish_and_process();
+        chunk
+    }
+
+    #[test]
+    fn test_column_set_pulls_in_dependencies() {
+        let set = ColumnSet::of(&[Column::Timestamp, Column::Variables]);
+        assert!(set.contains(Column::Timestamp));
+        assert!(set.contains(Column::TemplateId));
+        assert!(set.contains(Column::VariableCount));
+        assert!(!set.contains(Column::Hostname));
+        assert!(!set.contains(Column::StringPool));
+        assert!(ColumnSet::of(&[Column::Hostname]).contains(Column::StringPool));
+        assert!(ColumnSet::ALL.contains(Column::NodeId));
+    }
+
+    #[test]
+    fn test_projected_read_skips_columns() {
+        let path = "test_projected.lshrink";
+        let _ = fs::remove_file(path);
+        let _ = fs::remove_file(ChunkIndex::sidecar_path(path));
+
+        let texts = [
+            "Accepted password for alice from 10.0.0.1",
+            "Accepted password for bob from 10.0.0.2",
+            "Connection closed by 10.0.0.9",
+        ];
+        StorageEngine::save_chunk(&chunk_of(&texts), path).unwrap();
+        StorageEngine::save_chunk(&chunk_of(&texts[..1]), path).unwrap();
+
+        let projection = ColumnSet::of(&[Column::Timestamp, Column::TemplateId]);
+        let chunks: Vec<ChunkColumns> =
+            StorageEngine::iter_columns(path, projection, ReadMode::Buffered, None)
+                .unwrap()
+                .map(|c| c.unwrap())
+                .collect();
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0].len, 3);
+        assert!(chunks[0].timestamps.is_some());
+        assert!(chunks[0].variables.is_none());
+        assert!(chunks[0].hostname_ids.is_none());
+        assert!(chunks[0].string_pool.is_empty());
+        let record = chunks[0].record(0);
+        assert!(record.pattern.is_some());
+        assert_eq!(record.hostname, None);
+        assert_eq!(record.message(), None);
+
+        // An empty projection still yields record counts.
+        let counts: Vec<usize> =
+            StorageEngine::iter_columns(path, ColumnSet::empty(), ReadMode::Mapped, None)
+                .unwrap()
+                .map(|c| c.unwrap().len)
+                .collect();
+        assert_eq!(counts, vec![3, 1]);
+
+        let full = StorageEngine::iter_columns(path, ColumnSet::ALL, ReadMode::Mapped, None)
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap();
+        let mut messages: Vec<String> = full.records().filter_map(|r| r.message()).collect();
+        messages.sort();
+        let mut expected: Vec<String> = texts.iter().map(|t| t.to_string()).collect();
+        expected.sort();
+        assert_eq!(messages, expected);
+        assert_eq!(full.record(0).hostname, Some("web-1"));
+
+        let hosts = StorageEngine::iter_matching_columns(
+            path,
+            &ChunkFilter {
+                host: Some("web-1".to_string()),
+                ..Default::default()
+            },
+            ColumnSet::of(&[Column::Hostname]),
+            ReadMode::Mapped,
+            None,
+        )
+        .unwrap()
+        .next()
+        .unwrap()
+        .unwrap();
+        assert_eq!(hosts.string_pool, vec!["web-1", "sshd"]);
+        assert!(hosts.template_ids.is_none());
+        assert_eq!(hosts.records().filter_map(|r| r.hostname).count(), 3);
+
+        fs::remove_file(path).unwrap();
+        let _ = fs::remove_file(ChunkIndex::sidecar_path(path));
+    }
+
+    #[test]
+    fn test_structured_data_columns() {
+        let path = Path::new("test_reader_sd.lshrink");
+        let _ = fs::remove_file(path);
+        let _ = fs::remove_file(ChunkIndex::sidecar_path(path));
+
+        let sds = [
+            Some(r#"exampleSDID@32473 iut="3" eventID="1011""#.to_string()),
+            Some(r#"exampleSDID@32473 iut="3" eventID="1012"][origin ip="10.0.0.1""#.to_string()),
+            Some(r#"exampleSDID@32473 iut="4" eventID="1011""#.to_string()),
+            // Not in canonical form, so kept verbatim.
+            Some(r#"exampleSDID@32473 eventID="10\11""#.to_string()),
+            None,
+        ];
+        let mut chunk = LogChunk::new();
+        for sd in &sds {
+            chunk.add_message(SyslogMessage {
+                priority: 34,
+                facility: 4,
+                severity: 2,
+                timestamp: Some(Utc::now()),
+                hostname: Some("web-1".to_string()),
+                app_name: None,
+                procid: None,
+                msgid: None,
+                structured_data: sd.clone(),
+                message: "event".to_string(),
+                is_rfc5424: true,
+                node_id: None,
+                raw: None,
+            });
+        }
+        chunk.finish_and_process();
+        assert_eq!(chunk.sd_shapes.len(), 2);
+        StorageEngine::save_chunk(&chunk, &path.to_string_lossy()).unwrap();
+
+        let columns = StorageEngine::iter_columns(
+            path,
+            ColumnSet::of(&[Column::StructuredData]),
+            ReadMode::Buffered,
+            None,
+        )
+        .unwrap()
+        .next()
+        .unwrap()
+        .unwrap();
+        assert!(columns.template_ids.is_none());
+        let stored: Vec<Option<String>> = columns
+            .records()
+            .map(|r| r.structured_data().map(Cow::into_owned))
+            .collect();
+        assert_eq!(stored, sds);
+
+        let m: SdMatch = "sd.exampleSDID@32473.eventID=1011".parse().unwrap();
+        let matching: Vec<bool> = columns.records().map(|r| r.sd_matches(&m)).collect();
+        assert_eq!(matching, vec![true, false, true, false, false]);
+        let m: SdMatch = r"sd.exampleSDID@32473.eventID=10\11".parse().unwrap();
+        assert!(columns.record(3).sd_matches(&m));
+
+        fs::remove_file(path).unwrap();
+        fs::remove_file(ChunkIndex::sidecar_path(path)).unwrap();
+    }
+}
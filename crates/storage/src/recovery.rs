@@ -0,0 +1,247 @@
+use crate::StorageEngine;
+use crate::compression::BlockDictionaries;
+use crate::container::{self, DamagedRange, MAGIC};
+use crate::dictionary::DictionaryCache;
+use crate::encryption::Keyring;
+use crate::index::ChunkIndex;
+use crate::logshrink::LogChunk;
+use std::fs;
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+/// Everything still readable in a damaged container.
+#[derive(Default)]
+pub struct Recovered {
+    pub chunks: Vec<LogChunk>,
+    /// Skipped byte ranges: damaged frames, and intact frames whose chunk
+    /// could not be decoded.
+    pub damaged: Vec<DamagedRange>,
+}
+
+/// Outcome of [`fsck`].
+#[derive(Debug, Default)]
+pub struct FsckReport {
+    pub chunks: usize,
+    pub records: usize,
+    pub damaged: Vec<DamagedRange>,
+    /// The sidecar index is missing or does not match the container.
+    pub index_stale: bool,
+    /// The container was rewritten without the damaged ranges.
+    pub repaired: bool,
+}
+
+impl FsckReport {
+    pub fn is_clean(&self) -> bool {
+        self.damaged.is_empty() && !self.index_stale
+    }
+}
+
+struct Salvaged {
+    offset: u64,
+    payload: Vec<u8>,
+    chunk: LogChunk,
+}
+
+/// Reads the container at `path` in recovery mode: corrupt or truncated
+/// chunks are skipped and reported instead of failing the whole load.
+pub fn recover(path: impl AsRef<Path>, keys: Option<&Keyring>) -> anyhow::Result<Recovered> {
+    let (salvaged, damaged) = salvage(path.as_ref(), keys)?;
+    Ok(Recovered {
+        chunks: salvaged.into_iter().map(|s| s.chunk).collect(),
+        damaged,
+    })
+}
+
+/// Verifies every chunk of the container at `path` and its sidecar index.
+/// With `repair`, a damaged container is rewritten to hold only its intact
+/// chunks and the index is rebuilt. Sealed frames are opened, and moved
+/// ones resealed, with `keys`.
+pub fn fsck(
+    path: impl AsRef<Path>,
+    repair: bool,
+    keys: Option<&Keyring>,
+) -> anyhow::Result<FsckReport> {
+    let path = path.as_ref();
+    let (salvaged, damaged) = salvage(path, keys)?;
+    let index_stale = match ChunkIndex::load(path, keys) {
+        Ok(index) => {
+            !index.covers(fs::metadata(path)?.len())
+                || index
+                    .entries
+                    .iter()
+                    .map(|m| m.offset)
+                    .ne(salvaged.iter().map(|s| s.offset))
+        }
+        Err(_) => true,
+    };
+
+    let mut report = FsckReport {
+        chunks: salvaged.len(),
+        records: salvaged.iter().map(|s| s.chunk.records.len()).sum(),
+        damaged,
+        index_stale,
+        repaired: false,
+    };
+    if !repair || report.is_clean() {
+        return Ok(report);
+    }
+
+    if !report.damaged.is_empty() {
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+        let mut buf = Vec::new();
+        // Salvaged payloads keep their layout, so keep the version too.
+        let version = container::read_header(&mut fs::File::open(path)?, MAGIC)?;
+        container::write_header_version(&mut buf, MAGIC, version)?;
+        for s in &salvaged {
+            let offset = buf.len() as u64;
+            buf.extend(container::encode_frame(&s.payload, MAGIC, offset, keys)?);
+        }
+        fs::write(&tmp, buf)?;
+        fs::rename(&tmp, path)?;
+    }
+    ChunkIndex::rebuild(path, keys)?;
+    report.repaired = true;
+    Ok(report)
+}
+
+fn salvage(
+    path: &Path,
+    keys: Option<&Keyring>,
+) -> anyhow::Result<(Vec<Salvaged>, Vec<DamagedRange>)> {
+    let scan = container::scan_frames(path, MAGIC, keys)?;
+    let mut damaged = scan.damaged;
+    let blocks = BlockDictionaries::load(path, keys).unwrap_or_else(|e| {
+        warn!(
+            "Ignoring unreadable zstd dictionaries of {}: {}",
+            path.display(),
+            e
+        );
+        BlockDictionaries::default()
+    });
+    let mut dictionaries = DictionaryCache::new(keys);
+
+    let mut salvaged = Vec::new();
+    for frame in scan.frames {
+        let chunk = StorageEngine::decode_chunk(&frame.payload, scan.version, &blocks).and_then(
+            |mut chunk| {
+                dictionaries.resolve(path, &mut chunk)?;
+                Ok(chunk)
+            },
+        );
+        match chunk {
+            Ok(chunk) => salvaged.push(Salvaged {
+                offset: frame.offset,
+                payload: frame.payload,
+                chunk,
+            }),
+            Err(e) => damaged.push(DamagedRange {
+                offset: frame.offset,
+                len: frame.len,
+                reason: format!("undecodable chunk: {}", e),
+            }),
+        }
+    }
+    damaged.sort_by_key(|d| d.offset);
+    Ok((salvaged, damaged))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logshrink::LogChunk;
+    use chrono::Utc;
+    use sankshepa_protocol::SyslogMessage;
+
+    fn chunk_of(text: &str, n: usize) -> LogChunk {
+        let mut chunk = LogChunk::new();
+        for i in 0..n {
+            chunk.add_message(SyslogMessage {
+                priority: 34,
+                facility: 4,
+                severity: 2,
+                timestamp: Some(Utc::now()),
+                hostname: Some("host".to_string()),
+                app_name: None,
+                procid: None,
+                msgid: None,
+                structured_data: None,
+                message: format!("{} {}", text, i),
+                is_rfc5424: true,
+                node_id: None,
+                raw: None,
+            });
+        }
+        chunk.finish_and_process();
+        chunk
+    }
+
+    fn cleanup(path: &str) {
+        let _ = fs::remove_file(path);
+        let _ = fs::remove_file(ChunkIndex::sidecar_path(path));
+    }
+
+    #[test]
+    fn test_recover_skips_flipped_bit_and_torn_tail() {
+        let path = "test_recover.lshrink";
+        cleanup(path);
+
+        StorageEngine::save_chunk(&chunk_of("first", 2), path).unwrap();
+        let second = StorageEngine::save_chunk(&chunk_of("second", 3), path).unwrap();
+        StorageEngine::save_chunk(&chunk_of("third", 4), path).unwrap();
+        StorageEngine::save_chunk(&chunk_of("torn", 5), path).unwrap();
+
+        let mut data = fs::read(path).unwrap();
+        let flip = (second.offset + container::FRAME_OVERHEAD + 10) as usize;
+        data[flip] ^= 0x01;
+        data.truncate(data.len() - 5);
+        fs::write(path, &data).unwrap();
+
+        // The normal reader stops at the first bad chunk.
+        let mut iter = StorageEngine::iter_chunks(path, None).unwrap();
+        assert!(iter.next().unwrap().is_ok());
+        assert!(iter.next().unwrap().is_err());
+
+        let recovered = recover(path, None).unwrap();
+        let counts: Vec<usize> = recovered.chunks.iter().map(|c| c.records.len()).collect();
+        assert_eq!(counts, vec![2, 4]);
+        assert_eq!(recovered.damaged.len(), 2);
+        assert_eq!(recovered.damaged[0].offset, second.offset);
+
+        cleanup(path);
+    }
+
+    #[test]
+    fn test_fsck_repairs_container() {
+        let path = "test_fsck.lshrink";
+        cleanup(path);
+
+        StorageEngine::save_chunk(&chunk_of("kept", 2), path).unwrap();
+        StorageEngine::save_chunk(&chunk_of("lost", 3), path).unwrap();
+        assert!(fsck(path, false, None).unwrap().is_clean());
+
+        let len = fs::metadata(path).unwrap().len();
+        fs::OpenOptions::new()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_len(len - 1)
+            .unwrap();
+
+        let report = fsck(path, false, None).unwrap();
+        assert_eq!(report.chunks, 1);
+        assert_eq!(report.damaged.len(), 1);
+        assert!(report.index_stale);
+        assert!(!report.repaired);
+
+        let report = fsck(path, true, None).unwrap();
+        assert!(report.repaired);
+        let after = fsck(path, false, None).unwrap();
+        assert!(after.is_clean());
+        assert_eq!(after.records, 2);
+        assert_eq!(StorageEngine::iter_chunks(path, None).unwrap().count(), 1);
+
+        cleanup(path);
+    }
+}
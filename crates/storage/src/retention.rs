@@ -0,0 +1,424 @@
+use crate::StorageEngine;
+use crate::compression::{BlockDictionaries, BlockEncoder};
+use crate::encryption::Keyring;
+use crate::index::{ChunkIndex, ChunkMeta};
+use crate::segment::{Manifest, SegmentInfo};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use tracing::{info, warn};
+
+const SEVERITY_NAMES: [&str; 8] = [
+    "emerg", "alert", "crit", "err", "warning", "notice", "info", "debug",
+];
+
+/// Parses a syslog severity given by name (`err`, `warning`, ...) or number.
+pub fn parse_severity(s: &str) -> Result<u8, String> {
+    if let Some(pos) = SEVERITY_NAMES.iter().position(|&n| n == s) {
+        return Ok(pos as u8);
+    }
+    match s.parse::<u8>() {
+        Ok(n) if n <= 7 => Ok(n),
+        _ => Err(format!("unknown severity '{}'", s)),
+    }
+}
+
+/// Parses durations such as `90s`, `15m`, `12h`, `30d`, `2w` or `1y`.
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let split = s
+        .find(|c: char| !c.is_ascii_digit())
+        .ok_or_else(|| format!("missing unit in duration '{}'", s))?;
+    let (num, unit) = s.split_at(split);
+    let n: u64 = num
+        .parse()
+        .map_err(|_| format!("invalid duration '{}'", s))?;
+    let secs = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 86_400,
+        "w" => 7 * 86_400,
+        "y" => 365 * 86_400,
+        _ => return Err(format!("unknown unit '{}' in duration '{}'", unit, s)),
+    };
+    Ok(Duration::from_secs(n * secs))
+}
+
+/// Once records are older than `after`, only those with a severity of
+/// `min_severity` or more urgent (`priority & 7 <= min_severity`) are kept.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SeverityRule {
+    pub after: Duration,
+    pub min_severity: u8,
+}
+
+impl FromStr for SeverityRule {
+    type Err = String;
+
+    /// Parses `<age>:<severity>`, e.g. `30d:err`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (age, severity) = s
+            .split_once(':')
+            .ok_or_else(|| format!("expected <age>:<severity>, got '{}'", s))?;
+        Ok(Self {
+            after: parse_duration(age)?,
+            min_severity: parse_severity(severity)?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RetentionPolicy {
+    /// Segments whose newest record is older than this are deleted.
+    pub max_age: Option<Duration>,
+    /// Oldest segments are deleted until the total size fits this budget.
+    pub max_total_bytes: Option<u64>,
+    pub severity_rules: Vec<SeverityRule>,
+}
+
+impl RetentionPolicy {
+    pub fn is_empty(&self) -> bool {
+        self.max_age.is_none() && self.max_total_bytes.is_none() && self.severity_rules.is_empty()
+    }
+
+    /// The strictest severity floor applying to records last written at
+    /// `max_timestamp`, if any rule has kicked in.
+    fn severity_floor(&self, max_timestamp: i64, now: i64) -> Option<u8> {
+        self.severity_rules
+            .iter()
+            .filter(|r| max_timestamp < now - r.after.as_millis() as i64)
+            .map(|r| r.min_severity)
+            .min()
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RetentionReport {
+    pub deleted: Vec<String>,
+    pub rewritten: Vec<String>,
+    pub freed_bytes: u64,
+}
+
+impl RetentionReport {
+    pub fn is_empty(&self) -> bool {
+        self.deleted.is_empty() && self.rewritten.is_empty()
+    }
+}
+
+/// Applies `policy` to the segments listed in `manifest`, never touching the
+/// `active` segment file. `now` is Unix milliseconds. Rewritten segments are
+/// read and sealed with `keys`.
+pub fn apply(
+    dir: &Path,
+    manifest: &mut Manifest,
+    active: Option<&str>,
+    policy: &RetentionPolicy,
+    now: i64,
+    keys: Option<&Keyring>,
+) -> anyhow::Result<RetentionReport> {
+    let mut report = RetentionReport::default();
+    let is_active = |s: &SegmentInfo| active == Some(s.file.as_str());
+
+    // 1. Age-based deletion
+    if let Some(max_age) = policy.max_age {
+        let cutoff = now - max_age.as_millis() as i64;
+        let (expired, kept): (Vec<_>, Vec<_>) = manifest
+            .segments
+            .drain(..)
+            .partition(|s| !is_active(s) && s.max_timestamp < cutoff);
+        manifest.segments = kept;
+        for segment in expired {
+            delete_segment(dir, &segment, &mut report)?;
+        }
+    }
+
+    // 2. Per-severity rewrite
+    let mut emptied = Vec::new();
+    for (pos, segment) in manifest.segments.iter_mut().enumerate() {
+        if is_active(segment) || segment.record_count == 0 {
+            continue;
+        }
+        let Some(floor) = policy.severity_floor(segment.max_timestamp, now) else {
+            continue;
+        };
+        if segment.severity_floor.is_some_and(|f| f <= floor) {
+            continue;
+        }
+        let path = dir.join(&segment.file);
+        let before = segment_size(dir, segment);
+        if rewrite_segment(&path, segment, floor, keys)? {
+            report.freed_bytes += before.saturating_sub(segment_size(dir, segment));
+            report.rewritten.push(segment.file.clone());
+        } else {
+            emptied.push(pos);
+        }
+    }
+    for pos in emptied.into_iter().rev() {
+        let segment = manifest.segments.remove(pos);
+        delete_segment(dir, &segment, &mut report)?;
+    }
+
+    // 3. Disk budget, oldest segments first
+    if let Some(budget) = policy.max_total_bytes {
+        let mut total: u64 = manifest.segments.iter().map(|s| segment_size(dir, s)).sum();
+        manifest
+            .segments
+            .sort_by_key(|s| (s.min_timestamp, s.created_at));
+        while total > budget {
+            let Some(pos) = manifest.segments.iter().position(|s| !is_active(s)) else {
+                warn!("Disk budget exceeded but only the active segment remains");
+                break;
+            };
+            let segment = manifest.segments.remove(pos);
+            total = total.saturating_sub(segment_size(dir, &segment));
+            delete_segment(dir, &segment, &mut report)?;
+        }
+    }
+
+    if !report.is_empty() {
+        info!(
+            "Retention removed {} and rewrote {} segment(s), freeing {} bytes",
+            report.deleted.len(),
+            report.rewritten.len(),
+            report.freed_bytes
+        );
+    }
+    Ok(report)
+}
+
+fn file_size(path: &Path) -> u64 {
+    fs::metadata(path).map(|m| m.len()).unwrap_or(0)
+}
+
+/// Size of the container at `path` together with its sidecars.
+pub(crate) fn container_size(path: &Path) -> u64 {
+    file_size(path)
+        + file_size(&ChunkIndex::sidecar_path(path))
+        + file_size(&BlockDictionaries::sidecar_path(path))
+}
+
+fn segment_size(dir: &Path, segment: &SegmentInfo) -> u64 {
+    container_size(&dir.join(&segment.file))
+}
+
+fn delete_segment(
+    dir: &Path,
+    segment: &SegmentInfo,
+    report: &mut RetentionReport,
+) -> anyhow::Result<()> {
+    let path = dir.join(&segment.file);
+    report.freed_bytes += segment_size(dir, segment);
+    for p in [
+        ChunkIndex::sidecar_path(&path),
+        BlockDictionaries::sidecar_path(&path),
+        path,
+    ] {
+        match fs::remove_file(&p) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    report.deleted.push(segment.file.clone());
+    Ok(())
+}
+
+/// Rewrites the segment at `path` keeping only records with
+/// `priority & 7 <= floor`, then atomically replaces the original. Returns
+/// false, leaving the original untouched, if no record would be kept.
+fn rewrite_segment(
+    path: &Path,
+    segment: &mut SegmentInfo,
+    floor: u8,
+    keys: Option<&Keyring>,
+) -> anyhow::Result<bool> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    let _ = fs::remove_file(&tmp);
+    let _ = fs::remove_file(ChunkIndex::sidecar_path(&tmp));
+
+    let mut encoder = BlockEncoder::default().with_keys(keys);
+    let mut metas: Vec<ChunkMeta> = Vec::new();
+    for chunk in StorageEngine::iter_chunks(path, keys)? {
+        let mut chunk = chunk?;
+        chunk.records.retain(|r| r.priority & 0x07 <= floor);
+        if chunk.records.is_empty() {
+            continue;
+        }
+        let tmp = tmp.to_string_lossy();
+        metas.push(StorageEngine::save_chunk_with(&chunk, &tmp, &mut encoder)?);
+    }
+
+    if metas.is_empty() {
+        return Ok(false);
+    }
+
+    fs::rename(
+        ChunkIndex::sidecar_path(&tmp),
+        ChunkIndex::sidecar_path(path),
+    )?;
+    fs::rename(&tmp, path)?;
+    // Rewritten chunks are compressed without a trained dictionary.
+    match fs::remove_file(BlockDictionaries::sidecar_path(path)) {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+
+    segment.record_count = metas.iter().map(|m| m.record_count as u64).sum();
+    segment.min_timestamp = metas.iter().map(|m| m.min_timestamp).min().unwrap_or(0);
+    segment.max_timestamp = metas.iter().map(|m| m.max_timestamp).max().unwrap_or(0);
+    segment.severity_floor = Some(floor);
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logshrink::LogChunk;
+    use crate::segment::{Rotation, SegmentWriter};
+    use chrono::{TimeZone, Utc};
+    use sankshepa_protocol::SyslogMessage;
+
+    const DAY_MS: i64 = 86_400_000;
+
+    fn chunk_with(secs: i64, severities: &[u8]) -> LogChunk {
+        let mut chunk = LogChunk::new();
+        for &severity in severities {
+            chunk.add_message(SyslogMessage {
+                priority: (1 << 3) | severity,
+                facility: 1,
+                severity,
+                timestamp: Utc.timestamp_opt(secs, 0).single(),
+                hostname: Some("host".to_string()),
+                app_name: None,
+                procid: None,
+                msgid: None,
+                structured_data: None,
+                message: format!("severity {} event", severity),
+                is_rfc5424: true,
+                node_id: None,
+                raw: None,
+            });
+        }
+        chunk.finish_and_process();
+        chunk
+    }
+
+    /// Writes one daily segment per entry of `days` (days since the epoch).
+    fn write_segments(name: &str, days: &[(i64, &[u8])]) -> PathBuf {
+        let dir = std::env::temp_dir().join(name);
+        let _ = fs::remove_dir_all(&dir);
+        let mut writer =
+            SegmentWriter::new(dir.join("logs.lshrink"), Rotation::Daily, None, None).unwrap();
+        for &(day, severities) in days {
+            let at = Utc.timestamp_opt(day * 86_400, 0).unwrap();
+            writer
+                .save_chunk_at(&chunk_with(at.timestamp(), severities), at)
+                .unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn test_parse_rules() {
+        assert_eq!(
+            parse_duration("30d").unwrap(),
+            Duration::from_secs(30 * 86_400)
+        );
+        assert!(parse_duration("30").is_err());
+        assert_eq!(parse_severity("err").unwrap(), 3);
+        assert_eq!(parse_severity("6").unwrap(), 6);
+        let rule: SeverityRule = "30d:err".parse().unwrap();
+        assert_eq!(rule.min_severity, 3);
+        assert!("30d".parse::<SeverityRule>().is_err());
+    }
+
+    #[test]
+    fn test_max_age_deletes_old_segments() {
+        let dir = write_segments("sankshepa_test_retention_age", &[(1, &[6]), (10, &[6])]);
+        let mut manifest = Manifest::load(&dir).unwrap();
+        let policy = RetentionPolicy {
+            max_age: Some(Duration::from_secs(5 * 86_400)),
+            ..Default::default()
+        };
+
+        let report = apply(&dir, &mut manifest, None, &policy, 12 * DAY_MS, None).unwrap();
+
+        assert_eq!(report.deleted, vec!["logs-1970-01-02.lshrink".to_string()]);
+        assert!(!dir.join("logs-1970-01-02.lshrink").exists());
+        assert!(!dir.join("logs-1970-01-02.lshrink.idx").exists());
+        assert_eq!(manifest.segments.len(), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_severity_rule_rewrites_segment() {
+        let dir = write_segments(
+            "sankshepa_test_retention_severity",
+            &[(1, &[2, 3, 6, 7]), (2, &[6]), (30, &[6])],
+        );
+        let mut manifest = Manifest::load(&dir).unwrap();
+        let policy = RetentionPolicy {
+            severity_rules: vec!["7d:err".parse().unwrap()],
+            ..Default::default()
+        };
+
+        let report = apply(&dir, &mut manifest, None, &policy, 31 * DAY_MS, None).unwrap();
+        assert_eq!(
+            report.rewritten,
+            vec!["logs-1970-01-02.lshrink".to_string()]
+        );
+        // The info-only segment has nothing left and is removed entirely.
+        assert_eq!(report.deleted, vec!["logs-1970-01-03.lshrink".to_string()]);
+        assert_eq!(manifest.segments.len(), 2);
+        assert_eq!(manifest.segments[0].record_count, 2);
+        assert_eq!(manifest.segments[0].severity_floor, Some(3));
+
+        let kept: Vec<u8> = StorageEngine::iter_chunks(dir.join("logs-1970-01-02.lshrink"), None)
+            .unwrap()
+            .flat_map(|c| c.unwrap().records)
+            .map(|r| r.priority & 0x07)
+            .collect();
+        assert_eq!(kept.len(), 2);
+        assert!(kept.iter().all(|&s| s <= 3));
+
+        // A second pass has nothing left to do.
+        let again = apply(&dir, &mut manifest, None, &policy, 31 * DAY_MS, None).unwrap();
+        assert!(again.is_empty());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_disk_budget_keeps_active_segment() {
+        let dir = write_segments(
+            "sankshepa_test_retention_budget",
+            &[(1, &[6]), (2, &[6]), (3, &[6])],
+        );
+        let mut manifest = Manifest::load(&dir).unwrap();
+        let policy = RetentionPolicy {
+            max_total_bytes: Some(1),
+            ..Default::default()
+        };
+
+        let report = apply(
+            &dir,
+            &mut manifest,
+            Some("logs-1970-01-04.lshrink"),
+            &policy,
+            4 * DAY_MS,
+            None,
+        )
+        .unwrap();
+
+        assert_eq!(report.deleted.len(), 2);
+        assert_eq!(manifest.segments.len(), 1);
+        assert_eq!(manifest.segments[0].file, "logs-1970-01-04.lshrink");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
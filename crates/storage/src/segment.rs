@@ -0,0 +1,661 @@
+use crate::StorageEngine;
+use crate::compaction::{self, CompactionConfig, CompactionReport};
+use crate::compression::{BlockEncoder, CompressionConfig};
+use crate::container;
+use crate::dictionary::SharedDictionary;
+use crate::encryption::Keyring;
+use crate::index::{ChunkIndex, ChunkMeta};
+use crate::logshrink::LogChunk;
+use crate::merging::{self, MergeConfig, MergeReport};
+use crate::retention::{self, RetentionPolicy, RetentionReport};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use tracing::warn;
+
+/// File name of the segment manifest kept next to the segments.
+pub const MANIFEST_FILE: &str = "manifest.json";
+
+/// Wall-clock interval at which `serve` rolls over to a new segment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rotation {
+    Never,
+    Hourly,
+    Daily,
+}
+
+impl Rotation {
+    /// Name component identifying the period `now` falls in.
+    fn period_key(self, now: DateTime<Utc>) -> String {
+        match self {
+            Rotation::Never => now.format("%Y-%m-%dT%H%M%S").to_string(),
+            Rotation::Hourly => now.format("%Y-%m-%dT%H").to_string(),
+            Rotation::Daily => now.format("%Y-%m-%d").to_string(),
+        }
+    }
+}
+
+impl FromStr for Rotation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "never" => Ok(Rotation::Never),
+            "hourly" => Ok(Rotation::Hourly),
+            "daily" => Ok(Rotation::Daily),
+            other => Err(format!(
+                "unknown rotation '{}' (expected never, hourly or daily)",
+                other
+            )),
+        }
+    }
+}
+
+/// One segment file listed in the manifest.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SegmentInfo {
+    /// File name relative to the manifest directory.
+    pub file: String,
+    pub created_at: i64,
+    pub min_timestamp: i64,
+    pub max_timestamp: i64,
+    pub record_count: u64,
+    /// Set once retention has dropped every record less severe than this.
+    #[serde(default)]
+    pub severity_floor: Option<u8>,
+    /// Set once the compactor has merged the segment's chunks.
+    #[serde(default)]
+    pub compacted: bool,
+}
+
+impl SegmentInfo {
+    /// Whether the segment may hold records in `[since, until]` (Unix millis).
+    pub fn overlaps(&self, since: Option<i64>, until: Option<i64>) -> bool {
+        self.record_count > 0
+            && since.is_none_or(|ts| self.max_timestamp >= ts)
+            && until.is_none_or(|ts| self.min_timestamp <= ts)
+    }
+}
+
+/// List of segments in a directory together with their time ranges.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Manifest {
+    pub segments: Vec<SegmentInfo>,
+}
+
+impl Manifest {
+    pub fn path(dir: impl AsRef<Path>) -> PathBuf {
+        dir.as_ref().join(MANIFEST_FILE)
+    }
+
+    /// Loads the manifest in `dir`, or an empty one if none exists yet.
+    pub fn load(dir: impl AsRef<Path>) -> anyhow::Result<Self> {
+        match fs::read(Self::path(&dir)) {
+            Ok(bytes) => Ok(serde_json::from_slice(&bytes)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Atomically writes the manifest into `dir`.
+    pub fn save(&self, dir: impl AsRef<Path>) -> anyhow::Result<()> {
+        let path = Self::path(&dir);
+        let tmp = path.with_extension("json.tmp");
+        let mut file = fs::File::create(&tmp)?;
+        file.write_all(&serde_json::to_vec_pretty(self)?)?;
+        file.sync_all()?;
+        fs::rename(tmp, &path)?;
+        container::sync_parent(&path)?;
+        Ok(())
+    }
+
+    fn record_chunk(&mut self, file: &str, meta: &ChunkMeta) {
+        let Some(info) = self.segments.iter_mut().find(|s| s.file == file) else {
+            return;
+        };
+        if meta.record_count == 0 {
+            return;
+        }
+        if info.record_count == 0 {
+            info.min_timestamp = meta.min_timestamp;
+            info.max_timestamp = meta.max_timestamp;
+        } else {
+            info.min_timestamp = info.min_timestamp.min(meta.min_timestamp);
+            info.max_timestamp = info.max_timestamp.max(meta.max_timestamp);
+        }
+        info.record_count += meta.record_count as u64;
+    }
+
+    /// Resolves a query input to the container files to read. A directory is
+    /// expanded via its manifest to the segments overlapping `[since, until]`;
+    /// a plain file is returned as is.
+    pub fn resolve_inputs(
+        input: impl AsRef<Path>,
+        since: Option<i64>,
+        until: Option<i64>,
+    ) -> anyhow::Result<Vec<PathBuf>> {
+        let input = input.as_ref();
+        if !input.is_dir() {
+            return Ok(vec![input.to_path_buf()]);
+        }
+        if !Self::path(input).exists() {
+            return Err(anyhow::anyhow!(
+                "No {} in directory {}",
+                MANIFEST_FILE,
+                input.display()
+            ));
+        }
+        let mut segments: Vec<SegmentInfo> = Self::load(input)?
+            .segments
+            .into_iter()
+            .filter(|s| s.overlaps(since, until))
+            .collect();
+        segments.sort_by_key(|s| (s.min_timestamp, s.created_at));
+        Ok(segments.into_iter().map(|s| input.join(s.file)).collect())
+    }
+}
+
+/// Compaction or template merging over the segments a [`SegmentWriter`] had
+/// closed when the pass was taken. Newly mined and merged templates go to
+/// the shared dictionary, which the writer keeps using meanwhile.
+pub struct SegmentPass {
+    dir: PathBuf,
+    manifest: Manifest,
+    active: Option<String>,
+    dictionary: SharedDictionary,
+    keys: Option<Keyring>,
+}
+
+impl SegmentPass {
+    /// Compacts the closed segments not compacted yet. Hand the reports to
+    /// [`SegmentWriter::finish_compaction`].
+    pub fn compact(mut self, config: &CompactionConfig) -> anyhow::Result<Vec<CompactionReport>> {
+        compaction::compact_segments(
+            &self.dir,
+            &mut self.manifest,
+            self.active.as_deref(),
+            Some(&self.dictionary),
+            config,
+            self.keys.as_ref(),
+        )
+    }
+
+    /// Merges near-duplicate templates across the closed segments.
+    pub fn merge_templates(self, config: &MergeConfig) -> anyhow::Result<MergeReport> {
+        let paths: Vec<PathBuf> = self
+            .manifest
+            .segments
+            .iter()
+            .filter(|s| self.active.as_deref() != Some(s.file.as_str()))
+            .map(|s| self.dir.join(&s.file))
+            .collect();
+        merging::merge_templates(&paths, Some(&self.dictionary), config, self.keys.as_ref())
+    }
+}
+
+/// Writes chunks into a series of segment files derived from a base output
+/// path, rolling over by wall-clock period and/or size.
+pub struct SegmentWriter {
+    dir: PathBuf,
+    stem: String,
+    extension: String,
+    rotation: Rotation,
+    max_segment_bytes: Option<u64>,
+    manifest: Manifest,
+    current: Option<ActiveSegment>,
+    encoder: BlockEncoder,
+    keys: Option<Keyring>,
+}
+
+struct ActiveSegment {
+    file: String,
+    period: String,
+}
+
+impl SegmentWriter {
+    /// Creates a writer for segments named after `output`, e.g.
+    /// `logs.lshrink` yields `logs-2026-10-16T14.lshrink` with hourly
+    /// rotation. With `Rotation::Never` and no size limit every chunk goes to
+    /// `output` itself and no manifest is kept. Frames are sealed with
+    /// `keys`, if any.
+    pub fn new(
+        output: impl AsRef<Path>,
+        rotation: Rotation,
+        max_segment_bytes: Option<u64>,
+        keys: Option<&Keyring>,
+    ) -> anyhow::Result<Self> {
+        let output = output.as_ref();
+        let dir = match output.parent() {
+            Some(p) if !p.as_os_str().is_empty() => p.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        let stem = output
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .ok_or_else(|| anyhow::anyhow!("Invalid output path {}", output.display()))?
+            .to_string();
+        let extension = output
+            .extension()
+            .and_then(|s| s.to_str())
+            .unwrap_or("lshrink")
+            .to_string();
+        fs::create_dir_all(&dir)?;
+        let manifest = Manifest::load(&dir)?;
+
+        let writer = Self {
+            dir,
+            stem,
+            extension,
+            rotation,
+            max_segment_bytes,
+            manifest,
+            current: None,
+            encoder: BlockEncoder::default().with_keys(keys),
+            keys: keys.cloned(),
+        };
+        // A rotating writer starts a fresh segment, but the one it wrote
+        // last may still have been cut short by a crash; a plain writer keeps
+        // appending to that file, so new chunks must not land behind it.
+        let last = if writer.is_rotating() {
+            writer
+                .manifest
+                .segments
+                .last()
+                .map(|s| writer.dir.join(&s.file))
+        } else {
+            Some(
+                writer
+                    .dir
+                    .join(format!("{}.{}", writer.stem, writer.extension)),
+            )
+        };
+        if let Some(path) = last
+            && path.exists()
+        {
+            repair_tail(&path, keys)?;
+        }
+        Ok(writer)
+    }
+
+    /// Compresses chunks according to `config` instead of the defaults.
+    pub fn with_compression(mut self, config: CompressionConfig) -> Self {
+        self.encoder = BlockEncoder::new(config).with_keys(self.keys.as_ref());
+        self
+    }
+
+    /// Keys the store's frames are sealed with.
+    pub fn keys(&self) -> Option<&Keyring> {
+        self.keys.as_ref()
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Path of the store's template dictionary, e.g. `logs.templates`.
+    pub fn dictionary_path(&self) -> PathBuf {
+        self.dir.join(format!("{}.templates", self.stem))
+    }
+
+    pub fn manifest(&self) -> &Manifest {
+        &self.manifest
+    }
+
+    /// Applies `policy` to every segment except the one being written and
+    /// persists the updated manifest.
+    pub fn apply_retention(&mut self, policy: &RetentionPolicy) -> anyhow::Result<RetentionReport> {
+        if !self.is_rotating() {
+            return Ok(RetentionReport::default());
+        }
+        let active = self.current.as_ref().map(|s| s.file.clone());
+        let report = retention::apply(
+            &self.dir,
+            &mut self.manifest,
+            active.as_deref(),
+            policy,
+            Utc::now().timestamp_millis(),
+            self.keys.as_ref(),
+        )?;
+        if !report.is_empty() {
+            self.manifest.save(&self.dir)?;
+        }
+        Ok(report)
+    }
+
+    /// A compaction or merge pass over the segments closed so far, which
+    /// can run on another thread while this writer carries on. `None`
+    /// without rotation, as then no segment is ever closed.
+    pub fn pass(&self, dictionary: SharedDictionary) -> Option<SegmentPass> {
+        self.is_rotating().then(|| SegmentPass {
+            dir: self.dir.clone(),
+            manifest: self.manifest.clone(),
+            active: self.current.as_ref().map(|s| s.file.clone()),
+            dictionary,
+            keys: self.keys.clone(),
+        })
+    }
+
+    /// Records the segments a [`SegmentPass::compact`] compacted in the
+    /// manifest and persists it.
+    pub fn finish_compaction(&mut self, reports: &[CompactionReport]) -> anyhow::Result<()> {
+        if reports.is_empty() {
+            return Ok(());
+        }
+        for segment in &mut self.manifest.segments {
+            if reports
+                .iter()
+                .any(|r| r.path == self.dir.join(&segment.file))
+            {
+                segment.compacted = true;
+            }
+        }
+        self.manifest.save(&self.dir)
+    }
+
+    /// Path of the segment currently being written, if any.
+    pub fn current_path(&self) -> Option<PathBuf> {
+        self.current.as_ref().map(|s| self.dir.join(&s.file))
+    }
+
+    /// Appends `chunk` to the active segment, rotating first if the period
+    /// changed or the segment outgrew the size limit. Returns the segment path.
+    pub fn save_chunk(&mut self, chunk: &LogChunk) -> anyhow::Result<PathBuf> {
+        self.save_chunk_at(chunk, Utc::now())
+    }
+
+    pub(crate) fn save_chunk_at(
+        &mut self,
+        chunk: &LogChunk,
+        now: DateTime<Utc>,
+    ) -> anyhow::Result<PathBuf> {
+        if !self.is_rotating() {
+            let path = self.dir.join(format!("{}.{}", self.stem, self.extension));
+            StorageEngine::save_chunk_with(chunk, &path.to_string_lossy(), &mut self.encoder)?;
+            return Ok(path);
+        }
+
+        let file = self.segment_for(now);
+        let path = self.dir.join(&file);
+        let meta =
+            StorageEngine::save_chunk_with(chunk, &path.to_string_lossy(), &mut self.encoder)?;
+        self.manifest.record_chunk(&file, &meta);
+        self.manifest.save(&self.dir)?;
+        Ok(path)
+    }
+
+    fn is_rotating(&self) -> bool {
+        self.rotation != Rotation::Never || self.max_segment_bytes.is_some()
+    }
+
+    /// Picks (and registers) the segment file the next chunk belongs in.
+    fn segment_for(&mut self, now: DateTime<Utc>) -> String {
+        let period = self.rotation.period_key(now);
+        if let Some(active) = &self.current {
+            let same_period = self.rotation == Rotation::Never || active.period == period;
+            let size = fs::metadata(self.dir.join(&active.file))
+                .map(|m| m.len())
+                .unwrap_or(0);
+            let full = self.max_segment_bytes.is_some_and(|max| size >= max);
+            if same_period && !full {
+                return active.file.clone();
+            }
+        }
+
+        let file = self.next_free_name(&period);
+        self.register(&file, now);
+        // Each segment gets a dictionary fitted to the most recent data.
+        self.encoder.retrain();
+        self.current = Some(ActiveSegment {
+            file: file.clone(),
+            period,
+        });
+        file
+    }
+
+    /// `<stem>-<period>.<ext>`, or `<stem>-<period>-<n>.<ext>` if that
+    /// segment already exists (a restart or a size-based rollover within the
+    /// same period).
+    fn next_free_name(&self, period: &str) -> String {
+        let base = format!("{}-{}", self.stem, period);
+        let mut file = format!("{}.{}", base, self.extension);
+        let mut seq = 1;
+        while self.dir.join(&file).exists() {
+            file = format!("{}-{}.{}", base, seq, self.extension);
+            seq += 1;
+        }
+        file
+    }
+
+    fn register(&mut self, file: &str, now: DateTime<Utc>) {
+        if !self.manifest.segments.iter().any(|s| s.file == file) {
+            self.manifest.segments.push(SegmentInfo {
+                file: file.to_string(),
+                created_at: now.timestamp_millis(),
+                min_timestamp: 0,
+                max_timestamp: 0,
+                record_count: 0,
+                severity_floor: None,
+                compacted: false,
+            });
+        }
+    }
+}
+
+/// Cuts a torn tail off the container at `path` and brings its index in
+/// line with what is left.
+fn repair_tail(path: &Path, keys: Option<&Keyring>) -> anyhow::Result<()> {
+    let cut = container::truncate_torn_tail(path, container::MAGIC)?;
+    if cut > 0 {
+        warn!("Cut {} torn bytes off the end of {}", cut, path.display());
+    }
+    if fs::metadata(path)?.len() > 0 {
+        ChunkIndex::load_or_rebuild(path, keys)?;
+    } else {
+        let _ = fs::remove_file(ChunkIndex::sidecar_path(path));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dictionary::TemplateDictionary;
+    use chrono::TimeZone;
+    use sankshepa_protocol::SyslogMessage;
+    use std::sync::{Arc, Mutex};
+
+    fn message_at(secs: i64, text: &str) -> SyslogMessage {
+        SyslogMessage {
+            priority: 34,
+            facility: 4,
+            severity: 2,
+            timestamp: Utc.timestamp_opt(secs, 0).single(),
+            hostname: Some("host".to_string()),
+            app_name: None,
+            procid: None,
+            msgid: None,
+            structured_data: None,
+            message: text.to_string(),
+            is_rfc5424: true,
+            node_id: None,
+            raw: None,
+        }
+    }
+
+    fn chunk_at(secs: i64) -> LogChunk {
+        let mut chunk = LogChunk::new();
+        chunk.add_message(message_at(secs, "tick"));
+        chunk.finish_and_process();
+        chunk
+    }
+
+    fn fresh_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(name);
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_hourly_rotation_and_manifest() {
+        let dir = fresh_dir("sankshepa_test_hourly");
+        let mut writer =
+            SegmentWriter::new(dir.join("logs.lshrink"), Rotation::Hourly, None, None).unwrap();
+
+        let t0 = Utc.with_ymd_and_hms(2026, 10, 16, 14, 5, 0).unwrap();
+        let t1 = Utc.with_ymd_and_hms(2026, 10, 16, 14, 55, 0).unwrap();
+        let t2 = Utc.with_ymd_and_hms(2026, 10, 16, 15, 0, 1).unwrap();
+
+        let p0 = writer.save_chunk_at(&chunk_at(t0.timestamp()), t0).unwrap();
+        let p1 = writer.save_chunk_at(&chunk_at(t1.timestamp()), t1).unwrap();
+        let p2 = writer.save_chunk_at(&chunk_at(t2.timestamp()), t2).unwrap();
+
+        assert_eq!(p0, dir.join("logs-2026-10-16T14.lshrink"));
+        assert_eq!(p0, p1);
+        assert_eq!(p2, dir.join("logs-2026-10-16T15.lshrink"));
+
+        let manifest = Manifest::load(&dir).unwrap();
+        assert_eq!(manifest.segments.len(), 2);
+        assert_eq!(manifest.segments[0].record_count, 2);
+        assert_eq!(manifest.segments[0].min_timestamp, t0.timestamp_millis());
+        assert_eq!(manifest.segments[0].max_timestamp, t1.timestamp_millis());
+
+        let all = Manifest::resolve_inputs(&dir, None, None).unwrap();
+        assert_eq!(all, vec![p0.clone(), p2.clone()]);
+        let late = Manifest::resolve_inputs(&dir, Some(t2.timestamp_millis()), None).unwrap();
+        assert_eq!(late, vec![p2]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_size_rotation() {
+        let dir = fresh_dir("sankshepa_test_size_rotation");
+        let mut writer =
+            SegmentWriter::new(dir.join("logs.lshrink"), Rotation::Daily, Some(1), None).unwrap();
+
+        let t0 = Utc.with_ymd_and_hms(2026, 10, 16, 9, 0, 0).unwrap();
+        let p0 = writer.save_chunk_at(&chunk_at(t0.timestamp()), t0).unwrap();
+        let p1 = writer.save_chunk_at(&chunk_at(t0.timestamp()), t0).unwrap();
+
+        assert_eq!(p0, dir.join("logs-2026-10-16.lshrink"));
+        assert_eq!(p1, dir.join("logs-2026-10-16-1.lshrink"));
+        assert_eq!(writer.manifest().segments.len(), 2);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_compact_skips_active_segment() {
+        let dir = fresh_dir("sankshepa_test_compact_segments");
+        let mut writer =
+            SegmentWriter::new(dir.join("logs.lshrink"), Rotation::Hourly, None, None).unwrap();
+        let mut dictionary = TemplateDictionary::open(writer.dictionary_path(), None).unwrap();
+
+        let t0 = Utc.with_ymd_and_hms(2026, 10, 16, 14, 5, 0).unwrap();
+        let t1 = Utc.with_ymd_and_hms(2026, 10, 16, 15, 5, 0).unwrap();
+        for (user, now) in [("alice", t0), ("bob", t0), ("carol", t1)] {
+            let mut chunk = dictionary.new_chunk();
+            let text = format!("Session opened for {}", user);
+            chunk.add_message(message_at(now.timestamp(), &text));
+            chunk.finish_and_process();
+            dictionary.absorb(&chunk).unwrap();
+            dictionary.persist().unwrap();
+            writer.save_chunk_at(&chunk, now).unwrap();
+        }
+        assert_eq!(dictionary.len(), 3);
+
+        let dictionary: SharedDictionary = Arc::new(Mutex::new(dictionary));
+        let compact = |writer: &mut SegmentWriter| {
+            let pass = writer.pass(dictionary.clone()).unwrap();
+            let reports = pass.compact(&CompactionConfig::default()).unwrap();
+            writer.finish_compaction(&reports).unwrap();
+            reports
+        };
+        let reports = compact(&mut writer);
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].path, dir.join("logs-2026-10-16T14.lshrink"));
+        assert_eq!(reports[0].chunks_before, 2);
+        assert_eq!(reports[0].chunks_after, 1);
+        assert_eq!(reports[0].templates_after, 1);
+
+        let manifest = Manifest::load(&dir).unwrap();
+        assert!(manifest.segments[0].compacted);
+        assert!(!manifest.segments[1].compacted);
+        assert!(compact(&mut writer).is_empty());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_no_rotation_writes_output() {
+        let dir = fresh_dir("sankshepa_test_no_rotation");
+        let mut writer =
+            SegmentWriter::new(dir.join("logs.lshrink"), Rotation::Never, None, None).unwrap();
+
+        let path = writer.save_chunk(&chunk_at(0)).unwrap();
+        assert_eq!(path, dir.join("logs.lshrink"));
+        assert!(!Manifest::path(&dir).exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_reopening_cuts_torn_tail() {
+        let dir = fresh_dir("sankshepa_test_torn_reopen");
+        let output = dir.join("logs.lshrink");
+        let mut writer = SegmentWriter::new(&output, Rotation::Never, None, None).unwrap();
+        writer.save_chunk(&chunk_at(0)).unwrap();
+        writer.save_chunk(&chunk_at(1)).unwrap();
+        let len = fs::metadata(&output).unwrap().len();
+        fs::OpenOptions::new()
+            .write(true)
+            .open(&output)
+            .unwrap()
+            .set_len(len - 3)
+            .unwrap();
+
+        let mut writer = SegmentWriter::new(&output, Rotation::Never, None, None).unwrap();
+        writer.save_chunk(&chunk_at(2)).unwrap();
+        let secs: Vec<i64> = StorageEngine::iter_chunks(&output, None)
+            .unwrap()
+            .map(|c| c.unwrap().records[0].timestamp / 1000)
+            .collect();
+        assert_eq!(secs, vec![0, 2]);
+        assert_eq!(ChunkIndex::rebuild(&output, None).unwrap().entries.len(), 2);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_rotating_reopen_cuts_torn_tail_of_last_segment() {
+        let dir = fresh_dir("sankshepa_test_torn_rotating");
+        let output = dir.join("logs.lshrink");
+        let now = Utc.with_ymd_and_hms(2026, 10, 16, 14, 5, 0).unwrap();
+        let mut writer = SegmentWriter::new(&output, Rotation::Daily, None, None).unwrap();
+        writer.save_chunk_at(&chunk_at(0), now).unwrap();
+        let last = writer.save_chunk_at(&chunk_at(1), now).unwrap();
+        let len = fs::metadata(&last).unwrap().len();
+        fs::OpenOptions::new()
+            .write(true)
+            .open(&last)
+            .unwrap()
+            .set_len(len - 3)
+            .unwrap();
+
+        let mut writer = SegmentWriter::new(&output, Rotation::Daily, None, None).unwrap();
+        let secs: Vec<i64> = StorageEngine::iter_chunks(&last, None)
+            .unwrap()
+            .map(|c| c.unwrap().records[0].timestamp / 1000)
+            .collect();
+        assert_eq!(secs, vec![0]);
+        assert_eq!(ChunkIndex::load(&last, None).unwrap().entries.len(), 1);
+        assert_ne!(writer.save_chunk_at(&chunk_at(2), now).unwrap(), last);
+        assert!(!Manifest::path(&dir).with_extension("json.tmp").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,136 @@
+use sankshepa_protocol::structured_data::{self, SdElement};
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+/// The SD-IDs and parameter names of a record's structured data, in order.
+/// A chunk stores each distinct shape once, and the parameter values like
+/// template variables: typed, one column per shape and position.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct SdShape(pub Vec<(String, Vec<String>)>);
+
+impl SdShape {
+    /// Splits structured data into its shape and values, if it parses and
+    /// renders back to the same text.
+    pub fn split(sd: &str) -> Option<(SdShape, Vec<String>)> {
+        let elements = structured_data::parse(sd)?;
+        if structured_data::render(&elements) != sd {
+            return None;
+        }
+        let mut values = Vec::new();
+        let shape = elements
+            .into_iter()
+            .map(|element| {
+                let names = element
+                    .params
+                    .into_iter()
+                    .map(|(name, value)| {
+                        values.push(value);
+                        name
+                    })
+                    .collect();
+                (element.id, names)
+            })
+            .collect();
+        Some((SdShape(shape), values))
+    }
+
+    /// Number of parameter values a record of this shape has.
+    pub fn len(&self) -> usize {
+        self.0.iter().map(|(_, names)| names.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Structured data text of a record of this shape with `values`.
+    pub fn render(&self, values: &[String]) -> String {
+        let mut values = values.iter();
+        let elements: Vec<SdElement> = self
+            .0
+            .iter()
+            .map(|(id, names)| SdElement {
+                id: id.clone(),
+                params: names
+                    .iter()
+                    .map(|name| (name.clone(), values.next().cloned().unwrap_or_default()))
+                    .collect(),
+            })
+            .collect();
+        structured_data::render(&elements)
+    }
+
+    /// Value of parameter `param` in `values`, from the first element `id`
+    /// that has it.
+    pub fn value<'v>(&self, values: &'v [String], id: &str, param: &str) -> Option<&'v str> {
+        let mut pos = 0;
+        for (element, names) in &self.0 {
+            if element == id
+                && let Some(i) = names.iter().position(|n| n == param)
+            {
+                return values.get(pos + i).map(String::as_str);
+            }
+            pos += names.len();
+        }
+        None
+    }
+}
+
+/// A `sd.<SD-ID>.<param>=<value>` condition on a record's structured data.
+/// The SD-ID may itself contain dots; the parameter name is what follows
+/// the last one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SdMatch {
+    pub id: String,
+    pub param: String,
+    pub value: String,
+}
+
+impl FromStr for SdMatch {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("expected sd.<SD-ID>.<param>=<value>, got '{}'", s);
+        let (key, value) = s.split_once('=').ok_or_else(invalid)?;
+        let (id, param) = key
+            .strip_prefix("sd.")
+            .and_then(|k| k.rsplit_once('.'))
+            .ok_or_else(invalid)?;
+        if id.is_empty() || param.is_empty() {
+            return Err(invalid());
+        }
+        Ok(Self {
+            id: id.to_string(),
+            param: param.to_string(),
+            value: value.to_string(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shape_splits_and_renders() {
+        let sd = r#"exampleSDID@32473 iut="3" eventID="1011"][origin ip="10.0.0.1""#;
+        let (shape, values) = SdShape::split(sd).unwrap();
+        assert_eq!(values, vec!["3", "1011", "10.0.0.1"]);
+        assert_eq!(shape.len(), 3);
+        assert_eq!(shape.render(&values), sd);
+        assert_eq!(
+            shape.value(&values, "exampleSDID@32473", "eventID"),
+            Some("1011")
+        );
+        assert_eq!(shape.value(&values, "origin", "eventID"), None);
+
+        // Text that would not render back the same is not split.
+        assert!(SdShape::split(r#"x k="a\b""#).is_none());
+
+        let m: SdMatch = "sd.exampleSDID@32473.1.eventID=1011".parse().unwrap();
+        assert_eq!(m.id, "exampleSDID@32473.1");
+        assert_eq!(m.param, "eventID");
+        assert_eq!(m.value, "1011");
+        assert!("host=x".parse::<SdMatch>().is_err());
+    }
+}
@@ -0,0 +1,480 @@
+use crate::logshrink::{self, VarType};
+use crate::miner::{MinerConfig, TemplateMiner, WILDCARD};
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+/// Values of one variable slot of one template, in record order.
+#[derive(Debug)]
+pub struct SlotColumn {
+    pub template_id: u32,
+    pub slot: u32,
+    pub ty: VarType,
+    /// Distinct values, indexed by the column data, for `VarType::Str`;
+    /// templates and variables for `VarType::Template`.
+    pub dict: Vec<String>,
+    pub data: Vec<u8>,
+}
+
+/// Typed layout of a chunk's template variables.
+#[derive(Debug)]
+pub struct TypedVariables {
+    /// Number of variables of each record.
+    pub counts: Vec<u32>,
+    pub columns: Vec<SlotColumn>,
+}
+
+/// Splits each record's variables into per-slot columns, encoding every
+/// column with the most compact type that reproduces its values exactly.
+pub fn encode_variables(
+    template_ids: &[u32],
+    variables: &[Vec<String>],
+) -> anyhow::Result<TypedVariables> {
+    encode(template_ids, variables, None)
+}
+
+/// Like [`encode_variables`], but a column of free text is also mined into
+/// templates with `config`, and stored that way if it comes out smaller.
+pub fn encode_variables_mined(
+    template_ids: &[u32],
+    variables: &[Vec<String>],
+    config: &MinerConfig,
+) -> anyhow::Result<TypedVariables> {
+    encode(template_ids, variables, Some(config))
+}
+
+fn encode(
+    template_ids: &[u32],
+    variables: &[Vec<String>],
+    mining: Option<&MinerConfig>,
+) -> anyhow::Result<TypedVariables> {
+    if template_ids.len() != variables.len() {
+        return Err(anyhow::anyhow!(
+            "{} template IDs for {} variable lists",
+            template_ids.len(),
+            variables.len()
+        ));
+    }
+
+    let mut slots: Vec<((u32, u32), Vec<&str>)> = Vec::new();
+    let mut positions: HashMap<(u32, u32), usize> = HashMap::new();
+    for (&template_id, vars) in template_ids.iter().zip(variables) {
+        for (slot, value) in vars.iter().enumerate() {
+            let key = (template_id, slot as u32);
+            let pos = *positions.entry(key).or_insert_with(|| {
+                slots.push((key, Vec::new()));
+                slots.len() - 1
+            });
+            slots[pos].1.push(value);
+        }
+    }
+
+    let columns = slots
+        .into_iter()
+        .map(|((template_id, slot), values)| {
+            let column = encode_column(template_id, slot, &values)?;
+            Ok(match mining {
+                Some(config) if column.ty == VarType::Str => {
+                    let mined = mine_column(template_id, slot, &values, config);
+                    if stored_len(&mined) < stored_len(&column) {
+                        mined
+                    } else {
+                        column
+                    }
+                }
+                _ => column,
+            })
+        })
+        .collect::<anyhow::Result<_>>()?;
+    Ok(TypedVariables {
+        counts: variables.iter().map(|v| v.len() as u32).collect(),
+        columns,
+    })
+}
+
+/// Reassembles each record's variables from the columns of `typed`.
+pub fn decode_variables(
+    template_ids: &[u32],
+    typed: TypedVariables,
+) -> anyhow::Result<Vec<Vec<String>>> {
+    if template_ids.len() != typed.counts.len() {
+        return Err(anyhow::anyhow!(
+            "{} template IDs for {} variable counts",
+            template_ids.len(),
+            typed.counts.len()
+        ));
+    }
+
+    let mut columns: HashMap<(u32, u32), std::vec::IntoIter<String>> = HashMap::new();
+    for column in typed.columns {
+        let key = (column.template_id, column.slot);
+        columns.insert(key, decode_column(column)?.into_iter());
+    }
+
+    let mut variables = Vec::with_capacity(template_ids.len());
+    for (&template_id, &count) in template_ids.iter().zip(&typed.counts) {
+        let mut vars = Vec::with_capacity(count as usize);
+        for slot in 0..count {
+            let value = columns
+                .get_mut(&(template_id, slot))
+                .and_then(|c| c.next())
+                .ok_or_else(|| {
+                    anyhow::anyhow!("Variable column {}/{} exhausted", template_id, slot)
+                })?;
+            vars.push(value);
+        }
+        variables.push(vars);
+    }
+    Ok(variables)
+}
+
+fn encode_column(template_id: u32, slot: u32, values: &[&str]) -> anyhow::Result<SlotColumn> {
+    let ty = VarType::infer(values.iter().copied());
+    let mut dict = Vec::new();
+    let mut data = Vec::new();
+    match ty {
+        VarType::Int => {
+            let mut prev = 0i64;
+            for value in values {
+                let n: i64 = value.parse()?;
+                write_varint(&mut data, zigzag(n.wrapping_sub(prev)));
+                prev = n;
+            }
+        }
+        VarType::Ipv4 => {
+            for value in values {
+                data.extend(value.parse::<Ipv4Addr>()?.octets());
+            }
+        }
+        VarType::Ipv6 => {
+            for value in values {
+                data.extend(value.parse::<Ipv6Addr>()?.octets());
+            }
+        }
+        VarType::Uuid { .. } => {
+            for value in values {
+                let digits: String = value.chars().filter(|&c| c != '-').collect();
+                pack_nibbles(&mut data, &digits)?;
+            }
+        }
+        VarType::Hex { .. } => {
+            for value in values {
+                write_varint(&mut data, value.len() as u64);
+                pack_nibbles(&mut data, value)?;
+            }
+        }
+        VarType::Str => {
+            let mut ids: HashMap<&str, u64> = HashMap::new();
+            for value in values {
+                let id = *ids.entry(value).or_insert_with(|| {
+                    dict.push(value.to_string());
+                    dict.len() as u64 - 1
+                });
+                write_varint(&mut data, id);
+            }
+        }
+        VarType::Template => unreachable!("inference never picks templates"),
+    }
+    Ok(SlotColumn {
+        template_id,
+        slot,
+        ty,
+        dict,
+        data,
+    })
+}
+
+/// Mines `values` into templates local to the column. A value that its
+/// template and variables do not give back exactly (e.g. one with runs of
+/// spaces) is kept whole, as the one variable of `<*>`.
+fn mine_column(template_id: u32, slot: u32, values: &[&str], config: &MinerConfig) -> SlotColumn {
+    let mut miner = TemplateMiner::new(config.clone());
+    let texts: Vec<_> = values.iter().map(|v| logshrink::canonical(v)).collect();
+    // Templates are only final once every value has been added.
+    let clusters: Vec<usize> = texts
+        .iter()
+        .map(|text| miner.add(&miner.tokenize(text)))
+        .collect();
+
+    let mut dict = Vec::new();
+    let mut ids: HashMap<String, u64> = HashMap::new();
+    let mut intern = |s: String| {
+        *ids.entry(s).or_insert_with_key(|s| {
+            dict.push(s.clone());
+            dict.len() as u64 - 1
+        })
+    };
+    let mut data = Vec::new();
+    for ((value, text), cluster) in values.iter().zip(&texts).zip(clusters) {
+        let tokens = miner.tokenize(text);
+        let mut pattern = miner.pattern(cluster);
+        let mut vars = miner.variables(miner.template(cluster), &tokens);
+        if logshrink::reconstruct(&pattern, &vars) != *value {
+            pattern = WILDCARD.to_string();
+            vars = vec![value.to_string()];
+        }
+        write_varint(&mut data, intern(pattern));
+        write_varint(&mut data, vars.len() as u64);
+        for var in vars {
+            write_varint(&mut data, intern(var));
+        }
+    }
+    SlotColumn {
+        template_id,
+        slot,
+        ty: VarType::Template,
+        dict,
+        data,
+    }
+}
+
+/// Bytes `column` takes before compression.
+fn stored_len(column: &SlotColumn) -> usize {
+    column.data.len() + column.dict.iter().map(|s| s.len() + 1).sum::<usize>()
+}
+
+fn decode_column(column: SlotColumn) -> anyhow::Result<Vec<String>> {
+    let mut cursor = Cursor::new(&column.data);
+    let mut values = Vec::new();
+    match column.ty {
+        VarType::Int => {
+            let mut prev = 0i64;
+            while !cursor.is_empty() {
+                prev = prev.wrapping_add(unzigzag(cursor.varint()?));
+                values.push(prev.to_string());
+            }
+        }
+        VarType::Ipv4 => {
+            while !cursor.is_empty() {
+                let octets: [u8; 4] = cursor.take(4)?.try_into()?;
+                values.push(Ipv4Addr::from(octets).to_string());
+            }
+        }
+        VarType::Ipv6 => {
+            while !cursor.is_empty() {
+                let octets: [u8; 16] = cursor.take(16)?.try_into()?;
+                values.push(Ipv6Addr::from(octets).to_string());
+            }
+        }
+        VarType::Uuid { upper } => {
+            while !cursor.is_empty() {
+                let hex = unpack_nibbles(cursor.take(16)?, 32, upper);
+                values.push(format!(
+                    "{}-{}-{}-{}-{}",
+                    &hex[0..8],
+                    &hex[8..12],
+                    &hex[12..16],
+                    &hex[16..20],
+                    &hex[20..32]
+                ));
+            }
+        }
+        VarType::Hex { upper } => {
+            while !cursor.is_empty() {
+                let nibbles = cursor.varint()? as usize;
+                let bytes = cursor.take(nibbles.div_ceil(2))?;
+                values.push(unpack_nibbles(bytes, nibbles, upper));
+            }
+        }
+        VarType::Str => {
+            while !cursor.is_empty() {
+                let id = cursor.varint()? as usize;
+                let value = column
+                    .dict
+                    .get(id)
+                    .ok_or_else(|| anyhow::anyhow!("String ID {} out of range", id))?;
+                values.push(value.clone());
+            }
+        }
+        VarType::Template => {
+            let string = |id: u64| {
+                column
+                    .dict
+                    .get(id as usize)
+                    .ok_or_else(|| anyhow::anyhow!("String ID {} out of range", id))
+            };
+            while !cursor.is_empty() {
+                let pattern = string(cursor.varint()?)?;
+                let count = cursor.varint()?;
+                let vars = (0..count)
+                    .map(|_| string(cursor.varint()?).cloned())
+                    .collect::<anyhow::Result<Vec<_>>>()?;
+                values.push(logshrink::reconstruct(pattern, &vars));
+            }
+        }
+    }
+    Ok(values)
+}
+
+fn zigzag(n: i64) -> u64 {
+    ((n << 1) ^ (n >> 63)) as u64
+}
+
+fn unzigzag(n: u64) -> i64 {
+    ((n >> 1) as i64) ^ -((n & 1) as i64)
+}
+
+/// Appends `n` as an unsigned LEB128 varint.
+fn write_varint(buf: &mut Vec<u8>, mut n: u64) {
+    while n >= 0x80 {
+        buf.push((n as u8) | 0x80);
+        n >>= 7;
+    }
+    buf.push(n as u8);
+}
+
+/// Appends hex `digits` two per byte, high nibble first.
+fn pack_nibbles(buf: &mut Vec<u8>, digits: &str) -> anyhow::Result<()> {
+    for pair in digits.as_bytes().chunks(2) {
+        let mut byte = 0u8;
+        for (i, &d) in pair.iter().enumerate() {
+            let nibble = (d as char)
+                .to_digit(16)
+                .ok_or_else(|| anyhow::anyhow!("Invalid hex digit {:?}", d as char))?;
+            byte |= (nibble as u8) << (4 - 4 * i);
+        }
+        buf.push(byte);
+    }
+    Ok(())
+}
+
+fn unpack_nibbles(bytes: &[u8], nibbles: usize, upper: bool) -> String {
+    let digits: &[u8; 16] = if upper {
+        b"0123456789ABCDEF"
+    } else {
+        b"0123456789abcdef"
+    };
+    (0..nibbles)
+        .map(|i| {
+            let byte = bytes[i / 2];
+            let nibble = if i % 2 == 0 { byte >> 4 } else { byte & 0xf };
+            digits[nibble as usize] as char
+        })
+        .collect()
+}
+
+struct Cursor<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Cursor<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Self { buf }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    fn take(&mut self, n: usize) -> anyhow::Result<&'a [u8]> {
+        if self.buf.len() < n {
+            return Err(anyhow::anyhow!("Truncated variable column"));
+        }
+        let (head, tail) = self.buf.split_at(n);
+        self.buf = tail;
+        Ok(head)
+    }
+
+    fn varint(&mut self) -> anyhow::Result<u64> {
+        let mut n = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.take(1)?[0];
+            n |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(n);
+            }
+        }
+        Err(anyhow::anyhow!("Varint overflow in variable column"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(values: &[&str]) -> Vec<String> {
+        values.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_typed_variables_round_trip() {
+        let ids = vec![0, 1, 0, 2, 1, 0];
+        let variables = vec![
+            vars(&["10.0.0.1", "443", "deadbeef"]),
+            vars(&["123e4567-e89b-12d3-a456-426614174000", "fe80::1"]),
+            vars(&["192.168.1.23", "-9223372036854775808", "0ff"]),
+            vars(&[]),
+            vars(&["00000000-0000-0000-0000-00000000000A", "::"]),
+            vars(&["255.255.255.255", "9223372036854775807", "ABC"]),
+        ];
+
+        let typed = encode_variables(&ids, &variables).unwrap();
+        let types: HashMap<(u32, u32), VarType> = typed
+            .columns
+            .iter()
+            .map(|c| ((c.template_id, c.slot), c.ty))
+            .collect();
+        assert_eq!(types[&(0, 0)], VarType::Ipv4);
+        assert_eq!(types[&(0, 1)], VarType::Int);
+        assert_eq!(types[&(0, 2)], VarType::Str);
+        assert_eq!(types[&(1, 0)], VarType::Str);
+        assert_eq!(types[&(1, 1)], VarType::Ipv6);
+
+        assert_eq!(decode_variables(&ids, typed).unwrap(), variables);
+    }
+
+    #[test]
+    fn test_packed_hex_and_uuid() {
+        let ids = vec![7; 3];
+        let variables = vec![
+            vars(&["0a1b2c3d-0000-4000-8000-00000000ffff", "f"]),
+            vars(&["ffffffff-ffff-ffff-ffff-ffffffffffff", "00c0ffee"]),
+            vars(&["00000000-0000-0000-0000-000000000000", "123"]),
+        ];
+
+        let typed = encode_variables(&ids, &variables).unwrap();
+        assert_eq!(typed.columns[0].ty, VarType::Uuid { upper: false });
+        assert_eq!(typed.columns[0].data.len(), 48);
+        assert_eq!(typed.columns[1].ty, VarType::Hex { upper: false });
+        assert_eq!(decode_variables(&ids, typed).unwrap(), variables);
+    }
+
+    #[test]
+    fn test_free_text_is_templated() {
+        let mut texts: Vec<String> = (0..20).map(|i| format!("disk /dev/sd{} full", i)).collect();
+        texts.push("disk  /dev/sdc3 full".to_string());
+        texts.push("out of memory".to_string());
+        let ids = vec![3; texts.len()];
+        let variables: Vec<Vec<String>> = texts.into_iter().map(|t| vec![t]).collect();
+
+        let config = MinerConfig::default();
+        let typed = encode_variables_mined(&ids, &variables, &config).unwrap();
+        assert_eq!(typed.columns[0].ty, VarType::Template);
+        assert!(
+            typed.columns[0]
+                .dict
+                .contains(&"disk <PATH> full".to_string())
+        );
+        // Kept whole, as templates only give back single spaces.
+        assert!(
+            typed.columns[0]
+                .dict
+                .contains(&"disk  /dev/sdc3 full".to_string())
+        );
+        assert_eq!(decode_variables(&ids, typed).unwrap(), variables);
+
+        let plain = encode_variables(&ids, &variables).unwrap();
+        assert_eq!(plain.columns[0].ty, VarType::Str);
+        // Single words gain nothing from templates.
+        let words = vec![vars(&["alice"]), vars(&["bob"])];
+        let typed = encode_variables_mined(&[0, 0], &words, &config).unwrap();
+        assert_eq!(typed.columns[0].ty, VarType::Str);
+    }
+
+    #[test]
+    fn test_truncated_column_is_an_error() {
+        let ids = vec![0, 0];
+        let mut typed = encode_variables(&ids, &[vars(&["1.2.3.4"]), vars(&["5.6.7.8"])]).unwrap();
+        typed.columns[0].data.pop();
+        assert!(decode_variables(&ids, typed).is_err());
+    }
+}
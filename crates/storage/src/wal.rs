@@ -0,0 +1,267 @@
+use crate::container::{self, FrameReader, HEADER_LEN};
+use crate::encryption::{self, Keyring, SealError};
+use crate::migrate;
+use sankshepa_protocol::SyslogMessage;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+/// Magic bytes at the start of a write-ahead log.
+pub const WAL_MAGIC: [u8; 4] = *b"LSHW";
+
+/// Write-ahead log of messages that have been received but not yet persisted
+/// in a chunk. Each message is appended as a length-prefixed postcard frame,
+/// sealed with the log's keys if it has any, and synced to disk before
+/// `append` returns.
+pub struct Wal {
+    file: File,
+    path: PathBuf,
+    keys: Option<Keyring>,
+    /// Offset the next frame is appended at.
+    end: u64,
+}
+
+impl Wal {
+    /// Opens (or creates) the log at `path` for appending, sealing entries
+    /// with `keys`. A torn frame at the end is cut off so new entries stay
+    /// readable.
+    pub fn open(path: impl AsRef<Path>, keys: Option<&Keyring>) -> anyhow::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+
+        if file.metadata()?.len() < HEADER_LEN {
+            file.set_len(0)?;
+            container::write_header(&mut file, WAL_MAGIC)?;
+            file.sync_data()?;
+        } else if container::read_header(&mut file, WAL_MAGIC)? != container::FORMAT_VERSION
+            || encryption::needs_reseal(&path, WAL_MAGIC, keys)?
+        {
+            // Rewrite the log in the current layout (and sealed with the
+            // active key) so appends do not mix layouts. The new log replaces
+            // the old one by rename, so a crash meanwhile loses nothing.
+            let messages = Self::replay(&path, keys)?;
+            let mut buf = Vec::new();
+            container::write_header(&mut buf, WAL_MAGIC)?;
+            for msg in &messages {
+                let payload = postcard::to_allocvec(msg)?;
+                let offset = buf.len() as u64;
+                buf.extend(container::encode_frame(&payload, WAL_MAGIC, offset, keys)?);
+            }
+            let mut tmp = path.as_os_str().to_owned();
+            tmp.push(".tmp");
+            let mut rewritten = File::create(&tmp)?;
+            rewritten.write_all(&buf)?;
+            rewritten.sync_all()?;
+            fs::rename(&tmp, &path)?;
+            container::sync_parent(&path)?;
+            file = OpenOptions::new().read(true).write(true).open(&path)?;
+        } else {
+            let end = Self::valid_end(&path, keys)?;
+            if end < file.metadata()?.len() {
+                warn!("Discarding torn tail of WAL {}", path.display());
+                file.set_len(end)?;
+                file.sync_data()?;
+            }
+        }
+        let end = file.seek(SeekFrom::End(0))?;
+
+        Ok(Self {
+            file,
+            path,
+            keys: keys.cloned(),
+            end,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Durably appends `msg` to the log. On failure the log is cut back to
+    /// where it was, so a partial frame never sits before the next one.
+    pub fn append(&mut self, msg: &SyslogMessage) -> anyhow::Result<()> {
+        let payload = postcard::to_allocvec(msg)?;
+        let frame = container::encode_frame(&payload, WAL_MAGIC, self.end, self.keys.as_ref())?;
+        if let Err(e) = self
+            .file
+            .write_all(&frame)
+            .and_then(|_| self.file.sync_data())
+        {
+            self.file.set_len(self.end)?;
+            self.file.seek(SeekFrom::Start(self.end))?;
+            return Err(e.into());
+        }
+        self.end += frame.len() as u64;
+        Ok(())
+    }
+
+    /// Discards every logged message. Only call this once the chunk holding
+    /// them is synced to disk, as [`crate::StorageEngine::save_chunk`] does
+    /// before returning.
+    pub fn truncate(&mut self) -> anyhow::Result<()> {
+        self.file.set_len(HEADER_LEN)?;
+        self.end = self.file.seek(SeekFrom::End(0))?;
+        self.file.sync_data()?;
+        Ok(())
+    }
+
+    /// Byte offset just past the last complete frame.
+    fn valid_end(path: &Path, keys: Option<&Keyring>) -> anyhow::Result<u64> {
+        let mut frames = FrameReader::open_with(path, WAL_MAGIC, keys)?;
+        loop {
+            match frames.next_frame() {
+                Ok(Some(_)) => {}
+                Err(e) if e.is::<SealError>() => return Err(e),
+                Ok(None) | Err(_) => return Ok(frames.offset()),
+            }
+        }
+    }
+
+    /// Reads back every message in the log at `path`, opening sealed entries
+    /// with `keys`. A torn frame at the end (a crash mid-append) is dropped;
+    /// a missing log yields nothing.
+    pub fn replay(
+        path: impl AsRef<Path>,
+        keys: Option<&Keyring>,
+    ) -> anyhow::Result<Vec<SyslogMessage>> {
+        let path = path.as_ref();
+        if !path.exists() || fs::metadata(path)?.len() < HEADER_LEN {
+            return Ok(Vec::new());
+        }
+
+        let mut frames = FrameReader::open_with(path, WAL_MAGIC, keys)?;
+        let version = frames.version();
+        let mut messages = Vec::new();
+        loop {
+            match frames.next_frame() {
+                Ok(Some((_, payload))) => match migrate::parse_message(&payload, version) {
+                    Ok(msg) => messages.push(msg),
+                    Err(e) => {
+                        warn!("Stopping WAL replay at undecodable entry: {}", e);
+                        break;
+                    }
+                },
+                Ok(None) => break,
+                Err(e) if e.is::<SealError>() => return Err(e),
+                Err(e) => {
+                    warn!("Stopping WAL replay at torn entry: {}", e);
+                    break;
+                }
+            }
+        }
+        Ok(messages)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+    use std::fs;
+
+    fn msg(text: &str) -> SyslogMessage {
+        SyslogMessage {
+            priority: 34,
+            facility: 4,
+            severity: 2,
+            timestamp: Some(Utc::now()),
+            hostname: Some("host".to_string()),
+            app_name: Some("app".to_string()),
+            procid: None,
+            msgid: None,
+            structured_data: None,
+            message: text.to_string(),
+            is_rfc5424: true,
+            node_id: Some("node-1".to_string()),
+            raw: None,
+        }
+    }
+
+    #[test]
+    fn test_wal_replay_and_truncate() {
+        let path = "test_wal_replay.wal";
+        let _ = fs::remove_file(path);
+
+        let mut wal = Wal::open(path, None).unwrap();
+        wal.append(&msg("first")).unwrap();
+        wal.append(&msg("second")).unwrap();
+        drop(wal);
+
+        let replayed = Wal::replay(path, None).unwrap();
+        assert_eq!(replayed.len(), 2);
+        assert_eq!(replayed[1].message, "second");
+        assert_eq!(replayed[0].node_id.as_deref(), Some("node-1"));
+
+        let mut wal = Wal::open(path, None).unwrap();
+        wal.append(&msg("third")).unwrap();
+        assert_eq!(Wal::replay(path, None).unwrap().len(), 3);
+        wal.truncate().unwrap();
+        assert!(Wal::replay(path, None).unwrap().is_empty());
+        wal.append(&msg("fourth")).unwrap();
+        assert_eq!(Wal::replay(path, None).unwrap()[0].message, "fourth");
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_wal_drops_torn_tail() {
+        let path = "test_wal_torn.wal";
+        let _ = fs::remove_file(path);
+
+        let mut wal = Wal::open(path, None).unwrap();
+        wal.append(&msg("kept")).unwrap();
+        wal.append(&msg("torn")).unwrap();
+        let len = fs::metadata(path).unwrap().len();
+        wal.file.set_len(len - 2).unwrap();
+        drop(wal);
+
+        let replayed = Wal::replay(path, None).unwrap();
+        assert_eq!(replayed.len(), 1);
+        assert_eq!(replayed[0].message, "kept");
+
+        // Reopening cuts the torn frame so later appends are readable.
+        let mut wal = Wal::open(path, None).unwrap();
+        wal.append(&msg("after")).unwrap();
+        let replayed = Wal::replay(path, None).unwrap();
+        assert_eq!(replayed.len(), 2);
+        assert_eq!(replayed[1].message, "after");
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_wal_upgrades_old_version() {
+        let path = "test_wal_upgrade.wal";
+        // Version 8 entries lacked the trailing `raw` option.
+        let mut payload = postcard::to_allocvec(&msg("logged by 8")).unwrap();
+        payload.pop();
+        let mut buf = Vec::new();
+        container::write_header_version(&mut buf, WAL_MAGIC, 8).unwrap();
+        let offset = buf.len() as u64;
+        buf.extend(container::encode_frame(&payload, WAL_MAGIC, offset, None).unwrap());
+        fs::write(path, buf).unwrap();
+
+        assert_eq!(Wal::replay(path, None).unwrap()[0].message, "logged by 8");
+        let mut wal = Wal::open(path, None).unwrap();
+        assert!(!Path::new("test_wal_upgrade.wal.tmp").exists());
+        wal.append(&msg("logged after upgrade")).unwrap();
+        let replayed = Wal::replay(path, None).unwrap();
+        assert_eq!(replayed.len(), 2);
+        assert_eq!(replayed[0].message, "logged by 8");
+        assert_eq!(replayed[1].message, "logged after upgrade");
+        assert_eq!(
+            FrameReader::open_with(path, WAL_MAGIC, None)
+                .unwrap()
+                .version(),
+            container::FORMAT_VERSION
+        );
+
+        fs::remove_file(path).unwrap();
+    }
+}
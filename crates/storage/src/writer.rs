@@ -0,0 +1,467 @@
+use crate::compaction::{CompactionConfig, CompactionReport};
+use crate::dictionary::{self, SharedDictionary, TemplateDictionary};
+use crate::logshrink::LogChunk;
+use crate::merging::{MergeConfig, MergeReport};
+use crate::miner::{MinerConfig, TemplateMiner};
+use crate::retention::{RetentionPolicy, RetentionReport};
+use crate::segment::{SegmentPass, SegmentWriter};
+use crate::wal::Wal;
+use sankshepa_protocol::SyslogMessage;
+use std::collections::BTreeSet;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// When the buffered chunk is flushed: whichever limit is reached first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FlushPolicy {
+    /// Flush once this many records are buffered.
+    pub max_records: usize,
+    /// Flush once the buffered message text reaches this many bytes.
+    pub max_bytes: usize,
+    /// Flush once the oldest buffered record has waited this long.
+    pub max_latency: Duration,
+}
+
+impl Default for FlushPolicy {
+    fn default() -> Self {
+        Self {
+            max_records: 1000,
+            max_bytes: 1024 * 1024,
+            max_latency: Duration::from_secs(5),
+        }
+    }
+}
+
+/// Result of a flush.
+#[derive(Debug)]
+pub struct Flushed {
+    pub path: PathBuf,
+    pub records: usize,
+    /// Templates discovered in this chunk as `(id, pattern)`, for
+    /// propagation to the cluster.
+    pub new_templates: Vec<(u32, String)>,
+}
+
+/// Buffers incoming messages into a `LogChunk`, logging each one to the WAL,
+/// and writes the chunk to the segment store according to a `FlushPolicy`.
+/// Template IDs come from the store's persistent `TemplateDictionary`, whose
+/// templates also seed the miner that every chunk is mined with.
+pub struct ChunkWriter {
+    segments: SegmentWriter,
+    dictionary: SharedDictionary,
+    wal: Option<Wal>,
+    policy: FlushPolicy,
+    chunk: LogChunk,
+    /// Templates mined by flushes that failed, reported once one succeeds.
+    new_templates: Vec<(u32, String)>,
+    /// Templates merged into others, which no longer seed the miner.
+    retired: BTreeSet<u32>,
+    pending_records: usize,
+    pending_bytes: usize,
+    oldest_pending: Option<Instant>,
+}
+
+impl ChunkWriter {
+    pub fn new(
+        segments: SegmentWriter,
+        wal: Option<Wal>,
+        policy: FlushPolicy,
+    ) -> anyhow::Result<Self> {
+        let dictionary =
+            TemplateDictionary::open_for_writing(segments.dictionary_path(), segments.keys())?;
+        let mut chunk = dictionary.new_chunk();
+        chunk.miner = seeded_miner(&dictionary, MinerConfig::default(), &BTreeSet::new());
+        Ok(Self {
+            chunk,
+            new_templates: Vec::new(),
+            retired: BTreeSet::new(),
+            segments,
+            dictionary: Arc::new(Mutex::new(dictionary)),
+            wal,
+            policy,
+            pending_records: 0,
+            pending_bytes: 0,
+            oldest_pending: None,
+        })
+    }
+
+    /// Mines templates with `config` instead of the default.
+    pub fn with_miner(mut self, config: MinerConfig) -> Self {
+        self.chunk.miner = seeded_miner(&dictionary::lock(&self.dictionary), config, &self.retired);
+        self
+    }
+
+    /// Buffers messages replayed from the WAL without logging them again.
+    pub fn recover(&mut self, messages: Vec<SyslogMessage>) {
+        for msg in messages {
+            self.buffer(msg);
+        }
+    }
+
+    /// Logs `msg` to the WAL and buffers it, flushing if a size limit is hit.
+    /// A message the WAL could not log is refused: it is not buffered and
+    /// the error is returned, so the caller can drop or retry it.
+    pub fn push(&mut self, msg: SyslogMessage) -> anyhow::Result<Option<Flushed>> {
+        if let Some(wal) = &mut self.wal {
+            wal.append(&msg).map_err(|e| {
+                anyhow::anyhow!(
+                    "Failed to log message to WAL {}: {}",
+                    wal.path().display(),
+                    e
+                )
+            })?;
+        }
+        self.buffer(msg);
+
+        if self.pending_records >= self.policy.max_records
+            || self.pending_bytes >= self.policy.max_bytes
+        {
+            return self.flush();
+        }
+        Ok(None)
+    }
+
+    fn buffer(&mut self, msg: SyslogMessage) {
+        self.pending_records += 1;
+        self.pending_bytes += msg.message.len();
+        self.oldest_pending.get_or_insert_with(Instant::now);
+        self.chunk.add_message(msg);
+    }
+
+    /// Registers a template a cluster peer announced under `id` in the
+    /// dictionary, taking over the peer's ID where it is free here.
+    pub fn import_template(&mut self, id: u32, pattern: String) {
+        let mut dictionary = dictionary::lock(&self.dictionary);
+        if dictionary.id(&pattern).is_none() {
+            self.chunk.miner.seed(&pattern);
+        }
+        let id = dictionary.adopt(id, &pattern);
+        self.chunk.register_template(pattern, id);
+    }
+
+    /// The store's template dictionary, locked; a running [`SegmentPass`]
+    /// may be adding to it.
+    pub fn dictionary(&self) -> MutexGuard<'_, TemplateDictionary> {
+        dictionary::lock(&self.dictionary)
+    }
+
+    /// Instant by which the buffered records must be flushed, if any.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.oldest_pending.map(|t| t + self.policy.max_latency)
+    }
+
+    pub fn pending_records(&self) -> usize {
+        self.pending_records
+    }
+
+    /// Writes the buffered records as a chunk and, once it is on disk,
+    /// truncates the WAL. Does nothing if no records are buffered. New
+    /// templates are persisted to the dictionary before the chunk
+    /// referencing them is written.
+    pub fn flush(&mut self) -> anyhow::Result<Option<Flushed>> {
+        if self.pending_records == 0 {
+            return Ok(None);
+        }
+
+        let path = match self.save_chunk() {
+            Ok(path) => path,
+            Err(e) => {
+                // The chunk keeps its records and the WAL still holds them;
+                // wait a full interval before the next time-based retry.
+                self.oldest_pending = Some(Instant::now());
+                return Err(e);
+            }
+        };
+
+        let mut next = self.dictionary().new_chunk();
+        next.miner = std::mem::take(&mut self.chunk.miner);
+        self.chunk = next;
+        let records = std::mem::take(&mut self.pending_records);
+        self.pending_bytes = 0;
+        self.oldest_pending = None;
+        let new_templates = std::mem::take(&mut self.new_templates);
+        if let Some(wal) = &mut self.wal {
+            wal.truncate()?;
+        }
+
+        Ok(Some(Flushed {
+            path,
+            records,
+            new_templates,
+        }))
+    }
+
+    /// Mines the buffered messages and persists the new templates, then
+    /// the chunk.
+    fn save_chunk(&mut self) -> anyhow::Result<PathBuf> {
+        {
+            let mut dictionary = dictionary::lock(&self.dictionary);
+            let new_templates = dictionary.process(&mut self.chunk)?;
+            let templates = &self.chunk.templates;
+            self.new_templates
+                .extend(new_templates.into_iter().map(|p| (templates[&p], p)));
+            dictionary.persist()?;
+        }
+        self.segments.save_chunk(&self.chunk)
+    }
+
+    pub fn apply_retention(&mut self, policy: &RetentionPolicy) -> anyhow::Result<RetentionReport> {
+        self.segments.apply_retention(policy)
+    }
+
+    /// A compaction or merge pass over the closed segments, to run on
+    /// another thread while this writer keeps buffering and flushing. The
+    /// templates it adds to the dictionary reach the buffered chunk when it
+    /// is flushed; hand its result to [`Self::finish_compaction`] or
+    /// [`Self::finish_merge`]. Retention must not run meanwhile, as it may
+    /// delete the segments the pass is rewriting.
+    pub fn pass(&self) -> Option<SegmentPass> {
+        self.segments.pass(self.dictionary.clone())
+    }
+
+    /// Compacts the closed segments on this thread.
+    pub fn compact(&mut self, config: &CompactionConfig) -> anyhow::Result<Vec<CompactionReport>> {
+        let Some(pass) = self.pass() else {
+            return Ok(Vec::new());
+        };
+        let reports = pass.compact(config)?;
+        self.finish_compaction(&reports)?;
+        Ok(reports)
+    }
+
+    /// Records the segments a compaction pass compacted.
+    pub fn finish_compaction(&mut self, reports: &[CompactionReport]) -> anyhow::Result<()> {
+        self.segments.finish_compaction(reports)
+    }
+
+    /// Merges near-duplicate templates across the closed segments on this
+    /// thread.
+    pub fn merge_templates(&mut self, config: &MergeConfig) -> anyhow::Result<MergeReport> {
+        let Some(pass) = self.pass() else {
+            return Ok(MergeReport::default());
+        };
+        let report = pass.merge_templates(config)?;
+        self.finish_merge(&report);
+        Ok(report)
+    }
+
+    /// Reseeds the miner without the templates a merge pass replaced, so new
+    /// messages take the merged ones.
+    pub fn finish_merge(&mut self, report: &MergeReport) {
+        if report.remapped.is_empty() {
+            return;
+        }
+        self.retired.extend(report.remapped.keys());
+        let config = self.chunk.miner.config().clone();
+        self.chunk.miner = seeded_miner(&dictionary::lock(&self.dictionary), config, &self.retired);
+    }
+}
+
+/// A miner that starts out with every template of `dictionary` but the
+/// `retired` ones.
+fn seeded_miner(
+    dictionary: &TemplateDictionary,
+    config: MinerConfig,
+    retired: &BTreeSet<u32>,
+) -> TemplateMiner {
+    let mut miner = TemplateMiner::new(config);
+    for (_, pattern) in dictionary.iter().filter(|(id, _)| !retired.contains(id)) {
+        miner.seed(pattern);
+    }
+    miner
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::StorageEngine;
+    use crate::segment::Rotation;
+    use chrono::Utc;
+    use std::fs;
+
+    fn msg(text: &str) -> SyslogMessage {
+        SyslogMessage {
+            priority: 34,
+            facility: 4,
+            severity: 2,
+            timestamp: Some(Utc::now()),
+            hostname: Some("host".to_string()),
+            app_name: None,
+            procid: None,
+            msgid: None,
+            structured_data: None,
+            message: text.to_string(),
+            is_rfc5424: true,
+            node_id: None,
+            raw: None,
+        }
+    }
+
+    fn writer(name: &str, policy: FlushPolicy) -> (ChunkWriter, PathBuf) {
+        let dir = std::env::temp_dir().join(name);
+        let _ = fs::remove_dir_all(&dir);
+        let output = dir.join("logs.lshrink");
+        let segments = SegmentWriter::new(&output, Rotation::Never, None, None).unwrap();
+        let wal = Wal::open(dir.join("logs.wal"), None).unwrap();
+        (ChunkWriter::new(segments, Some(wal), policy).unwrap(), dir)
+    }
+
+    #[test]
+    fn test_flush_on_record_count() {
+        let policy = FlushPolicy {
+            max_records: 3,
+            ..Default::default()
+        };
+        let (mut w, dir) = writer("sankshepa_test_flush_count", policy);
+
+        assert!(w.push(msg("a 1")).unwrap().is_none());
+        assert!(w.push(msg("a 2")).unwrap().is_none());
+        assert_eq!(Wal::replay(dir.join("logs.wal"), None).unwrap().len(), 2);
+        let flushed = w.push(msg("a 3")).unwrap().expect("third record flushes");
+
+        assert_eq!(flushed.records, 3);
+        assert_eq!(w.pending_records(), 0);
+        assert!(w.deadline().is_none());
+        assert!(Wal::replay(dir.join("logs.wal"), None).unwrap().is_empty());
+        assert_eq!(
+            StorageEngine::load_chunk(&flushed.path.to_string_lossy(), None)
+                .unwrap()
+                .records
+                .len(),
+            3
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_flush_on_bytes_and_latency() {
+        let policy = FlushPolicy {
+            max_records: 1000,
+            max_bytes: 10,
+            max_latency: Duration::from_secs(60),
+        };
+        let (mut w, dir) = writer("sankshepa_test_flush_bytes", policy);
+
+        assert!(w.push(msg("short")).unwrap().is_none());
+        let deadline = w.deadline().expect("deadline once a record is pending");
+        assert!(deadline > Instant::now() + Duration::from_secs(59));
+        assert!(w.push(msg("long enough")).unwrap().is_some());
+
+        w.recover(vec![msg("replayed")]);
+        assert_eq!(w.pending_records(), 1);
+        assert_eq!(w.flush().unwrap().unwrap().records, 1);
+        assert!(w.flush().unwrap().is_none());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_failed_flush_keeps_records() {
+        let (mut w, dir) = writer("sankshepa_test_failed_flush", FlushPolicy::default());
+        let output = dir.join("logs.lshrink");
+        fs::write(&output, b"not a container").unwrap();
+
+        w.push(msg("User alice logged in")).unwrap();
+        assert!(w.flush().is_err());
+        assert_eq!(w.pending_records(), 1);
+        assert_eq!(Wal::replay(dir.join("logs.wal"), None).unwrap().len(), 1);
+
+        // Records buffered after the failure go into the retried chunk.
+        fs::remove_file(&output).unwrap();
+        w.push(msg("User bob logged in")).unwrap();
+        let flushed = w.flush().unwrap().unwrap();
+        assert_eq!(flushed.records, 2);
+        assert_eq!(flushed.new_templates.len(), 2);
+        assert!(Wal::replay(dir.join("logs.wal"), None).unwrap().is_empty());
+        let chunk = StorageEngine::load_chunk(&output.to_string_lossy(), None).unwrap();
+        assert_eq!(chunk.records.len(), 2);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_template_ids_stable_across_chunks() {
+        let policy = FlushPolicy {
+            max_records: 2,
+            ..Default::default()
+        };
+        let (mut w, dir) = writer("sankshepa_test_stable_ids", policy);
+
+        w.push(msg("System restart")).unwrap();
+        let first = w.push(msg("User alice logged in")).unwrap().unwrap();
+        w.push(msg("User bob logged in")).unwrap();
+        w.push(msg("System restart")).unwrap();
+        w.flush().unwrap();
+        assert_eq!(first.new_templates.len(), 2);
+        assert_eq!(w.dictionary().len(), 3);
+
+        // A restarted writer picks up the persisted dictionary.
+        drop(w);
+        let segments =
+            SegmentWriter::new(dir.join("logs.lshrink"), Rotation::Never, None, None).unwrap();
+        let mut w = ChunkWriter::new(segments, None, policy).unwrap();
+        assert_eq!(w.dictionary().len(), 3);
+        // Its miner is seeded from the dictionary, so a known shape is not
+        // mined again.
+        w.push(msg("User carol logged in")).unwrap();
+        assert!(w.flush().unwrap().unwrap().new_templates.is_empty());
+        assert_eq!(w.dictionary().len(), 3);
+
+        let chunks: Vec<LogChunk> = StorageEngine::iter_chunks(&first.path, None)
+            .unwrap()
+            .map(|c| c.unwrap())
+            .collect();
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(
+            chunks[2].records[0].template_id,
+            chunks[1].templates["User <*> logged in"]
+        );
+        let restart_id = |c: &LogChunk| c.templates["System restart"];
+        assert_eq!(restart_id(&chunks[0]), restart_id(&chunks[1]));
+        let restart_records = chunks
+            .iter()
+            .flat_map(|c| &c.records)
+            .filter(|r| r.template_id == restart_id(&chunks[0]))
+            .count();
+        assert_eq!(restart_records, 2);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_merge_pass_runs_beside_flushes() {
+        let dir = std::env::temp_dir().join("sankshepa_test_writer_pass");
+        let _ = fs::remove_dir_all(&dir);
+        // Every chunk goes to a segment of its own.
+        let segments =
+            SegmentWriter::new(dir.join("logs.lshrink"), Rotation::Never, Some(1), None).unwrap();
+        let policy = FlushPolicy {
+            max_records: 1,
+            ..Default::default()
+        };
+        let mut w = ChunkWriter::new(segments, None, policy).unwrap();
+        w.push(msg("User alice logged in")).unwrap();
+        w.push(msg("User bob logged in")).unwrap();
+        // The segment being written is left out of the pass.
+        w.push(msg("System restart")).unwrap();
+
+        let pass = w.pass().expect("segments rotate");
+        let merging = std::thread::spawn(move || pass.merge_templates(&MergeConfig::default()));
+        let during = w.push(msg("Disk /dev/sda full")).unwrap().unwrap();
+        let report = merging.join().unwrap().unwrap();
+        w.finish_merge(&report);
+
+        let merged = w.dictionary().id("User <*> logged in").unwrap();
+        assert_eq!(report.remapped.values().collect::<Vec<_>>(), vec![&merged]);
+        assert_eq!(during.new_templates.len(), 1);
+        // The miner no longer knows the merged-away template.
+        let after = w.push(msg("User alice logged in")).unwrap().unwrap();
+        assert!(after.new_templates.is_empty());
+        let chunk = StorageEngine::load_chunk(&after.path.to_string_lossy(), None).unwrap();
+        assert_eq!(chunk.records[0].template_id, merged);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,26 +1,49 @@
-use chrono::{TimeZone, Utc};
-use clap::{Parser, Subcommand};
+use chrono::{DateTime, TimeZone, Utc};
+use clap::{Args, Parser, Subcommand, ValueEnum};
 use sankshepa_cluster::ClusterManager;
 use sankshepa_ingestion::IngestionServer;
 use sankshepa_protocol::UnifiedParser;
 use sankshepa_storage::StorageEngine;
-use sankshepa_storage::logshrink::LogChunk;
+use sankshepa_storage::compaction::{self, CompactionConfig, CompactionReport};
+use sankshepa_storage::compression::{BlockEncoder, Column, ColumnLevel, CompressionConfig};
+use sankshepa_storage::container::FORMAT_VERSION;
+use sankshepa_storage::encryption::Keyring;
+use sankshepa_storage::index::{ChunkFilter, ChunkIndex, ChunkMeta};
+use sankshepa_storage::logshrink::{LogChunk, Tokenizer};
+use sankshepa_storage::masking::{MaskRule, Masking, Shape};
+use sankshepa_storage::merging::{self, MergeConfig, MergeReport};
+use sankshepa_storage::migrate;
+use sankshepa_storage::miner::{self, MinerConfig, TemplateMiner};
+use sankshepa_storage::reader::{ChunkColumns, ColumnSet, ReadMode};
+use sankshepa_storage::recovery;
+use sankshepa_storage::retention::{RetentionPolicy, SeverityRule, parse_duration};
+use sankshepa_storage::segment::{Manifest, Rotation, SegmentWriter};
+use sankshepa_storage::structured::SdMatch;
+use sankshepa_storage::wal::Wal;
+use sankshepa_storage::writer::{ChunkWriter, FlushPolicy};
 use sankshepa_ui::UiServer;
+use std::collections::BTreeMap;
 use std::io::{self, Write};
 use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 use tokio::io::AsyncWriteExt;
-use tokio::sync::{broadcast, mpsc};
-use tracing::{error, info};
+use tokio::sync::{broadcast, mpsc, watch};
+use tracing::{error, info, warn};
 
 #[derive(Parser)]
 #[command(name = "sankshepa")]
 #[command(about = "Multi-Protocol Syslog Suite with LogShrink Storage", long_about = None)]
 struct Cli {
+    /// Encrypt everything written with the newest key of this file, and decrypt with any of its keys
+    #[arg(long, global = true)]
+    key_file: Option<PathBuf>,
     #[command(subcommand)]
     command: Commands,
 }
 
 #[derive(Subcommand)]
+#[allow(clippy::large_enum_variant)]
 enum Commands {
     /// Starts the syslog collector
     Serve {
@@ -34,6 +57,59 @@ enum Commands {
         ui_addr: String,
         #[arg(long, default_value = "logs.lshrink")]
         output: String,
+        /// Roll over to a new segment file every hour or day (never, hourly, daily)
+        #[arg(long, default_value = "never")]
+        rotate: Rotation,
+        /// Roll over to a new segment file once the current one reaches this size
+        #[arg(long)]
+        max_segment_bytes: Option<u64>,
+        /// Delete segments whose newest record is older than this (e.g. 365d)
+        #[arg(long, value_parser = parse_duration)]
+        retention_max_age: Option<Duration>,
+        /// Delete the oldest segments once all segments together exceed this size
+        #[arg(long)]
+        retention_max_bytes: Option<u64>,
+        /// After <age>, keep only records at <severity> or above, e.g. 30d:err (repeatable)
+        #[arg(long = "retention-rule")]
+        retention_rules: Vec<SeverityRule>,
+        /// How often the retention policy is applied
+        #[arg(long, default_value = "1h", value_parser = parse_duration)]
+        retention_interval: Duration,
+        /// Merge the chunks of closed segments and re-mine their templates this often
+        #[arg(long, value_parser = parse_duration)]
+        compact_interval: Option<Duration>,
+        /// Records per chunk written by the compactor
+        #[arg(long, default_value = "100000")]
+        compact_chunk_records: usize,
+        /// Merge near-duplicate templates of closed segments and remap their records this often
+        #[arg(long, value_parser = parse_duration)]
+        merge_interval: Option<Duration>,
+        /// Fraction of tokens two templates must share to be merged (0 to 1)
+        #[arg(long, default_value = "0.8", value_parser = parse_fraction)]
+        merge_similarity: f32,
+        /// Write-ahead log for messages not yet saved in a chunk [default: <output>.wal]
+        #[arg(long)]
+        wal: Option<String>,
+        /// Flush a chunk once it holds this many records
+        #[arg(long, default_value = "1000")]
+        flush_records: usize,
+        /// Flush a chunk once its message text reaches this many bytes
+        #[arg(long, default_value = "1048576")]
+        flush_bytes: usize,
+        /// Flush a chunk once its oldest record has waited this long
+        #[arg(long, default_value = "5s", value_parser = parse_duration)]
+        flush_interval: Duration,
+        /// zstd level for a column, or for all others with `default` (e.g. variables=19, repeatable)
+        #[arg(long = "compression-level")]
+        compression_levels: Vec<ColumnLevel>,
+        /// Train a zstd dictionary after this many chunks and compress later chunks with it (0 disables)
+        #[arg(long, default_value = "16")]
+        zstd_dict_chunks: usize,
+        /// Also store each message's header text and whitespace so `query --original` gives back the exact received text
+        #[arg(long)]
+        lossless: bool,
+        #[command(flatten)]
+        miner: MinerArgs,
         /// Unique node identifier
         #[arg(long)]
         node_id: Option<String>,
@@ -46,6 +122,7 @@ enum Commands {
     },
     /// Extracts and reconstructs logs from LogShrink storage
     Query {
+        /// A .lshrink file, or a segment directory containing a manifest.json
         #[arg(long, default_value = "logs.lshrink")]
         input: String,
         #[arg(long)]
@@ -53,6 +130,63 @@ enum Commands {
         /// Search string to filter logs
         #[arg(long)]
         filter: Option<String>,
+        /// Only show logs at or after this RFC 3339 timestamp
+        #[arg(long, value_parser = parse_rfc3339_millis)]
+        since: Option<i64>,
+        /// Only show logs at or before this RFC 3339 timestamp
+        #[arg(long, value_parser = parse_rfc3339_millis)]
+        until: Option<i64>,
+        /// Only show logs from this hostname
+        #[arg(long)]
+        host: Option<String>,
+        /// Only show logs whose structured data has this parameter value, e.g. sd.exampleSDID@32473.eventID=1011 (repeatable)
+        #[arg(long = "where")]
+        sd_matches: Vec<SdMatch>,
+        /// Skip corrupt or truncated chunks (reporting them) instead of failing
+        #[arg(long)]
+        recover: bool,
+        /// Print record counts grouped by these keys instead of the records (repeatable)
+        #[arg(long, value_enum)]
+        count_by: Vec<CountKey>,
+        /// Read the files through a memory mapping instead of buffered reads
+        #[arg(long)]
+        mmap: bool,
+        /// Print records stored with `serve --lossless` exactly as they were received
+        #[arg(long)]
+        original: bool,
+    },
+    /// Merges small chunks and re-mines their templates, rewriting the files in place
+    Compact {
+        /// A .lshrink file, or a segment directory containing a manifest.json
+        #[arg(long, default_value = "logs.lshrink")]
+        input: String,
+        /// Records per merged chunk
+        #[arg(long, default_value = "100000")]
+        chunk_records: usize,
+        /// Then merge near-duplicate templates across the files and remap their records
+        #[arg(long)]
+        merge_templates: bool,
+        /// Fraction of tokens two templates must share to be merged (0 to 1)
+        #[arg(long, default_value = "0.8", value_parser = parse_fraction)]
+        merge_similarity: f32,
+        #[command(flatten)]
+        miner: MinerArgs,
+    },
+    /// Adds a new random key to the key file; it encrypts everything written from then on
+    Keygen,
+    /// Rewrites files of an older format version in the current one, or not yet encrypted with the newest key
+    Migrate {
+        /// A .lshrink file, or a segment directory containing a manifest.json
+        #[arg(long, default_value = "logs.lshrink")]
+        input: String,
+    },
+    /// Verifies the chunks and index of a .lshrink file
+    Fsck {
+        #[arg(long, default_value = "logs.lshrink")]
+        input: String,
+        /// Rewrite the file without its damaged chunks and rebuild the index
+        #[arg(long)]
+        repair: bool,
     },
     /// Generates test syslog messages
     Generate {
@@ -69,9 +203,212 @@ enum Commands {
         count: usize,
         #[arg(long, default_value = "bench.lshrink")]
         output: String,
+        /// Also time template mining of this many varied lines with the prefix tree and the old linear scan
+        #[arg(long, default_value = "0")]
+        miner_lines: usize,
     },
 }
 
+/// Template miner settings of `serve` and `compact`.
+#[derive(Debug, Clone, Args)]
+struct MinerArgs {
+    /// Leading tokens the template miner routes messages by before comparing them with templates
+    #[arg(long, default_value = "1")]
+    miner_depth: usize,
+    /// Fraction of tokens a message must share with a template to join it (0 to 1)
+    #[arg(long, default_value = "0.5", value_parser = parse_fraction)]
+    miner_similarity: f32,
+    /// Only let messages with the same number of tokens share a template (no `<*...>` slots)
+    #[arg(long)]
+    miner_fixed_length: bool,
+    /// Start a new template rather than let wildcards outnumber this fraction of a template's words (0 to 1)
+    #[arg(long, default_value = "0.75", value_parser = parse_fraction)]
+    miner_max_wildcards: f32,
+    /// Mask tokens wholly matching the regular expression REGEX as <NAME> before mining (repeatable)
+    #[arg(long = "mask", value_name = "NAME=REGEX")]
+    mask_rules: Vec<MaskRule>,
+    /// Do not mask this built-in shape: ip, num, uuid, hex, mac, path, duration, email, time (repeatable)
+    #[arg(long = "no-mask", value_name = "SHAPE")]
+    unmasked: Vec<Shape>,
+    /// Characters that split tokens besides whitespace; each run of them is kept as a separator (empty for whitespace only)
+    #[arg(long, value_name = "CHARS", default_value = Tokenizer::DEFAULT_DELIMITERS)]
+    delimiters: Tokenizer,
+}
+
+impl MinerArgs {
+    fn config(&self) -> MinerConfig {
+        MinerConfig {
+            depth: self.miner_depth,
+            similarity: self.miner_similarity,
+            variable_length: !self.miner_fixed_length,
+            max_wildcard_ratio: self.miner_max_wildcards,
+            masking: Masking {
+                shapes: Shape::ALL
+                    .into_iter()
+                    .filter(|s| !self.unmasked.contains(s))
+                    .collect(),
+                rules: self.mask_rules.clone(),
+            },
+            tokenizer: self.delimiters.clone(),
+            ..Default::default()
+        }
+    }
+}
+
+fn parse_fraction(s: &str) -> Result<f32, String> {
+    match s.parse::<f32>() {
+        Ok(f) if (0.0..=1.0).contains(&f) => Ok(f),
+        _ => Err(format!("expected a number from 0 to 1, got '{}'", s)),
+    }
+}
+
+/// Outcome of a compaction or merge pass run beside the ingest loop.
+enum PassResult {
+    Compaction(anyhow::Result<Vec<CompactionReport>>),
+    Merge(anyhow::Result<MergeReport>),
+}
+
+/// Hands the outcome of a finished pass back to the writer.
+fn finish_pass(writer: &mut ChunkWriter, done: Result<PassResult, tokio::task::JoinError>) {
+    match done {
+        Ok(PassResult::Compaction(Ok(reports))) => {
+            if let Err(e) = writer.finish_compaction(&reports) {
+                error!("Failed to record compacted segments: {}", e);
+            }
+        }
+        Ok(PassResult::Compaction(Err(e))) => error!("Compaction pass failed: {}", e),
+        Ok(PassResult::Merge(Ok(report))) => {
+            if !report.remapped.is_empty() {
+                info!("{}", merge_summary(&report));
+            }
+            writer.finish_merge(&report);
+        }
+        Ok(PassResult::Merge(Err(e))) => error!("Template merge pass failed: {}", e),
+        Err(e) => error!("Compaction or merge pass panicked: {}", e),
+    }
+}
+
+/// An interval of `period` whose first tick is one period from now, not
+/// straight away. Ticks missed while a pass runs fire once afterwards, not
+/// in a burst.
+fn later_interval(period: Option<Duration>) -> tokio::time::Interval {
+    // Without a period the ticks are never awaited.
+    let period = period.unwrap_or(Duration::from_secs(3600));
+    let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    interval
+}
+
+fn merge_summary(r: &MergeReport) -> String {
+    format!(
+        "Merged templates: {} -> {} in use, {} records remapped in {} files",
+        r.templates_before, r.templates_after, r.records_remapped, r.files_rewritten
+    )
+}
+
+/// A grouping key for `query --count-by`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum CountKey {
+    Template,
+    Hour,
+    Host,
+    App,
+}
+
+impl CountKey {
+    fn column(self) -> Column {
+        match self {
+            CountKey::Template => Column::TemplateId,
+            CountKey::Hour => Column::Timestamp,
+            CountKey::Host => Column::Hostname,
+            CountKey::App => Column::AppName,
+        }
+    }
+}
+
+/// Best of five runs of `read`, in milliseconds.
+fn time_reads(read: impl Fn() -> anyhow::Result<usize>) -> anyhow::Result<f64> {
+    let mut best = f64::MAX;
+    for _ in 0..5 {
+        let start = Instant::now();
+        read()?;
+        best = best.min(start.elapsed().as_secs_f64() * 1000.0);
+    }
+    Ok(best)
+}
+
+/// Line `i` of a stream of 2000 message shapes, each with a few variable
+/// fields, for timing template mining.
+fn varied_line(i: usize) -> String {
+    const WORDS: [&str; 32] = [
+        "connection",
+        "session",
+        "user",
+        "disk",
+        "request",
+        "worker",
+        "cache",
+        "job",
+        "opened",
+        "closed",
+        "failed",
+        "started",
+        "stopped",
+        "from",
+        "to",
+        "for",
+        "on",
+        "with",
+        "timeout",
+        "retry",
+        "error",
+        "warning",
+        "queue",
+        "backend",
+        "client",
+        "server",
+        "token",
+        "expired",
+        "mounted",
+        "received",
+        "sent",
+        "update",
+    ];
+    // splitmix64, so every run times the same lines.
+    let mix = |x: u64| {
+        let x = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        let x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        x ^ (x >> 31)
+    };
+    let shape = mix(i as u64) % 2000;
+    let len = 3 + mix(shape) % 10;
+    (0..len)
+        .map(|pos| {
+            let h = mix(shape * 31 + pos);
+            let v = mix(i as u64 * 31 + pos);
+            // At most every third token is a variable.
+            match (pos % 3, h % 6) {
+                (2, 0) => (v % 100_000).to_string(),
+                (2, 1) => format!("10.{}.{}.{}", v % 256, (v >> 8) % 256, (v >> 16) % 256),
+                (2, 2) => format!("{:x}", v >> 32),
+                _ => format!(
+                    "{}_{}",
+                    WORDS[(h >> 8) as usize % WORDS.len()],
+                    WORDS[(h >> 16) as usize % WORDS.len()]
+                ),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn parse_rfc3339_millis(s: &str) -> Result<i64, String> {
+    DateTime::parse_from_rfc3339(s)
+        .map(|dt| dt.timestamp_millis())
+        .map_err(|e| format!("invalid RFC 3339 timestamp: {}", e))
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     if std::env::var("RUST_LOG").is_err() {
@@ -79,6 +416,10 @@ async fn main() -> anyhow::Result<()> {
     }
     tracing_subscriber::fmt::init();
     let cli = Cli::parse();
+    let keys = match &cli.key_file {
+        Some(path) if !matches!(cli.command, Commands::Keygen) => Some(Keyring::load(path)?),
+        _ => None,
+    };
 
     match cli.command {
         Commands::Serve {
@@ -87,6 +428,24 @@ async fn main() -> anyhow::Result<()> {
             beep_addr,
             ui_addr,
             output,
+            rotate,
+            max_segment_bytes,
+            retention_max_age,
+            retention_max_bytes,
+            retention_rules,
+            retention_interval,
+            compact_interval,
+            compact_chunk_records,
+            merge_interval,
+            merge_similarity,
+            wal,
+            flush_records,
+            flush_bytes,
+            flush_interval,
+            compression_levels,
+            zstd_dict_chunks,
+            lossless,
+            miner,
             node_id,
             cluster_addr,
             peers,
@@ -95,7 +454,7 @@ async fn main() -> anyhow::Result<()> {
             let (ui_tx, _) = broadcast::channel(1000);
             let (cluster_template_tx, mut cluster_template_rx) = broadcast::channel(100);
 
-            let server = IngestionServer::new(udp_addr, tcp_addr, beep_addr, tx);
+            let server = IngestionServer::new(udp_addr, tcp_addr, beep_addr, tx).lossless(lossless);
             let ui_server = UiServer::new(ui_tx.clone());
             let node_id = node_id.unwrap_or_else(|| format!("node-{}", std::process::id()));
             let cluster_socket_addr: SocketAddr = cluster_addr.parse()?;
@@ -107,41 +466,137 @@ async fn main() -> anyhow::Result<()> {
             );
 
             let cluster_tx = cluster_manager.template_tx.clone();
-            let output_path = output.clone();
+            let compression = CompressionConfig {
+                dictionary_chunks: zstd_dict_chunks,
+                ..Default::default()
+            }
+            .with_levels(&compression_levels);
+            let compaction = CompactionConfig {
+                chunk_records: compact_chunk_records,
+                compression: compression.clone(),
+                miner: miner.config(),
+            };
+            let merging = MergeConfig {
+                similarity: merge_similarity,
+                compression: compression.clone(),
+                miner: miner.config(),
+            };
+            let segments = SegmentWriter::new(&output, rotate, max_segment_bytes, keys.as_ref())?
+                .with_compression(compression);
+            let retention = RetentionPolicy {
+                max_age: retention_max_age,
+                max_total_bytes: retention_max_bytes,
+                severity_rules: retention_rules,
+            };
+            if !retention.is_empty() && rotate == Rotation::Never && max_segment_bytes.is_none() {
+                warn!(
+                    "Retention only applies to rotated segments; enable --rotate or --max-segment-bytes"
+                );
+            }
+            if (compact_interval.is_some() || merge_interval.is_some())
+                && rotate == Rotation::Never
+                && max_segment_bytes.is_none()
+            {
+                warn!(
+                    "Compaction and template merging only apply to rotated segments; enable --rotate or --max-segment-bytes"
+                );
+            }
             let ui_tx_clone = ui_tx.clone();
-            let storage_handle = tokio::spawn(async move {
-                let mut chunk = LogChunk::new();
-                let mut count = 0;
+            let wal_path = wal.unwrap_or_else(|| format!("{}.wal", output));
+            let replayed = Wal::replay(&wal_path, keys.as_ref())?;
+            let flush_policy = FlushPolicy {
+                max_records: flush_records,
+                max_bytes: flush_bytes,
+                max_latency: flush_interval,
+            };
+            let wal = Wal::open(&wal_path, keys.as_ref())?;
+            let mut writer =
+                ChunkWriter::new(segments, Some(wal), flush_policy)?.with_miner(miner.config());
+            if !replayed.is_empty() {
+                info!("Replaying {} messages from {}", replayed.len(), wal_path);
+                writer.recover(replayed);
+            }
+
+            let (shutdown_tx, mut shutdown_rx) = watch::channel(false);
+            let mut storage_handle = tokio::spawn(async move {
                 let node_id_for_logs = node_id.clone();
+                let mut retention_tick = tokio::time::interval(retention_interval);
+                let mut compact_tick = later_interval(compact_interval);
+                let mut merge_tick = later_interval(merge_interval);
+                // The compaction or merge pass running on a blocking thread.
+                let mut pass: Option<tokio::task::JoinHandle<PassResult>> = None;
                 loop {
-                    tokio::select! {
+                    let deadline = writer.deadline();
+                    let flushed = tokio::select! {
                         Some(mut msg) = rx.recv() => {
                             msg.node_id = Some(node_id_for_logs.clone());
                             let _ = ui_tx_clone.send(msg.clone());
-                            chunk.add_message(msg);
-                            count += 1;
-                            if count >= 10 {
-                                let new_templates = chunk.finish_and_process();
-                                for t in new_templates {
-                                    let _ = cluster_tx.send(t).await;
-                                }
-                                let _ = StorageEngine::save_chunk(chunk, &output_path);
-                                chunk = LogChunk::new();
-                                count = 0;
-                                info!("Saved chunk to {}", output_path);
+                            writer.push(msg)
+                        }
+                        _ = tokio::time::sleep_until(deadline.unwrap_or_else(Instant::now).into()), if deadline.is_some() => {
+                            writer.flush()
+                        }
+                        Ok((id, pattern)) = cluster_template_rx.recv() => {
+                            writer.import_template(id, pattern);
+                            Ok(None)
+                        }
+                        // A pass may be rewriting segments retention would delete.
+                        _ = retention_tick.tick(), if !retention.is_empty() && pass.is_none() => {
+                            if let Err(e) = writer.apply_retention(&retention) {
+                                error!("Retention pass failed: {}", e);
                             }
+                            Ok(None)
+                        }
+                        _ = compact_tick.tick(), if compact_interval.is_some() && pass.is_none() => {
+                            let compaction = compaction.clone();
+                            pass = writer.pass().map(|p| {
+                                tokio::task::spawn_blocking(move || {
+                                    PassResult::Compaction(p.compact(&compaction))
+                                })
+                            });
+                            Ok(None)
+                        }
+                        _ = merge_tick.tick(), if merge_interval.is_some() && pass.is_none() => {
+                            let merging = merging.clone();
+                            pass = writer.pass().map(|p| {
+                                tokio::task::spawn_blocking(move || {
+                                    PassResult::Merge(p.merge_templates(&merging))
+                                })
+                            });
+                            Ok(None)
                         }
-                        Ok(pattern) = cluster_template_rx.recv() => {
-                            chunk.import_template(pattern);
+                        done = async { pass.as_mut().unwrap().await }, if pass.is_some() => {
+                            pass = None;
+                            finish_pass(&mut writer, done);
+                            Ok(None)
                         }
-                        _ = tokio::signal::ctrl_c() => {
-                            if count > 0 {
-                                chunk.finish_and_process();
-                                let _ = StorageEngine::save_chunk(chunk, &output_path);
-                                info!("Saved final chunk on Ctrl-C");
+                        _ = shutdown_rx.changed() => {
+                            if let Some(running) = pass.take() {
+                                info!("Waiting for the running compaction or merge pass");
+                                finish_pass(&mut writer, running.await);
+                            }
+                            match writer.flush() {
+                                Ok(Some(_)) => info!("Saved final chunk on Ctrl-C"),
+                                Ok(None) => {}
+                                Err(e) => error!("Failed to save final chunk: {}", e),
                             }
                             break;
                         }
+                    };
+
+                    match flushed {
+                        Ok(Some(flushed)) => {
+                            info!(
+                                "Saved chunk of {} records to {}",
+                                flushed.records,
+                                flushed.path.display()
+                            );
+                            for t in flushed.new_templates {
+                                let _ = cluster_tx.send(t).await;
+                            }
+                        }
+                        Ok(None) => {}
+                        Err(e) => error!("Failed to store message or save chunk: {}", e),
                     }
                 }
             });
@@ -169,11 +624,14 @@ async fn main() -> anyhow::Result<()> {
                         Err(e) => error!("Cluster task panicked: {}", e),
                     }
                 }
-                _ = storage_handle => {
+                _ = &mut storage_handle => {
                     info!("Storage handler stopped");
                 }
                 _ = tokio::signal::ctrl_c() => {
                     info!("Shutdown signal received");
+                    // Let the storage task write out what it has buffered.
+                    let _ = shutdown_tx.send(true);
+                    let _ = storage_handle.await;
                 }
             }
             info!("Sankshepa shutting down...");
@@ -182,109 +640,217 @@ async fn main() -> anyhow::Result<()> {
             input,
             template_id,
             filter,
+            since,
+            until,
+            host: host_filter,
+            sd_matches,
+            recover,
+            count_by,
+            mmap,
+            original,
         } => {
-            let chunk = StorageEngine::load_chunk(&input)?;
-
-            let mut pattern_map = std::collections::HashMap::new();
-            for (pattern, &id) in &chunk.templates {
-                pattern_map.insert(id, pattern.clone());
-            }
-
             let filter_lower = filter.as_ref().map(|s| s.to_lowercase());
             let mut stdout = io::stdout().lock();
+            let chunk_filter = ChunkFilter {
+                since,
+                until,
+                host: host_filter.clone(),
+                app_name: None,
+                template_id,
+            };
 
-            for record in chunk.records {
-                if template_id.is_some_and(|tid| record.template_id != tid) {
-                    continue;
+            // Counting only needs the grouped and filtered columns; printing
+            // or text search needs whole records.
+            let columns = if count_by.is_empty() || filter.is_some() {
+                ColumnSet::ALL
+            } else {
+                let mut columns =
+                    ColumnSet::of(&count_by.iter().map(|k| k.column()).collect::<Vec<_>>());
+                if since.is_some() || until.is_some() {
+                    columns = columns.with(Column::Timestamp);
                 }
+                if template_id.is_some() {
+                    columns = columns.with(Column::TemplateId);
+                }
+                if host_filter.is_some() {
+                    columns = columns.with(Column::Hostname);
+                }
+                if !sd_matches.is_empty() {
+                    columns = columns.with(Column::StructuredData);
+                }
+                columns
+            };
 
-                let pattern = pattern_map
-                    .get(&record.template_id)
-                    .cloned()
-                    .unwrap_or_else(|| "UNKNOWN".to_string());
-                let mut reconstructed = pattern.clone();
-                for var in record.variables {
-                    reconstructed = reconstructed.replacen("<*>", &var, 1);
+            let inputs = Manifest::resolve_inputs(&input, since, until)?;
+
+            let chunks: Box<dyn Iterator<Item = anyhow::Result<ChunkColumns>>> = if recover {
+                let mut recovered = Vec::new();
+                for path in inputs {
+                    let r = StorageEngine::recover_chunks(&path, keys.as_ref())?;
+                    for d in &r.damaged {
+                        warn!(
+                            "Skipped {} bytes at offset {} of {}: {}",
+                            d.len,
+                            d.offset,
+                            path.display(),
+                            d.reason
+                        );
+                    }
+                    recovered.extend(
+                        r.chunks
+                            .into_iter()
+                            .filter(|c| ChunkMeta::from_chunk(c, 0, 0).may_match(&chunk_filter))
+                            .map(ChunkColumns::from_chunk),
+                    );
                 }
+                Box::new(recovered.into_iter().map(Ok))
+            } else {
+                let mode = if mmap {
+                    ReadMode::Mapped
+                } else {
+                    ReadMode::Buffered
+                };
+                Box::new(StorageEngine::iter_projected_files(
+                    inputs,
+                    chunk_filter,
+                    columns,
+                    mode,
+                    keys,
+                ))
+            };
+
+            let mut counts: BTreeMap<Vec<(i64, String)>, u64> = BTreeMap::new();
+            for chunk in chunks {
+                let chunk = chunk?;
 
-                let host = record
-                    .hostname_id
-                    .and_then(|id| chunk.string_pool.get(id as usize))
-                    .map(|s| s.as_str())
-                    .unwrap_or("-");
-                let app = record
-                    .app_name_id
-                    .and_then(|id| chunk.string_pool.get(id as usize))
-                    .map(|s| s.as_str())
-                    .unwrap_or("-");
-                let proc = record
-                    .procid_id
-                    .and_then(|id| chunk.string_pool.get(id as usize))
-                    .map(|s| s.as_str())
-                    .unwrap_or("-");
-                let msgid = record
-                    .msgid_id
-                    .and_then(|id| chunk.string_pool.get(id as usize))
-                    .map(|s| s.as_str())
-                    .unwrap_or("-");
-                let sd = record
-                    .structured_data_id
-                    .and_then(|id| chunk.string_pool.get(id as usize))
-                    .map(|s| s.as_str())
-                    .unwrap_or("-");
-                let node = record
-                    .node_id_id
-                    .and_then(|id| chunk.string_pool.get(id as usize))
-                    .map(|s| s.as_str())
-                    .unwrap_or("-");
-
-                if let Some(f) = &filter_lower {
-                    let hay = format!(
-                        "{} {} {} {} {} {} {} {}",
-                        host, app, proc, msgid, sd, reconstructed, record.priority, node
-                    )
-                    .to_lowercase();
-                    if !hay.contains(f) {
+                for record in chunk.records() {
+                    if template_id.is_some_and(|tid| record.template_id != Some(tid)) {
                         continue;
                     }
-                }
+                    if let Some(ts) = record.timestamp
+                        && (since.is_some_and(|since| ts < since)
+                            || until.is_some_and(|until| ts > until))
+                    {
+                        continue;
+                    }
+                    let host = record.hostname.unwrap_or("-");
+                    if host_filter.as_ref().is_some_and(|h| h != host) {
+                        continue;
+                    }
+                    if !sd_matches.iter().all(|m| record.sd_matches(m)) {
+                        continue;
+                    }
+                    let app = record.app_name.unwrap_or("-");
+                    let proc = record.procid.unwrap_or("-");
+                    let msgid = record.msgid.unwrap_or("-");
+                    let sd = record.structured_data();
+                    let sd = sd.as_deref().unwrap_or("-");
+                    let node = record.node_id.unwrap_or("-");
+                    let priority = record.priority.unwrap_or_default();
+                    let reconstructed = match record.message() {
+                        Some(message) => message,
+                        None => record.pattern.unwrap_or("UNKNOWN").to_string(),
+                    };
 
-                if let Some(dt) = Utc.timestamp_millis_opt(record.timestamp).earliest() {
-                    let res = if record.is_rfc5424 {
-                        writeln!(
-                            stdout,
-                            "[{}] <{}>1 {} {} {} {} {} [{}] {}",
-                            node,
-                            record.priority,
-                            dt.to_rfc3339(),
-                            host,
-                            app,
-                            proc,
-                            msgid,
-                            sd,
-                            reconstructed
-                        )
-                    } else {
-                        // RFC 3164
-                        writeln!(
-                            stdout,
-                            "[{}] <{}>{} {} {}",
-                            node,
-                            record.priority,
-                            dt.format("%b %d %H:%M:%S"),
-                            host,
-                            reconstructed
+                    if let Some(f) = &filter_lower {
+                        let hay = format!(
+                            "{} {} {} {} {} {} {} {}",
+                            host, app, proc, msgid, sd, reconstructed, priority, node
                         )
-                    };
+                        .to_lowercase();
+                        if !hay.contains(f) {
+                            continue;
+                        }
+                    }
 
-                    if let Err(e) = res {
-                        if e.kind() == io::ErrorKind::BrokenPipe {
-                            return Ok(());
+                    if !count_by.is_empty() {
+                        let key = count_by
+                            .iter()
+                            .map(|k| match k {
+                                CountKey::Template => {
+                                    let id = record.template_id.unwrap_or_default();
+                                    let pattern = record.pattern.unwrap_or("UNKNOWN");
+                                    (id as i64, format!("{} {}", id, pattern))
+                                }
+                                CountKey::Hour => {
+                                    let hour =
+                                        record.timestamp.unwrap_or_default().div_euclid(3_600_000)
+                                            * 3_600_000;
+                                    let label = Utc
+                                        .timestamp_millis_opt(hour)
+                                        .earliest()
+                                        .map_or_else(|| hour.to_string(), |dt| dt.to_rfc3339());
+                                    (hour, label)
+                                }
+                                CountKey::Host => (0, host.to_string()),
+                                CountKey::App => (0, app.to_string()),
+                            })
+                            .collect();
+                        *counts.entry(key).or_default() += 1;
+                        continue;
+                    }
+
+                    if original && let Some(bytes) = record.original() {
+                        if let Err(e) = stdout
+                            .write_all(&bytes)
+                            .and_then(|_| stdout.write_all(b"\n"))
+                        {
+                            if e.kind() == io::ErrorKind::BrokenPipe {
+                                return Ok(());
+                            }
+                            return Err(e.into());
+                        }
+                        continue;
+                    }
+
+                    let timestamp = record.timestamp.unwrap_or_default();
+                    if let Some(dt) = Utc.timestamp_millis_opt(timestamp).earliest() {
+                        let res = if record.is_rfc5424.unwrap_or_default() {
+                            writeln!(
+                                stdout,
+                                "[{}] <{}>1 {} {} {} {} {} [{}] {}",
+                                node,
+                                priority,
+                                dt.to_rfc3339(),
+                                host,
+                                app,
+                                proc,
+                                msgid,
+                                sd,
+                                reconstructed
+                            )
+                        } else {
+                            // RFC 3164
+                            writeln!(
+                                stdout,
+                                "[{}] <{}>{} {} {}",
+                                node,
+                                priority,
+                                dt.format("%b %d %H:%M:%S"),
+                                host,
+                                reconstructed
+                            )
+                        };
+
+                        if let Err(e) = res {
+                            if e.kind() == io::ErrorKind::BrokenPipe {
+                                return Ok(());
+                            }
+                            return Err(e.into());
                         }
-                        return Err(e.into());
                     }
                 }
             }
+
+            for (key, count) in counts {
+                let labels: Vec<String> = key.into_iter().map(|(_, label)| label).collect();
+                if let Err(e) = writeln!(stdout, "{}\t{}", labels.join("\t"), count) {
+                    if e.kind() == io::ErrorKind::BrokenPipe {
+                        return Ok(());
+                    }
+                    return Err(e.into());
+                }
+            }
         }
         Commands::Generate {
             addr,
@@ -312,14 +878,147 @@ async fn main() -> anyhow::Result<()> {
             }
             info!("Generated {} messages to {}", count, addr);
         }
-        Commands::Bench { count, output } => {
+        Commands::Compact {
+            input,
+            chunk_records,
+            merge_templates,
+            merge_similarity,
+            miner,
+        } => {
+            let config = CompactionConfig {
+                chunk_records,
+                miner: miner.config(),
+                ..Default::default()
+            };
+            let input = Path::new(&input);
+            let reports = if input.is_dir() {
+                let mut manifest = Manifest::load(input)?;
+                // Compact everything, including segments compacted before.
+                for segment in &mut manifest.segments {
+                    segment.compacted = false;
+                }
+                let reports = compaction::compact_segments(
+                    input,
+                    &mut manifest,
+                    None,
+                    None,
+                    &config,
+                    keys.as_ref(),
+                )?;
+                manifest.save(input)?;
+                reports
+            } else {
+                vec![compaction::compact_file(
+                    input,
+                    None,
+                    &config,
+                    keys.as_ref(),
+                )?]
+            };
+            for r in &reports {
+                println!(
+                    "{}: {} records, {} -> {} chunks, {} -> {} templates, {} -> {} bytes",
+                    r.path.display(),
+                    r.records,
+                    r.chunks_before,
+                    r.chunks_after,
+                    r.templates_before,
+                    r.templates_after,
+                    r.bytes_before,
+                    r.bytes_after
+                );
+            }
+            if merge_templates {
+                let config = MergeConfig {
+                    similarity: merge_similarity,
+                    miner: miner.config(),
+                    ..Default::default()
+                };
+                let paths: Vec<PathBuf> = reports.iter().map(|r| r.path.clone()).collect();
+                let report = merging::merge_templates(&paths, None, &config, keys.as_ref())?;
+                println!("{}", merge_summary(&report));
+            }
+        }
+        Commands::Migrate { input } => {
+            let input = Path::new(&input);
+            let files = if input.is_dir() {
+                Manifest::load(input)?
+                    .segments
+                    .iter()
+                    .map(|s| input.join(&s.file))
+                    .collect()
+            } else {
+                vec![input.to_path_buf()]
+            };
+            for file in files {
+                let r = migrate::migrate_file(&file, keys.as_ref())?;
+                if r.migrated() {
+                    let sealed = keys
+                        .as_ref()
+                        .map(|k| format!(", encrypted with key {}", k.active_id()))
+                        .unwrap_or_default();
+                    println!(
+                        "{}: version {} -> {}{}, {} chunks, {} records, {} -> {} bytes",
+                        r.path.display(),
+                        r.from_version,
+                        FORMAT_VERSION,
+                        sealed,
+                        r.chunks,
+                        r.records,
+                        r.bytes_before,
+                        r.bytes_after
+                    );
+                } else {
+                    println!("{}: already version {}", r.path.display(), FORMAT_VERSION);
+                }
+            }
+        }
+        Commands::Keygen => {
+            let path = cli
+                .key_file
+                .ok_or_else(|| anyhow::anyhow!("keygen needs --key-file"))?;
+            let id = Keyring::generate(&path)?;
+            println!("Added key {} to {}", id, path.display());
+        }
+        Commands::Fsck { input, repair } => {
+            let report = recovery::fsck(&input, repair, keys.as_ref())?;
+            println!(
+                "{}: {} chunks, {} records",
+                input, report.chunks, report.records
+            );
+            for d in &report.damaged {
+                println!(
+                    "  damaged: {} bytes at offset {} ({})",
+                    d.len, d.offset, d.reason
+                );
+            }
+            if report.index_stale {
+                println!("  index: stale or missing");
+            }
+            if report.repaired {
+                println!("Repaired {}", input);
+            } else if !report.is_clean() {
+                return Err(anyhow::anyhow!(
+                    "{} is damaged; run with --repair to fix it",
+                    input
+                ));
+            } else {
+                println!("OK");
+            }
+        }
+        Commands::Bench {
+            count,
+            output,
+            miner_lines,
+        } => {
             info!("Starting storage benchmark with {} logs...", count);
             let mut raw_size = 0;
             let mut chunk = LogChunk::new();
             let mut total_chunks_saved = 0;
 
-            // Remove existing bench file if any
+            // Remove existing bench file and its index if any
             let _ = std::fs::remove_file(&output);
+            let _ = std::fs::remove_file(ChunkIndex::sidecar_path(&output));
 
             for i in 0..count {
                 let msg_str = format!(
@@ -336,7 +1035,8 @@ async fn main() -> anyhow::Result<()> {
 
                 if (i + 1) % 1000 == 0 {
                     chunk.finish_and_process();
-                    StorageEngine::save_chunk(chunk, &output)?;
+                    let mut encoder = BlockEncoder::default().with_keys(keys.as_ref());
+                    StorageEngine::save_chunk_with(&chunk, &output, &mut encoder)?;
                     chunk = LogChunk::new();
                     total_chunks_saved += 1;
                 }
@@ -344,7 +1044,8 @@ async fn main() -> anyhow::Result<()> {
 
             if !chunk.raw_messages.is_empty() {
                 chunk.finish_and_process();
-                StorageEngine::save_chunk(chunk, &output)?;
+                let mut encoder = BlockEncoder::default().with_keys(keys.as_ref());
+                StorageEngine::save_chunk_with(&chunk, &output, &mut encoder)?;
                 total_chunks_saved += 1;
             }
 
@@ -367,6 +1068,68 @@ async fn main() -> anyhow::Result<()> {
                 (1.0 - (compressed_size as f64 / raw_size as f64)) * 100.0
             );
             println!("Chunks Saved:     {}", total_chunks_saved);
+
+            // Read the file back through both read paths, once in full and
+            // once for a count by template.
+            let full_buffered = time_reads(|| {
+                StorageEngine::iter_chunks(&output, keys.as_ref())?
+                    .try_fold(0, |n, c| Ok(n + c?.records.len()))
+            })?;
+            let full_mapped = time_reads(|| {
+                StorageEngine::iter_chunks_mapped(&output, keys.as_ref())?
+                    .try_fold(0, |n, c| Ok(n + c?.records.len()))
+            })?;
+            let templates = ColumnSet::of(&[Column::TemplateId]);
+            let count = |mode| {
+                time_reads(|| {
+                    StorageEngine::iter_columns(&output, templates, mode, keys.as_ref())?
+                        .try_fold(0, |n, c| Ok(n + c?.len))
+                })
+            };
+            let count_buffered = count(ReadMode::Buffered)?;
+            let count_mapped = count(ReadMode::Mapped)?;
+            println!("\nRead Path           Buffered      Mapped");
+            println!(
+                "Full decode:      {:>8.2} ms {:>8.2} ms",
+                full_buffered, full_mapped
+            );
+            println!(
+                "Template count:   {:>8.2} ms {:>8.2} ms",
+                count_buffered, count_mapped
+            );
+
+            if miner_lines > 0 {
+                let lines: Vec<String> = (0..miner_lines).map(varied_line).collect();
+                let config = MinerConfig::default();
+                let similarity = config.similarity;
+
+                let start = Instant::now();
+                let mut tree = TemplateMiner::new(config);
+                for line in &lines {
+                    let tokens = tree.tokenize(line);
+                    tree.add(&tokens);
+                }
+                let tree_secs = start.elapsed().as_secs_f64();
+
+                let start = Instant::now();
+                let linear = miner::linear_scan(lines.iter().map(String::as_str), similarity);
+                let linear_secs = start.elapsed().as_secs_f64();
+
+                println!(
+                    "\nTemplate Mining ({} lines)   Lines/s   Templates",
+                    miner_lines
+                );
+                println!(
+                    "Prefix tree:            {:>12.0} {:>11}",
+                    miner_lines as f64 / tree_secs,
+                    tree.len()
+                );
+                println!(
+                    "Linear scan:            {:>12.0} {:>11}",
+                    miner_lines as f64 / linear_secs,
+                    linear
+                );
+            }
         }
     }
 